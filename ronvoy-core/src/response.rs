@@ -10,8 +10,7 @@ where
     StatusCode: TryFrom<T>,
     <StatusCode as TryFrom<T>>::Error: Into<axum::http::Error>,
 {
-    // FIXME: YOLO escaping is bad and I feel bad
-    let json_body = format!("{{\"error\": \"{}\"}}", msg.replace("\"", "\\\""));
+    let json_body = serde_json::json!({ "error": msg }).to_string();
     axum::http::Response::builder()
         .status(status)
         .header("Content-Type", "application/json")
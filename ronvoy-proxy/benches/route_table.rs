@@ -0,0 +1,70 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::http::Request;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use envoy_control_plane::envoy::config::route::v3::{
+    route::Action as V3Action, route_action::ClusterSpecifier as V3ClusterSpecifier,
+    route_match::PathSpecifier as V3PathSpecifier, Route as V3Route, RouteAction as V3RouteAction,
+    RouteMatch as V3RouteMatch,
+};
+use ronvoy_proxy::bench::{Route, RouteTable};
+
+const ROUTE_COUNT: usize = 5000;
+
+/// prefix_routes builds `n` routes like `/svc42/`, each to its own cluster.
+fn prefix_routes(n: usize) -> Vec<Arc<Route>> {
+    (0..n)
+        .map(|i| {
+            let route = V3Route {
+                name: format!("svc{}", i),
+                r#match: Some(V3RouteMatch {
+                    path_specifier: Some(V3PathSpecifier::Prefix(format!("/svc{}/", i))),
+                    ..Default::default()
+                }),
+                action: Some(V3Action::Route(V3RouteAction {
+                    cluster_specifier: Some(V3ClusterSpecifier::Cluster(format!("svc{}", i))),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            };
+            Arc::new(Route::try_from(route).unwrap())
+        })
+        .collect()
+}
+
+fn bench_route_matching(c: &mut Criterion) {
+    let routes = prefix_routes(ROUTE_COUNT);
+    let table = RouteTable::new(routes.clone());
+
+    let last = format!("/svc{}/api/users", ROUTE_COUNT - 1);
+    let cases = [
+        ("first", "/svc0/api/users"),
+        ("middle", "/svc2500/api/users"),
+        ("last", last.as_str()),
+        ("miss", "/unrouted/api/users"),
+    ];
+
+    let mut group = c.benchmark_group("route_matching");
+    for (name, path) in cases {
+        let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+        group.bench_with_input(BenchmarkId::new("linear", name), &req, |b, req| {
+            b.iter(|| {
+                routes
+                    .iter()
+                    .find(|route| route.matches(black_box(req)).is_some())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("route_table", name), &req, |b, req| {
+            b.iter(|| table.find(black_box(req)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_route_matching);
+criterion_main!(benches);
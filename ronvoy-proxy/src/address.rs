@@ -2,10 +2,11 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
-use std::net::{AddrParseError, SocketAddr};
+use std::net::{AddrParseError, IpAddr, SocketAddr};
 
 use envoy_control_plane::envoy::config::core::v3::{
     address::Address as V3InnerAddress, socket_address::PortSpecifier, Address as V3Address,
+    CidrRange as V3CidrRange, SocketAddress as V3SocketAddress,
 };
 
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
@@ -20,6 +21,8 @@ pub enum Error {
     PortTooBig(u32),
     #[error("parse error: {0}")]
     Parse(AddrParseError),
+    #[error("prefix length {0} too big for {1}")]
+    PrefixTooLong(u32, IpAddr),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -56,6 +59,61 @@ impl TryFrom<V3Address> for Address {
     }
 }
 
+/// to_v3 converts a socket address to an Envoy (TCP) address, for messages sent
+/// to other services.
+pub fn to_v3(addr: SocketAddr) -> V3Address {
+    V3Address {
+        address: Some(V3InnerAddress::SocketAddress(V3SocketAddress {
+            address: addr.ip().to_string(),
+            port_specifier: Some(PortSpecifier::PortValue(addr.port() as u32)),
+            ..Default::default()
+        })),
+    }
+}
+
+/// CidrRange is an IP address prefix, like `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CidrRange {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+/// ip_bits returns an address as an integer, and its width in bits.
+fn ip_bits(addr: IpAddr) -> (u128, u32) {
+    match addr {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => (u128::from(v6), 128),
+    }
+}
+
+impl CidrRange {
+    /// contains reports whether an address is in the range.  IPv4 addresses are
+    /// never in IPv6 ranges, or vice versa.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (range, width) = ip_bits(self.addr);
+        let (addr, addr_width) = ip_bits(addr);
+        if width != addr_width {
+            return false;
+        }
+        let host_bits = width - self.prefix_len;
+        host_bits == width || range >> host_bits == addr >> host_bits
+    }
+}
+
+impl TryFrom<V3CidrRange> for CidrRange {
+    type Error = Error;
+
+    fn try_from(value: V3CidrRange) -> Result<Self, Self::Error> {
+        let addr: IpAddr = value.address_prefix.parse().map_err(Error::Parse)?;
+        let (_, width) = ip_bits(addr);
+        let prefix_len = value.prefix_len.unwrap_or(0);
+        if prefix_len > width {
+            return Err(Error::PrefixTooLong(prefix_len, addr));
+        }
+        Ok(CidrRange { addr, prefix_len })
+    }
+}
+
 #[test]
 fn test_try_from() {
     use envoy_control_plane::envoy::config::core::v3::{
@@ -109,3 +167,47 @@ fn test_try_from() {
         assert_eq!(expected, &actual);
     }
 }
+
+#[test]
+fn test_cidr_range() {
+    let range = |prefix: &str, prefix_len: u32| {
+        CidrRange::try_from(V3CidrRange {
+            address_prefix: prefix.to_owned(),
+            prefix_len: Some(prefix_len),
+        })
+    };
+    let cases: &[(&str, u32, &str, bool)] = &[
+        ("10.0.0.0", 8, "10.1.2.3", true),
+        ("10.0.0.0", 8, "11.0.0.1", false),
+        ("10.0.0.1", 32, "10.0.0.1", true),
+        ("10.0.0.1", 32, "10.0.0.2", false),
+        ("0.0.0.0", 0, "192.168.1.1", true),
+        ("0.0.0.0", 0, "::1", false),
+        ("2001:db8::", 32, "2001:db8:1::1", true),
+        ("2001:db8::", 32, "2001:db9::1", false),
+        ("2001:db8::1", 128, "2001:db8::1", true),
+        ("2001:db8::1", 128, "2001:db8::2", false),
+        ("::", 0, "2001:db8::1", true),
+        ("::", 0, "10.0.0.1", false),
+    ];
+
+    for (prefix, prefix_len, addr, expected) in cases.iter() {
+        let addr: IpAddr = addr.parse().unwrap();
+        assert_eq!(
+            *expected,
+            range(prefix, *prefix_len).unwrap().contains(addr),
+            "{}/{} {}",
+            prefix,
+            prefix_len,
+            addr
+        );
+    }
+    assert_eq!(
+        Err(Error::PrefixTooLong(33, [10, 0, 0, 0].into())),
+        range("10.0.0.0", 33)
+    );
+    assert_eq!(
+        Err(Error::PrefixTooLong(129, "::".parse().unwrap())),
+        range("::", 129)
+    );
+}
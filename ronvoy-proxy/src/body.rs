@@ -0,0 +1,491 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::http::HeaderMap;
+use hyper::body::HttpBody;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// within awaits `f`, giving up (returning None) after `idle` or at `deadline`, whichever is sooner.
+pub(crate) async fn within<F: Future>(
+    idle: Option<Duration>,
+    deadline: Option<Instant>,
+    f: F,
+) -> Option<F::Output> {
+    let idle_deadline = idle.map(|idle| Instant::now() + idle);
+    let deadline = match (idle_deadline, deadline) {
+        (Some(idle_deadline), Some(deadline)) => Some(idle_deadline.min(deadline)),
+        (idle_deadline, deadline) => idle_deadline.or(deadline),
+    };
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, f).await.ok(),
+        None => Some(f.await),
+    }
+}
+
+/// with_timeouts wraps a body so that the stream is reset if no data arrives for
+/// `idle`, or if it is still streaming at `deadline`.  Trailers are preserved.
+///
+/// Wrapping costs a task per body, so without an idle timeout the deadline is
+/// only enforced on bodies that could otherwise stream forever: ones of unknown
+/// length (like gRPC streams).  Bodies of a known length end by themselves.
+pub(crate) fn with_timeouts(body: Body, idle: Option<Duration>, deadline: Option<Instant>) -> Body {
+    if body.is_end_stream() {
+        return body;
+    }
+    let bounded = body.size_hint().exact().is_some();
+    if idle.is_none() && (deadline.is_none() || bounded) {
+        return body;
+    }
+
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = body;
+        loop {
+            match within(idle, deadline, body.data()).await {
+                Some(Some(Ok(data))) => {
+                    if tx.send_data(data).await.is_err() {
+                        // the downstream went away
+                        return;
+                    }
+                }
+                Some(None) => break,
+                Some(Some(Err(_))) | None => {
+                    tx.abort();
+                    return;
+                }
+            }
+        }
+        match within(idle, deadline, body.trailers()).await {
+            Some(Ok(Some(trailers))) => {
+                let _ = tx.send_trailers(trailers).await;
+            }
+            Some(Ok(None)) => {}
+            Some(Err(_)) | None => tx.abort(),
+        }
+    });
+    rx
+}
+
+/// throttle wraps a body so that it streams at no more than `bytes_per_sec`.
+/// Trailers are preserved.
+pub(crate) fn throttle(body: Body, bytes_per_sec: u64) -> Body {
+    // send a tenth of a second's worth of data at a time
+    let piece = (bytes_per_sec / 10).max(1) as usize;
+
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = body;
+        let start = Instant::now();
+        let mut sent: u64 = 0;
+        while let Some(data) = body.data().await {
+            let mut data = match data {
+                Ok(data) => data,
+                Err(_) => {
+                    tx.abort();
+                    return;
+                }
+            };
+            while !data.is_empty() {
+                let chunk = data.split_to(piece.min(data.len()));
+                tokio::time::sleep_until(
+                    start + Duration::from_secs_f64(sent as f64 / bytes_per_sec as f64),
+                )
+                .await;
+                sent += chunk.len() as u64;
+                if tx.send_data(chunk).await.is_err() {
+                    // the downstream went away
+                    return;
+                }
+            }
+        }
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                let _ = tx.send_trailers(trailers).await;
+            }
+            Ok(None) => {}
+            Err(_) => tx.abort(),
+        }
+    });
+    rx
+}
+
+/// Buffered is a request body that was (possibly) read into memory so it can be replayed.
+pub(crate) enum Buffered {
+    Complete(Bytes, Option<HeaderMap>),
+    /// Streaming is a body that exceeded the buffer limit.  Any data read while
+    /// trying to buffer it is replayed at the front of the stream.
+    Streaming(Body),
+}
+
+/// buffer reads a body (and its trailers) into memory, up to `limit` bytes.
+pub(crate) async fn buffer(mut body: Body, limit: usize) -> Result<Buffered, hyper::Error> {
+    if body.is_end_stream() {
+        return Ok(Buffered::Complete(Bytes::new(), None));
+    }
+    if body.size_hint().lower() > limit as u64 {
+        return Ok(Buffered::Streaming(body));
+    }
+
+    let mut chunks: Vec<Bytes> = Vec::new();
+    let mut len = 0;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        len += chunk.len();
+        chunks.push(chunk);
+        if len > limit {
+            return Ok(Buffered::Streaming(prepend(chunks, body)));
+        }
+    }
+    let trailers = body.trailers().await?;
+
+    if chunks.len() == 1 {
+        return Ok(Buffered::Complete(chunks.pop().unwrap(), trailers));
+    }
+    let mut buf = Vec::with_capacity(len);
+    for chunk in chunks {
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Buffered::Complete(Bytes::from(buf), trailers))
+}
+
+/// replay makes a body of buffered data and trailers.
+pub(crate) fn replay(data: Bytes, trailers: Option<HeaderMap>) -> Body {
+    match trailers {
+        Some(trailers) => prepend_with_trailers(vec![data], Body::empty(), Some(trailers)),
+        None => Body::from(data),
+    }
+}
+
+/// prepend makes a body that streams `head` followed by the rest of `body`,
+/// trailers included.
+fn prepend(head: Vec<Bytes>, body: Body) -> Body {
+    prepend_with_trailers(head, body, None)
+}
+
+/// prepend_with_trailers is prepend for a `body` whose trailers (if any) have
+/// already been read.
+fn prepend_with_trailers(head: Vec<Bytes>, body: Body, trailers: Option<HeaderMap>) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = body;
+        for data in head {
+            if tx.send_data(data).await.is_err() {
+                return;
+            }
+        }
+        while let Some(data) = body.data().await {
+            match data {
+                Ok(data) => {
+                    if tx.send_data(data).await.is_err() {
+                        return;
+                    }
+                }
+                Err(_) => {
+                    tx.abort();
+                    return;
+                }
+            }
+        }
+        let trailers = match trailers {
+            Some(trailers) => Some(trailers),
+            None => match body.trailers().await {
+                Ok(trailers) => trailers,
+                Err(_) => {
+                    tx.abort();
+                    return;
+                }
+            },
+        };
+        if let Some(trailers) = trailers {
+            let _ = tx.send_trailers(trailers).await;
+        }
+    });
+    rx
+}
+
+/// TeeFrame is what the tee task hands on to a copy of a body.
+enum TeeFrame {
+    Data(Bytes),
+    End(Option<HeaderMap>),
+}
+
+/// TeeCopy is the tee task's end of a copy of a body.
+struct TeeCopy {
+    frames: mpsc::UnboundedSender<TeeFrame>,
+    /// behind is how much data has been handed to the copy but not yet sent on.
+    behind: Arc<AtomicUsize>,
+}
+
+impl TeeCopy {
+    /// send hands a frame on to the copy, reporting false if the copy is gone or
+    /// would fall more than `limit` bytes behind.
+    fn send(&self, frame: TeeFrame, limit: usize) -> bool {
+        if let TeeFrame::Data(data) = &frame {
+            if self.behind.fetch_add(data.len(), Ordering::SeqCst) + data.len() > limit {
+                return false;
+            }
+        }
+        self.frames.send(frame).is_ok()
+    }
+}
+
+/// tee streams a body on as it arrives, along with `copies` copies of it.  The
+/// copies never hold the body up: one that falls more than `limit` bytes behind
+/// (or whose reader goes away) is reset, and the body carries on without it.
+/// Trailers are preserved.
+pub(crate) fn tee(body: Body, copies: usize, limit: usize) -> (Body, Vec<Body>) {
+    if copies == 0 {
+        return (body, vec![]);
+    }
+    if body.is_end_stream() {
+        return (body, (0..copies).map(|_| Body::empty()).collect());
+    }
+
+    let mut tee_copies = Vec::with_capacity(copies);
+    let mut copy_bodies = Vec::with_capacity(copies);
+    for _ in 0..copies {
+        let (frames, mut frames_rx) = mpsc::unbounded_channel();
+        let behind = Arc::new(AtomicUsize::new(0));
+        tee_copies.push(TeeCopy {
+            frames,
+            behind: behind.clone(),
+        });
+        let (mut tx, rx) = Body::channel();
+        copy_bodies.push(rx);
+        tokio::spawn(async move {
+            while let Some(frame) = frames_rx.recv().await {
+                match frame {
+                    TeeFrame::Data(data) => {
+                        let len = data.len();
+                        if tx.send_data(data).await.is_err() {
+                            return;
+                        }
+                        behind.fetch_sub(len, Ordering::SeqCst);
+                    }
+                    TeeFrame::End(trailers) => {
+                        if let Some(trailers) = trailers {
+                            let _ = tx.send_trailers(trailers).await;
+                        }
+                        return;
+                    }
+                }
+            }
+            // the tee gave up on the copy before the end of the body
+            tx.abort();
+        });
+    }
+
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = body;
+        let mut copies = tee_copies;
+        while let Some(data) = body.data().await {
+            let data = match data {
+                Ok(data) => data,
+                Err(_) => {
+                    tx.abort();
+                    return;
+                }
+            };
+            copies.retain(|copy| copy.send(TeeFrame::Data(data.clone()), limit));
+            if tx.send_data(data).await.is_err() {
+                return;
+            }
+        }
+        match body.trailers().await {
+            Ok(trailers) => {
+                for copy in copies.iter() {
+                    copy.send(TeeFrame::End(trailers.clone()), limit);
+                }
+                if let Some(trailers) = trailers {
+                    let _ = tx.send_trailers(trailers).await;
+                }
+            }
+            Err(_) => tx.abort(),
+        }
+    });
+    (rx, copy_bodies)
+}
+
+/// read_prefix reads a body until more than `limit` bytes (or all of it) have been
+/// read, returning the data read and a body that replays it followed by the rest
+/// of the stream, trailers included.
+pub(crate) async fn read_prefix(
+    mut body: Body,
+    limit: usize,
+) -> Result<(Bytes, Body), hyper::Error> {
+    let mut buf = Vec::new();
+    while buf.len() <= limit {
+        match body.data().await {
+            Some(chunk) => buf.extend_from_slice(&chunk?),
+            None => {
+                let data = Bytes::from(buf);
+                let trailers = body.trailers().await?;
+                return Ok((data.clone(), replay(data, trailers)));
+            }
+        }
+    }
+    let data = Bytes::from(buf);
+    Ok((data.clone(), prepend(vec![data], body)))
+}
+
+#[tokio::test]
+async fn test_buffer() {
+    let body_with_trailers = |chunks: &[&str]| {
+        let (mut tx, rx) = Body::channel();
+        let chunks: Vec<Bytes> = chunks
+            .iter()
+            .map(|c| Bytes::copy_from_slice(c.as_bytes()))
+            .collect();
+        tokio::spawn(async move {
+            for chunk in chunks {
+                tx.send_data(chunk).await.unwrap();
+            }
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            tx.send_trailers(trailers).await.unwrap();
+        });
+        rx
+    };
+
+    // whether or not a body fits in the buffer (or in read_prefix's limit), its
+    // trailers make it through
+    let cases: &[(&[&str], usize, bool)] = &[
+        (&["hello, ", "world"], 1024, true),
+        (&["hello, ", "world"], 8, false),
+    ];
+    for (chunks, limit, complete) in cases.iter() {
+        let buffered = match buffer(body_with_trailers(chunks), *limit).await.unwrap() {
+            Buffered::Complete(data, trailers) => {
+                assert!(*complete, "{:?}", chunks);
+                replay(data, trailers)
+            }
+            Buffered::Streaming(body) => {
+                assert!(!*complete, "{:?}", chunks);
+                body
+            }
+        };
+        let (prefix, prefixed) = read_prefix(body_with_trailers(chunks), *limit)
+            .await
+            .unwrap();
+        assert_eq!(*complete, prefix.len() <= *limit, "{:?}", chunks);
+
+        for mut body in [buffered, prefixed] {
+            let mut data = Vec::new();
+            while let Some(chunk) = body.data().await {
+                data.extend_from_slice(&chunk.unwrap());
+            }
+            assert_eq!(b"hello, world", &data[..]);
+            let trailers = body.trailers().await.unwrap().unwrap();
+            assert_eq!("0", trailers["grpc-status"]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_tee() {
+    async fn read_all(body: &mut Body) -> Result<(Vec<u8>, Option<HeaderMap>), hyper::Error> {
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok((data, body.trailers().await?))
+    }
+
+    // the body streams on in full while its copies go unread, and the copies get
+    // everything unless they fall too far behind
+    let cases = [(1024, true), (4, false)];
+    for (limit, copied) in cases {
+        let (mut tx, body) = Body::channel();
+        let (mut body, copies) = tee(body, 2, limit);
+        tokio::spawn(async move {
+            for chunk in ["hello", ", ", "world"] {
+                tx.send_data(Bytes::from_static(chunk.as_bytes()))
+                    .await
+                    .unwrap();
+            }
+            let mut trailers = HeaderMap::new();
+            trailers.insert("grpc-status", "0".parse().unwrap());
+            tx.send_trailers(trailers).await.unwrap();
+        });
+
+        let (data, trailers) = read_all(&mut body).await.unwrap();
+        assert_eq!(b"hello, world", &data[..]);
+        assert_eq!("0", trailers.unwrap()["grpc-status"]);
+        for mut copy in copies {
+            match read_all(&mut copy).await {
+                Ok((data, trailers)) => {
+                    assert!(copied, "{}", limit);
+                    assert_eq!(b"hello, world", &data[..]);
+                    assert_eq!("0", trailers.unwrap()["grpc-status"]);
+                }
+                Err(_) => assert!(!copied, "{}", limit),
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_with_timeouts() {
+    let timeout = Duration::from_millis(50);
+    let deadline = |after: Option<Duration>| after.map(|after| Instant::now() + after);
+
+    // bodies that end by themselves are left alone without an idle timeout, as
+    // wrapping them would lose their length
+    let body = with_timeouts(Body::from("hello"), None, deadline(Some(timeout)));
+    assert_eq!(Some(5), body.size_hint().exact());
+    let body = with_timeouts(Body::from("hello"), Some(Duration::from_secs(1)), None);
+    assert_eq!(None, body.size_hint().exact());
+
+    // a stalled stream of unknown length is reset at either timeout, while one
+    // that finishes in time keeps its trailers
+    for (idle, after) in [(None, Some(timeout)), (Some(timeout), None)] {
+        let (mut tx, body) = Body::channel();
+        let mut body = with_timeouts(body, idle, deadline(after));
+        tx.send_data(Bytes::from_static(b"hello")).await.unwrap();
+        assert_eq!(b"hello", &body.data().await.unwrap().unwrap()[..]);
+        assert!(body.data().await.unwrap().is_err(), "{:?}", idle);
+
+        let (mut tx, body) = Body::channel();
+        let mut body = with_timeouts(body, idle, deadline(after));
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        tx.send_trailers(trailers).await.unwrap();
+        drop(tx);
+        assert!(body.data().await.is_none(), "{:?}", idle);
+        let trailers = body.trailers().await.unwrap().unwrap();
+        assert_eq!("0", trailers["grpc-status"]);
+    }
+}
+
+#[tokio::test]
+async fn test_throttle() {
+    // at 1000 bytes per second, data is sent 100 bytes (a tenth of a second's
+    // worth) at a time
+    let mut trailers = HeaderMap::new();
+    trailers.insert("grpc-status", "0".parse().unwrap());
+    let data = Bytes::from(vec![b'x'; 250]);
+    let start = Instant::now();
+    let mut body = throttle(replay(data, Some(trailers)), 1000);
+
+    let mut chunks = Vec::new();
+    while let Some(chunk) = body.data().await {
+        chunks.push((chunk.unwrap().len(), start.elapsed()));
+    }
+    let lens: Vec<usize> = chunks.iter().map(|(len, _)| *len).collect();
+    assert_eq!(vec![100, 100, 50], lens);
+    for (i, (_, elapsed)) in chunks.iter().enumerate() {
+        let paced = Duration::from_millis(100 * i as u64);
+        assert!(*elapsed >= paced, "chunk {} after {:?}", i, elapsed);
+    }
+    let trailers = body.trailers().await.unwrap().unwrap();
+    assert_eq!("0", trailers["grpc-status"]);
+}
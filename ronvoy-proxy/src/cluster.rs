@@ -6,24 +6,38 @@ use std::collections::HashMap;
 use std::convert::Infallible;
 use std::error::Error;
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 use std::task::Poll;
 
 use arc_swap::ArcSwapAny;
-use axum::http::Uri;
+use axum::http::header::HOST;
+use axum::http::{HeaderValue, Uri, Version};
 use envoy_control_plane::envoy::config::cluster::v3::{
     cluster::LbPolicy as V3LbPolicy, Cluster as V3Cluster,
 };
 use envoy_control_plane::envoy::config::endpoint::v3::lb_endpoint::HostIdentifier;
 use envoy_control_plane::envoy::config::endpoint::v3::Endpoint;
-use ronvoy_core::response;
+use envoy_control_plane::envoy::extensions::upstreams::http::v3::{
+    http_protocol_options::explicit_http_config::ProtocolConfig as V3ProtocolConfig,
+    http_protocol_options::UpstreamProtocolOptions as V3UpstreamProtocolOptions,
+    HttpProtocolOptions as V3HttpProtocolOptions,
+};
+use envoy_control_plane::prost::Message;
+use tokio::net::TcpStream;
 
 use crate::address::{self, Address};
+use crate::local_reply::{local_reply, ResponseFlag};
+use crate::{Request, Response};
 
 type Client = hyper::client::Client<hyper::client::HttpConnector>;
 
+/// HTTP_PROTOCOL_OPTIONS is the key of a cluster's upstream HTTP protocol options
+/// in its typed_extension_protocol_options.
+const HTTP_PROTOCOL_OPTIONS: &str = "envoy.extensions.upstreams.http.v3.HttpProtocolOptions";
+
 #[derive(Clone, Debug)]
 pub enum LbPolicy {
     RoundRobin,
@@ -61,15 +75,148 @@ impl TryFrom<V3LbPolicy> for LbPolicy {
 /// Clusters is the updatable set of clusters a Ronvoy instance can route to
 pub type Clusters = ArcSwapAny<Arc<HashMap<String, Arc<Cluster>>>>;
 
+/// Host is a single upstream service instance (endpoint) belonging to a Cluster
+#[derive(Debug, Clone, PartialEq)]
+pub struct Host {
+    pub address: SocketAddr,
+    hostname: String,
+    /// metadata is the endpoint's filter metadata, keyed by filter namespace.
+    metadata: HashMap<String, serde_json::Value>,
+}
+
+impl Host {
+    /// hostname is the endpoint's configured hostname, falling back to its address.
+    /// It is what `auto_host_rewrite` sets the Host header to.
+    pub fn hostname(&self) -> String {
+        if self.hostname.is_empty() {
+            self.address.to_string()
+        } else {
+            self.hostname.clone()
+        }
+    }
+
+    /// metadata looks up a value in the endpoint's metadata: `path` is the filter
+    /// namespace followed by the keys to descend through.
+    pub fn metadata(&self, path: &[String]) -> Option<&serde_json::Value> {
+        let (namespace, keys) = path.split_first()?;
+        let mut value = self.metadata.get(namespace)?;
+        for key in keys {
+            value = value.get(key.as_str())?;
+        }
+        Some(value)
+    }
+}
+
+/// UpstreamFailure is attached to the extensions of the local reply a Cluster
+/// generates when it couldn't get a response from an upstream host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamFailure {
+    ConnectFailure,
+    /// Reset means the connection failed after it was established.
+    Reset,
+}
+
+impl From<UpstreamFailure> for ResponseFlag {
+    fn from(failure: UpstreamFailure) -> Self {
+        match failure {
+            UpstreamFailure::ConnectFailure => ResponseFlag::UpstreamConnectionFailure,
+            UpstreamFailure::Reset => ResponseFlag::UpstreamRemoteReset,
+        }
+    }
+}
+
 /// Cluster proxies requests to a specific set of upstream service instances
 #[allow(dead_code)]
 #[derive(Debug, Default, Clone)]
 pub struct Cluster {
     pub name: String,
     client: Client,
+    /// http2 says whether requests are sent to the upstream hosts over HTTP/2
+    /// (with prior knowledge), whatever protocol the downstream used.
+    http2: bool,
     lb_policy: LbPolicy,
-    endpoints: Arc<Vec<Address>>,
-    off: Arc<AtomicUsize>, // used to index endpoints for round robin LB policy
+    hosts: Arc<Vec<Arc<Host>>>,
+    off: Arc<AtomicUsize>, // used to index hosts for round robin LB policy
+}
+
+impl Cluster {
+    /// choose_host picks the upstream host the next request should be sent to
+    /// according to the cluster's load balancing policy.
+    pub fn choose_host(&self) -> Option<Arc<Host>> {
+        if self.hosts.is_empty() {
+            return None;
+        }
+        let off = self.off.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Some(self.hosts[off % self.hosts.len()].clone())
+    }
+
+    /// send forwards a request to a specific upstream host.
+    pub async fn send(&self, host: &Host, mut req: Request) -> Response {
+        // HTTP/2 requests carry the authority in the URI rather than a Host
+        // header; preserve it since we replace the URI below.
+        if !req.headers().contains_key(HOST) {
+            if let Some(authority) = req.uri().authority() {
+                if let Ok(authority) = HeaderValue::from_str(authority.as_str()) {
+                    req.headers_mut().insert(HOST, authority);
+                }
+            }
+        }
+
+        let path = req.uri().path();
+        let path_query = req
+            .uri()
+            .path_and_query()
+            .map(|v| v.as_str())
+            .unwrap_or(path);
+
+        let uri = format!("http://{}{}", host.address, path_query);
+
+        *req.uri_mut() = Uri::try_from(uri).unwrap();
+        if self.http2 {
+            *req.version_mut() = Version::HTTP_2;
+        }
+
+        match self.client.request(req).await {
+            Ok(resp) => resp,
+            Err(err) => {
+                let failure = if err.is_connect() {
+                    UpstreamFailure::ConnectFailure
+                } else {
+                    UpstreamFailure::Reset
+                };
+                upstream_error(failure, &err)
+            }
+        }
+    }
+
+    /// send_connect forwards a CONNECT request to a specific upstream host.  Unlike
+    /// other requests, it gets a connection of its own, as the tunnel the upstream
+    /// sets up takes the connection over.
+    pub async fn send_connect(&self, host: &Host, req: Request) -> Response {
+        let stream = match TcpStream::connect(host.address).await {
+            Ok(stream) => stream,
+            Err(err) => return upstream_error(UpstreamFailure::ConnectFailure, &err),
+        };
+        let (mut sender, conn) = match hyper::client::conn::handshake(stream).await {
+            Ok(handshake) => handshake,
+            Err(err) => return upstream_error(UpstreamFailure::Reset, &err),
+        };
+        // the connection task hands the connection over to the upgrade on a 2xx response
+        tokio::spawn(conn);
+        match sender.send_request(req).await {
+            Ok(resp) => resp,
+            Err(err) => upstream_error(UpstreamFailure::Reset, &err),
+        }
+    }
+}
+
+/// upstream_error is the local reply for a request we couldn't get a response
+/// to from an upstream host.
+pub fn upstream_error(failure: UpstreamFailure, err: &dyn std::fmt::Display) -> Response {
+    let msg = format!("upstream error: {}", err);
+    let mut resp = local_reply(503, Some(failure.into()), &msg);
+    resp.extensions_mut().insert(failure);
+    resp
 }
 
 impl tower::Service<axum::http::Request<axum::body::Body>> for Cluster {
@@ -84,31 +231,16 @@ impl tower::Service<axum::http::Request<axum::body::Body>> for Cluster {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, mut req: axum::http::Request<axum::body::Body>) -> Self::Future {
-        let off = self.off.clone();
-        let endpoints = self.endpoints.clone();
-        let client = self.client.clone();
+    fn call(&mut self, req: axum::http::Request<axum::body::Body>) -> Self::Future {
+        let cluster = self.clone();
         Box::pin(async move {
-            let off = off.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            let Address::Socket(endpoint) = &endpoints[off % endpoints.len()];
-
-            let path = req.uri().path();
-            let path_query = req
-                .uri()
-                .path_and_query()
-                .map(|v| v.as_str())
-                .unwrap_or(path);
-
-            let uri = format!("http://{}{}", endpoint, path_query);
-
-            *req.uri_mut() = Uri::try_from(uri).unwrap();
-
-            match client.request(req).await {
-                Ok(resp) => Ok(resp),
-                Err(err) => {
-                    let msg = format!("upstream error: {}", err);
-                    Ok(response::json_error(503, &msg))
-                }
+            match cluster.choose_host() {
+                Some(host) => Ok(cluster.send(&host, req).await),
+                None => Ok(local_reply(
+                    503,
+                    Some(ResponseFlag::NoHealthyUpstream),
+                    "no healthy upstream",
+                )),
             }
         })
     }
@@ -123,7 +255,7 @@ impl TryFrom<V3Cluster> for Cluster {
         )?;
 
         let load_assignment = v3_cluster.load_assignment.unwrap_or_default();
-        let endpoints = Arc::new(
+        let hosts = Arc::new(
             load_assignment
                 .endpoints
                 .into_iter()
@@ -134,10 +266,26 @@ impl TryFrom<V3Cluster> for Cluster {
                         .filter_map(|endpoint| {
                             if let Some(HostIdentifier::Endpoint(Endpoint {
                                 address: Some(address),
+                                hostname,
                                 ..
                             })) = endpoint.host_identifier
                             {
-                                address::Address::try_from(address).ok()
+                                let Address::Socket(address) =
+                                    address::Address::try_from(address).ok()?;
+                                let metadata = endpoint
+                                    .metadata
+                                    .map(|metadata| metadata.filter_metadata)
+                                    .unwrap_or_default()
+                                    .into_iter()
+                                    .filter_map(|(namespace, fields)| {
+                                        Some((namespace, serde_json::to_value(fields).ok()?))
+                                    })
+                                    .collect();
+                                Some(Arc::new(Host {
+                                    address,
+                                    hostname,
+                                    metadata,
+                                }))
                             } else {
                                 None
                             }
@@ -146,13 +294,40 @@ impl TryFrom<V3Cluster> for Cluster {
                 .collect(),
         );
 
+        let http2 = match v3_cluster
+            .typed_extension_protocol_options
+            .get(HTTP_PROTOCOL_OPTIONS)
+        {
+            Some(options) => {
+                let options = V3HttpProtocolOptions::decode(&*options.value)?;
+                let protocol_config = match options.upstream_protocol_options {
+                    Some(V3UpstreamProtocolOptions::ExplicitHttpConfig(config)) => {
+                        config.protocol_config
+                    }
+                    _ => None,
+                };
+                matches!(
+                    protocol_config,
+                    Some(V3ProtocolConfig::Http2ProtocolOptions(_))
+                )
+            }
+            // the deprecated way of asking for HTTP/2
+            None => v3_cluster.http2_protocol_options.is_some(),
+        };
+        let client = if http2 {
+            hyper::Client::builder().http2_only(true).build_http()
+        } else {
+            Default::default()
+        };
+
         // TODO: transport socket with TLS client cert
 
         Ok(Cluster {
             name: v3_cluster.name,
-            client: Default::default(),
+            client,
+            http2,
             lb_policy,
-            endpoints,
+            hosts,
             off: Arc::new(Default::default()),
         })
     }
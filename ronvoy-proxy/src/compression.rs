@@ -0,0 +1,549 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::io::{self, Write};
+
+use axum::body::Bytes;
+use envoy_control_plane::envoy::extensions::compression::brotli::compressor::v3::Brotli as V3BrotliCompressor;
+use envoy_control_plane::envoy::extensions::compression::brotli::decompressor::v3::Brotli as V3BrotliDecompressor;
+use envoy_control_plane::envoy::extensions::compression::gzip::compressor::v3::Gzip as V3GzipCompressor;
+use envoy_control_plane::envoy::extensions::compression::gzip::decompressor::v3::Gzip as V3GzipDecompressor;
+use envoy_control_plane::prost::Message;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+/// ZSTD_COMPRESSOR_TYPE_URL and ZSTD_DECOMPRESSOR_TYPE_URL are the type URLs of
+/// Envoy's zstd configs.  Their options aren't supported (configs setting any
+/// are rejected), so only their types are needed.
+const ZSTD_COMPRESSOR_TYPE_URL: &str =
+    "type.googleapis.com/envoy.extensions.compression.zstd.compressor.v3.Zstd";
+const ZSTD_DECOMPRESSOR_TYPE_URL: &str =
+    "type.googleapis.com/envoy.extensions.compression.zstd.decompressor.v3.Zstd";
+
+// Envoy's defaults for brotli, which favour speed over size
+const BROTLI_DEFAULT_QUALITY: u32 = 3;
+const BROTLI_DEFAULT_WINDOW_BITS: u32 = 18;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const ZSTD_DEFAULT_LEVEL: i32 = 3;
+
+/// MAX_INFLATE_RATIO is how many times larger than its input a decoder's output
+/// may be (Envoy's default), which guards against "zip bombs" along with the
+/// maximum size decoders are given.  Streams are always allowed to inflate to
+/// MIN_INFLATE_LIMIT, as short inputs can have high ratios legitimately.
+const MAX_INFLATE_RATIO: u64 = 100;
+const MIN_INFLATE_LIMIT: u64 = 64 * 1024;
+/// INFLATE_SLICE is how much input a decoder is given at once, which bounds how
+/// far past the limit its output can get before it is stopped.
+const INFLATE_SLICE: usize = 1024;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("unsupported compression library {0}")]
+    UnsupportedLibrary(String),
+    #[error("bad {0} config: {1}")]
+    BadConfig(&'static str, String),
+}
+
+/// Compressor is a compression library, configured by one of Envoy's
+/// `envoy.compression.*.compressor` extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compressor {
+    Gzip { level: u32 },
+    Brotli { quality: u32, window_bits: u32 },
+    Zstd { level: i32 },
+}
+
+impl Compressor {
+    /// encoding is the Content-Encoding of what the compressor produces.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Compressor::Gzip { .. } => "gzip",
+            Compressor::Brotli { .. } => "br",
+            Compressor::Zstd { .. } => "zstd",
+        }
+    }
+
+    /// encoder starts compressing a stream.
+    pub fn encoder(&self) -> io::Result<Box<dyn Encoder>> {
+        Ok(match *self {
+            Compressor::Gzip { level } => Box::new(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            )),
+            Compressor::Brotli {
+                quality,
+                window_bits,
+            } => Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                quality,
+                window_bits,
+            )),
+            Compressor::Zstd { level } => {
+                Box::new(zstd::stream::write::Encoder::new(Vec::new(), level)?)
+            }
+        })
+    }
+}
+
+impl TryFrom<&Any> for Compressor {
+    type Error = Error;
+
+    fn try_from(value: &Any) -> Result<Self, Self::Error> {
+        if value.type_url == V3GzipCompressor::default().type_url() {
+            let gzip = V3GzipCompressor::decode(&*value.value)
+                .map_err(|err| Error::BadConfig("gzip", err.to_string()))?;
+            // the levels are 1-9, with 0 meaning zlib's default
+            let level = match gzip.compression_level {
+                0 => flate2::Compression::default().level(),
+                level @ 1..=9 => level as u32,
+                level => {
+                    return Err(Error::BadConfig(
+                        "gzip",
+                        format!("invalid compression_level {}", level),
+                    ))
+                }
+            };
+            Ok(Compressor::Gzip { level })
+        } else if value.type_url == V3BrotliCompressor::default().type_url() {
+            let brotli = V3BrotliCompressor::decode(&*value.value)
+                .map_err(|err| Error::BadConfig("brotli", err.to_string()))?;
+            let quality = brotli.quality.unwrap_or(BROTLI_DEFAULT_QUALITY);
+            let window_bits = brotli.window_bits.unwrap_or(BROTLI_DEFAULT_WINDOW_BITS);
+            if quality > 11 || !(10..=24).contains(&window_bits) {
+                return Err(Error::BadConfig(
+                    "brotli",
+                    "quality must be at most 11, and window_bits 10-24".to_owned(),
+                ));
+            }
+            Ok(Compressor::Brotli {
+                quality,
+                window_bits,
+            })
+        } else if value.type_url == ZSTD_COMPRESSOR_TYPE_URL {
+            // an empty message is one with every option left as the default
+            if !value.value.is_empty() {
+                return Err(Error::BadConfig(
+                    "zstd",
+                    "TODO: options like compression_level aren't supported".to_owned(),
+                ));
+            }
+            Ok(Compressor::Zstd {
+                level: ZSTD_DEFAULT_LEVEL,
+            })
+        } else {
+            Err(Error::UnsupportedLibrary(value.type_url.clone()))
+        }
+    }
+}
+
+/// choose_encoding picks which of the `available` encodings to use for a
+/// downstream that sent `accept_encoding`: the one with the highest q-value,
+/// preferring those listed first when they tie.  It returns None if the
+/// downstream would rather the response wasn't encoded.
+pub fn choose_encoding<'a>(accept_encoding: &str, available: &[&'a str]) -> Option<&'a str> {
+    let mut preferences: Vec<(&str, f32)> = vec![];
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        if coding.is_empty() {
+            continue;
+        }
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        preferences.push((coding, q));
+    }
+    let q_value = |coding: &str| {
+        preferences
+            .iter()
+            .find(|(c, _)| c.eq_ignore_ascii_case(coding))
+            .or_else(|| preferences.iter().find(|(c, _)| *c == "*"))
+            .map(|(_, q)| *q)
+    };
+
+    let mut best: Option<(&str, f32)> = None;
+    for &coding in available {
+        let q = q_value(coding).unwrap_or(0.0);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((coding, q));
+        }
+    }
+    let (coding, q) = best?;
+    // identity is acceptable unless it is explicitly ruled out, but only wins if
+    // the downstream actually prefers it
+    let identity = preferences
+        .iter()
+        .find(|(c, _)| c.eq_ignore_ascii_case("identity"))
+        .map_or(0.0, |(_, q)| *q);
+    if identity > q {
+        None
+    } else {
+        Some(coding)
+    }
+}
+
+/// Encoder compresses a stream a chunk at a time.  Compressed data is returned
+/// as it becomes available, so a stream is never buffered in full.
+pub trait Encoder: Send {
+    /// write compresses a chunk, returning whatever compressed data is ready.
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes>;
+
+    /// finish ends the stream, returning the rest of the compressed data.
+    fn finish(self: Box<Self>) -> io::Result<Bytes>;
+}
+
+impl Encoder for flate2::write::GzEncoder<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        self.write_all(data)?;
+        Ok(std::mem::take(self.get_mut()).into())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        flate2::write::GzEncoder::finish(*self).map(Bytes::from)
+    }
+}
+
+impl Encoder for brotli::CompressorWriter<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        self.write_all(data)?;
+        Ok(std::mem::take(self.get_mut()).into())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        // into_inner ends the brotli stream
+        Ok(self.into_inner().into())
+    }
+}
+
+impl Encoder for zstd::stream::write::Encoder<'static, Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        self.write_all(data)?;
+        Ok(std::mem::take(self.get_mut()).into())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        zstd::stream::write::Encoder::finish(*self).map(Bytes::from)
+    }
+}
+
+/// Decompressor is a decompression library, configured by one of Envoy's
+/// `envoy.compression.*.decompressor` extensions.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decompressor {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Decompressor {
+    /// encoding is the Content-Encoding of what the decompressor decodes.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Decompressor::Gzip => "gzip",
+            Decompressor::Brotli => "br",
+            Decompressor::Zstd => "zstd",
+        }
+    }
+
+    /// decoder starts decompressing a stream, which fails once it has
+    /// decompressed to more than `max_size` bytes.
+    pub fn decoder(&self, max_size: u64) -> io::Result<Box<dyn Decoder>> {
+        let decoder: Box<dyn Decoder> = match self {
+            Decompressor::Gzip => Box::new(flate2::write::GzDecoder::new(Vec::new())),
+            Decompressor::Brotli => Box::new(brotli::DecompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+            )),
+            Decompressor::Zstd => Box::new(zstd::stream::zio::Writer::new(
+                Vec::new(),
+                zstd::stream::raw::Decoder::new()?,
+            )),
+        };
+        Ok(Box::new(InflateLimit {
+            decoder,
+            max_size,
+            read: 0,
+            written: 0,
+        }))
+    }
+}
+
+impl TryFrom<&Any> for Decompressor {
+    type Error = Error;
+
+    fn try_from(value: &Any) -> Result<Self, Self::Error> {
+        // the libraries' tuning options (window sizes and the like) don't apply to
+        // the decoders used here, so the configs are only checked
+        if value.type_url == V3GzipDecompressor::default().type_url() {
+            V3GzipDecompressor::decode(&*value.value)
+                .map_err(|err| Error::BadConfig("gzip", err.to_string()))?;
+            Ok(Decompressor::Gzip)
+        } else if value.type_url == V3BrotliDecompressor::default().type_url() {
+            V3BrotliDecompressor::decode(&*value.value)
+                .map_err(|err| Error::BadConfig("brotli", err.to_string()))?;
+            Ok(Decompressor::Brotli)
+        } else if value.type_url == ZSTD_DECOMPRESSOR_TYPE_URL {
+            Ok(Decompressor::Zstd)
+        } else {
+            Err(Error::UnsupportedLibrary(value.type_url.clone()))
+        }
+    }
+}
+
+/// Decoder decompresses a stream a chunk at a time, returning decompressed data
+/// as it becomes available.
+pub trait Decoder: Send {
+    /// write decompresses a chunk, returning whatever decompressed data is ready.
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes>;
+
+    /// finish ends the stream, returning the rest of the decompressed data.  It
+    /// fails if the stream was cut short.
+    fn finish(self: Box<Self>) -> io::Result<Bytes>;
+}
+
+impl Decoder for flate2::write::GzDecoder<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        self.write_all(data)?;
+        Ok(std::mem::take(self.get_mut()).into())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        flate2::write::GzDecoder::finish(*self).map(Bytes::from)
+    }
+}
+
+impl Decoder for brotli::DecompressorWriter<Vec<u8>> {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        self.write_all(data)?;
+        Ok(std::mem::take(self.get_mut()).into())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        self.into_inner()
+            .map(Bytes::from)
+            .map_err(|_| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated brotli stream"))
+    }
+}
+
+// zstd's Writer is used rather than its write::Decoder, which doesn't say
+// whether the stream ended partway through a frame
+impl Decoder for zstd::stream::zio::Writer<Vec<u8>, zstd::stream::raw::Decoder<'static>> {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        self.write_all(data)?;
+        Ok(std::mem::take(self.get_mut()).into())
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<Bytes> {
+        // fails if the stream was cut short
+        zstd::stream::zio::Writer::finish(&mut *self)?;
+        let (output, _) = self.into_inner();
+        Ok(output.into())
+    }
+}
+
+/// InflateLimit stops a decoder whose output grows past `max_size`, or past
+/// MAX_INFLATE_RATIO times its input.
+struct InflateLimit {
+    decoder: Box<dyn Decoder>,
+    max_size: u64,
+    read: u64,
+    written: u64,
+}
+
+/// check_inflate fails if a decoder's output has grown larger than `max_size`,
+/// or too large for its input.
+fn check_inflate(read: u64, written: u64, max_size: u64) -> io::Result<()> {
+    if written > max_size {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed data exceeds the maximum size",
+        ));
+    }
+    let limit = read
+        .saturating_mul(MAX_INFLATE_RATIO)
+        .max(MIN_INFLATE_LIMIT);
+    if written > limit {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "decompressed data exceeds the maximum inflate ratio",
+        ));
+    }
+    Ok(())
+}
+
+impl Decoder for InflateLimit {
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let mut decoded = Vec::new();
+        for slice in data.chunks(INFLATE_SLICE) {
+            let output = self.decoder.write(slice)?;
+            self.read += slice.len() as u64;
+            self.written += output.len() as u64;
+            check_inflate(self.read, self.written, self.max_size)?;
+            decoded.extend_from_slice(&output);
+        }
+        Ok(decoded.into())
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<Bytes> {
+        let InflateLimit {
+            decoder,
+            max_size,
+            read,
+            written,
+        } = *self;
+        let output = decoder.finish()?;
+        check_inflate(read, written + output.len() as u64, max_size)?;
+        Ok(output)
+    }
+}
+
+#[test]
+fn test_choose_encoding() {
+    let all = &["gzip", "br", "zstd"];
+    let cases: &[(&str, &[&str], Option<&str>)] = &[
+        ("gzip", all, Some("gzip")),
+        ("gzip, br", all, Some("gzip")),
+        ("gzip;q=0.5, br", all, Some("br")),
+        ("GZIP;q=0.5, zstd;q=0.8", all, Some("zstd")),
+        ("*", all, Some("gzip")),
+        ("br;q=0, *;q=0.1", &["br", "zstd"], Some("zstd")),
+        ("br", &["gzip"], None),
+        ("gzip;q=0", all, None),
+        ("identity;q=1, gzip;q=0.5", all, None),
+        ("identity, gzip", all, Some("gzip")),
+        ("", all, None),
+    ];
+
+    for (accept_encoding, available, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            choose_encoding(accept_encoding, available),
+            "{}",
+            accept_encoding
+        );
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let json = br#"{"items":[{"id":1,"name":"alpha"},{"id":2,"name":"beta"}]}"#.repeat(100);
+    let zeros = vec![0u8; 16 * 1024 * 1024];
+    let cases: &[(Compressor, Decompressor)] = &[
+        (Compressor::Gzip { level: 6 }, Decompressor::Gzip),
+        (
+            Compressor::Brotli {
+                quality: BROTLI_DEFAULT_QUALITY,
+                window_bits: BROTLI_DEFAULT_WINDOW_BITS,
+            },
+            Decompressor::Brotli,
+        ),
+        (
+            Compressor::Zstd {
+                level: ZSTD_DEFAULT_LEVEL,
+            },
+            Decompressor::Zstd,
+        ),
+    ];
+
+    let compress = |compressor: &Compressor, data: &[u8]| {
+        let mut encoder = compressor.encoder().unwrap();
+        let mut compressed = Vec::new();
+        // in a few chunks, like a streamed body
+        for chunk in data.chunks(1000) {
+            compressed.extend_from_slice(&encoder.write(chunk).unwrap());
+        }
+        compressed.extend_from_slice(&encoder.finish().unwrap());
+        compressed
+    };
+    let decompress = |decompressor: &Decompressor, data: &[u8], max_size: u64| {
+        let mut decoder = decompressor.decoder(max_size)?;
+        let mut decompressed = Vec::new();
+        for chunk in data.chunks(1000) {
+            decompressed.extend_from_slice(&decoder.write(chunk)?);
+        }
+        decompressed.extend_from_slice(&decoder.finish()?);
+        io::Result::Ok(decompressed)
+    };
+
+    for (compressor, decompressor) in cases.iter() {
+        assert_eq!(compressor.encoding(), decompressor.encoding());
+        let compressed = compress(compressor, &json);
+        assert!(compressed.len() < json.len(), "{:?}", compressor);
+        assert_eq!(
+            json,
+            decompress(decompressor, &compressed, u64::MAX).unwrap(),
+            "{:?}",
+            compressor
+        );
+
+        // a stream that is cut short fails
+        let truncated = &compressed[..compressed.len() - 4];
+        assert!(
+            decompress(decompressor, truncated, u64::MAX).is_err(),
+            "{:?}",
+            compressor
+        );
+
+        // a "zip bomb" is stopped, as is anything decompressing to more than the
+        // maximum size
+        let bomb = compress(compressor, &zeros);
+        let err = decompress(decompressor, &bomb, u64::MAX).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind(), "{:?}", compressor);
+        let max_size = json.len() as u64 - 1;
+        let err = decompress(decompressor, &compressed, max_size).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind(), "{:?}", compressor);
+        assert_eq!(
+            json,
+            decompress(decompressor, &compressed, json.len() as u64).unwrap(),
+            "{:?}",
+            compressor
+        );
+    }
+}
+
+#[test]
+fn test_compressor_try_from() {
+    let any = |type_url: String, value: Vec<u8>| Any { type_url, value };
+    let gzip = |compression_level: i32| {
+        let gzip = V3GzipCompressor {
+            compression_level,
+            ..Default::default()
+        };
+        any(gzip.type_url(), gzip.encode_to_vec())
+    };
+    let zstd = |value: Vec<u8>| any(ZSTD_COMPRESSOR_TYPE_URL.to_owned(), value);
+
+    let cases: Vec<(Any, Result<Compressor, ()>)> = vec![
+        (
+            gzip(0),
+            Ok(Compressor::Gzip {
+                level: flate2::Compression::default().level(),
+            }),
+        ),
+        (gzip(9), Ok(Compressor::Gzip { level: 9 })),
+        (gzip(10), Err(())),
+        (
+            zstd(vec![]),
+            Ok(Compressor::Zstd {
+                level: ZSTD_DEFAULT_LEVEL,
+            }),
+        ),
+        // compression_level 19, which would otherwise be silently ignored
+        (zstd(vec![0x0a, 0x02, 0x08, 0x13]), Err(())),
+        (
+            any("type.googleapis.com/unknown".to_owned(), vec![]),
+            Err(()),
+        ),
+    ];
+
+    for (config, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            Compressor::try_from(config).map_err(|_| ()),
+            "{:?}",
+            config
+        );
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use axum::http::header::{
+    ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+    ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, Method};
+use envoy_control_plane::envoy::config::route::v3::{
+    cors_policy::EnabledSpecifier as V3EnabledSpecifier, CorsPolicy as V3CorsPolicy,
+};
+
+use crate::matcher::{self, header_value, StringMatcher};
+use crate::protobuf::{self, Fraction};
+use crate::{Request, Response};
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("invalid allow_origin_string_match: {0}")]
+    BadOriginMatcher(matcher::Error),
+    #[error("CORS policy's {0} isn't a valid header value")]
+    BadHeaderValue(&'static str),
+}
+
+/// CorsPolicy is a route's (or virtual host's) Cross-Origin Resource Sharing
+/// policy, which the CORS filter enforces: which origins browsers may make
+/// requests from, and with which methods and headers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorsPolicy {
+    allow_origins: Vec<StringMatcher>,
+    allow_methods: Option<HeaderValue>,
+    allow_headers: Option<HeaderValue>,
+    expose_headers: Option<HeaderValue>,
+    max_age: Option<HeaderValue>,
+    allow_credentials: bool,
+    /// enabled is the fraction of requests the policy is enforced for.
+    enabled: Fraction,
+}
+
+/// Cors is what the CORS filter makes of a request.
+#[derive(Debug)]
+pub enum Cors {
+    /// Preflight is a browser checking whether it may make a request, which is
+    /// answered without going to the upstream.
+    Preflight(Response),
+    /// Request is a request from an allowed origin, whose response needs CORS
+    /// headers.
+    Request(HeaderValue),
+}
+
+impl CorsPolicy {
+    /// check decides what to do with a request: None means it either isn't a
+    /// CORS request or isn't from an allowed origin, and is left alone.
+    pub fn check(&self, req: &Request) -> Option<Cors> {
+        if !self.enabled.sample() {
+            return None;
+        }
+        let origin = req.headers().get(ORIGIN)?;
+        let allowed = origin.to_str().map_or(false, |origin| {
+            self.allow_origins
+                .iter()
+                .any(|matcher| matcher.matches(origin))
+        });
+        if !allowed {
+            return None;
+        }
+
+        let preflight = req.method() == Method::OPTIONS
+            && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD);
+        if !preflight {
+            return Some(Cors::Request(origin.clone()));
+        }
+        let mut resp = Response::default();
+        let headers = resp.headers_mut();
+        self.allow_origin(origin, headers);
+        let preflight_headers = [
+            (ACCESS_CONTROL_ALLOW_METHODS, &self.allow_methods),
+            (ACCESS_CONTROL_ALLOW_HEADERS, &self.allow_headers),
+            (ACCESS_CONTROL_MAX_AGE, &self.max_age),
+        ];
+        for (name, value) in preflight_headers {
+            if let Some(value) = value {
+                headers.insert(name, value.clone());
+            }
+        }
+        Some(Cors::Preflight(resp))
+    }
+
+    /// add_response_headers adds the CORS headers for a request from `origin` to
+    /// its response.
+    pub fn add_response_headers(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        self.allow_origin(origin, headers);
+        if let Some(expose_headers) = &self.expose_headers {
+            headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, expose_headers.clone());
+        }
+    }
+
+    fn allow_origin(&self, origin: &HeaderValue, headers: &mut HeaderMap) {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        // the response depends on the origin, which caches need to know
+        let varies = header_value(headers, VARY.as_str()).map_or(false, |vary| {
+            vary.split(',')
+                .any(|v| v.trim() == "*" || v.trim().eq_ignore_ascii_case("origin"))
+        });
+        if !varies {
+            headers.append(VARY, HeaderValue::from_static("Origin"));
+        }
+    }
+}
+
+impl TryFrom<V3CorsPolicy> for CorsPolicy {
+    type Error = Error;
+
+    fn try_from(value: V3CorsPolicy) -> Result<Self, Self::Error> {
+        let header_value = |name: &'static str, value: String| {
+            if value.is_empty() {
+                return Ok(None);
+            }
+            HeaderValue::from_str(&value)
+                .map(Some)
+                .map_err(|_| Error::BadHeaderValue(name))
+        };
+        let enabled = match &value.enabled_specifier {
+            Some(V3EnabledSpecifier::FilterEnabled(enabled)) => protobuf::runtime_fraction(enabled),
+            None => Fraction::ALWAYS,
+        };
+        Ok(CorsPolicy {
+            allow_origins: value
+                .allow_origin_string_match
+                .into_iter()
+                .map(StringMatcher::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(Error::BadOriginMatcher)?,
+            allow_methods: header_value("allow_methods", value.allow_methods)?,
+            allow_headers: header_value("allow_headers", value.allow_headers)?,
+            expose_headers: header_value("expose_headers", value.expose_headers)?,
+            max_age: header_value("max_age", value.max_age)?,
+            allow_credentials: value.allow_credentials.unwrap_or(false),
+            enabled,
+        })
+    }
+}
+
+#[test]
+fn test_check() {
+    use envoy_control_plane::envoy::r#type::matcher::v3::{
+        string_matcher::MatchPattern, StringMatcher as V3StringMatcher,
+    };
+
+    let policy = CorsPolicy::try_from(V3CorsPolicy {
+        allow_origin_string_match: vec![V3StringMatcher {
+            match_pattern: Some(MatchPattern::Suffix(".example.com".to_owned())),
+            ..Default::default()
+        }],
+        allow_methods: "GET, POST".to_owned(),
+        allow_headers: "authorization".to_owned(),
+        max_age: "600".to_owned(),
+        allow_credentials: Some(true),
+        ..Default::default()
+    })
+    .unwrap();
+
+    let req = |method: &str, headers: &[(&str, &str)]| {
+        let mut req = axum::http::Request::builder().method(method).uri("/api");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(axum::body::Body::empty()).unwrap()
+    };
+    let origin = "https://app.example.com";
+    let preflight = || {
+        let mut resp = Response::default();
+        let headers = resp.headers_mut();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_ORIGIN,
+            HeaderValue::from_static(origin),
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+        headers.insert(VARY, HeaderValue::from_static("Origin"));
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static("GET, POST"),
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            HeaderValue::from_static("authorization"),
+        );
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("600"));
+        resp
+    };
+
+    let cases: Vec<(Request, Option<&str>)> = vec![
+        (req("GET", &[]), None),
+        (req("GET", &[("origin", "https://evil.test")]), None),
+        (req("GET", &[("origin", origin)]), Some("request")),
+        (req("OPTIONS", &[("origin", origin)]), Some("request")),
+        (
+            req(
+                "OPTIONS",
+                &[
+                    ("origin", origin),
+                    ("access-control-request-method", "POST"),
+                ],
+            ),
+            Some("preflight"),
+        ),
+    ];
+
+    for (req, expected) in cases.iter() {
+        let cors = policy.check(req);
+        match (*expected, cors) {
+            (None, None) => {}
+            (Some("request"), Some(Cors::Request(value))) => assert_eq!(origin, value),
+            (Some("preflight"), Some(Cors::Preflight(resp))) => {
+                assert_eq!(preflight().headers(), resp.headers())
+            }
+            (expected, cors) => panic!("{:?} {:?}: expected {:?}", req, cors, expected),
+        }
+    }
+}
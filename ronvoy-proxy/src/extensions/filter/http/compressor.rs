@@ -0,0 +1,516 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::http::header::{
+    ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use envoy_control_plane::envoy::extensions::filters::http::compressor::v3::{
+    compressor::CommonDirectionConfig as V3CommonDirectionConfig, Compressor as V3Compressor,
+};
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+use hyper::body::HttpBody;
+
+use super::{
+    decode, BodyFilter, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory,
+};
+use crate::compression::{choose_encoding, Compressor, Encoder};
+use crate::matcher::header_value;
+use crate::protobuf;
+use crate::{Request, Response};
+
+// Envoy's defaults for which responses are worth compressing
+const DEFAULT_MIN_CONTENT_LENGTH: u64 = 30;
+const DEFAULT_CONTENT_TYPES: &[&str] = &[
+    "application/javascript",
+    "application/json",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/css",
+    "text/html",
+    "text/plain",
+    "text/xml",
+];
+
+/// type_url is the type URL of the compressor filter's config.
+pub fn type_url() -> String {
+    V3Compressor::default().type_url()
+}
+
+/// Encodings are the encodings offered by each of a request's compressor filters,
+/// in filter chain order.  The filters share them so that just one of them (the
+/// one whose encoding the downstream prefers) compresses the response.
+#[derive(Debug, Clone, Default)]
+struct Encodings(Arc<Mutex<Vec<&'static str>>>);
+
+/// DirectionConfig decides which requests or responses are compressed.
+#[derive(Debug, Clone, PartialEq)]
+struct DirectionConfig {
+    enabled: bool,
+    min_content_length: u64,
+    content_types: Vec<String>,
+}
+
+impl DirectionConfig {
+    /// should_compress reports whether a message with these headers is worth
+    /// compressing, and isn't already encoded.
+    fn should_compress(&self, headers: &HeaderMap) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let encoded = headers
+            .get(CONTENT_ENCODING)
+            .map_or(false, |v| v.as_bytes() != b"identity");
+        let no_transform = header_value(headers, CACHE_CONTROL.as_str())
+            .map_or(false, |v| v.to_ascii_lowercase().contains("no-transform"));
+        let too_short = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .map_or(false, |len| len < self.min_content_length);
+        // like Envoy, messages without a content type are compressed
+        let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        let compressible = content_type.map_or(true, |content_type| {
+            let media_type = content_type.split(';').next().unwrap_or_default().trim();
+            self.content_types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(media_type))
+        });
+        !encoded && !no_transform && !too_short && compressible
+    }
+}
+
+impl From<Option<V3CommonDirectionConfig>> for DirectionConfig {
+    fn from(value: Option<V3CommonDirectionConfig>) -> Self {
+        let value = value.unwrap_or_default();
+        let content_types = if value.content_type.is_empty() {
+            DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|t| t.to_string())
+                .collect()
+        } else {
+            value.content_type
+        };
+        DirectionConfig {
+            enabled: value
+                .enabled
+                .as_ref()
+                .map_or(true, protobuf::runtime_feature_flag),
+            min_content_length: value
+                .min_content_length
+                .map_or(DEFAULT_MIN_CONTENT_LENGTH, u64::from),
+            content_types,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Config {
+    compressor: Compressor,
+    /// request is how request bodies are compressed, if they are at all.
+    request: Option<DirectionConfig>,
+    response: DirectionConfig,
+    disable_on_etag_header: bool,
+    remove_accept_encoding_header: bool,
+}
+
+impl TryFrom<V3Compressor> for Config {
+    type Error = Error;
+
+    fn try_from(value: V3Compressor) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+        let compressor = value
+            .compressor_library
+            .and_then(|library| library.typed_config)
+            .ok_or_else(|| bad_config("missing compressor_library".to_owned()))
+            .and_then(|library| {
+                Compressor::try_from(&library).map_err(|err| bad_config(err.to_string()))
+            })?;
+        let request = value
+            .request_direction_config
+            .map(|request| DirectionConfig::from(request.common_config));
+        let response = value.response_direction_config.unwrap_or_default();
+        Ok(Config {
+            compressor,
+            request,
+            response: DirectionConfig::from(response.common_config),
+            disable_on_etag_header: response.disable_on_etag_header,
+            remove_accept_encoding_header: response.remove_accept_encoding_header,
+        })
+    }
+}
+
+/// Compress streams a body through an encoder.
+struct Compress(Option<Box<dyn Encoder>>);
+
+impl BodyFilter for Compress {
+    fn data(&mut self, data: Bytes) -> io::Result<Bytes> {
+        match &mut self.0 {
+            Some(encoder) => encoder.write(&data),
+            None => Ok(data),
+        }
+    }
+
+    fn end(&mut self, _trailers: &mut HeaderMap) -> io::Result<Bytes> {
+        match self.0.take() {
+            Some(encoder) => encoder.finish(),
+            None => Ok(Bytes::new()),
+        }
+    }
+}
+
+/// add_vary adds Accept-Encoding to a response's Vary header, so that caches
+/// keep its encoded and unencoded versions apart.
+fn add_vary(headers: &mut HeaderMap) {
+    let varies = header_value(headers, VARY.as_str()).map_or(false, |vary| {
+        vary.split(',').any(|v| {
+            let v = v.trim();
+            v == "*" || v.eq_ignore_ascii_case(ACCEPT_ENCODING.as_str())
+        })
+    });
+    if !varies {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+/// CompressorFilter is `envoy.filters.http.compressor`: it compresses responses
+/// for downstreams that accept its encoding (and, if configured, request bodies
+/// for upstreams).  Bodies are compressed as they stream.
+struct CompressorFilter {
+    config: Arc<Config>,
+    accept_encoding: Option<String>,
+    head: bool,
+    encodings: Encodings,
+    request_encoder: Option<Box<dyn Encoder>>,
+    response_encoder: Option<Box<dyn Encoder>>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for CompressorFilter {
+    async fn decode_headers(
+        &mut self,
+        req: &mut Request,
+        _ctx: &FilterContext<'_>,
+    ) -> FilterStatus {
+        let config = &self.config;
+        let encoding = config.compressor.encoding();
+        self.head = req.method() == Method::HEAD;
+        self.accept_encoding =
+            header_value(req.headers(), ACCEPT_ENCODING.as_str()).map(|v| v.into_owned());
+        if config.remove_accept_encoding_header {
+            req.headers_mut().remove(ACCEPT_ENCODING);
+        }
+        self.encodings = req
+            .extensions()
+            .get::<Encodings>()
+            .cloned()
+            .unwrap_or_default();
+        self.encodings.0.lock().unwrap().push(encoding);
+        req.extensions_mut().insert(self.encodings.clone());
+
+        let compress_request = config.request.as_ref().map_or(false, |request| {
+            !req.body().is_end_stream() && request.should_compress(req.headers())
+        });
+        if compress_request {
+            // if the encoder can't be created, the request is left as it is
+            if let Ok(encoder) = config.compressor.encoder() {
+                let headers = req.headers_mut();
+                headers.remove(CONTENT_LENGTH);
+                headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+                self.request_encoder = Some(encoder);
+            }
+        }
+        FilterStatus::Continue
+    }
+
+    fn decode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        let encoder = self.request_encoder.take()?;
+        Some(Box::new(Compress(Some(encoder))))
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        let config = &self.config;
+        let no_body = self.head
+            || resp.status() == StatusCode::NO_CONTENT
+            || resp.status() == StatusCode::NOT_MODIFIED
+            || resp.body().is_end_stream();
+        if no_body || !config.response.should_compress(resp.headers()) {
+            return;
+        }
+        add_vary(resp.headers_mut());
+        if config.disable_on_etag_header && resp.headers().contains_key(ETAG) {
+            return;
+        }
+
+        let encodings = self.encodings.0.lock().unwrap().clone();
+        let encoding = config.compressor.encoding();
+        let chosen = self
+            .accept_encoding
+            .as_deref()
+            .and_then(|accept_encoding| choose_encoding(accept_encoding, &encodings));
+        if chosen != Some(encoding) {
+            return;
+        }
+        let encoder = match config.compressor.encoder() {
+            Ok(encoder) => encoder,
+            Err(_) => return,
+        };
+
+        let headers = resp.headers_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding));
+        // the compressed body is no longer byte-for-byte what a strong ETag
+        // promises
+        if let Some(etag) = headers.get(ETAG).and_then(|v| v.to_str().ok()) {
+            if !etag.starts_with("W/") {
+                if let Ok(weak) = HeaderValue::from_str(&format!("W/{}", etag)) {
+                    headers.insert(ETAG, weak);
+                }
+            }
+        }
+        self.response_encoder = Some(encoder);
+    }
+
+    fn encode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        let encoder = self.response_encoder.take()?;
+        Some(Box::new(Compress(Some(encoder))))
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(CompressorFilter {
+            config: self.0.clone(),
+            accept_encoding: None,
+            head: false,
+            encodings: Encodings::default(),
+            request_encoder: None,
+            response_encoder: None,
+        })
+    }
+}
+
+/// Factory creates compressor filters.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = Config::try_from(decode::<V3Compressor>(config)?)?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+}
+
+#[test]
+fn test_should_compress() {
+    let enabled = DirectionConfig::from(None);
+    let disabled = DirectionConfig {
+        enabled: false,
+        ..enabled.clone()
+    };
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    };
+    let html = ("content-type", "text/html");
+
+    let cases: Vec<(&DirectionConfig, HeaderMap, bool)> = vec![
+        (&enabled, headers(&[html, ("content-length", "100")]), true),
+        (
+            &enabled,
+            headers(&[("content-type", "text/html; charset=utf-8")]),
+            true,
+        ),
+        // like Envoy, messages without a content type or length are compressed
+        (&enabled, headers(&[]), true),
+        (&enabled, headers(&[("content-type", "image/png")]), false),
+        (&enabled, headers(&[html, ("content-length", "10")]), false),
+        (
+            &enabled,
+            headers(&[html, ("content-encoding", "gzip")]),
+            false,
+        ),
+        (
+            &enabled,
+            headers(&[html, ("content-encoding", "identity")]),
+            true,
+        ),
+        (
+            &enabled,
+            headers(&[html, ("cache-control", "public, No-Transform")]),
+            false,
+        ),
+        (&disabled, headers(&[html]), false),
+    ];
+
+    for (config, headers, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            config.should_compress(headers),
+            "{:?} {:?}",
+            config,
+            headers
+        );
+    }
+}
+
+#[test]
+fn test_add_vary() {
+    let cases: &[(&[&str], &[&str])] = &[
+        (&[], &["Accept-Encoding"]),
+        (&["Origin"], &["Origin", "Accept-Encoding"]),
+        (&["Origin, accept-encoding"], &["Origin, accept-encoding"]),
+        (&["*"], &["*"]),
+    ];
+
+    for (vary, expected) in cases.iter() {
+        let mut headers = HeaderMap::new();
+        for value in vary.iter() {
+            headers.append(VARY, HeaderValue::from_static(value));
+        }
+        add_vary(&mut headers);
+        let actual: Vec<&str> = headers
+            .get_all(VARY)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect();
+        assert_eq!(*expected, &actual[..], "{:?}", vary);
+    }
+}
+
+#[tokio::test]
+async fn test_encode_headers() {
+    use axum::body::Body;
+
+    use crate::compression::Compressor;
+    use crate::testing::test_clusters;
+
+    let config = |disable_on_etag_header: bool| {
+        Arc::new(Config {
+            compressor: Compressor::Gzip { level: 6 },
+            request: None,
+            response: DirectionConfig::from(None),
+            disable_on_etag_header,
+            remove_accept_encoding_header: false,
+        })
+    };
+    let clusters = test_clusters("upstream", ([127, 0, 0, 1], 1).into());
+    let ctx = FilterContext {
+        routed: None,
+        name: "envoy.filters.http.compressor",
+        clusters: &clusters,
+    };
+    let strong = "\"v1\"";
+    let weak = "W/\"v1\"";
+
+    // (request method and Accept-Encoding, response status and ETag,
+    // disable_on_etag_header, and the response's Content-Encoding, ETag and
+    // Vary afterwards)
+    #[allow(clippy::type_complexity)]
+    let cases: &[(
+        &str,
+        &str,
+        u16,
+        Option<&str>,
+        bool,
+        (Option<&str>, Option<&str>, Option<&str>),
+    )] = &[
+        (
+            "GET",
+            "gzip",
+            200,
+            None,
+            false,
+            (Some("gzip"), None, Some("Accept-Encoding")),
+        ),
+        // a compressed body only matches a weak ETag
+        (
+            "GET",
+            "gzip",
+            200,
+            Some(strong),
+            false,
+            (Some("gzip"), Some(weak), Some("Accept-Encoding")),
+        ),
+        (
+            "GET",
+            "gzip",
+            200,
+            Some(weak),
+            false,
+            (Some("gzip"), Some(weak), Some("Accept-Encoding")),
+        ),
+        (
+            "GET",
+            "gzip",
+            200,
+            Some(strong),
+            true,
+            (None, Some(strong), Some("Accept-Encoding")),
+        ),
+        // the response could have been compressed for another downstream
+        (
+            "GET",
+            "br",
+            200,
+            None,
+            false,
+            (None, None, Some("Accept-Encoding")),
+        ),
+        // responses without bodies are left alone
+        (
+            "HEAD",
+            "gzip",
+            200,
+            Some(strong),
+            false,
+            (None, Some(strong), None),
+        ),
+        ("GET", "gzip", 204, None, false, (None, None, None)),
+        (
+            "GET",
+            "gzip",
+            304,
+            Some(strong),
+            false,
+            (None, Some(strong), None),
+        ),
+    ];
+
+    for case in cases.iter() {
+        let (method, accept_encoding, status, etag, disable_on_etag_header, expected) = case;
+        let mut filter = FilterFactory(config(*disable_on_etag_header)).create();
+        let mut req = axum::http::Request::builder()
+            .method(*method)
+            .header(ACCEPT_ENCODING, *accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        filter.decode_headers(&mut req, &ctx).await;
+
+        let mut resp = axum::http::Response::builder()
+            .status(*status)
+            .header(CONTENT_TYPE, "text/plain");
+        if let Some(etag) = etag {
+            resp = resp.header(ETAG, *etag);
+        }
+        let mut resp = resp.body(Body::from("hello, world. ".repeat(10))).unwrap();
+        filter.encode_headers(&mut resp);
+
+        let header = |name| resp.headers().get(name).map(|v| v.to_str().unwrap());
+        let actual = (header(CONTENT_ENCODING), header(ETAG), header(VARY));
+        assert_eq!(*expected, actual, "{:?}", case);
+        let compressed = expected.0.is_some();
+        assert_eq!(compressed, filter.encode_body().is_some(), "{:?}", case);
+    }
+}
@@ -0,0 +1,80 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::Arc;
+
+use axum::http::HeaderValue;
+use envoy_control_plane::envoy::extensions::filters::http::cors::v3::Cors as V3Cors;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{
+    decode, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory,
+};
+use crate::cors::Cors;
+use crate::route::{Action, Route};
+use crate::{Request, Response};
+
+/// type_url is the type URL of the CORS filter's config.
+pub fn type_url() -> String {
+    V3Cors::default().type_url()
+}
+
+/// CorsFilter is `envoy.filters.http.cors`: it enforces the CORS policy of the
+/// request's route (or virtual host), answering preflight requests itself and
+/// adding CORS headers to the responses of requests from allowed origins.
+struct CorsFilter {
+    /// allowed is the route and origin of a request whose response needs CORS
+    /// headers.
+    allowed: Option<(Arc<Route>, HeaderValue)>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for CorsFilter {
+    async fn decode_headers(&mut self, req: &mut Request, ctx: &FilterContext<'_>) -> FilterStatus {
+        let route = match ctx.routed {
+            Some(routed) => &routed.route,
+            None => return FilterStatus::Continue,
+        };
+        let Action::Route(action) = route.action();
+        let cors = action.cors.as_ref().and_then(|policy| policy.check(req));
+        match cors {
+            Some(Cors::Preflight(resp)) => FilterStatus::Respond(resp),
+            Some(Cors::Request(origin)) => {
+                self.allowed = Some((route.clone(), origin));
+                FilterStatus::Continue
+            }
+            None => FilterStatus::Continue,
+        }
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        if let Some((route, origin)) = &self.allowed {
+            let Action::Route(action) = route.action();
+            if let Some(policy) = &action.cors {
+                policy.add_response_headers(origin, resp.headers_mut());
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory;
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(CorsFilter { allowed: None })
+    }
+}
+
+/// Factory creates CORS filters.  The filter has no config of its own: policies
+/// are configured on routes and virtual hosts.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        decode::<V3Cors>(config)?;
+        Ok(Arc::new(FilterFactory))
+    }
+}
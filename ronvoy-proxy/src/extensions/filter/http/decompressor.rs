@@ -0,0 +1,477 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::io;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::http::header::{ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH};
+use axum::http::{HeaderMap, HeaderValue};
+use envoy_control_plane::envoy::extensions::filters::http::decompressor::v3::{
+    decompressor::CommonDirectionConfig as V3CommonDirectionConfig, Decompressor as V3Decompressor,
+};
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{
+    decode, BodyFilter, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory,
+};
+use crate::compression::{choose_encoding, Decoder, Decompressor};
+use crate::matcher::header_value;
+use crate::protobuf;
+use crate::{Request, Response};
+
+/// DEFAULT_MAX_DECOMPRESSED_SIZE is the most a body is decompressed to, unless
+/// the filter's Factory is registered with another limit.
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
+
+/// type_url is the type URL of the decompressor filter's config.
+pub fn type_url() -> String {
+    V3Decompressor::default().type_url()
+}
+
+/// DirectionConfig decides whether requests or responses are decompressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct DirectionConfig {
+    enabled: bool,
+    ignore_no_transform_header: bool,
+}
+
+impl From<Option<V3CommonDirectionConfig>> for DirectionConfig {
+    fn from(value: Option<V3CommonDirectionConfig>) -> Self {
+        let value = value.unwrap_or_default();
+        DirectionConfig {
+            enabled: value
+                .enabled
+                .as_ref()
+                .map_or(true, protobuf::runtime_feature_flag),
+            ignore_no_transform_header: value.ignore_no_transform_header,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Config {
+    decompressor: Decompressor,
+    request: DirectionConfig,
+    response: DirectionConfig,
+    /// advertise_accept_encoding adds the decompressor's encoding to requests'
+    /// Accept-Encoding, so upstreams know they can use it.
+    advertise_accept_encoding: bool,
+    /// max_decompressed_size is the most a body is decompressed to before its
+    /// stream is reset.
+    max_decompressed_size: u64,
+}
+
+impl Config {
+    /// decoder returns a decoder for a message encoded with the decompressor's
+    /// encoding, after removing that encoding from its headers.
+    fn decoder(
+        &self,
+        direction: &DirectionConfig,
+        headers: &mut HeaderMap,
+    ) -> Option<Box<dyn Decoder>> {
+        if !direction.enabled {
+            return None;
+        }
+        let no_transform = header_value(headers, CACHE_CONTROL.as_str())
+            .map_or(false, |v| v.to_ascii_lowercase().contains("no-transform"));
+        if no_transform && !direction.ignore_no_transform_header {
+            return None;
+        }
+
+        // encodings are listed in the order they were applied, so only the last
+        // one can be undone
+        let content_encoding = header_value(headers, CONTENT_ENCODING.as_str())?.into_owned();
+        let mut encodings: Vec<&str> = content_encoding.split(',').map(str::trim).collect();
+        let last = encodings.pop()?;
+        if !last.eq_ignore_ascii_case(self.decompressor.encoding()) {
+            return None;
+        }
+        let decoder = self.decompressor.decoder(self.max_decompressed_size).ok()?;
+
+        headers.remove(CONTENT_LENGTH);
+        let remaining = encodings.join(", ");
+        match HeaderValue::from_str(&remaining) {
+            Ok(value) if !remaining.is_empty() => {
+                headers.insert(CONTENT_ENCODING, value);
+            }
+            _ => {
+                headers.remove(CONTENT_ENCODING);
+            }
+        }
+        Some(decoder)
+    }
+}
+
+impl TryFrom<(V3Decompressor, u64)> for Config {
+    type Error = Error;
+
+    fn try_from(
+        (value, max_decompressed_size): (V3Decompressor, u64),
+    ) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+        let decompressor = value
+            .decompressor_library
+            .and_then(|library| library.typed_config)
+            .ok_or_else(|| bad_config("missing decompressor_library".to_owned()))
+            .and_then(|library| {
+                Decompressor::try_from(&library).map_err(|err| bad_config(err.to_string()))
+            })?;
+        let request = value.request_direction_config.unwrap_or_default();
+        let response = value.response_direction_config.unwrap_or_default();
+        Ok(Config {
+            decompressor,
+            request: DirectionConfig::from(request.common_config),
+            response: DirectionConfig::from(response.common_config),
+            advertise_accept_encoding: request.advertise_accept_encoding.unwrap_or(true),
+            max_decompressed_size,
+        })
+    }
+}
+
+/// Decompress streams a body through a decoder.  If the body can't be decoded
+/// (or inflates too much), the stream is reset.
+struct Decompress(Option<Box<dyn Decoder>>);
+
+impl BodyFilter for Decompress {
+    fn data(&mut self, data: Bytes) -> io::Result<Bytes> {
+        match &mut self.0 {
+            Some(decoder) => decoder.write(&data),
+            None => Ok(data),
+        }
+    }
+
+    fn end(&mut self, _trailers: &mut HeaderMap) -> io::Result<Bytes> {
+        match self.0.take() {
+            Some(decoder) => decoder.finish(),
+            None => Ok(Bytes::new()),
+        }
+    }
+}
+
+/// DecompressorFilter is `envoy.filters.http.decompressor`: it decompresses
+/// request bodies encoded by downstreams, for upstreams that can't handle them,
+/// and responses encoded by upstreams, for downstreams that didn't ask for them.
+struct DecompressorFilter {
+    config: Arc<Config>,
+    /// downstream_accepts is whether the downstream said it accepts the
+    /// decompressor's encoding, in which case responses are passed on as they are.
+    downstream_accepts: bool,
+    request_decoder: Option<Box<dyn Decoder>>,
+    response_decoder: Option<Box<dyn Decoder>>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for DecompressorFilter {
+    async fn decode_headers(
+        &mut self,
+        req: &mut Request,
+        _ctx: &FilterContext<'_>,
+    ) -> FilterStatus {
+        let config = &self.config;
+        let encoding = config.decompressor.encoding();
+        self.request_decoder = config.decoder(&config.request, req.headers_mut());
+
+        let accept_encoding = header_value(req.headers(), ACCEPT_ENCODING.as_str());
+        self.downstream_accepts = accept_encoding
+            .as_deref()
+            .and_then(|accept_encoding| choose_encoding(accept_encoding, &[encoding]))
+            .is_some();
+        if config.response.enabled && config.advertise_accept_encoding && !self.downstream_accepts {
+            let advertised = match accept_encoding {
+                Some(accept_encoding) if !accept_encoding.trim().is_empty() => {
+                    format!("{}, {}", accept_encoding, encoding)
+                }
+                _ => encoding.to_owned(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&advertised) {
+                req.headers_mut().insert(ACCEPT_ENCODING, value);
+            }
+        }
+        FilterStatus::Continue
+    }
+
+    fn decode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        let decoder = self.request_decoder.take()?;
+        Some(Box::new(Decompress(Some(decoder))))
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        if !self.downstream_accepts {
+            self.response_decoder = self
+                .config
+                .decoder(&self.config.response, resp.headers_mut());
+        }
+    }
+
+    fn encode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        let decoder = self.response_decoder.take()?;
+        Some(Box::new(Decompress(Some(decoder))))
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(DecompressorFilter {
+            config: self.0.clone(),
+            downstream_accepts: false,
+            request_decoder: None,
+            response_decoder: None,
+        })
+    }
+}
+
+/// Factory creates decompressor filters, which reset the streams of bodies
+/// that decompress to more than `max_decompressed_size` bytes.  To change the
+/// limit, register a Factory with another one for the decompressor's type_url.
+pub struct Factory {
+    pub max_decompressed_size: u64,
+}
+
+impl Default for Factory {
+    fn default() -> Self {
+        Factory {
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        }
+    }
+}
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = decode::<V3Decompressor>(config)?;
+        let config = Config::try_from((config, self.max_decompressed_size))?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+}
+
+#[test]
+fn test_decoder() {
+    let config = Config {
+        decompressor: Decompressor::Gzip,
+        request: DirectionConfig {
+            enabled: true,
+            ignore_no_transform_header: false,
+        },
+        response: DirectionConfig {
+            enabled: true,
+            ignore_no_transform_header: true,
+        },
+        advertise_accept_encoding: true,
+        max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+    };
+    let disabled = DirectionConfig {
+        enabled: false,
+        ignore_no_transform_header: false,
+    };
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("10"));
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    };
+    let no_transform = ("cache-control", "public, No-Transform");
+
+    // (direction, headers, and the Content-Encoding left if the message is
+    // decoded, or None if it isn't)
+    let cases: Vec<(&DirectionConfig, HeaderMap, Option<Option<&str>>)> = vec![
+        (
+            &config.request,
+            headers(&[("content-encoding", "gzip")]),
+            Some(None),
+        ),
+        (
+            &config.request,
+            headers(&[("content-encoding", "GZIP")]),
+            Some(None),
+        ),
+        // only the last encoding applied can be undone
+        (
+            &config.request,
+            headers(&[("content-encoding", "br, gzip")]),
+            Some(Some("br")),
+        ),
+        (
+            &config.request,
+            headers(&[("content-encoding", "gzip, br")]),
+            None,
+        ),
+        (&config.request, headers(&[]), None),
+        (
+            &config.request,
+            headers(&[("content-encoding", "gzip"), no_transform]),
+            None,
+        ),
+        (
+            &config.response,
+            headers(&[("content-encoding", "gzip"), no_transform]),
+            Some(None),
+        ),
+        (&disabled, headers(&[("content-encoding", "gzip")]), None),
+    ];
+
+    for (direction, headers, expected) in cases.iter() {
+        let mut actual = headers.clone();
+        let decoder = config.decoder(direction, &mut actual);
+        let content_encoding = actual.get(CONTENT_ENCODING).map(|v| v.to_str().unwrap());
+        match expected {
+            Some(expected) => {
+                assert!(decoder.is_some(), "{:?}", headers);
+                assert_eq!(*expected, content_encoding, "{:?}", headers);
+                assert!(!actual.contains_key(CONTENT_LENGTH), "{:?}", headers);
+            }
+            None => {
+                assert!(decoder.is_none(), "{:?}", headers);
+                assert_eq!(headers, &actual);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_accept_encoding() {
+    use axum::body::Body;
+
+    use crate::testing::test_clusters;
+
+    let config = |advertise_accept_encoding: bool| {
+        let enabled = DirectionConfig {
+            enabled: true,
+            ignore_no_transform_header: false,
+        };
+        Arc::new(Config {
+            decompressor: Decompressor::Gzip,
+            request: enabled,
+            response: enabled,
+            advertise_accept_encoding,
+            max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE,
+        })
+    };
+    let clusters = test_clusters("upstream", ([127, 0, 0, 1], 1).into());
+    let ctx = FilterContext {
+        routed: None,
+        name: "envoy.filters.http.decompressor",
+        clusters: &clusters,
+    };
+
+    // (advertise_accept_encoding, the request's Accept-Encoding, what the
+    // upstream is sent, and whether a gzip-encoded response is decoded)
+    let cases: &[(bool, Option<&str>, Option<&str>, bool)] = &[
+        (true, None, Some("gzip"), true),
+        (true, Some("br"), Some("br, gzip"), true),
+        // the downstream can handle the encoded response itself
+        (true, Some("br, gzip"), Some("br, gzip"), false),
+        (false, Some("br"), Some("br"), true),
+        (false, None, None, true),
+    ];
+
+    for case in cases.iter() {
+        let (advertise, accept_encoding, upstream_accept_encoding, decoded) = case;
+        let mut filter = FilterFactory(config(*advertise)).create();
+        let mut req = axum::http::Request::builder();
+        if let Some(accept_encoding) = accept_encoding {
+            req = req.header(ACCEPT_ENCODING, *accept_encoding);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+        filter.decode_headers(&mut req, &ctx).await;
+        assert_eq!(
+            *upstream_accept_encoding,
+            req.headers()
+                .get(ACCEPT_ENCODING)
+                .map(|v| v.to_str().unwrap()),
+            "{:?}",
+            case
+        );
+
+        let mut resp = axum::http::Response::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from("compressed"))
+            .unwrap();
+        filter.encode_headers(&mut resp);
+        assert_eq!(
+            !*decoded,
+            resp.headers().contains_key(CONTENT_ENCODING),
+            "{:?}",
+            case
+        );
+        assert_eq!(*decoded, filter.encode_body().is_some(), "{:?}", case);
+    }
+}
+
+#[tokio::test]
+async fn test_max_decompressed_size() {
+    use std::io::Write;
+
+    use axum::body::Body;
+    use envoy_control_plane::envoy::config::core::v3::TypedExtensionConfig as V3TypedExtensionConfig;
+    use envoy_control_plane::envoy::extensions::compression::gzip::decompressor::v3::Gzip as V3GzipDecompressor;
+    use envoy_control_plane::prost::Message;
+    use hyper::body::HttpBody;
+
+    use super::filter_body;
+    use crate::testing::test_clusters;
+
+    let config = V3Decompressor {
+        decompressor_library: Some(V3TypedExtensionConfig {
+            name: "gzip".to_owned(),
+            typed_config: Some(Any {
+                type_url: V3GzipDecompressor::default().type_url(),
+                value: vec![],
+            }),
+        }),
+        ..Default::default()
+    };
+    let config = Any {
+        type_url: type_url(),
+        value: config.encode_to_vec(),
+    };
+    let factory = Factory {
+        max_decompressed_size: 1024,
+    }
+    .create_filter_factory(&config)
+    .unwrap();
+    let clusters = test_clusters("upstream", ([127, 0, 0, 1], 1).into());
+    let ctx = FilterContext {
+        routed: None,
+        name: "envoy.filters.http.decompressor",
+        clusters: &clusters,
+    };
+
+    // a request body that decompresses to more than the limit has its stream
+    // reset, however well it compresses
+    for (len, allowed) in [(1024, true), (4096, false)] {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&vec![b'x'; len]).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut filter = factory.create();
+        let mut req = axum::http::Request::builder()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Body::from(compressed))
+            .unwrap();
+        filter.decode_headers(&mut req, &ctx).await;
+        let body_filter = filter.decode_body().unwrap();
+        let mut body = filter_body(req.into_body(), body_filter);
+        let mut decoded = Vec::new();
+        let mut reset = false;
+        while let Some(chunk) = body.data().await {
+            match chunk {
+                Ok(chunk) => decoded.extend_from_slice(&chunk),
+                Err(_) => {
+                    reset = true;
+                    break;
+                }
+            }
+        }
+        assert_eq!(!allowed, reset, "{}", len);
+        if allowed {
+            assert_eq!(vec![b'x'; len], decoded);
+        }
+    }
+}
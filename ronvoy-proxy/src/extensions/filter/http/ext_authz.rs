@@ -0,0 +1,1021 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::http::header::{AUTHORIZATION, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use envoy_control_plane::envoy::config::core::v3::{
+    http_uri::HttpUpstreamType as V3HttpUpstreamType, HeaderValue as V3HeaderValue,
+    HeaderValueOption as V3HeaderValueOption,
+};
+use envoy_control_plane::envoy::extensions::filters::http::ext_authz::v3::{
+    ext_authz::Services as V3Services, ext_authz_per_route::Override as V3Override,
+    BufferSettings as V3BufferSettings, ExtAuthz as V3ExtAuthz,
+    ExtAuthzPerRoute as V3ExtAuthzPerRoute, HttpService as V3HttpService,
+};
+use envoy_control_plane::envoy::r#type::matcher::v3::ListStringMatcher as V3ListStringMatcher;
+use envoy_control_plane::envoy::service::auth::v3::{
+    attribute_context::{
+        HttpRequest as V3HttpRequest, Peer as V3Peer, Request as V3AttributeRequest,
+    },
+    check_response::HttpResponse as V3HttpResponse,
+    AttributeContext as V3AttributeContext, CheckRequest as V3CheckRequest,
+    CheckResponse as V3CheckResponse,
+};
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{
+    decode, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory, PerFilterConfig,
+};
+use crate::address;
+use crate::body;
+use crate::cluster::{Clusters, UpstreamFailure};
+use crate::grpc;
+use crate::listener::ConnectionInfo;
+use crate::local_reply::{local_reply, ResponseFlag};
+use crate::matcher::StringMatcher;
+use crate::protobuf;
+use crate::{Request, Response};
+
+/// CHECK_PATH is the authorization service method Ronvoy calls in gRPC mode.
+const CHECK_PATH: &str = "/envoy.service.auth.v3.Authorization/Check";
+
+// how long to wait for the authorization service if the config doesn't say
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// type_url is the type URL of the external authorization filter's config.
+pub fn type_url() -> String {
+    V3ExtAuthz::default().type_url()
+}
+
+#[derive(thiserror::Error, Debug)]
+enum CheckError {
+    #[error(transparent)]
+    Grpc(#[from] grpc::Error),
+    #[error("unknown authorization service cluster {0}")]
+    UnknownCluster(String),
+    #[error("no healthy upstream in authorization service cluster {0}")]
+    NoHealthyUpstream(String),
+    #[error("authorization service call timed out")]
+    Timeout,
+    #[error("authorization service call failed: {0}")]
+    Failed(String),
+}
+
+/// HeaderChanges are the headers an authorization service asked to be set,
+/// appended or removed on a request or response.
+#[derive(Debug, Default)]
+struct HeaderChanges {
+    set: Vec<(HeaderName, HeaderValue)>,
+    append: Vec<(HeaderName, HeaderValue)>,
+    remove: Vec<HeaderName>,
+}
+
+impl HeaderChanges {
+    /// add_options adds headers from a check response.  Unlike elsewhere in
+    /// Envoy's API, headers replace existing ones unless `append` is set.
+    fn add_options(&mut self, options: Vec<V3HeaderValueOption>) {
+        for option in options {
+            let append = option.append.unwrap_or(false);
+            if let Some(header) = option.header.as_ref().and_then(header) {
+                if append {
+                    self.append.push(header);
+                } else {
+                    self.set.push(header);
+                }
+            }
+        }
+    }
+
+    fn apply(self, headers: &mut HeaderMap) {
+        for name in self.remove.iter() {
+            headers.remove(name);
+        }
+        for (name, _) in self.set.iter() {
+            headers.remove(name);
+        }
+        for (name, value) in self.set.into_iter().chain(self.append) {
+            headers.append(name, value);
+        }
+    }
+}
+
+/// header converts a header from a check response, if it is valid.
+fn header(header: &V3HeaderValue) -> Option<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_bytes(header.key.as_bytes()).ok()?;
+    let value = HeaderValue::from_str(&header.value).ok()?;
+    Some((name, value))
+}
+
+/// Check is an authorization service's decision about a request.
+#[derive(Debug)]
+enum Check {
+    /// Allowed requests are forwarded with the `request` header changes, and
+    /// their responses get the `response` header changes.
+    Allowed {
+        request: HeaderChanges,
+        response: HeaderChanges,
+    },
+    /// Denied requests get the authorization service's response instead.
+    Denied(Response),
+}
+
+/// denied_response is the reply to a request the authorization service denied,
+/// with the status, headers and body it asked for.  It isn't a local reply, so
+/// it reaches the downstream as the service made it, but it records why the
+/// request was rejected.
+fn denied_response(status: u16, headers: HeaderChanges, body: Bytes) -> Response {
+    let status = StatusCode::from_u16(status)
+        .ok()
+        .filter(|status| status.is_client_error() || status.is_server_error())
+        .unwrap_or(StatusCode::FORBIDDEN);
+    let mut resp = Response::new(Body::from(body));
+    *resp.status_mut() = status;
+    headers.apply(resp.headers_mut());
+    resp.headers_mut().remove(CONTENT_LENGTH);
+    resp.headers_mut().remove(TRANSFER_ENCODING);
+    resp.extensions_mut()
+        .insert(ResponseFlag::UnauthorizedExternalService);
+    resp
+}
+
+/// matches_any reports whether a header name matches any of `matchers`.
+fn matches_any(matchers: &[StringMatcher], name: &HeaderName) -> bool {
+    matchers
+        .iter()
+        .any(|matcher| matcher.matches(name.as_str()))
+}
+
+fn string_matchers(list: Option<V3ListStringMatcher>) -> Result<Vec<StringMatcher>, String> {
+    list.map(|list| list.patterns)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pattern| StringMatcher::try_from(pattern).map_err(|err| err.to_string()))
+        .collect()
+}
+
+/// HttpService is an authorization service in raw HTTP mode: requests are checked
+/// by sending the authorization service a copy of their headers (and body), and
+/// any response but a 200 denies them.
+#[derive(Debug)]
+struct HttpService {
+    cluster_name: String,
+    timeout: Duration,
+    path_prefix: String,
+    /// allowed_headers are the request headers sent to the authorization service,
+    /// in addition to Host and Authorization.
+    allowed_headers: Vec<StringMatcher>,
+    headers_to_add: Vec<(HeaderName, HeaderValue)>,
+    /// allowed_upstream_headers are the headers of an OK response that are set on
+    /// (or, for allowed_upstream_headers_to_append, appended to) the request.
+    allowed_upstream_headers: Vec<StringMatcher>,
+    allowed_upstream_headers_to_append: Vec<StringMatcher>,
+    /// allowed_client_headers are the headers of a denial that are sent to the
+    /// client; if there are none, all of them are.
+    allowed_client_headers: Vec<StringMatcher>,
+    allowed_client_headers_on_success: Vec<StringMatcher>,
+}
+
+impl HttpService {
+    async fn check(
+        &self,
+        clusters: &Clusters,
+        req: &Request,
+        body: Option<Bytes>,
+    ) -> Result<Check, CheckError> {
+        let cluster = clusters
+            .load()
+            .get(&self.cluster_name)
+            .cloned()
+            .ok_or_else(|| CheckError::UnknownCluster(self.cluster_name.clone()))?;
+        let host = cluster
+            .choose_host()
+            .ok_or_else(|| CheckError::NoHealthyUpstream(self.cluster_name.clone()))?;
+
+        let path_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+        let mut check_req = axum::http::Request::builder()
+            .method(req.method().clone())
+            .uri(format!("{}{}", self.path_prefix, path_query));
+        for (name, value) in req.headers().iter() {
+            if name == HOST || name == AUTHORIZATION || matches_any(&self.allowed_headers, name) {
+                check_req = check_req.header(name, value);
+            }
+        }
+        for (name, value) in self.headers_to_add.iter() {
+            check_req = check_req.header(name, value);
+        }
+        let body = body.unwrap_or_default();
+        let check_req = check_req
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .map_err(|err| CheckError::Failed(err.to_string()))?;
+
+        let resp = tokio::time::timeout(self.timeout, cluster.send(&host, check_req))
+            .await
+            .map_err(|_| CheckError::Timeout)?;
+        if let Some(failure) = resp.extensions().get::<UpstreamFailure>() {
+            return Err(CheckError::Failed(format!("{:?}", failure)));
+        }
+
+        let (parts, body) = resp.into_parts();
+        if parts.status == StatusCode::OK {
+            let mut request = HeaderChanges::default();
+            let mut response = HeaderChanges::default();
+            for (name, value) in parts.headers.iter() {
+                let header = (name.clone(), value.clone());
+                if matches_any(&self.allowed_upstream_headers, name) {
+                    request.set.push(header.clone());
+                }
+                if matches_any(&self.allowed_upstream_headers_to_append, name) {
+                    request.append.push(header.clone());
+                }
+                if matches_any(&self.allowed_client_headers_on_success, name) {
+                    response.set.push(header);
+                }
+            }
+            return Ok(Check::Allowed { request, response });
+        }
+
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|err| CheckError::Failed(err.to_string()))?;
+        let mut headers = HeaderChanges::default();
+        for (name, value) in parts.headers.iter() {
+            let allowed = if self.allowed_client_headers.is_empty() {
+                name != HOST
+            } else {
+                matches_any(&self.allowed_client_headers, name)
+            };
+            if allowed {
+                headers.set.push((name.clone(), value.clone()));
+            }
+        }
+        Ok(Check::Denied(denied_response(
+            parts.status.as_u16(),
+            headers,
+            body,
+        )))
+    }
+}
+
+impl TryFrom<V3HttpService> for HttpService {
+    type Error = String;
+
+    fn try_from(value: V3HttpService) -> Result<Self, Self::Error> {
+        let server_uri = value
+            .server_uri
+            .ok_or_else(|| "http_service is missing its server_uri".to_owned())?;
+        let cluster_name = match server_uri.http_upstream_type {
+            Some(V3HttpUpstreamType::Cluster(cluster)) if !cluster.is_empty() => cluster,
+            _ => return Err("http_service's server_uri is missing its cluster".to_owned()),
+        };
+        let timeout = server_uri
+            .timeout
+            .as_ref()
+            .map(protobuf::duration)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let authorization_request = value.authorization_request.unwrap_or_default();
+        let authorization_response = value.authorization_response.unwrap_or_default();
+        Ok(HttpService {
+            cluster_name,
+            timeout,
+            path_prefix: value.path_prefix,
+            allowed_headers: string_matchers(authorization_request.allowed_headers)?,
+            headers_to_add: authorization_request
+                .headers_to_add
+                .iter()
+                .filter_map(header)
+                .collect(),
+            allowed_upstream_headers: string_matchers(
+                authorization_response.allowed_upstream_headers,
+            )?,
+            allowed_upstream_headers_to_append: string_matchers(
+                authorization_response.allowed_upstream_headers_to_append,
+            )?,
+            allowed_client_headers: string_matchers(authorization_response.allowed_client_headers)?,
+            allowed_client_headers_on_success: string_matchers(
+                authorization_response.allowed_client_headers_on_success,
+            )?,
+        })
+    }
+}
+
+/// check_request builds the gRPC check request describing a request.
+fn check_request(
+    req: &Request,
+    body: Option<Bytes>,
+    pack_as_bytes: bool,
+    context_extensions: HashMap<String, String>,
+) -> V3CheckRequest {
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in req.headers().iter() {
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers
+            .entry(name.as_str().to_owned())
+            .and_modify(|values| {
+                values.push(',');
+                values.push_str(&value);
+            })
+            .or_insert_with(|| value.into_owned());
+    }
+    let path = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().host())
+        .unwrap_or_default();
+    headers.insert(":method".to_owned(), req.method().to_string());
+    headers.insert(":path".to_owned(), path.to_owned());
+    headers.insert(":authority".to_owned(), host.to_owned());
+
+    let size = match &body {
+        Some(body) => body.len() as i64,
+        None => req
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(-1),
+    };
+    let mut http = V3HttpRequest {
+        id: req
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_owned(),
+        method: req.method().to_string(),
+        path: path.to_owned(),
+        host: host.to_owned(),
+        scheme: req.uri().scheme_str().unwrap_or("http").to_owned(),
+        query: req.uri().query().unwrap_or_default().to_owned(),
+        size,
+        protocol: format!("{:?}", req.version()),
+        headers,
+        ..Default::default()
+    };
+    if let Some(body) = body {
+        if pack_as_bytes {
+            http.raw_body = body.to_vec();
+        } else {
+            http.body = String::from_utf8_lossy(&body).into_owned();
+        }
+    }
+
+    let conn = req.extensions().get::<ConnectionInfo>();
+    let peer = |addr: SocketAddr| V3Peer {
+        address: Some(address::to_v3(addr)),
+        ..Default::default()
+    };
+    V3CheckRequest {
+        attributes: Some(V3AttributeContext {
+            source: conn.map(|conn| peer(conn.remote_addr)),
+            destination: conn.map(|conn| peer(conn.local_addr)),
+            request: Some(V3AttributeRequest {
+                time: None,
+                http: Some(http),
+            }),
+            context_extensions,
+            ..Default::default()
+        }),
+    }
+}
+
+/// check_grpc asks an authorization service in gRPC mode about a request.
+async fn check_grpc(
+    client: &grpc::Client,
+    clusters: &Clusters,
+    check_req: V3CheckRequest,
+) -> Result<Check, CheckError> {
+    let resp: V3CheckResponse = client.unary(clusters, CHECK_PATH, &check_req).await?;
+    let code = resp.status.map_or(0, |status| status.code);
+    if code == 0 {
+        let mut request = HeaderChanges::default();
+        let mut response = HeaderChanges::default();
+        if let Some(V3HttpResponse::OkResponse(ok)) = resp.http_response {
+            request.add_options(ok.headers);
+            request.remove = ok
+                .headers_to_remove
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect();
+            response.add_options(ok.response_headers_to_add);
+        }
+        return Ok(Check::Allowed { request, response });
+    }
+
+    let resp = match resp.http_response {
+        Some(V3HttpResponse::DeniedResponse(denied)) => {
+            let mut headers = HeaderChanges::default();
+            headers.add_options(denied.headers);
+            let status = denied.status.map_or(0, |status| status.code);
+            denied_response(status as u16, headers, Bytes::from(denied.body))
+        }
+        _ => denied_response(0, HeaderChanges::default(), Bytes::new()),
+    };
+    Ok(Check::Denied(resp))
+}
+
+#[derive(Debug)]
+enum Service {
+    Grpc(grpc::Client),
+    Http(HttpService),
+}
+
+/// BufferSettings are how much of a request's body is sent to the authorization
+/// service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BufferSettings {
+    max_request_bytes: usize,
+    /// allow_partial_message sends the first max_request_bytes of larger bodies,
+    /// rather than rejecting their requests with a 413.
+    allow_partial_message: bool,
+    pack_as_bytes: bool,
+}
+
+impl From<V3BufferSettings> for BufferSettings {
+    fn from(value: V3BufferSettings) -> Self {
+        BufferSettings {
+            max_request_bytes: value.max_request_bytes as usize,
+            allow_partial_message: value.allow_partial_message,
+            pack_as_bytes: value.pack_as_bytes,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Config {
+    service: Service,
+    /// failure_mode_allow lets requests through if the authorization service
+    /// can't be reached (or fails), rather than rejecting them.
+    failure_mode_allow: bool,
+    with_request_body: Option<BufferSettings>,
+    status_on_error: u16,
+}
+
+impl TryFrom<V3ExtAuthz> for Config {
+    type Error = Error;
+
+    fn try_from(value: V3ExtAuthz) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+
+        let service = match value.services {
+            Some(V3Services::GrpcService(grpc_service)) => {
+                let timeout = grpc_service
+                    .timeout
+                    .as_ref()
+                    .map(protobuf::duration)
+                    .unwrap_or(DEFAULT_TIMEOUT);
+                let client = grpc::Client::try_from(grpc_service)
+                    .map_err(|err| bad_config(err.to_string()))?;
+                Service::Grpc(client.with_timeout(timeout))
+            }
+            Some(V3Services::HttpService(http_service)) => {
+                Service::Http(HttpService::try_from(http_service).map_err(bad_config)?)
+            }
+            None => {
+                return Err(bad_config(
+                    "missing grpc_service or http_service".to_owned(),
+                ))
+            }
+        };
+        let status_on_error = match value.status_on_error {
+            Some(status) if (400..600).contains(&status.code) => status.code as u16,
+            _ => StatusCode::FORBIDDEN.as_u16(),
+        };
+        Ok(Config {
+            service,
+            failure_mode_allow: value.failure_mode_allow,
+            with_request_body: value.with_request_body.map(BufferSettings::from),
+            status_on_error,
+        })
+    }
+}
+
+impl Config {
+    /// check asks the authorization service about a request.
+    async fn check(
+        &self,
+        clusters: &Clusters,
+        req: &Request,
+        body: Option<Bytes>,
+        context_extensions: HashMap<String, String>,
+    ) -> Result<Check, CheckError> {
+        match &self.service {
+            Service::Grpc(client) => {
+                let pack_as_bytes = self
+                    .with_request_body
+                    .map_or(false, |settings| settings.pack_as_bytes);
+                let check_req = check_request(req, body, pack_as_bytes, context_extensions);
+                check_grpc(client, clusters, check_req).await
+            }
+            Service::Http(http_service) => http_service.check(clusters, req, body).await,
+        }
+    }
+}
+
+/// PerRoute is a route's override of the external authorization filter.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct PerRoute {
+    disabled: bool,
+    /// context_extensions are passed to the authorization service (in gRPC mode)
+    /// with requests using the route.
+    context_extensions: HashMap<String, String>,
+    disable_request_body_buffering: bool,
+}
+
+impl TryFrom<V3ExtAuthzPerRoute> for PerRoute {
+    type Error = Error;
+
+    fn try_from(value: V3ExtAuthzPerRoute) -> Result<Self, Self::Error> {
+        match value.r#override {
+            Some(V3Override::Disabled(true)) => Ok(PerRoute {
+                disabled: true,
+                ..Default::default()
+            }),
+            Some(V3Override::CheckSettings(settings)) => Ok(PerRoute {
+                disabled: false,
+                context_extensions: settings.context_extensions,
+                disable_request_body_buffering: settings.disable_request_body_buffering,
+            }),
+            _ => Err(Error::BadConfig(
+                V3ExtAuthzPerRoute::default().type_url(),
+                "expected disabled or check_settings".to_owned(),
+            )),
+        }
+    }
+}
+
+/// ExtAuthz is `envoy.filters.http.ext_authz`: it asks an external authorization
+/// service whether each request is allowed, forwarding it (with any headers the
+/// service adds) if so, and replying with the service's denial if not.
+#[derive(Debug)]
+struct ExtAuthz {
+    config: Arc<Config>,
+    response_headers: HeaderChanges,
+}
+
+impl ExtAuthz {
+    /// buffer_body reads (up to the configured limit of) a request's body for the
+    /// authorization service, leaving the request with a body that replays it.
+    async fn buffer_body(req: &mut Request, settings: &BufferSettings) -> Result<Bytes, Response> {
+        let body = std::mem::take(req.body_mut());
+        let (data, body) = body::read_prefix(body, settings.max_request_bytes)
+            .await
+            .map_err(|_| {
+                local_reply(
+                    400,
+                    Some(ResponseFlag::DownstreamProtocolError),
+                    "ext_authz_error",
+                )
+            })?;
+        *req.body_mut() = body;
+        if data.len() <= settings.max_request_bytes {
+            Ok(data)
+        } else if settings.allow_partial_message {
+            Ok(data.slice(..settings.max_request_bytes))
+        } else {
+            Err(local_reply(413, None, "request_payload_too_large"))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl HttpFilter for ExtAuthz {
+    async fn decode_headers(&mut self, req: &mut Request, ctx: &FilterContext<'_>) -> FilterStatus {
+        let per_route = ctx.per_filter_config::<PerRoute>();
+        if per_route.map_or(false, |per_route| per_route.disabled) {
+            return FilterStatus::Continue;
+        }
+        let config = self.config.clone();
+
+        let buffer = config.with_request_body.filter(|_| {
+            !per_route.map_or(false, |per_route| per_route.disable_request_body_buffering)
+        });
+        let body = match buffer {
+            Some(settings) => match ExtAuthz::buffer_body(req, &settings).await {
+                Ok(body) => Some(body),
+                Err(resp) => return FilterStatus::Respond(resp),
+            },
+            None => None,
+        };
+        let context_extensions = per_route
+            .map(|per_route| per_route.context_extensions.clone())
+            .unwrap_or_default();
+
+        match config
+            .check(ctx.clusters(), req, body, context_extensions)
+            .await
+        {
+            Ok(Check::Allowed { request, response }) => {
+                request.apply(req.headers_mut());
+                self.response_headers = response;
+                FilterStatus::Continue
+            }
+            Ok(Check::Denied(resp)) => FilterStatus::Respond(resp),
+            Err(_) if config.failure_mode_allow => FilterStatus::Continue,
+            Err(_) => FilterStatus::Respond(local_reply(
+                config.status_on_error,
+                Some(ResponseFlag::UnauthorizedExternalService),
+                "ext_authz_error",
+            )),
+        }
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        std::mem::take(&mut self.response_headers).apply(resp.headers_mut());
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(ExtAuthz {
+            config: self.0.clone(),
+            response_headers: Default::default(),
+        })
+    }
+}
+
+/// Factory creates external authorization filters, and their per-route configs.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = Config::try_from(decode::<V3ExtAuthz>(config)?)?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+
+    fn create_per_filter_config(&self, config: &Any) -> Result<Arc<dyn PerFilterConfig>, Error> {
+        let per_route = PerRoute::try_from(decode::<V3ExtAuthzPerRoute>(config)?)?;
+        Ok(Arc::new(per_route))
+    }
+}
+
+#[tokio::test]
+async fn test_grpc_check() {
+    use crate::testing::{test_clusters, TestAuthorizationServer};
+
+    let authorizer = TestAuthorizationServer::new();
+    let clusters = test_clusters("ext_authz", authorizer.addr);
+    let config = Config {
+        service: Service::Grpc(grpc::Client::new(
+            "ext_authz".to_owned(),
+            Some(Duration::from_secs(5)),
+        )),
+        failure_mode_allow: false,
+        with_request_body: None,
+        status_on_error: 403,
+    };
+
+    // (Authorization header, the x-user header the request is forwarded with, or
+    // the status and body of its denial)
+    let cases: &[(&str, Result<&str, (u16, &str)>)] = &[
+        ("Bearer alice", Ok("alice")),
+        ("Bearer mallory", Err((401, "denied"))),
+        ("", Err((401, "denied"))),
+    ];
+
+    for (authorization, expected) in cases.iter() {
+        let mut req = axum::http::Request::builder()
+            .uri("/private")
+            .header(HOST, "example.com")
+            .header("x-user", "spoofed");
+        if !authorization.is_empty() {
+            req = req.header(AUTHORIZATION, *authorization);
+        }
+        let mut req = req.body(Body::empty()).unwrap();
+
+        let check = config
+            .check(&clusters, &req, None, HashMap::new())
+            .await
+            .unwrap();
+        match (check, expected) {
+            (Check::Allowed { request, .. }, Ok(user)) => {
+                request.apply(req.headers_mut());
+                let users: Vec<&str> = req
+                    .headers()
+                    .get_all("x-user")
+                    .iter()
+                    .map(|v| v.to_str().unwrap())
+                    .collect();
+                assert_eq!(vec![*user], users, "{}", authorization);
+            }
+            (Check::Denied(resp), Err((status, body))) => {
+                assert_eq!(*status, resp.status().as_u16(), "{}", authorization);
+                let resp_body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                assert_eq!(body.as_bytes(), &resp_body[..], "{}", authorization);
+            }
+            (check, _) => panic!("{}: unexpected {:?}", authorization, check),
+        }
+    }
+
+    // with the authorizer gone, requests fail the check
+    let clusters = test_clusters("ext_authz", ([127, 0, 0, 1], 1).into());
+    let req = axum::http::Request::new(Body::empty());
+    assert!(config
+        .check(&clusters, &req, None, HashMap::new())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn test_http_service() {
+    use axum::http::Uri;
+    use axum::routing::any;
+    use envoy_control_plane::envoy::config::core::v3::HttpUri as V3HttpUri;
+    use envoy_control_plane::envoy::config::route::v3::route::Action as V3Action;
+    use envoy_control_plane::envoy::extensions::filters::http::ext_authz::v3::{
+        AuthorizationRequest as V3AuthorizationRequest,
+        AuthorizationResponse as V3AuthorizationResponse,
+    };
+    use envoy_control_plane::envoy::r#type::matcher::v3::{
+        string_matcher::MatchPattern, StringMatcher as V3StringMatcher,
+    };
+    use envoy_control_plane::prost::Message;
+    use envoy_control_plane::prost_wkt_types::Duration as PbDuration;
+
+    use crate::local_reply::LocalReply;
+    use crate::testing::{test_clusters, test_http_conn_mgr, test_route, TestHttpServer};
+
+    // the authorization service allows alice, telling the upstream what it
+    // checked with x-checked, and denies anyone else.
+    let authorize = |uri: Uri, headers: HeaderMap, body: Bytes| async move {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .map_or("-", |value| value.to_str().unwrap())
+                .to_owned()
+        };
+        let resp = axum::http::Response::builder();
+        let resp = if header("authorization") == "Bearer alice" {
+            let checked = format!(
+                "{} {} {} {} {}",
+                uri.path(),
+                header("x-tenant"),
+                header("x-secret"),
+                header("x-authz-client"),
+                String::from_utf8_lossy(&body)
+            );
+            resp.header("x-checked", checked)
+                .header("x-user", "alice")
+                .header("x-tag", "authz")
+                .header("x-greeting", "hello")
+                .header("x-internal", "secret")
+                .body(Body::empty())
+        } else {
+            resp.status(401)
+                .header("www-authenticate", "Bearer")
+                .header("x-internal", "secret")
+                .body(Body::from("denied"))
+        };
+        resp.unwrap()
+    };
+    let authorizer =
+        TestHttpServer::with_router(axum::Router::new().route("/check/private", any(authorize)));
+    let up = test_clusters("ext_authz", authorizer.addr);
+    let down = test_clusters("ext_authz", ([127, 0, 0, 1], 1).into());
+
+    let mut public = test_route("public", "/public", "ext_authz");
+    let disabled = V3ExtAuthzPerRoute {
+        r#override: Some(V3Override::Disabled(true)),
+    };
+    public.typed_per_filter_config = HashMap::from([(
+        "envoy.filters.http.ext_authz".to_owned(),
+        Any {
+            type_url: disabled.type_url(),
+            value: disabled.encode_to_vec(),
+        },
+    )]);
+    let http_conn_mgr = test_http_conn_mgr(
+        vec![public, test_route("private", "/private", "ext_authz")],
+        test_clusters("ext_authz", authorizer.addr),
+    );
+
+    let matchers = |names: &[&str]| {
+        Some(V3ListStringMatcher {
+            patterns: names
+                .iter()
+                .map(|name| V3StringMatcher {
+                    match_pattern: Some(MatchPattern::Exact(name.to_string())),
+                    ..Default::default()
+                })
+                .collect(),
+        })
+    };
+    let http_service = V3HttpService {
+        server_uri: Some(V3HttpUri {
+            uri: "http://ext-authz".to_owned(),
+            http_upstream_type: Some(V3HttpUpstreamType::Cluster("ext_authz".to_owned())),
+            timeout: Some(PbDuration {
+                seconds: 5,
+                nanos: 0,
+            }),
+        }),
+        path_prefix: "/check".to_owned(),
+        authorization_request: Some(V3AuthorizationRequest {
+            allowed_headers: matchers(&["x-tenant"]),
+            headers_to_add: vec![V3HeaderValue {
+                key: "x-authz-client".to_owned(),
+                value: "ronvoy".to_owned(),
+            }],
+        }),
+        authorization_response: Some(V3AuthorizationResponse {
+            allowed_upstream_headers: matchers(&["x-checked", "x-user"]),
+            allowed_upstream_headers_to_append: matchers(&["x-tag"]),
+            allowed_client_headers: matchers(&["www-authenticate"]),
+            allowed_client_headers_on_success: matchers(&["x-greeting"]),
+            ..Default::default()
+        }),
+    };
+    let config = |failure_mode_allow: bool, with_request_body: Option<(u32, bool)>| {
+        let config = Config::try_from(V3ExtAuthz {
+            services: Some(V3Services::HttpService(http_service.clone())),
+            failure_mode_allow,
+            with_request_body: with_request_body.map(|(max_request_bytes, allow_partial)| {
+                V3BufferSettings {
+                    max_request_bytes,
+                    allow_partial_message: allow_partial,
+                    ..Default::default()
+                }
+            }),
+            ..Default::default()
+        });
+        Arc::new(config.unwrap())
+    };
+
+    // (path, Authorization header, request body, whether the authorization
+    // service is up, failure_mode_allow, with_request_body's max_request_bytes
+    // and allow_partial_message, and what the upstream is told was checked, if
+    // anything, or the status and body of the response to the downstream)
+    type Expected = Result<Option<&'static str>, (u16, Option<&'static str>)>;
+    #[allow(clippy::type_complexity)]
+    let cases: &[(&str, &str, &str, bool, bool, Option<(u32, bool)>, Expected)] = &[
+        (
+            "/private",
+            "Bearer alice",
+            "hello",
+            true,
+            false,
+            None,
+            Ok(Some("/check/private acme - ronvoy ")),
+        ),
+        (
+            "/private",
+            "",
+            "",
+            true,
+            false,
+            None,
+            Err((401, Some("denied"))),
+        ),
+        // the route disables the filter
+        ("/public", "", "", true, false, None, Ok(None)),
+        (
+            "/private",
+            "Bearer alice",
+            "hello",
+            true,
+            false,
+            Some((8, false)),
+            Ok(Some("/check/private acme - ronvoy hello")),
+        ),
+        (
+            "/private",
+            "Bearer alice",
+            "hello, world",
+            true,
+            false,
+            Some((8, false)),
+            Err((413, None)),
+        ),
+        (
+            "/private",
+            "Bearer alice",
+            "hello, world",
+            true,
+            false,
+            Some((8, true)),
+            Ok(Some("/check/private acme - ronvoy hello, w")),
+        ),
+        (
+            "/private",
+            "Bearer alice",
+            "",
+            false,
+            false,
+            None,
+            Err((403, None)),
+        ),
+        ("/private", "Bearer alice", "", false, true, None, Ok(None)),
+    ];
+
+    for case in cases.iter() {
+        let (
+            path,
+            authorization,
+            body,
+            service_up,
+            failure_mode_allow,
+            with_request_body,
+            expected,
+        ) = case;
+        let mut req = axum::http::Request::builder()
+            .uri(*path)
+            .header(HOST, "example.com")
+            .header("x-tenant", "acme")
+            .header("x-secret", "s3cret")
+            .header("x-user", "spoofed")
+            .header("x-tag", "client");
+        if !authorization.is_empty() {
+            req = req.header(AUTHORIZATION, *authorization);
+        }
+        let mut req = req.body(Body::from(*body)).unwrap();
+        let routed = http_conn_mgr.get_cluster(&req);
+        let ctx = FilterContext {
+            routed: routed.as_ref(),
+            name: "envoy.filters.http.ext_authz",
+            clusters: if *service_up { &up } else { &down },
+        };
+        let mut filter = ExtAuthz {
+            config: config(*failure_mode_allow, *with_request_body),
+            response_headers: Default::default(),
+        };
+        let msg = format!("{:?}", case);
+
+        let status = filter.decode_headers(&mut req, &ctx).await;
+        let values = |headers: &HeaderMap, name: &str| -> Vec<String> {
+            headers
+                .get_all(name)
+                .iter()
+                .map(|v| v.to_str().unwrap().to_owned())
+                .collect()
+        };
+        match (status, expected) {
+            (FilterStatus::Continue, Ok(checked)) => {
+                // the request body still makes it upstream, whatever was checked
+                let req_body = hyper::body::to_bytes(std::mem::take(req.body_mut()))
+                    .await
+                    .unwrap();
+                assert_eq!(body.as_bytes(), &req_body[..], "{}", msg);
+                let headers = req.headers();
+                assert_eq!(
+                    *checked,
+                    headers.get("x-checked").map(|v| v.to_str().unwrap()),
+                    "{}",
+                    msg
+                );
+                if checked.is_none() {
+                    continue;
+                }
+                assert_eq!(vec!["alice"], values(headers, "x-user"), "{}", msg);
+                assert_eq!(vec!["client", "authz"], values(headers, "x-tag"), "{}", msg);
+                let mut resp = Response::default();
+                filter.encode_headers(&mut resp);
+                assert_eq!(
+                    vec!["hello"],
+                    values(resp.headers(), "x-greeting"),
+                    "{}",
+                    msg
+                );
+                assert!(!resp.headers().contains_key("x-internal"), "{}", msg);
+            }
+            (FilterStatus::Respond(resp), Err((status, body))) => {
+                assert_eq!(*status, resp.status().as_u16(), "{}", msg);
+                let denied = resp.status() == StatusCode::UNAUTHORIZED;
+                if denied {
+                    // a denial is sent as the service made it, only flagged
+                    assert_eq!(
+                        Some(&ResponseFlag::UnauthorizedExternalService),
+                        resp.extensions().get::<ResponseFlag>(),
+                        "{}",
+                        msg
+                    );
+                    assert!(resp.extensions().get::<LocalReply>().is_none(), "{}", msg);
+                    assert_eq!(
+                        vec!["Bearer"],
+                        values(resp.headers(), "www-authenticate"),
+                        "{}",
+                        msg
+                    );
+                    assert!(!resp.headers().contains_key("x-internal"), "{}", msg);
+                }
+                let resp_body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+                if let Some(body) = body {
+                    assert_eq!(body.as_bytes(), &resp_body[..], "{}", msg);
+                }
+            }
+            (status, _) => panic!("{}: unexpected {:?}", msg, status),
+        }
+    }
+}
@@ -0,0 +1,677 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::header::CONTENT_TYPE;
+use axum::http::{HeaderMap, HeaderValue};
+use envoy_control_plane::envoy::extensions::filters::common::fault::v3::{
+    fault_delay::FaultDelaySecifier as V3FaultDelaySpecifier,
+    fault_rate_limit::LimitType as V3LimitType, FaultDelay as V3FaultDelay,
+    FaultRateLimit as V3FaultRateLimit,
+};
+use envoy_control_plane::envoy::extensions::filters::http::fault::v3::{
+    fault_abort::ErrorType as V3ErrorType, FaultAbort as V3FaultAbort, HttpFault as V3HttpFault,
+};
+use envoy_control_plane::envoy::r#type::v3::FractionalPercent as V3FractionalPercent;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{
+    decode, BodyFilter, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory, PerFilterConfig,
+};
+use crate::body;
+use crate::grpc;
+use crate::local_reply::{local_reply, ResponseFlag};
+use crate::matcher::HeaderMatcher;
+use crate::protobuf::{self, Fraction};
+use crate::{Request, Response};
+
+/// The headers that downstreams can use to control faults, if the filter is
+/// configured to let them.
+pub const DELAY_REQUEST_HEADER: &str = "x-envoy-fault-delay-request";
+pub const DELAY_REQUEST_PERCENTAGE_HEADER: &str = "x-envoy-fault-delay-request-percentage";
+pub const ABORT_REQUEST_HEADER: &str = "x-envoy-fault-abort-request";
+pub const ABORT_GRPC_REQUEST_HEADER: &str = "x-envoy-fault-abort-grpc-request";
+pub const ABORT_REQUEST_PERCENTAGE_HEADER: &str = "x-envoy-fault-abort-request-percentage";
+pub const THROUGHPUT_RESPONSE_HEADER: &str = "x-envoy-fault-throughput-response";
+pub const THROUGHPUT_RESPONSE_PERCENTAGE_HEADER: &str =
+    "x-envoy-fault-throughput-response-percentage";
+
+const DOWNSTREAM_SERVICE_NODE_HEADER: &str = "x-envoy-downstream-service-node";
+const ABORT_MESSAGE: &str = "fault filter abort";
+
+/// ACTIVE_FAULTS counts the requests currently having faults injected, by every
+/// fault filter, which is what Envoy's max_active_faults limits.
+static ACTIVE_FAULTS: AtomicU64 = AtomicU64::new(0);
+
+/// type_url is the type URL of the fault filter's config.
+pub fn type_url() -> String {
+    V3HttpFault::default().type_url()
+}
+
+/// Fault is a fault the filter can inject, with the value a request asks for
+/// with a header if its value is None.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Fault<T> {
+    value: Option<T>,
+    percentage: Fraction,
+}
+
+impl<T: Copy> Fault<T> {
+    /// resolve decides whether to inject the fault into a request, and with what
+    /// value: header-controlled faults take their value (and optionally a lower
+    /// percentage) from the request's headers.
+    fn resolve(
+        &self,
+        headers: &HeaderMap,
+        from_header: impl FnOnce(&HeaderMap) -> Option<T>,
+        percentage_header: &str,
+    ) -> Option<T> {
+        let value = match self.value {
+            Some(value) => value,
+            None => from_header(headers)?,
+        };
+        let percentage = match parse_header::<u32>(headers, percentage_header) {
+            Some(numerator) => self.percentage.capped(numerator),
+            None => self.percentage,
+        };
+        if percentage.sample() {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+fn parse_header<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Abort is how an aborted request is answered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Abort {
+    Http(u16),
+    Grpc(u32),
+}
+
+impl Abort {
+    fn from_headers(headers: &HeaderMap) -> Option<Abort> {
+        // the HTTP status wins if a request asks for both
+        match parse_header::<u16>(headers, ABORT_REQUEST_HEADER) {
+            Some(status) if (200..600).contains(&status) => Some(Abort::Http(status)),
+            _ => parse_header(headers, ABORT_GRPC_REQUEST_HEADER).map(Abort::Grpc),
+        }
+    }
+
+    fn response(&self) -> Response {
+        match self {
+            Abort::Http(status) => {
+                local_reply(*status, Some(ResponseFlag::FaultInjected), ABORT_MESSAGE)
+            }
+            // a gRPC "trailers-only" response, whose status is exactly what was
+            // configured rather than derived from an HTTP status
+            Abort::Grpc(status) => {
+                let mut resp = Response::default();
+                let headers = resp.headers_mut();
+                headers.insert(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static(grpc::CONTENT_TYPE_GRPC),
+                );
+                headers.insert(grpc::STATUS_HEADER, HeaderValue::from(*status));
+                headers.insert(
+                    grpc::MESSAGE_HEADER,
+                    HeaderValue::from_static(ABORT_MESSAGE),
+                );
+                resp.extensions_mut().insert(ResponseFlag::FaultInjected);
+                resp
+            }
+        }
+    }
+}
+
+/// Faults are the faults to inject into a particular request.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct Faults {
+    delay: Option<Duration>,
+    abort: Option<Abort>,
+    /// throughput is the response's rate limit, in bytes per second.
+    throughput: Option<u64>,
+}
+
+impl Faults {
+    fn is_empty(&self) -> bool {
+        self.delay.is_none() && self.abort.is_none() && self.throughput.is_none()
+    }
+}
+
+/// Config is a fault filter's config, or a route's override of it.
+#[derive(Debug, Clone, PartialEq)]
+struct Config {
+    delay: Option<Fault<Duration>>,
+    abort: Option<Fault<Abort>>,
+    /// response_rate_limit is in KiB per second.
+    response_rate_limit: Option<Fault<u64>>,
+    /// upstream_cluster, headers and downstream_nodes restrict the requests
+    /// faults are injected into.
+    upstream_cluster: Option<String>,
+    headers: Vec<HeaderMatcher>,
+    downstream_nodes: Vec<String>,
+    max_active_faults: Option<u64>,
+}
+
+impl Config {
+    fn applies_to(&self, req: &Request, cluster: Option<&str>) -> bool {
+        if let Some(upstream_cluster) = &self.upstream_cluster {
+            if cluster != Some(upstream_cluster.as_str()) {
+                return false;
+            }
+        }
+        if !self.downstream_nodes.is_empty() {
+            let node = req
+                .headers()
+                .get(DOWNSTREAM_SERVICE_NODE_HEADER)
+                .and_then(|v| v.to_str().ok());
+            if !node.map_or(false, |node| {
+                self.downstream_nodes.iter().any(|n| n == node)
+            }) {
+                return false;
+            }
+        }
+        self.headers
+            .iter()
+            .all(|matcher| matcher.matches_request(req))
+    }
+
+    /// faults decides which faults to inject into a request.
+    fn faults(&self, headers: &HeaderMap) -> Faults {
+        let delay = self.delay.and_then(|delay| {
+            delay.resolve(
+                headers,
+                |headers| parse_header(headers, DELAY_REQUEST_HEADER).map(Duration::from_millis),
+                DELAY_REQUEST_PERCENTAGE_HEADER,
+            )
+        });
+        let abort = self.abort.and_then(|abort| {
+            abort.resolve(
+                headers,
+                Abort::from_headers,
+                ABORT_REQUEST_PERCENTAGE_HEADER,
+            )
+        });
+        let throughput = self.response_rate_limit.and_then(|limit| {
+            limit.resolve(
+                headers,
+                |headers| parse_header::<u64>(headers, THROUGHPUT_RESPONSE_HEADER),
+                THROUGHPUT_RESPONSE_PERCENTAGE_HEADER,
+            )
+        });
+        Faults {
+            delay,
+            abort,
+            throughput: throughput
+                .filter(|kbps| *kbps > 0)
+                .map(|kbps| kbps.saturating_mul(1024)),
+        }
+    }
+}
+
+fn percentage(percentage: Option<&V3FractionalPercent>) -> Fraction {
+    // like Envoy, a fault without a percentage is never injected
+    percentage.map_or(Fraction::NEVER, Fraction::from)
+}
+
+impl TryFrom<V3FaultDelay> for Fault<Duration> {
+    type Error = String;
+
+    fn try_from(value: V3FaultDelay) -> Result<Self, Self::Error> {
+        let delay = match value.fault_delay_secifier {
+            Some(V3FaultDelaySpecifier::FixedDelay(delay)) => Some(protobuf::duration(&delay)),
+            Some(V3FaultDelaySpecifier::HeaderDelay(_)) => None,
+            None => return Err("delay is missing its fixed_delay or header_delay".to_owned()),
+        };
+        Ok(Fault {
+            value: delay,
+            percentage: percentage(value.percentage.as_ref()),
+        })
+    }
+}
+
+impl TryFrom<V3FaultAbort> for Fault<Abort> {
+    type Error = String;
+
+    fn try_from(value: V3FaultAbort) -> Result<Self, Self::Error> {
+        let abort = match value.error_type {
+            Some(V3ErrorType::HttpStatus(status)) if (200..600).contains(&status) => {
+                Some(Abort::Http(status as u16))
+            }
+            Some(V3ErrorType::HttpStatus(status)) => {
+                return Err(format!("invalid abort http_status {}", status))
+            }
+            Some(V3ErrorType::GrpcStatus(status)) => Some(Abort::Grpc(status)),
+            Some(V3ErrorType::HeaderAbort(_)) => None,
+            None => return Err("abort is missing its error_type".to_owned()),
+        };
+        Ok(Fault {
+            value: abort,
+            percentage: percentage(value.percentage.as_ref()),
+        })
+    }
+}
+
+impl TryFrom<V3FaultRateLimit> for Fault<u64> {
+    type Error = String;
+
+    fn try_from(value: V3FaultRateLimit) -> Result<Self, Self::Error> {
+        let limit = match value.limit_type {
+            Some(V3LimitType::FixedLimit(fixed)) if fixed.limit_kbps > 0 => Some(fixed.limit_kbps),
+            Some(V3LimitType::FixedLimit(_)) => {
+                return Err("response_rate_limit's limit_kbps must be positive".to_owned())
+            }
+            Some(V3LimitType::HeaderLimit(_)) => None,
+            None => return Err("response_rate_limit is missing its limit_type".to_owned()),
+        };
+        Ok(Fault {
+            value: limit,
+            percentage: percentage(value.percentage.as_ref()),
+        })
+    }
+}
+
+impl TryFrom<V3HttpFault> for Config {
+    type Error = Error;
+
+    fn try_from(value: V3HttpFault) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+        Ok(Config {
+            delay: value
+                .delay
+                .map(Fault::try_from)
+                .transpose()
+                .map_err(bad_config)?,
+            abort: value
+                .abort
+                .map(Fault::try_from)
+                .transpose()
+                .map_err(bad_config)?,
+            response_rate_limit: value
+                .response_rate_limit
+                .map(Fault::try_from)
+                .transpose()
+                .map_err(bad_config)?,
+            upstream_cluster: Some(value.upstream_cluster).filter(|name| !name.is_empty()),
+            headers: value
+                .headers
+                .into_iter()
+                .map(HeaderMatcher::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(|err| bad_config(err.to_string()))?,
+            downstream_nodes: value.downstream_nodes,
+            max_active_faults: value.max_active_faults.map(u64::from),
+        })
+    }
+}
+
+/// ActiveFault counts a request in ACTIVE_FAULTS until it is dropped.
+#[derive(Debug)]
+struct ActiveFault;
+
+impl ActiveFault {
+    /// start counts a request as having faults injected, unless there are already
+    /// `max` active faults.
+    fn start(max: Option<u64>) -> Option<ActiveFault> {
+        let active = ACTIVE_FAULTS.fetch_add(1, Ordering::SeqCst);
+        if max.map_or(false, |max| active >= max) {
+            ACTIVE_FAULTS.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(ActiveFault)
+    }
+}
+
+impl Drop for ActiveFault {
+    fn drop(&mut self) {
+        ACTIVE_FAULTS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// EndOfFault keeps a request's fault active until its response has been sent.
+struct EndOfFault(ActiveFault);
+
+impl BodyFilter for EndOfFault {}
+
+/// FaultFilter is `envoy.filters.http.fault`: for testing how services cope with
+/// failures, it delays requests, aborts them with an error, and limits how fast
+/// responses are sent.
+#[derive(Debug)]
+struct FaultFilter {
+    config: Arc<Config>,
+    active: Option<ActiveFault>,
+    /// throughput is the response's rate limit, in bytes per second.
+    throughput: Option<u64>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for FaultFilter {
+    async fn decode_headers(&mut self, req: &mut Request, ctx: &FilterContext<'_>) -> FilterStatus {
+        let config = ctx
+            .per_filter_config::<Config>()
+            .unwrap_or_else(|| self.config.as_ref());
+        let cluster = ctx.routed.map(|routed| routed.cluster.name.as_str());
+        if !config.applies_to(req, cluster) {
+            return FilterStatus::Continue;
+        }
+        let faults = config.faults(req.headers());
+        if faults.is_empty() {
+            return FilterStatus::Continue;
+        }
+        self.active = match ActiveFault::start(config.max_active_faults) {
+            Some(active) => Some(active),
+            None => return FilterStatus::Continue,
+        };
+
+        self.throughput = faults.throughput;
+        if let Some(delay) = faults.delay {
+            tokio::time::sleep(delay).await;
+        }
+        match faults.abort {
+            Some(abort) => FilterStatus::Respond(abort.response()),
+            None => FilterStatus::Continue,
+        }
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        if let Some(throughput) = self.throughput {
+            let body = std::mem::take(resp.body_mut());
+            *resp.body_mut() = body::throttle(body, throughput);
+        }
+    }
+
+    fn encode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        let active = self.active.take()?;
+        Some(Box::new(EndOfFault(active)))
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(FaultFilter {
+            config: self.0.clone(),
+            active: None,
+            throughput: None,
+        })
+    }
+}
+
+/// Factory creates fault filters, and their per-route configs.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = Config::try_from(decode::<V3HttpFault>(config)?)?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+
+    fn create_per_filter_config(&self, config: &Any) -> Result<Arc<dyn PerFilterConfig>, Error> {
+        let config = Config::try_from(decode::<V3HttpFault>(config)?)?;
+        Ok(Arc::new(config))
+    }
+}
+
+#[test]
+fn test_faults() {
+    let always = |value| Fault {
+        value,
+        percentage: Fraction::ALWAYS,
+    };
+    let config = |delay, abort, rate_limit| Config {
+        delay: Some(always(delay)),
+        abort: Some(always(abort)),
+        response_rate_limit: Some(always(rate_limit)),
+        upstream_cluster: None,
+        headers: vec![],
+        downstream_nodes: vec![],
+        max_active_faults: None,
+    };
+    let fixed = config(
+        Some(Duration::from_millis(100)),
+        Some(Abort::Http(503)),
+        Some(64),
+    );
+    let from_headers = config(None, None, None);
+    let headers = |pairs: &[(&'static str, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    };
+
+    let cases: Vec<(&Config, HeaderMap, Faults)> = vec![
+        (
+            &fixed,
+            headers(&[(DELAY_REQUEST_HEADER, "5")]),
+            Faults {
+                delay: Some(Duration::from_millis(100)),
+                abort: Some(Abort::Http(503)),
+                throughput: Some(64 * 1024),
+            },
+        ),
+        (&from_headers, headers(&[]), Faults::default()),
+        (
+            &from_headers,
+            headers(&[
+                (DELAY_REQUEST_HEADER, "5"),
+                (ABORT_GRPC_REQUEST_HEADER, "14"),
+                (THROUGHPUT_RESPONSE_HEADER, "1"),
+            ]),
+            Faults {
+                delay: Some(Duration::from_millis(5)),
+                abort: Some(Abort::Grpc(14)),
+                throughput: Some(1024),
+            },
+        ),
+        (
+            &from_headers,
+            headers(&[
+                (ABORT_REQUEST_HEADER, "429"),
+                (ABORT_GRPC_REQUEST_HEADER, "14"),
+            ]),
+            Faults {
+                abort: Some(Abort::Http(429)),
+                ..Default::default()
+            },
+        ),
+        // headers can lower the percentage of requests faults are injected into
+        (
+            &from_headers,
+            headers(&[
+                (DELAY_REQUEST_HEADER, "5"),
+                (DELAY_REQUEST_PERCENTAGE_HEADER, "0"),
+                (ABORT_REQUEST_HEADER, "not a status"),
+                (THROUGHPUT_RESPONSE_HEADER, "0"),
+            ]),
+            Faults::default(),
+        ),
+    ];
+
+    for (i, (config, headers, expected)) in cases.iter().enumerate() {
+        assert_eq!(*expected, config.faults(headers), "case {}", i);
+    }
+}
+
+#[test]
+fn test_applies_to() {
+    use envoy_control_plane::envoy::config::route::v3::{
+        header_matcher::HeaderMatchSpecifier, HeaderMatcher as V3HeaderMatcher,
+    };
+
+    let config =
+        |upstream_cluster: Option<&str>, headers: &[&str], downstream_nodes: &[&str]| Config {
+            delay: None,
+            abort: None,
+            response_rate_limit: None,
+            upstream_cluster: upstream_cluster.map(str::to_owned),
+            headers: headers
+                .iter()
+                .map(|name| {
+                    HeaderMatcher::try_from(V3HeaderMatcher {
+                        name: name.to_string(),
+                        header_match_specifier: Some(HeaderMatchSpecifier::PresentMatch(true)),
+                        ..Default::default()
+                    })
+                    .unwrap()
+                })
+                .collect(),
+            downstream_nodes: downstream_nodes.iter().map(|n| n.to_string()).collect(),
+            max_active_faults: None,
+        };
+    let unrestricted = config(None, &[], &[]);
+    let restricted = config(Some("backend"), &["x-fault"], &["canary", "test"]);
+    let req = |headers: &[(&str, &str)]| {
+        let mut req = axum::http::Request::builder();
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(axum::body::Body::empty()).unwrap()
+    };
+    let node = DOWNSTREAM_SERVICE_NODE_HEADER;
+
+    let cases: Vec<(&Config, Option<&str>, Request, bool)> = vec![
+        (&unrestricted, None, req(&[]), true),
+        (
+            &restricted,
+            Some("backend"),
+            req(&[(node, "test"), ("x-fault", "1")]),
+            true,
+        ),
+        (
+            &restricted,
+            Some("other"),
+            req(&[(node, "test"), ("x-fault", "1")]),
+            false,
+        ),
+        (
+            &restricted,
+            None,
+            req(&[(node, "test"), ("x-fault", "1")]),
+            false,
+        ),
+        (
+            &restricted,
+            Some("backend"),
+            req(&[(node, "prod"), ("x-fault", "1")]),
+            false,
+        ),
+        (
+            &restricted,
+            Some("backend"),
+            req(&[("x-fault", "1")]),
+            false,
+        ),
+        (
+            &restricted,
+            Some("backend"),
+            req(&[(node, "canary")]),
+            false,
+        ),
+    ];
+
+    for (config, cluster, req, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            config.applies_to(req, *cluster),
+            "{:?} {:?}",
+            cluster,
+            req.headers()
+        );
+    }
+}
+
+#[test]
+fn test_abort_response() {
+    use crate::local_reply::LocalReply;
+
+    let resp = Abort::Http(503).response();
+    assert_eq!(503, resp.status().as_u16());
+    assert_eq!(
+        Some(ResponseFlag::FaultInjected),
+        resp.extensions()
+            .get::<LocalReply>()
+            .and_then(|reply| reply.flag)
+    );
+
+    // gRPC aborts are trailers-only responses with exactly the configured status
+    let resp = Abort::Grpc(14).response();
+    assert_eq!(200, resp.status().as_u16());
+    let headers = resp.headers();
+    assert_eq!(grpc::CONTENT_TYPE_GRPC, headers[CONTENT_TYPE]);
+    assert_eq!("14", headers[grpc::STATUS_HEADER]);
+    assert_eq!(ABORT_MESSAGE, headers[grpc::MESSAGE_HEADER]);
+    assert_eq!(
+        Some(&ResponseFlag::FaultInjected),
+        resp.extensions().get::<ResponseFlag>()
+    );
+}
+
+#[tokio::test]
+async fn test_max_active_faults() {
+    use crate::testing::test_clusters;
+
+    async fn aborts(filter: &mut dyn HttpFilter, ctx: &FilterContext<'_>) -> bool {
+        let mut req = Request::new(axum::body::Body::empty());
+        let status = filter.decode_headers(&mut req, ctx).await;
+        matches!(status, FilterStatus::Respond(_))
+    }
+
+    let config = Arc::new(Config {
+        delay: None,
+        abort: Some(Fault {
+            value: Some(Abort::Http(503)),
+            percentage: Fraction::ALWAYS,
+        }),
+        response_rate_limit: None,
+        upstream_cluster: None,
+        headers: vec![],
+        downstream_nodes: vec![],
+        max_active_faults: Some(1),
+    });
+    let factory = FilterFactory(config);
+    let clusters = test_clusters("upstream", ([127, 0, 0, 1], 1).into());
+    let ctx = FilterContext {
+        routed: None,
+        name: "envoy.filters.http.fault",
+        clusters: &clusters,
+    };
+    let active = || ACTIVE_FAULTS.load(Ordering::SeqCst);
+
+    let mut first = factory.create();
+    assert!(aborts(first.as_mut(), &ctx).await);
+    assert_eq!(1, active());
+    // no more faults are injected while one is active
+    let mut second = factory.create();
+    assert!(!aborts(second.as_mut(), &ctx).await);
+    assert_eq!(1, active());
+
+    // the fault stays active until its response has been sent
+    let end_of_fault = first.encode_body();
+    assert!(end_of_fault.is_some());
+    drop(first);
+    assert!(!aborts(factory.create().as_mut(), &ctx).await);
+    assert_eq!(1, active());
+    drop(end_of_fault);
+    assert_eq!(0, active());
+
+    // or until the filter is dropped, if its response never was
+    let mut third = factory.create();
+    assert!(aborts(third.as_mut(), &ctx).await);
+    assert_eq!(1, active());
+    drop(third);
+    assert_eq!(0, active());
+}
@@ -0,0 +1,434 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::io;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::http::header::{CONTENT_LENGTH, CONTENT_TYPE, TE};
+use axum::http::{HeaderMap, HeaderValue};
+use envoy_control_plane::envoy::extensions::filters::http::grpc_web::v3::GrpcWeb as V3GrpcWeb;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{
+    decode, BodyFilter, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory,
+};
+use crate::grpc::{self, CONTENT_TYPE_GRPC, CONTENT_TYPE_GRPC_WEB, CONTENT_TYPE_GRPC_WEB_TEXT};
+use crate::{Request, Response};
+
+// the flag that marks the frame carrying a gRPC-Web response's trailers
+const TRAILERS_FRAME_FLAG: u8 = 0x80;
+
+/// type_url is the type URL of the gRPC-Web filter's config.
+pub fn type_url() -> String {
+    V3GrpcWeb::default().type_url()
+}
+
+/// Format is how a gRPC-Web body is encoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Binary,
+    Text,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Binary => CONTENT_TYPE_GRPC_WEB,
+            Format::Text => CONTENT_TYPE_GRPC_WEB_TEXT,
+        }
+    }
+}
+
+/// web_format returns the format of a gRPC-Web request (None if it isn't one)
+/// and the codec of its Content-Type.
+fn web_format(headers: &HeaderMap) -> Option<(Format, &str)> {
+    // grpc-web is a prefix of grpc-web-text, so text has to be checked first
+    match grpc::codec(headers, CONTENT_TYPE_GRPC_WEB_TEXT) {
+        Some(codec) => Some((Format::Text, codec)),
+        None => grpc::codec(headers, CONTENT_TYPE_GRPC_WEB).map(|codec| (Format::Binary, codec)),
+    }
+}
+
+fn invalid_text() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "gRPC-Web body isn't valid base64",
+    )
+}
+
+/// DecodeText decodes a grpc-web-text request body.  Clients encode (and pad)
+/// each write separately, so padding can turn up in the middle of the body.
+#[derive(Default)]
+struct DecodeText {
+    /// pending is the end of the body seen so far that doesn't fill a base64
+    /// group of 4 characters yet.
+    pending: Vec<u8>,
+}
+
+impl DecodeText {
+    fn decode(&mut self, data: &[u8], last: bool) -> io::Result<Bytes> {
+        self.pending.extend_from_slice(data);
+        let n = self.pending.len() / 4 * 4;
+        if last && n != self.pending.len() {
+            return Err(invalid_text());
+        }
+
+        let mut decoded = Vec::with_capacity(n / 4 * 3);
+        let mut start = 0;
+        for (i, group) in self.pending[..n].chunks(4).enumerate() {
+            let end = (i + 1) * 4;
+            if group[3] == b'=' || end == n {
+                base64::decode_config_buf(
+                    &self.pending[start..end],
+                    base64::STANDARD,
+                    &mut decoded,
+                )
+                .map_err(|_| invalid_text())?;
+                start = end;
+            }
+        }
+        self.pending.drain(..n);
+        Ok(Bytes::from(decoded))
+    }
+}
+
+impl BodyFilter for DecodeText {
+    fn data(&mut self, data: Bytes) -> io::Result<Bytes> {
+        self.decode(&data, false)
+    }
+
+    fn end(&mut self, _trailers: &mut HeaderMap) -> io::Result<Bytes> {
+        self.decode(&[], true)
+    }
+}
+
+/// trailers_frame encodes a response's trailers the way gRPC-Web carries them,
+/// in a frame at the end of the body: like a message frame, but flagged, with
+/// the trailers in HTTP/1 header format.
+fn trailers_frame(trailers: &HeaderMap) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in trailers {
+        block.extend_from_slice(name.as_str().as_bytes());
+        block.push(b':');
+        block.extend_from_slice(value.as_bytes());
+        block.extend_from_slice(b"\r\n");
+    }
+    let mut frame = Vec::with_capacity(5 + block.len());
+    frame.push(TRAILERS_FRAME_FLAG);
+    frame.extend_from_slice(&(block.len() as u32).to_be_bytes());
+    frame.extend(block);
+    frame
+}
+
+/// EncodeResponse turns a gRPC response body into a gRPC-Web one, moving its
+/// trailers into the body (which browsers can't otherwise get at) and, for
+/// grpc-web-text, base64 encoding it.
+struct EncodeResponse {
+    format: Format,
+    /// pending is the end of a grpc-web-text body seen so far that doesn't fill a
+    /// base64 group of 3 bytes yet.
+    pending: Vec<u8>,
+}
+
+impl EncodeResponse {
+    fn encode(&mut self, data: &[u8], last: bool) -> Bytes {
+        if self.format == Format::Binary {
+            return Bytes::copy_from_slice(data);
+        }
+        self.pending.extend_from_slice(data);
+        let n = if last {
+            self.pending.len()
+        } else {
+            self.pending.len() / 3 * 3
+        };
+        let encoded = base64::encode(&self.pending[..n]);
+        self.pending.drain(..n);
+        Bytes::from(encoded)
+    }
+}
+
+impl BodyFilter for EncodeResponse {
+    fn data(&mut self, data: Bytes) -> io::Result<Bytes> {
+        if self.format == Format::Binary {
+            return Ok(data);
+        }
+        Ok(self.encode(&data, false))
+    }
+
+    fn end(&mut self, trailers: &mut HeaderMap) -> io::Result<Bytes> {
+        // trailers-only responses have nothing to move: their status is in the
+        // headers
+        let frame = if trailers.is_empty() {
+            vec![]
+        } else {
+            trailers_frame(trailers)
+        };
+        trailers.clear();
+        Ok(self.encode(&frame, true))
+    }
+}
+
+/// GrpcWebFilter is `envoy.filters.http.grpc_web`: it turns gRPC-Web requests from
+/// browsers into gRPC ones, and their responses back into gRPC-Web.  Upstreams
+/// need to be reached over HTTP/2, as for any gRPC call.
+struct GrpcWebFilter {
+    /// request is the format of a gRPC-Web request's body.
+    request: Option<Format>,
+    /// response is the format the downstream wants its response in.
+    response: Option<Format>,
+    encode: Option<EncodeResponse>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for GrpcWebFilter {
+    async fn decode_headers(
+        &mut self,
+        req: &mut Request,
+        _ctx: &FilterContext<'_>,
+    ) -> FilterStatus {
+        let (format, codec) = match web_format(req.headers()) {
+            Some(web) => web,
+            None => return FilterStatus::Continue,
+        };
+        self.request = Some(format);
+        self.response = if grpc::accepts_web_text(req.headers()) {
+            Some(Format::Text)
+        } else {
+            Some(format)
+        };
+
+        // the codec comes from the header, so it's a valid header value
+        let grpc_content_type = format!("{}{}", CONTENT_TYPE_GRPC, codec);
+        let headers = req.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&grpc_content_type) {
+            headers.insert(CONTENT_TYPE, value);
+        }
+        if format == Format::Text {
+            headers.remove(CONTENT_LENGTH);
+        }
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        FilterStatus::Continue
+    }
+
+    fn decode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        match self.request? {
+            Format::Text => Some(Box::new(DecodeText::default())),
+            Format::Binary => None,
+        }
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        let format = match self.response {
+            Some(format) => format,
+            None => return,
+        };
+        // other responses are passed on as they are: local replies get the gRPC-Web
+        // Content-Type when they're rendered
+        if !grpc::is_grpc(resp.headers()) {
+            return;
+        }
+        let codec = grpc::codec(resp.headers(), CONTENT_TYPE_GRPC).unwrap_or_default();
+        let web_content_type = format!("{}{}", format.content_type(), codec);
+        let headers = resp.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&web_content_type) {
+            headers.insert(CONTENT_TYPE, value);
+        }
+        headers.remove(CONTENT_LENGTH);
+        self.encode = Some(EncodeResponse {
+            format,
+            pending: vec![],
+        });
+    }
+
+    fn encode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        let encode = self.encode.take()?;
+        Some(Box::new(encode))
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory;
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(GrpcWebFilter {
+            request: None,
+            response: None,
+            encode: None,
+        })
+    }
+}
+
+/// Factory creates gRPC-Web filters.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        decode::<V3GrpcWeb>(config)?;
+        Ok(Arc::new(FilterFactory))
+    }
+}
+
+#[test]
+fn test_body_filters() {
+    let message = grpc::frame_message(b"hello");
+    let mut trailers = HeaderMap::new();
+    trailers.insert(grpc::STATUS_HEADER, HeaderValue::from_static("0"));
+    trailers.insert(grpc::MESSAGE_HEADER, HeaderValue::from_static("ok"));
+    let mut web_body = message.to_vec();
+    web_body.extend_from_slice(b"\x80\x00\x00\x00\x20grpc-status:0\r\ngrpc-message:ok\r\n");
+
+    // a gRPC response, split oddly, comes out as a gRPC-Web body in either format
+    let cases = [
+        (Format::Binary, web_body.clone()),
+        (Format::Text, base64::encode(&web_body).into_bytes()),
+    ];
+    for (format, expected) in cases.iter() {
+        let mut encode = EncodeResponse {
+            format: *format,
+            pending: vec![],
+        };
+        let mut body = Vec::new();
+        for chunk in message.chunks(4) {
+            let data = encode.data(Bytes::copy_from_slice(chunk)).unwrap();
+            body.extend_from_slice(&data);
+        }
+        let mut trailers = trailers.clone();
+        body.extend_from_slice(&encode.end(&mut trailers).unwrap());
+        assert_eq!(expected, &body, "{:?}", format);
+        assert!(trailers.is_empty());
+    }
+
+    // grpc-web-text requests decode whatever the chunking, and whether or not
+    // each write was padded separately
+    let writes: [&[u8]; 2] = [b"hello", b"grpc-web"];
+    let separately: String = writes.iter().map(base64::encode).collect();
+    let together = base64::encode(writes.concat());
+    let cases = [
+        (separately.as_bytes(), Some(writes.concat())),
+        (together.as_bytes(), Some(writes.concat())),
+        (&together.as_bytes()[1..], None),
+        (b"aGk*".as_ref(), None),
+    ];
+    let decode_all = |body: &[u8]| -> io::Result<Vec<u8>> {
+        let mut decode = DecodeText::default();
+        let mut decoded = Vec::new();
+        for chunk in body.chunks(3) {
+            decoded.extend_from_slice(&decode.data(Bytes::copy_from_slice(chunk))?);
+        }
+        decoded.extend_from_slice(&decode.end(&mut HeaderMap::new())?);
+        Ok(decoded)
+    };
+    for (body, expected) in cases.iter() {
+        assert_eq!(
+            expected.as_ref(),
+            decode_all(body).as_ref().ok(),
+            "{}",
+            String::from_utf8_lossy(body)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_headers() {
+    use axum::http::header::ACCEPT;
+    use ronvoy_core::response::json_error;
+
+    use crate::testing::test_clusters;
+
+    let clusters = test_clusters("upstream", ([127, 0, 0, 1], 1).into());
+    let ctx = FilterContext {
+        routed: None,
+        name: "envoy.filters.http.grpc_web",
+        clusters: &clusters,
+    };
+    let request = |content_type: &str, accept: Option<&str>| {
+        let mut req = axum::http::Request::post("/pkg.Service/Method")
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, "8");
+        if let Some(accept) = accept {
+            req = req.header(ACCEPT, accept);
+        }
+        req.body(axum::body::Body::empty()).unwrap()
+    };
+
+    // gRPC-Web requests become gRPC ones with the same codec, and their responses
+    // come back in the format the client accepts
+    let cases = [
+        (
+            "application/grpc-web",
+            None,
+            "application/grpc",
+            "application/grpc-web",
+        ),
+        (
+            "application/grpc-web+proto",
+            None,
+            "application/grpc+proto",
+            "application/grpc-web+proto",
+        ),
+        (
+            "application/grpc-web-text+proto",
+            None,
+            "application/grpc+proto",
+            "application/grpc-web-text+proto",
+        ),
+        (
+            "application/grpc-web+proto",
+            Some("application/grpc-web-text"),
+            "application/grpc+proto",
+            "application/grpc-web-text+proto",
+        ),
+    ];
+    for (content_type, accept, grpc_content_type, web_content_type) in cases {
+        let mut filter = FilterFactory.create();
+        let mut req = request(content_type, accept);
+        let status = filter.decode_headers(&mut req, &ctx).await;
+        assert!(matches!(status, FilterStatus::Continue), "{}", content_type);
+        let headers = req.headers();
+        assert_eq!(grpc_content_type, headers[CONTENT_TYPE], "{}", content_type);
+        assert_eq!("trailers", headers[TE], "{}", content_type);
+        // base64 changes the length of a grpc-web-text body
+        let text = content_type.starts_with(CONTENT_TYPE_GRPC_WEB_TEXT);
+        assert_eq!(
+            !text,
+            headers.contains_key(CONTENT_LENGTH),
+            "{}",
+            content_type
+        );
+
+        let mut resp = axum::http::Response::builder()
+            .header(CONTENT_TYPE, "application/grpc+proto")
+            .header(CONTENT_LENGTH, "7")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        filter.encode_headers(&mut resp);
+        let headers = resp.headers();
+        assert_eq!(web_content_type, headers[CONTENT_TYPE], "{}", content_type);
+        assert!(!headers.contains_key(CONTENT_LENGTH), "{}", content_type);
+        assert!(filter.encode_body().is_some(), "{}", content_type);
+    }
+
+    // other requests, and other responses, are left alone
+    let mut filter = FilterFactory.create();
+    let mut req = request("application/grpc+proto", None);
+    filter.decode_headers(&mut req, &ctx).await;
+    assert_eq!("application/grpc+proto", req.headers()[CONTENT_TYPE]);
+    assert!(!req.headers().contains_key(TE));
+    let mut resp = json_error(403, "denied");
+    let expected = resp.headers().clone();
+    filter.encode_headers(&mut resp);
+    assert_eq!(&expected, resp.headers());
+
+    let mut filter = FilterFactory.create();
+    let mut req = request("application/grpc-web", None);
+    filter.decode_headers(&mut req, &ctx).await;
+    let mut resp = json_error(403, "denied");
+    let expected = resp.headers().clone();
+    filter.encode_headers(&mut resp);
+    assert_eq!(&expected, resp.headers());
+    assert!(filter.encode_body().is_none());
+}
@@ -0,0 +1,441 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use axum::http::HeaderValue;
+use envoy_control_plane::envoy::extensions::common::ratelimit::v3::XRateLimitHeadersRfcVersion as V3XRateLimitHeadersRfcVersion;
+use envoy_control_plane::envoy::extensions::filters::http::local_ratelimit::v3::LocalRateLimit as V3LocalRateLimit;
+use envoy_control_plane::envoy::r#type::v3::TokenBucket as V3TokenBucket;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+use tokio::time::Instant;
+
+use super::{
+    decode, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory, PerFilterConfig,
+};
+use crate::headers::{FormatContext, HeaderMutations};
+use crate::listener::ConnectionInfo;
+use crate::local_reply::{local_reply, ResponseFlag};
+use crate::protobuf::{self, Fraction};
+use crate::rate_limit::{self, Descriptor};
+use crate::route::Action;
+use crate::{Request, Response};
+
+/// RATELIMIT_LIMIT_HEADER, RATELIMIT_REMAINING_HEADER and RATELIMIT_RESET_HEADER
+/// are the rate limit headers of the IETF draft (version 03) Envoy implements.
+pub const RATELIMIT_LIMIT_HEADER: &str = "x-ratelimit-limit";
+pub const RATELIMIT_REMAINING_HEADER: &str = "x-ratelimit-remaining";
+pub const RATELIMIT_RESET_HEADER: &str = "x-ratelimit-reset";
+
+// Envoy doesn't allow buckets to be refilled more often than this
+const MIN_FILL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// type_url is the type URL of the local rate limit filter's config.
+pub fn type_url() -> String {
+    V3LocalRateLimit::default().type_url()
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: u32,
+    last_fill: Instant,
+}
+
+/// TokenBucket allows `max_tokens` requests in a burst, and `tokens_per_fill`
+/// more every `fill_interval`.  Tokens are added when the bucket is used rather
+/// than by a timer, so a bucket is shared by all of the worker threads (however
+/// many event loops there are) without its rate being multiplied by them.
+#[derive(Debug)]
+struct TokenBucket {
+    max_tokens: u32,
+    tokens_per_fill: u32,
+    fill_interval: Duration,
+    state: Mutex<BucketState>,
+}
+
+/// Consumed is the outcome of taking a token from a bucket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Consumed {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    /// reset is how long until the bucket is next refilled.
+    reset: Duration,
+}
+
+impl TokenBucket {
+    /// fill adds the tokens due since the bucket was last filled.
+    fn fill(&self, state: &mut BucketState, now: Instant) {
+        let fills = now.saturating_duration_since(state.last_fill).as_nanos()
+            / self.fill_interval.as_nanos();
+        if fills > 0 {
+            let added = (fills as u64).saturating_mul(self.tokens_per_fill as u64);
+            state.tokens = (state.tokens as u64 + added).min(self.max_tokens as u64) as u32;
+            state.last_fill += self.fill_interval * fills.min(u32::MAX as u128) as u32;
+        }
+    }
+}
+
+/// consume takes a token for a request from each of `buckets`, returning the
+/// outcome for the bucket with the fewest tokens left.  The request is only
+/// allowed, and only takes tokens, if every bucket has a token for it: a
+/// rejected request doesn't use up the other buckets.
+fn consume(buckets: &[&TokenBucket], now: Instant) -> Consumed {
+    // buckets are locked in a fixed order, and only once each, so requests
+    // sharing buckets can't deadlock
+    let mut buckets = buckets.to_vec();
+    buckets.sort_by_key(|bucket| *bucket as *const TokenBucket);
+    buckets.dedup_by_key(|bucket| *bucket as *const TokenBucket);
+    let mut states: Vec<MutexGuard<BucketState>> = buckets
+        .iter()
+        .map(|bucket| bucket.state.lock().unwrap())
+        .collect();
+
+    for (bucket, state) in buckets.iter().zip(states.iter_mut()) {
+        bucket.fill(state, now);
+    }
+    let allowed = states.iter().all(|state| state.tokens > 0);
+    if allowed {
+        for state in states.iter_mut() {
+            state.tokens -= 1;
+        }
+    }
+    buckets
+        .iter()
+        .zip(states.iter())
+        .map(|(bucket, state)| Consumed {
+            allowed,
+            limit: bucket.max_tokens,
+            remaining: state.tokens,
+            reset: (state.last_fill + bucket.fill_interval).saturating_duration_since(now),
+        })
+        .min_by_key(|consumed| consumed.remaining)
+        .unwrap()
+}
+
+impl TryFrom<V3TokenBucket> for TokenBucket {
+    type Error = String;
+
+    fn try_from(value: V3TokenBucket) -> Result<Self, Self::Error> {
+        let fill_interval = value
+            .fill_interval
+            .as_ref()
+            .map(protobuf::duration)
+            .ok_or_else(|| "token bucket is missing its fill_interval".to_owned())?;
+        if fill_interval < MIN_FILL_INTERVAL {
+            return Err("token bucket's fill_interval must be at least 50ms".to_owned());
+        }
+        let tokens_per_fill = value.tokens_per_fill.unwrap_or(1);
+        if value.max_tokens == 0 || tokens_per_fill == 0 {
+            return Err("token bucket must have tokens".to_owned());
+        }
+        Ok(TokenBucket {
+            max_tokens: value.max_tokens,
+            tokens_per_fill,
+            fill_interval,
+            state: Mutex::new(BucketState {
+                tokens: value.max_tokens,
+                last_fill: Instant::now(),
+            }),
+        })
+    }
+}
+
+/// Config is a local rate limit filter's config, or a route's override of it.
+/// Each config has its own buckets.
+#[derive(Debug)]
+struct Config {
+    status: u16,
+    /// enabled and enforced are the fractions of requests that are rate limited
+    /// at all, and that are rejected if they are over the limit.  Like Envoy,
+    /// the filter is disabled (or only observes) if they aren't configured.
+    enabled: Option<Fraction>,
+    enforced: Option<Fraction>,
+    request_headers_to_add_when_not_enforced: HeaderMutations,
+    response_headers_to_add: HeaderMutations,
+    bucket: TokenBucket,
+    /// descriptors have their own buckets, which are used instead of the default
+    /// bucket for requests with matching descriptors.
+    descriptors: HashMap<Descriptor, TokenBucket>,
+    stage: u32,
+    x_ratelimit_headers: bool,
+}
+
+impl Config {
+    /// consume takes a token for a request from the buckets of its descriptors,
+    /// or from the default bucket if it has none.
+    fn consume(&self, descriptors: &[Descriptor], now: Instant) -> Consumed {
+        let mut buckets: Vec<&TokenBucket> = descriptors
+            .iter()
+            .filter_map(|descriptor| self.descriptors.get(descriptor))
+            .collect();
+        if buckets.is_empty() {
+            buckets.push(&self.bucket);
+        }
+        consume(&buckets, now)
+    }
+}
+
+impl TryFrom<V3LocalRateLimit> for Config {
+    type Error = Error;
+
+    fn try_from(value: V3LocalRateLimit) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+
+        if value.local_rate_limit_per_downstream_connection {
+            return Err(bad_config(
+                "TODO: local_rate_limit_per_downstream_connection isn't supported".to_owned(),
+            ));
+        }
+        let status = match value.status {
+            Some(status) if (400..600).contains(&status.code) => status.code as u16,
+            Some(status) => return Err(bad_config(format!("invalid status {}", status.code))),
+            None => 429,
+        };
+        let bucket = value
+            .token_bucket
+            .ok_or_else(|| "missing token_bucket".to_owned())
+            .and_then(TokenBucket::try_from)
+            .map_err(bad_config)?;
+        let descriptors = value
+            .descriptors
+            .into_iter()
+            .map(|descriptor| {
+                let entries: Descriptor = descriptor
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect();
+                let bucket = descriptor
+                    .token_bucket
+                    .ok_or_else(|| "descriptor is missing its token_bucket".to_owned())
+                    .and_then(TokenBucket::try_from)?;
+                Ok((entries, bucket))
+            })
+            .collect::<Result<_, String>>()
+            .map_err(bad_config)?;
+        let header_mutations = |to_add| {
+            HeaderMutations::new(to_add, vec![]).map_err(|err| bad_config(err.to_string()))
+        };
+
+        Ok(Config {
+            status,
+            enabled: value
+                .filter_enabled
+                .as_ref()
+                .map(protobuf::runtime_fraction),
+            enforced: value
+                .filter_enforced
+                .as_ref()
+                .map(protobuf::runtime_fraction),
+            request_headers_to_add_when_not_enforced: header_mutations(
+                value.request_headers_to_add_when_not_enforced,
+            )?,
+            response_headers_to_add: header_mutations(value.response_headers_to_add)?,
+            bucket,
+            descriptors,
+            stage: value.stage,
+            x_ratelimit_headers: value.enable_x_ratelimit_headers
+                == V3XRateLimitHeadersRfcVersion::DraftVersion03 as i32,
+        })
+    }
+}
+
+/// LocalRateLimit is `envoy.filters.http.local_ratelimit`: it rate limits requests
+/// with token buckets kept by this Ronvoy instance.  Requests over the limit are
+/// rejected with a 429 (or the configured status).
+#[derive(Debug)]
+struct LocalRateLimit {
+    config: Arc<Config>,
+    /// x_ratelimit is what to report in the response's rate limit headers.
+    x_ratelimit: Option<Consumed>,
+}
+
+/// insert_x_ratelimit_headers adds the draft rate limit headers for a request's
+/// outcome to its response.
+fn insert_x_ratelimit_headers(resp: &mut Response, consumed: &Consumed) {
+    let headers = resp.headers_mut();
+    headers.insert(RATELIMIT_LIMIT_HEADER, HeaderValue::from(consumed.limit));
+    headers.insert(
+        RATELIMIT_REMAINING_HEADER,
+        HeaderValue::from(consumed.remaining),
+    );
+    // in whole seconds, rounded up
+    let reset = (consumed.reset.as_millis() + 999) / 1000;
+    headers.insert(RATELIMIT_RESET_HEADER, HeaderValue::from(reset as u64));
+}
+
+#[tonic::async_trait]
+impl HttpFilter for LocalRateLimit {
+    async fn decode_headers(&mut self, req: &mut Request, ctx: &FilterContext<'_>) -> FilterStatus {
+        let config = ctx
+            .per_filter_config::<Config>()
+            .unwrap_or_else(|| self.config.as_ref());
+        if !config.enabled.map_or(false, |enabled| enabled.sample()) {
+            return FilterStatus::Continue;
+        }
+
+        let descriptors = match ctx.routed {
+            Some(routed) if !config.descriptors.is_empty() => {
+                let Action::Route(action) = routed.route.action();
+                rate_limit::descriptors(
+                    &action.rate_limits,
+                    config.stage,
+                    req,
+                    &routed.cluster.name,
+                )
+            }
+            _ => vec![],
+        };
+        let consumed = config.consume(&descriptors, Instant::now());
+        if config.x_ratelimit_headers {
+            self.x_ratelimit = Some(consumed);
+        }
+        if consumed.allowed {
+            return FilterStatus::Continue;
+        }
+
+        let conn = req.extensions().get::<ConnectionInfo>().copied();
+        if !config.enforced.map_or(false, |enforced| enforced.sample()) {
+            let format_ctx = FormatContext {
+                connection: conn.as_ref(),
+                ..Default::default()
+            };
+            config
+                .request_headers_to_add_when_not_enforced
+                .apply(req.headers_mut(), &format_ctx);
+            return FilterStatus::Continue;
+        }
+
+        let mut resp = local_reply(
+            config.status,
+            Some(ResponseFlag::RateLimited),
+            "local_rate_limited",
+        );
+        let format_ctx = FormatContext {
+            connection: conn.as_ref(),
+            request_headers: Some(req.headers()),
+            ..Default::default()
+        };
+        config
+            .response_headers_to_add
+            .apply(resp.headers_mut(), &format_ctx);
+        if let Some(consumed) = &self.x_ratelimit {
+            insert_x_ratelimit_headers(&mut resp, consumed);
+        }
+        FilterStatus::Respond(resp)
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        if let Some(consumed) = &self.x_ratelimit {
+            insert_x_ratelimit_headers(resp, consumed);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(LocalRateLimit {
+            config: self.0.clone(),
+            x_ratelimit: None,
+        })
+    }
+}
+
+/// Factory creates local rate limit filters, and their per-route configs.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = Config::try_from(decode::<V3LocalRateLimit>(config)?)?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+
+    fn create_per_filter_config(&self, config: &Any) -> Result<Arc<dyn PerFilterConfig>, Error> {
+        let config = Config::try_from(decode::<V3LocalRateLimit>(config)?)?;
+        Ok(Arc::new(config))
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let start = Instant::now();
+    let bucket = TokenBucket {
+        max_tokens: 2,
+        tokens_per_fill: 1,
+        fill_interval: Duration::from_secs(1),
+        state: Mutex::new(BucketState {
+            tokens: 2,
+            last_fill: start,
+        }),
+    };
+    let consumed = |allowed: bool, remaining: u32, reset_millis: u64| Consumed {
+        allowed,
+        limit: 2,
+        remaining,
+        reset: Duration::from_millis(reset_millis),
+    };
+
+    let cases: &[(u64, Consumed)] = &[
+        (0, consumed(true, 1, 1000)),
+        (100, consumed(true, 0, 900)),
+        (200, consumed(false, 0, 800)),
+        // one token is added a second after the bucket was created
+        (1000, consumed(true, 0, 1000)),
+        (1500, consumed(false, 0, 500)),
+        // a long wait only refills the bucket up to max_tokens
+        (10_500, consumed(true, 1, 500)),
+        (10_600, consumed(true, 0, 400)),
+        (10_700, consumed(false, 0, 300)),
+    ];
+
+    for (millis, expected) in cases.iter() {
+        let now = start + Duration::from_millis(*millis);
+        assert_eq!(*expected, consume(&[&bucket], now), "at {}ms", millis);
+    }
+}
+
+#[test]
+fn test_consume() {
+    let now = Instant::now();
+    let bucket = |tokens: u32| TokenBucket {
+        max_tokens: tokens,
+        tokens_per_fill: 1,
+        fill_interval: Duration::from_secs(60),
+        state: Mutex::new(BucketState {
+            tokens,
+            last_fill: now,
+        }),
+    };
+    let (small, large) = (bucket(1), bucket(3));
+
+    // (the buckets a request uses, and whether it is allowed and the tokens left
+    // in the most limited of them)
+    let cases: &[(&[&TokenBucket], (bool, u32))] = &[
+        (&[&large, &small], (true, 0)),
+        // a rejected request takes no tokens from the buckets that had them...
+        (&[&small, &large], (false, 0)),
+        (&[&large, &small], (false, 0)),
+        // ...and a bucket used twice by a request is only charged once
+        (&[&large, &large], (true, 1)),
+        (&[&large], (true, 0)),
+        (&[&large], (false, 0)),
+    ];
+
+    for (i, (buckets, (allowed, remaining))) in cases.iter().enumerate() {
+        let consumed = consume(buckets, now);
+        assert_eq!(
+            (*allowed, *remaining),
+            (consumed.allowed, consumed.remaining),
+            "request {}",
+            i
+        );
+    }
+}
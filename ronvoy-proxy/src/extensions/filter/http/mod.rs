@@ -0,0 +1,707 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::any::Any as StdAny;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use axum::body::{Body, Bytes};
+use axum::http::{HeaderMap, HeaderValue, Method, Uri};
+use envoy_control_plane::envoy::config::route::v3::FilterConfig as V3FilterConfig;
+use envoy_control_plane::envoy::extensions::filters::http::router::v3::Router as V3Router;
+use envoy_control_plane::envoy::extensions::filters::network::http_connection_manager::v3::{
+    http_filter::ConfigType as V3HttpFilterConfigType, HttpFilter as V3HttpFilter,
+};
+use envoy_control_plane::prost::Message;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+use hyper::body::HttpBody;
+use once_cell::sync::Lazy;
+
+use crate::cluster::Clusters;
+use crate::extensions::filter::network::http_connection_manager::{HttpConnectionManager, Routed};
+use crate::router;
+use crate::{Request, Response};
+
+mod compressor;
+mod cors;
+pub mod decompressor;
+mod ext_authz;
+mod fault;
+mod grpc_web;
+mod jwt_authn;
+mod local_ratelimit;
+mod ratelimit;
+mod rbac;
+
+/// ROUTER_FILTER_NAME is the well-known name of the router filter, which is how
+/// it is found when it is configured without a typed_config.
+const ROUTER_FILTER_NAME: &str = "envoy.filters.http.router";
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("HTTP filter {0} is missing its typed_config")]
+    MissingConfig(String),
+    #[error("TODO: HTTP filter {0} uses config discovery, which isn't supported yet")]
+    UnsupportedConfigDiscovery(String),
+    #[error("unsupported HTTP filter type {0}")]
+    UnsupportedFilter(String),
+    #[error("HTTP filter {0} has an invalid config: {1}")]
+    BadConfig(String, String),
+    #[error("HTTP filter type {0} doesn't support per-route configs")]
+    UnsupportedPerFilterConfig(String),
+    #[error("the router must be the last HTTP filter")]
+    RouterNotLast,
+    #[error("HTTP filters must end with the router")]
+    MissingRouter,
+}
+
+/// FilterStatus is what a filter decides after seeing a request's headers.
+#[derive(Debug)]
+pub enum FilterStatus {
+    /// Continue passes the request on to the next filter.
+    Continue,
+    /// Respond stops the request from going any further, sending the response to
+    /// the downstream instead.  On its way out, the response is encoded by the
+    /// filters before the one that responded.
+    Respond(Response),
+}
+
+/// HttpFilter processes a single request and its response.  Requests are decoded
+/// by each filter of the chain in order before the router (the last filter)
+/// forwards them upstream, and responses are encoded by the same filters in the
+/// reverse order.  Every phase defaults to passing things through untouched.
+/// Filters that change the length of a body must fix up its Content-Length.
+#[tonic::async_trait]
+pub trait HttpFilter: Send {
+    /// decode_headers is called with a request before its body is streamed.
+    async fn decode_headers(
+        &mut self,
+        _req: &mut Request,
+        _ctx: &FilterContext<'_>,
+    ) -> FilterStatus {
+        FilterStatus::Continue
+    }
+
+    /// decode_body is called once every filter has passed a request on, and
+    /// returns the BodyFilter (if any) its body and trailers are streamed through.
+    fn decode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        None
+    }
+
+    /// encode_headers is called with a response before its body is streamed.
+    fn encode_headers(&mut self, _resp: &mut Response) {}
+
+    /// encode_body returns the BodyFilter (if any) a response's body and trailers
+    /// are streamed through.
+    fn encode_body(&mut self) -> Option<Box<dyn BodyFilter>> {
+        None
+    }
+}
+
+/// BodyFilter sees (and may transform) a request or response body as it streams
+/// through the filter chain.  If it returns an error, the stream is reset.
+pub trait BodyFilter: Send {
+    /// data is called with each chunk of the body, and returns what to send on in
+    /// its place.
+    fn data(&mut self, data: Bytes) -> io::Result<Bytes> {
+        Ok(data)
+    }
+
+    /// end is called at the end of the body with its trailers (empty if it has
+    /// none), and returns any final data to send before them.  Trailers that are
+    /// empty afterwards aren't sent.
+    fn end(&mut self, _trailers: &mut HeaderMap) -> io::Result<Bytes> {
+        Ok(Bytes::new())
+    }
+}
+
+/// filter_body streams a body through a BodyFilter.
+fn filter_body(body: Body, mut filter: Box<dyn BodyFilter>) -> Body {
+    let (mut tx, rx) = Body::channel();
+    tokio::spawn(async move {
+        let mut body = body;
+        while let Some(data) = body.data().await {
+            match data.map(|data| filter.data(data)) {
+                Ok(Ok(data)) => {
+                    if !data.is_empty() && tx.send_data(data).await.is_err() {
+                        // the receiving side went away
+                        return;
+                    }
+                }
+                Ok(Err(_)) | Err(_) => {
+                    tx.abort();
+                    return;
+                }
+            }
+        }
+        let mut trailers = match body.trailers().await {
+            Ok(trailers) => trailers.unwrap_or_default(),
+            Err(_) => {
+                tx.abort();
+                return;
+            }
+        };
+        match filter.end(&mut trailers) {
+            Ok(data) => {
+                if !data.is_empty() && tx.send_data(data).await.is_err() {
+                    return;
+                }
+            }
+            Err(_) => {
+                tx.abort();
+                return;
+            }
+        }
+        if !trailers.is_empty() {
+            let _ = tx.send_trailers(trailers).await;
+        }
+    });
+    rx
+}
+
+/// FilterContext is what a filter knows about the request it is decoding, beyond
+/// the request itself.
+pub struct FilterContext<'a> {
+    /// routed is the route (and cluster) the request matched, if any.
+    pub routed: Option<&'a Routed>,
+    // the filter's name in the chain, which its per-route configs are keyed by
+    name: &'a str,
+    clusters: &'a Clusters,
+}
+
+impl FilterContext<'_> {
+    /// per_filter_config returns the filter's config override from the request's
+    /// route (or that route's virtual host), if it has one of type T.
+    pub fn per_filter_config<T: StdAny>(&self) -> Option<&T> {
+        let config = self.routed?.route.per_filter_config(self.name)?;
+        PerFilterConfig::as_any(config).downcast_ref()
+    }
+
+    /// clusters are the clusters the request could be sent to, which filters
+    /// calling out to other services (like a rate limit service) use too.
+    pub(crate) fn clusters(&self) -> &Clusters {
+        self.clusters
+    }
+}
+
+/// HttpFilterFactory is a configured HTTP filter, which creates an HttpFilter for
+/// each request.
+pub trait HttpFilterFactory: Debug + Send + Sync {
+    fn create(&self) -> Box<dyn HttpFilter>;
+}
+
+/// PerFilterConfig is a filter's config override for the requests using a
+/// particular route or virtual host.
+pub trait PerFilterConfig: StdAny + Debug + Send + Sync {
+    fn as_any(&self) -> &dyn StdAny;
+}
+
+impl<T: StdAny + Debug + Send + Sync> PerFilterConfig for T {
+    fn as_any(&self) -> &dyn StdAny {
+        self
+    }
+}
+
+/// HttpFilterConfigFactory creates HTTP filters from their configs.  Filters are
+/// added to Ronvoy by registering their HttpFilterConfigFactory under the type
+/// URL of their typed_config (see register).
+pub trait HttpFilterConfigFactory: Send + Sync {
+    /// create_filter_factory decodes a filter's typed_config from the
+    /// HttpConnectionManager's http_filters.
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error>;
+
+    /// create_per_filter_config decodes a filter's config override from the
+    /// typed_per_filter_config of a route or virtual host.  A factory that
+    /// supports them should also be registered under their type URL, if it differs.
+    fn create_per_filter_config(&self, config: &Any) -> Result<Arc<dyn PerFilterConfig>, Error> {
+        Err(Error::UnsupportedPerFilterConfig(config.type_url.clone()))
+    }
+}
+
+type Factories = HashMap<String, Arc<dyn HttpFilterConfigFactory>>;
+
+/// FACTORIES are the HTTP filters Ronvoy knows about, by the type URL of their
+/// configs.  It starts out with the filters built in to Ronvoy.
+static FACTORIES: Lazy<RwLock<Factories>> = Lazy::new(|| {
+    let mut factories: Factories = HashMap::new();
+    factories.insert(compressor::type_url(), Arc::new(compressor::Factory));
+    factories.insert(cors::type_url(), Arc::new(cors::Factory));
+    factories.insert(
+        decompressor::type_url(),
+        Arc::new(decompressor::Factory::default()),
+    );
+    factories.insert(ext_authz::type_url(), Arc::new(ext_authz::Factory));
+    factories.insert(fault::type_url(), Arc::new(fault::Factory));
+    factories.insert(grpc_web::type_url(), Arc::new(grpc_web::Factory));
+    factories.insert(jwt_authn::type_url(), Arc::new(jwt_authn::Factory));
+    factories.insert(
+        local_ratelimit::type_url(),
+        Arc::new(local_ratelimit::Factory),
+    );
+    factories.insert(ratelimit::type_url(), Arc::new(ratelimit::Factory));
+    factories.insert(rbac::type_url(), Arc::new(rbac::Factory));
+    RwLock::new(factories)
+});
+
+/// register makes an HTTP filter available to HttpConnectionManagers: filters and
+/// per-route configs whose typed_config has the type URL `type_url` are created
+/// by `factory`.  It returns the factory previously registered for `type_url`, if
+/// any.  Filters must be registered before the listeners using them are built.
+pub fn register(
+    type_url: impl Into<String>,
+    factory: Arc<dyn HttpFilterConfigFactory>,
+) -> Option<Arc<dyn HttpFilterConfigFactory>> {
+    FACTORIES.write().unwrap().insert(type_url.into(), factory)
+}
+
+fn factory(type_url: &str) -> Option<Arc<dyn HttpFilterConfigFactory>> {
+    FACTORIES.read().unwrap().get(type_url).cloned()
+}
+
+/// decode decodes a filter's typed_config as a T, for HttpFilterConfigFactory
+/// implementations.
+pub fn decode<T: Message + Default>(config: &Any) -> Result<T, Error> {
+    T::decode(&*config.value)
+        .map_err(|err| Error::BadConfig(config.type_url.clone(), err.to_string()))
+}
+
+/// FilterConfigs are the per-filter config overrides of a route or virtual host,
+/// keyed by the name of the filter they are for.
+#[derive(Debug, Clone, Default)]
+pub struct FilterConfigs(HashMap<String, Arc<dyn PerFilterConfig>>);
+
+impl FilterConfigs {
+    pub fn get(&self, name: &str) -> Option<&dyn PerFilterConfig> {
+        self.0.get(name).map(|config| config.as_ref())
+    }
+
+    /// inherit adds the configs of an enclosing level (a virtual host) for filters
+    /// that aren't configured at this level.
+    pub fn inherit(&mut self, enclosing: &FilterConfigs) {
+        for (name, config) in enclosing.0.iter() {
+            self.0.entry(name.clone()).or_insert_with(|| config.clone());
+        }
+    }
+}
+
+impl PartialEq for FilterConfigs {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.len() == other.0.len()
+            && self.0.iter().all(|(name, config)| {
+                other.0.get(name).map_or(false, |other| {
+                    Arc::as_ptr(config) as *const u8 == Arc::as_ptr(other) as *const u8
+                })
+            })
+    }
+}
+
+impl TryFrom<HashMap<String, Any>> for FilterConfigs {
+    type Error = Error;
+
+    fn try_from(typed_per_filter_config: HashMap<String, Any>) -> Result<Self, Self::Error> {
+        let filter_config_type_url = V3FilterConfig::default().type_url();
+        let mut configs = HashMap::new();
+        for (name, mut config) in typed_per_filter_config {
+            // configs may be wrapped in a FilterConfig to mark them as optional
+            let mut is_optional = false;
+            if config.type_url == filter_config_type_url {
+                let wrapper: V3FilterConfig = decode(&config)?;
+                is_optional = wrapper.is_optional;
+                config = wrapper
+                    .config
+                    .ok_or_else(|| Error::MissingConfig(name.clone()))?;
+            }
+            match factory(&config.type_url) {
+                Some(factory) => {
+                    configs.insert(name, factory.create_per_filter_config(&config)?);
+                }
+                None if is_optional => {}
+                None => return Err(Error::UnsupportedFilter(config.type_url)),
+            }
+        }
+        Ok(FilterConfigs(configs))
+    }
+}
+
+/// RouteInputs are the parts of a request its route is computed from: its
+/// method, URI and the few headers routing looks at.
+struct RouteInputs<'a> {
+    method: Method,
+    uri: Uri,
+    headers: Vec<(&'a str, Option<HeaderValue>)>,
+}
+
+impl<'a> RouteInputs<'a> {
+    fn of(req: &Request, http_conn_mgr: &'a HttpConnectionManager) -> Self {
+        let headers = http_conn_mgr
+            .route_headers()
+            .into_iter()
+            .map(|name| (name, req.headers().get(name).cloned()))
+            .collect();
+        RouteInputs {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            headers,
+        }
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        self.method == req.method()
+            && self.uri == *req.uri()
+            && self
+                .headers
+                .iter()
+                .all(|(name, value)| req.headers().get(*name) == value.as_ref())
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NamedFilter {
+    name: String,
+    factory: Arc<dyn HttpFilterFactory>,
+}
+
+/// FilterChain is an HttpConnectionManager's HTTP filters, from its http_filters
+/// config.  The router, which must be the last filter, is implicit.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    filters: Vec<NamedFilter>,
+}
+
+impl FilterChain {
+    /// run passes a request through the filter chain and on to the router, and
+    /// the response back out through the filters.  `upgrade_type` is the protocol
+    /// the request asks to upgrade to, if any.  Like Envoy clearing its route
+    /// cache, a filter that changes what the request is routed by (its method,
+    /// URI or the headers routing looks at) has it routed again for the filters
+    /// after it and the router.
+    pub async fn run(
+        &self,
+        http_conn_mgr: &HttpConnectionManager,
+        upgrade_type: Option<String>,
+        mut req: Request,
+    ) -> Response {
+        let mut routed = http_conn_mgr.get_cluster(&req);
+        let mut routed_by = RouteInputs::of(&req, http_conn_mgr);
+        let mut filters: Vec<Box<dyn HttpFilter>> = self
+            .filters
+            .iter()
+            .map(|filter| filter.factory.create())
+            .collect();
+
+        // the number of filters that passed the request on
+        let mut decoded = 0;
+        let mut local_resp = None;
+        for (filter, config) in filters.iter_mut().zip(self.filters.iter()) {
+            let ctx = FilterContext {
+                routed: routed.as_ref(),
+                name: &config.name,
+                clusters: http_conn_mgr.clusters(),
+            };
+            if let FilterStatus::Respond(resp) = filter.decode_headers(&mut req, &ctx).await {
+                local_resp = Some(resp);
+                break;
+            }
+            decoded += 1;
+            if !routed_by.matches(&req) {
+                routed = http_conn_mgr.get_cluster(&req);
+                routed_by = RouteInputs::of(&req, http_conn_mgr);
+            }
+        }
+
+        let mut resp = match local_resp {
+            Some(resp) => resp,
+            None => {
+                for filter in filters.iter_mut() {
+                    if let Some(body_filter) = filter.decode_body() {
+                        req = req.map(|body| filter_body(body, body_filter));
+                    }
+                }
+                router::route(http_conn_mgr, routed, upgrade_type, req).await
+            }
+        };
+
+        for filter in filters[..decoded].iter_mut().rev() {
+            filter.encode_headers(&mut resp);
+            if let Some(body_filter) = filter.encode_body() {
+                resp = resp.map(|body| filter_body(body, body_filter));
+            }
+        }
+        resp
+    }
+}
+
+impl TryFrom<Vec<V3HttpFilter>> for FilterChain {
+    type Error = Error;
+
+    /// try_from builds a filter chain.  Like Envoy, filters whose type isn't known
+    /// are an error unless they are marked is_optional, in which case they are
+    /// skipped.  An empty list of filters is just the router.
+    fn try_from(http_filters: Vec<V3HttpFilter>) -> Result<Self, Self::Error> {
+        let router_type_url = V3Router::default().type_url();
+        let len = http_filters.len();
+        let mut filters = vec![];
+        for (i, filter) in http_filters.into_iter().enumerate() {
+            let last = i == len - 1;
+            let config = match filter.config_type {
+                Some(V3HttpFilterConfigType::TypedConfig(config)) => Some(config),
+                Some(V3HttpFilterConfigType::ConfigDiscovery(_)) => {
+                    return Err(Error::UnsupportedConfigDiscovery(filter.name))
+                }
+                None => None,
+            };
+            let is_router = match &config {
+                Some(config) => config.type_url == router_type_url,
+                // without a typed_config, a filter is known by its well-known name
+                None => filter.name == ROUTER_FILTER_NAME,
+            };
+            if is_router {
+                if !last {
+                    return Err(Error::RouterNotLast);
+                }
+                continue;
+            }
+
+            let config = config.ok_or_else(|| Error::MissingConfig(filter.name.clone()))?;
+            match factory(&config.type_url) {
+                Some(factory) => filters.push(NamedFilter {
+                    name: filter.name,
+                    factory: factory.create_filter_factory(&config)?,
+                }),
+                None if filter.is_optional => {}
+                None => return Err(Error::UnsupportedFilter(config.type_url)),
+            }
+            if last {
+                return Err(Error::MissingRouter);
+            }
+        }
+        Ok(FilterChain { filters })
+    }
+}
+
+#[test]
+fn test_filter_chain() {
+    let router_type_url = V3Router::default().type_url();
+    let unknown_type_url = "type.googleapis.com/example.Unknown";
+    let filter = |name: &str, type_url: Option<&str>, is_optional: bool| V3HttpFilter {
+        name: name.to_owned(),
+        config_type: type_url.map(|type_url| {
+            V3HttpFilterConfigType::TypedConfig(Any {
+                type_url: type_url.to_owned(),
+                value: vec![],
+            })
+        }),
+        is_optional,
+        ..Default::default()
+    };
+    let router = filter("router", Some(&router_type_url), false);
+
+    let cases: Vec<(Vec<V3HttpFilter>, Result<(), Error>)> = vec![
+        (vec![], Ok(())),
+        (vec![filter(ROUTER_FILTER_NAME, None, false)], Ok(())),
+        (vec![router.clone()], Ok(())),
+        (
+            vec![
+                filter("unknown", Some(unknown_type_url), true),
+                router.clone(),
+            ],
+            Ok(()),
+        ),
+        (
+            vec![
+                filter("unknown", Some(unknown_type_url), false),
+                router.clone(),
+            ],
+            Err(Error::UnsupportedFilter(unknown_type_url.to_owned())),
+        ),
+        (
+            vec![
+                filter("envoy.filters.http.cors", None, false),
+                router.clone(),
+            ],
+            Err(Error::MissingConfig("envoy.filters.http.cors".to_owned())),
+        ),
+        (
+            vec![
+                router.clone(),
+                filter("unknown", Some(unknown_type_url), true),
+            ],
+            Err(Error::RouterNotLast),
+        ),
+        (
+            vec![filter("unknown", Some(unknown_type_url), true)],
+            Err(Error::MissingRouter),
+        ),
+    ];
+
+    for (filters, expected) in cases.into_iter() {
+        let names: Vec<_> = filters.iter().map(|filter| filter.name.clone()).collect();
+        let actual = FilterChain::try_from(filters).map(|_| ());
+        assert_eq!(expected, actual, "{:?}", names);
+    }
+}
+
+#[test]
+fn test_register() {
+    const TYPE_URL: &str = "type.googleapis.com/ronvoy.test.Noop";
+
+    #[derive(Debug)]
+    struct Noop;
+
+    impl HttpFilter for Noop {}
+
+    impl HttpFilterFactory for Noop {
+        fn create(&self) -> Box<dyn HttpFilter> {
+            Box::new(Noop)
+        }
+    }
+
+    impl HttpFilterConfigFactory for Noop {
+        fn create_filter_factory(
+            &self,
+            _config: &Any,
+        ) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+            Ok(Arc::new(Noop))
+        }
+
+        fn create_per_filter_config(
+            &self,
+            _config: &Any,
+        ) -> Result<Arc<dyn PerFilterConfig>, Error> {
+            Ok(Arc::new(Noop))
+        }
+    }
+
+    let noop = Any {
+        type_url: TYPE_URL.to_owned(),
+        value: vec![],
+    };
+    let filters = vec![
+        V3HttpFilter {
+            name: "noop".to_owned(),
+            config_type: Some(V3HttpFilterConfigType::TypedConfig(noop.clone())),
+            ..Default::default()
+        },
+        V3HttpFilter {
+            name: ROUTER_FILTER_NAME.to_owned(),
+            ..Default::default()
+        },
+    ];
+    let per_filter_configs: HashMap<_, _> = [("noop".to_owned(), noop)].into_iter().collect();
+
+    assert!(FilterChain::try_from(filters.clone()).is_err());
+    assert!(FilterConfigs::try_from(per_filter_configs.clone()).is_err());
+
+    assert!(register(TYPE_URL, Arc::new(Noop)).is_none());
+    let chain = FilterChain::try_from(filters).unwrap();
+    assert_eq!(1, chain.filters.len());
+    assert_eq!("noop", chain.filters[0].name);
+    let configs = FilterConfigs::try_from(per_filter_configs).unwrap();
+    let config = configs.get("noop").unwrap();
+    assert!(PerFilterConfig::as_any(config)
+        .downcast_ref::<Noop>()
+        .is_some());
+}
+
+#[tokio::test]
+async fn test_reroute() {
+    use axum::routing::get;
+
+    use crate::testing::{test_clusters, test_http_conn_mgr, test_route, TestHttpServer};
+
+    /// Rewrite moves requests for /old to /new.
+    #[derive(Debug)]
+    struct Rewrite;
+
+    #[tonic::async_trait]
+    impl HttpFilter for Rewrite {
+        async fn decode_headers(
+            &mut self,
+            req: &mut Request,
+            _ctx: &FilterContext<'_>,
+        ) -> FilterStatus {
+            if req.uri().path() == "/old" {
+                *req.uri_mut() = Uri::from_static("/new");
+            }
+            FilterStatus::Continue
+        }
+    }
+
+    impl HttpFilterFactory for Rewrite {
+        fn create(&self) -> Box<dyn HttpFilter> {
+            Box::new(Rewrite)
+        }
+    }
+
+    /// RouteName tells the downstream which route it saw the request on.
+    #[derive(Debug)]
+    struct RouteName(Option<String>);
+
+    #[tonic::async_trait]
+    impl HttpFilter for RouteName {
+        async fn decode_headers(
+            &mut self,
+            _req: &mut Request,
+            ctx: &FilterContext<'_>,
+        ) -> FilterStatus {
+            self.0 = ctx.routed.map(|routed| routed.route.name.clone());
+            FilterStatus::Continue
+        }
+
+        fn encode_headers(&mut self, resp: &mut Response) {
+            if let Some(name) = &self.0 {
+                let name = HeaderValue::from_str(name).unwrap();
+                resp.headers_mut().insert("x-route-name", name);
+            }
+        }
+    }
+
+    impl HttpFilterFactory for RouteName {
+        fn create(&self) -> Box<dyn HttpFilter> {
+            Box::new(RouteName(None))
+        }
+    }
+
+    let upstream =
+        TestHttpServer::with_router(axum::Router::new().route("/new", get(|| async { "new" })));
+    let http_conn_mgr = test_http_conn_mgr(
+        vec![test_route("new", "/new", "upstream")],
+        test_clusters("upstream", upstream.addr),
+    );
+    let named = |name: &str, factory: Arc<dyn HttpFilterFactory>| NamedFilter {
+        name: name.to_owned(),
+        factory,
+    };
+    let chain = FilterChain {
+        filters: vec![
+            named("rewrite", Arc::new(Rewrite)),
+            named("route_name", Arc::new(RouteName(None))),
+        ],
+    };
+
+    // (path, and the status and route name of the response)
+    let cases: &[(&str, (u16, Option<&str>))] = &[
+        ("/new", (200, Some("new"))),
+        // the rewritten request is routed again, for later filters and the router
+        ("/old", (200, Some("new"))),
+        ("/other", (404, None)),
+    ];
+
+    for (path, (status, route_name)) in cases.iter() {
+        let req = axum::http::Request::builder()
+            .uri(*path)
+            .header("host", "example.com")
+            .body(Body::empty())
+            .unwrap();
+        let resp = chain.run(&http_conn_mgr, None, req).await;
+        assert_eq!(*status, resp.status().as_u16(), "{}", path);
+        let actual = resp
+            .headers()
+            .get("x-route-name")
+            .map(|name| name.to_str().unwrap());
+        assert_eq!(*route_name, actual, "{}", path);
+    }
+}
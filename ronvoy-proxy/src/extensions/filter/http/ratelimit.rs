@@ -0,0 +1,435 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue};
+use envoy_control_plane::envoy::extensions::common::ratelimit::v3::XRateLimitHeadersRfcVersion as V3XRateLimitHeadersRfcVersion;
+use envoy_control_plane::envoy::extensions::filters::http::ratelimit::v3::RateLimit as V3RateLimit;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::local_ratelimit::{
+    RATELIMIT_LIMIT_HEADER, RATELIMIT_REMAINING_HEADER, RATELIMIT_RESET_HEADER,
+};
+use super::{
+    decode, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory,
+};
+use crate::local_reply::{local_reply, ResponseFlag};
+use crate::protobuf;
+use crate::rate_limit::{self, Limit, RateLimitService};
+use crate::route::Action;
+use crate::{Request, Response};
+
+/// RATELIMITED_HEADER is set on responses to requests that were over the limit.
+pub const RATELIMITED_HEADER: &str = "x-envoy-ratelimited";
+
+// the request header that marks a request as coming from inside the mesh
+const INTERNAL_HEADER: &str = "x-envoy-internal";
+
+// how long to wait for the rate limit service if the config doesn't say
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// type_url is the type URL of the rate limit filter's config.
+pub fn type_url() -> String {
+    V3RateLimit::default().type_url()
+}
+
+/// RequestType is which requests the filter applies to, going by whether they
+/// are internal (have `x-envoy-internal: true`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RequestType {
+    Internal,
+    External,
+    Both,
+}
+
+impl RequestType {
+    fn applies_to(&self, req: &Request) -> bool {
+        let internal = req
+            .headers()
+            .get(INTERNAL_HEADER)
+            .map_or(false, |v| v.as_bytes() == b"true");
+        match self {
+            RequestType::Internal => internal,
+            RequestType::External => !internal,
+            RequestType::Both => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Config {
+    domain: String,
+    stage: u32,
+    request_type: RequestType,
+    /// failure_mode_deny rejects requests if the rate limit service can't be
+    /// reached (or fails), rather than letting them through.
+    failure_mode_deny: bool,
+    status: u16,
+    ratelimited_header: bool,
+    /// x_ratelimit_headers adds the draft rate limit headers to responses.
+    x_ratelimit_headers: bool,
+    service: RateLimitService,
+}
+
+/// x_ratelimit_headers are the draft rate limit headers for the most limited of
+/// a request's `limits`.  Like Envoy's, the limit header also lists the quota
+/// policy of every limit, as in `10, 10;w=60;name="per_user"`.
+fn x_ratelimit_headers(limits: &[Limit]) -> Vec<(HeaderName, HeaderValue)> {
+    let most_limited = match limits.iter().min_by_key(|limit| limit.remaining) {
+        Some(limit) => limit,
+        None => return vec![],
+    };
+    let mut limit_header = most_limited.requests_per_unit.to_string();
+    for limit in limits.iter() {
+        limit_header.push_str(&format!(
+            ", {};w={}",
+            limit.requests_per_unit,
+            limit.unit.as_secs()
+        ));
+        if !limit.name.is_empty() {
+            limit_header.push_str(&format!(";name=\"{}\"", limit.name));
+        }
+    }
+    let limit_header = match HeaderValue::from_str(&limit_header) {
+        Ok(value) => value,
+        Err(_) => return vec![],
+    };
+    vec![
+        (
+            HeaderName::from_static(RATELIMIT_LIMIT_HEADER),
+            limit_header,
+        ),
+        (
+            HeaderName::from_static(RATELIMIT_REMAINING_HEADER),
+            HeaderValue::from(most_limited.remaining),
+        ),
+        (
+            HeaderName::from_static(RATELIMIT_RESET_HEADER),
+            HeaderValue::from(most_limited.reset.as_secs()),
+        ),
+    ]
+}
+
+impl TryFrom<V3RateLimit> for Config {
+    type Error = Error;
+
+    fn try_from(value: V3RateLimit) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+
+        if value.domain.is_empty() {
+            return Err(bad_config("missing domain".to_owned()));
+        }
+        let request_type = match value.request_type.as_str() {
+            "internal" => RequestType::Internal,
+            "external" => RequestType::External,
+            "both" | "" => RequestType::Both,
+            other => return Err(bad_config(format!("invalid request_type {}", other))),
+        };
+        if value.rate_limited_as_resource_exhausted {
+            return Err(bad_config(
+                "TODO: rate_limited_as_resource_exhausted isn't supported".to_owned(),
+            ));
+        }
+        let x_ratelimit_headers =
+            match V3XRateLimitHeadersRfcVersion::from_i32(value.enable_x_ratelimit_headers) {
+                Some(V3XRateLimitHeadersRfcVersion::Off) => false,
+                Some(V3XRateLimitHeadersRfcVersion::DraftVersion03) => true,
+                None => {
+                    return Err(bad_config(format!(
+                        "invalid enable_x_ratelimit_headers {}",
+                        value.enable_x_ratelimit_headers
+                    )))
+                }
+            };
+        let status = match value.rate_limited_status {
+            Some(status) if status.code >= 400 => status.code as u16,
+            // like Envoy, invalid statuses are ignored
+            _ => 429,
+        };
+        let timeout = value
+            .timeout
+            .as_ref()
+            .map(protobuf::duration)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let service = value
+            .rate_limit_service
+            .ok_or_else(|| bad_config("missing rate_limit_service".to_owned()))
+            .and_then(|service| {
+                RateLimitService::try_from(service).map_err(|err| bad_config(err.to_string()))
+            })?
+            .with_timeout(timeout);
+
+        Ok(Config {
+            domain: value.domain,
+            stage: value.stage,
+            request_type,
+            failure_mode_deny: value.failure_mode_deny,
+            status,
+            ratelimited_header: !value.disable_x_envoy_ratelimited_header,
+            x_ratelimit_headers,
+            service,
+        })
+    }
+}
+
+/// RateLimit is `envoy.filters.http.ratelimit`: it asks an external rate limit
+/// service whether a request's descriptors (built from its route's rate_limits)
+/// are over the limit, and rejects it with a 429 (or the configured status) if so.
+#[derive(Debug)]
+struct RateLimit {
+    config: Arc<Config>,
+    response_headers_to_add: Vec<(HeaderName, HeaderValue)>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for RateLimit {
+    async fn decode_headers(&mut self, req: &mut Request, ctx: &FilterContext<'_>) -> FilterStatus {
+        let config = self.config.as_ref();
+        let routed = match ctx.routed {
+            Some(routed) if config.request_type.applies_to(req) => routed,
+            _ => return FilterStatus::Continue,
+        };
+        let Action::Route(action) = routed.route.action();
+        let descriptors =
+            rate_limit::descriptors(&action.rate_limits, config.stage, req, &routed.cluster.name);
+        if descriptors.is_empty() {
+            return FilterStatus::Continue;
+        }
+
+        let decision = config
+            .service
+            .should_rate_limit(ctx.clusters(), &config.domain, &descriptors)
+            .await;
+        let decision = match decision {
+            Ok(decision) => decision,
+            Err(_) if config.failure_mode_deny => {
+                return FilterStatus::Respond(local_reply(
+                    500,
+                    Some(ResponseFlag::RateLimitServiceError),
+                    "rate_limiter_error",
+                ))
+            }
+            // fail open
+            Err(_) => return FilterStatus::Continue,
+        };
+
+        let mut response_headers_to_add = decision.response_headers_to_add;
+        if config.x_ratelimit_headers {
+            response_headers_to_add.extend(x_ratelimit_headers(&decision.limits));
+        }
+        if decision.over_limit {
+            let mut resp = local_reply(
+                config.status,
+                Some(ResponseFlag::RateLimited),
+                "request_rate_limited",
+            );
+            let headers = resp.headers_mut();
+            for (name, value) in response_headers_to_add {
+                headers.append(name, value);
+            }
+            if config.ratelimited_header {
+                headers.insert(RATELIMITED_HEADER, HeaderValue::from_static("true"));
+            }
+            return FilterStatus::Respond(resp);
+        }
+
+        let headers = req.headers_mut();
+        for (name, value) in decision.request_headers_to_add {
+            headers.append(name, value);
+        }
+        self.response_headers_to_add = response_headers_to_add;
+        FilterStatus::Continue
+    }
+
+    fn encode_headers(&mut self, resp: &mut Response) {
+        let headers = resp.headers_mut();
+        for (name, value) in self.response_headers_to_add.drain(..) {
+            headers.append(name, value);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(RateLimit {
+            config: self.0.clone(),
+            response_headers_to_add: vec![],
+        })
+    }
+}
+
+/// Factory creates rate limit filters.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = Config::try_from(decode::<V3RateLimit>(config)?)?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limit() {
+    use envoy_control_plane::envoy::config::ratelimit::v3::RateLimitServiceConfig as V3RateLimitServiceConfig;
+    use envoy_control_plane::envoy::config::route::v3::{
+        rate_limit::action::{ActionSpecifier as V3ActionSpecifier, RequestHeaders},
+        rate_limit::Action as V3Action,
+        route::Action as V3RouteAction,
+        RateLimit as V3RouteRateLimit,
+    };
+    use envoy_control_plane::prost_wkt_types::Duration as PbDuration;
+
+    use crate::local_reply::LocalReply;
+    use crate::testing::{
+        test_clusters, test_grpc_service, test_http_conn_mgr, test_route, TestRateLimitServer,
+    };
+
+    let rate_limiter = TestRateLimitServer::new();
+    // requests are rate limited by their x-limit header, which the test rate
+    // limiter decides by
+    let mut route = test_route("limited", "/", "ratelimit");
+    if let Some(V3RouteAction::Route(action)) = &mut route.action {
+        action.rate_limits = vec![V3RouteRateLimit {
+            actions: vec![V3Action {
+                action_specifier: Some(V3ActionSpecifier::RequestHeaders(RequestHeaders {
+                    header_name: "x-limit".to_owned(),
+                    descriptor_key: "limit".to_owned(),
+                    skip_if_absent: false,
+                })),
+            }],
+            ..Default::default()
+        }];
+    }
+    let http_conn_mgr =
+        test_http_conn_mgr(vec![route], test_clusters("ratelimit", rate_limiter.addr));
+    let config = |failure_mode_deny: bool, request_type: &str| {
+        let config = Config::try_from(V3RateLimit {
+            domain: "test".to_owned(),
+            request_type: request_type.to_owned(),
+            failure_mode_deny,
+            timeout: Some(PbDuration {
+                seconds: 5,
+                nanos: 0,
+            }),
+            enable_x_ratelimit_headers: V3XRateLimitHeadersRfcVersion::DraftVersion03 as i32,
+            rate_limit_service: Some(V3RateLimitServiceConfig {
+                grpc_service: Some(test_grpc_service("ratelimit")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        Arc::new(config.unwrap())
+    };
+
+    // (failure_mode_deny, request_type, request headers, and the
+    // x-ratelimit-remaining of an allowed request, if it was checked, or the
+    // status and response flag of its rejection)
+    type Expected = Result<Option<&'static str>, (u16, ResponseFlag)>;
+    let cases: &[(bool, &str, &[(&str, &str)], Expected)] = &[
+        (false, "both", &[("x-limit", "ok")], Ok(Some("9"))),
+        (
+            false,
+            "both",
+            &[("x-limit", "over")],
+            Err((429, ResponseFlag::RateLimited)),
+        ),
+        // without descriptors, the service isn't asked
+        (false, "both", &[], Ok(None)),
+        // if the service fails, requests are let through unless failure_mode_deny
+        (false, "both", &[("x-limit", "fail")], Ok(None)),
+        (
+            true,
+            "both",
+            &[("x-limit", "fail")],
+            Err((500, ResponseFlag::RateLimitServiceError)),
+        ),
+        (false, "internal", &[("x-limit", "over")], Ok(None)),
+        (
+            false,
+            "internal",
+            &[("x-limit", "over"), ("x-envoy-internal", "true")],
+            Err((429, ResponseFlag::RateLimited)),
+        ),
+        (
+            false,
+            "external",
+            &[("x-limit", "over"), ("x-envoy-internal", "true")],
+            Ok(None),
+        ),
+    ];
+
+    let limit_header = "10, 10;w=60;name=\"per_minute\"";
+    for (failure_mode_deny, request_type, headers, expected) in cases.iter() {
+        let mut req = axum::http::Request::builder()
+            .uri("/")
+            .header("host", "example.com");
+        for (name, value) in headers.iter() {
+            req = req.header(*name, *value);
+        }
+        let mut req = req.body(axum::body::Body::empty()).unwrap();
+        let routed = http_conn_mgr.get_cluster(&req);
+        let ctx = FilterContext {
+            routed: routed.as_ref(),
+            name: "envoy.filters.http.ratelimit",
+            clusters: http_conn_mgr.clusters(),
+        };
+        let mut filter = RateLimit {
+            config: config(*failure_mode_deny, *request_type),
+            response_headers_to_add: vec![],
+        };
+        let msg = format!("{} {} {:?}", failure_mode_deny, request_type, headers);
+
+        let status = filter.decode_headers(&mut req, &ctx).await;
+        let checked = req.headers().get("x-ratelimit-checked");
+        match (status, expected) {
+            (FilterStatus::Continue, Ok(remaining)) => {
+                assert_eq!(remaining.is_some(), checked.is_some(), "{}", msg);
+                let mut resp = Response::default();
+                filter.encode_headers(&mut resp);
+                let headers = resp.headers();
+                assert_eq!(
+                    remaining.map(|_| limit_header),
+                    headers
+                        .get(RATELIMIT_LIMIT_HEADER)
+                        .map(|v| v.to_str().unwrap()),
+                    "{}",
+                    msg
+                );
+                assert_eq!(
+                    *remaining,
+                    headers
+                        .get(RATELIMIT_REMAINING_HEADER)
+                        .map(|v| v.to_str().unwrap()),
+                    "{}",
+                    msg
+                );
+            }
+            (FilterStatus::Respond(resp), Err((status, flag))) => {
+                assert_eq!(*status, resp.status().as_u16(), "{}", msg);
+                let reply = resp.extensions().get::<LocalReply>().unwrap();
+                assert_eq!(Some(*flag), reply.flag, "{}", msg);
+                let headers = resp.headers();
+                let rate_limited = *flag == ResponseFlag::RateLimited;
+                assert_eq!(
+                    rate_limited,
+                    headers.contains_key(RATELIMITED_HEADER),
+                    "{}",
+                    msg
+                );
+                if rate_limited {
+                    assert_eq!(limit_header, headers[RATELIMIT_LIMIT_HEADER], "{}", msg);
+                    assert_eq!("0", headers[RATELIMIT_REMAINING_HEADER], "{}", msg);
+                    assert_eq!("30", headers[RATELIMIT_RESET_HEADER], "{}", msg);
+                }
+            }
+            (status, _) => panic!("{}: unexpected {:?}", msg, status),
+        }
+    }
+}
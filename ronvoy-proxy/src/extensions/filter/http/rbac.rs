@@ -0,0 +1,840 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use envoy_control_plane::envoy::config::core::v3::CidrRange as V3CidrRange;
+use envoy_control_plane::envoy::config::rbac::v3::{
+    permission::Rule as V3Rule, principal::Identifier as V3Identifier, rbac::Action as V3Action,
+    Permission as V3Permission, Policy as V3Policy, Principal as V3Principal, Rbac as V3Rbac,
+};
+use envoy_control_plane::envoy::extensions::filters::http::rbac::v3::{
+    Rbac as V3RbacFilter, RbacPerRoute as V3RbacPerRoute,
+};
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{
+    decode, Error, FilterContext, FilterStatus, HttpFilter, HttpFilterConfigFactory,
+    HttpFilterFactory, PerFilterConfig,
+};
+use crate::address::CidrRange;
+use crate::listener::ConnectionInfo;
+use crate::local_reply::local_reply;
+use crate::matcher::{HeaderMatcher, MetadataMatcher, PathMatcher, StringMatcher};
+use crate::metadata::DynamicMetadata;
+use crate::Request;
+
+/// METADATA_NAMESPACE is the dynamic metadata namespace the filter records its
+/// decisions in.
+pub const METADATA_NAMESPACE: &str = "envoy.filters.http.rbac";
+
+/// type_url is the type URL of the RBAC filter's config.
+pub fn type_url() -> String {
+    V3RbacFilter::default().type_url()
+}
+
+/// Permission is an action a policy applies to, like Envoy's
+/// `config.rbac.v3.Permission`.
+#[derive(Debug, Clone, PartialEq)]
+enum Permission {
+    And(Vec<Permission>),
+    Or(Vec<Permission>),
+    Not(Box<Permission>),
+    Any,
+    Header(HeaderMatcher),
+    UrlPath(PathMatcher),
+    DestinationIp(CidrRange),
+    DestinationPort(u32),
+    /// DestinationPortRange is a half-open range of ports.
+    DestinationPortRange(i32, i32),
+    Metadata(MetadataMatcher),
+    RequestedServerName(StringMatcher),
+}
+
+impl Permission {
+    fn matches(&self, req: &Request) -> bool {
+        let local_addr = || {
+            req.extensions()
+                .get::<ConnectionInfo>()
+                .map(|conn| conn.local_addr)
+        };
+        match self {
+            Permission::And(rules) => rules.iter().all(|rule| rule.matches(req)),
+            Permission::Or(rules) => rules.iter().any(|rule| rule.matches(req)),
+            Permission::Not(rule) => !rule.matches(req),
+            Permission::Any => true,
+            Permission::Header(matcher) => matcher.matches_request(req),
+            Permission::UrlPath(matcher) => matcher.matches(req),
+            Permission::DestinationIp(range) => {
+                local_addr().map_or(false, |addr| range.contains(addr.ip()))
+            }
+            Permission::DestinationPort(port) => {
+                local_addr().map_or(false, |addr| addr.port() as u32 == *port)
+            }
+            Permission::DestinationPortRange(start, end) => {
+                local_addr().map_or(false, |addr| (*start..*end).contains(&(addr.port() as i32)))
+            }
+            Permission::Metadata(matcher) => matcher.matches(req.extensions().get()),
+            // downstream connections aren't TLS, so there is never an SNI
+            Permission::RequestedServerName(matcher) => matcher.matches(""),
+        }
+    }
+}
+
+impl TryFrom<V3Permission> for Permission {
+    type Error = String;
+
+    fn try_from(value: V3Permission) -> Result<Self, Self::Error> {
+        let rules = |rules: Vec<V3Permission>| {
+            rules
+                .into_iter()
+                .map(Permission::try_from)
+                .collect::<Result<_, _>>()
+        };
+        match value.rule {
+            Some(V3Rule::AndRules(set)) => Ok(Permission::And(rules(set.rules)?)),
+            Some(V3Rule::OrRules(set)) => Ok(Permission::Or(rules(set.rules)?)),
+            Some(V3Rule::NotRule(rule)) => {
+                Ok(Permission::Not(Box::new(Permission::try_from(*rule)?)))
+            }
+            Some(V3Rule::Any(_)) => Ok(Permission::Any),
+            Some(V3Rule::Header(matcher)) => HeaderMatcher::try_from(matcher)
+                .map(Permission::Header)
+                .map_err(|err| err.to_string()),
+            Some(V3Rule::UrlPath(matcher)) => PathMatcher::try_from(matcher)
+                .map(Permission::UrlPath)
+                .map_err(|err| err.to_string()),
+            Some(V3Rule::DestinationIp(range)) => CidrRange::try_from(range)
+                .map(Permission::DestinationIp)
+                .map_err(|err| err.to_string()),
+            Some(V3Rule::DestinationPort(port)) => Ok(Permission::DestinationPort(port)),
+            Some(V3Rule::DestinationPortRange(range)) => {
+                Ok(Permission::DestinationPortRange(range.start, range.end))
+            }
+            Some(V3Rule::Metadata(matcher)) => MetadataMatcher::try_from(matcher)
+                .map(Permission::Metadata)
+                .map_err(|err| err.to_string()),
+            Some(V3Rule::RequestedServerName(matcher)) => StringMatcher::try_from(matcher)
+                .map(Permission::RequestedServerName)
+                .map_err(|err| err.to_string()),
+            Some(_) => Err("TODO: extension permissions aren't supported".to_owned()),
+            None => Err("permission is missing its rule".to_owned()),
+        }
+    }
+}
+
+/// Principal is who a policy applies to, like Envoy's `config.rbac.v3.Principal`.
+#[derive(Debug, Clone, PartialEq)]
+enum Principal {
+    And(Vec<Principal>),
+    Or(Vec<Principal>),
+    Not(Box<Principal>),
+    Any,
+    /// RemoteIp matches the downstream's address.  The `source_ip`,
+    /// `direct_remote_ip` and `remote_ip` identifiers are all the peer's address,
+    /// as x-forwarded-for isn't trusted.
+    RemoteIp(CidrRange),
+    Header(HeaderMatcher),
+    UrlPath(PathMatcher),
+    Metadata(MetadataMatcher),
+}
+
+impl Principal {
+    fn matches(&self, req: &Request) -> bool {
+        match self {
+            Principal::And(ids) => ids.iter().all(|id| id.matches(req)),
+            Principal::Or(ids) => ids.iter().any(|id| id.matches(req)),
+            Principal::Not(id) => !id.matches(req),
+            Principal::Any => true,
+            Principal::RemoteIp(range) => req
+                .extensions()
+                .get::<ConnectionInfo>()
+                .map_or(false, |conn| range.contains(conn.remote_addr.ip())),
+            Principal::Header(matcher) => matcher.matches_request(req),
+            Principal::UrlPath(matcher) => matcher.matches(req),
+            Principal::Metadata(matcher) => matcher.matches(req.extensions().get()),
+        }
+    }
+}
+
+impl TryFrom<V3Principal> for Principal {
+    type Error = String;
+
+    fn try_from(value: V3Principal) -> Result<Self, Self::Error> {
+        let ids = |ids: Vec<V3Principal>| {
+            ids.into_iter()
+                .map(Principal::try_from)
+                .collect::<Result<_, _>>()
+        };
+        let cidr = |range: V3CidrRange| {
+            CidrRange::try_from(range)
+                .map(Principal::RemoteIp)
+                .map_err(|err| err.to_string())
+        };
+        match value.identifier {
+            Some(V3Identifier::AndIds(set)) => Ok(Principal::And(ids(set.ids)?)),
+            Some(V3Identifier::OrIds(set)) => Ok(Principal::Or(ids(set.ids)?)),
+            Some(V3Identifier::NotId(id)) => {
+                Ok(Principal::Not(Box::new(Principal::try_from(*id)?)))
+            }
+            Some(V3Identifier::Any(_)) => Ok(Principal::Any),
+            // downstream connections aren't TLS, so there are no client
+            // certificates to authenticate
+            Some(V3Identifier::Authenticated(_)) => {
+                Err("authenticated principals aren't supported without TLS".to_owned())
+            }
+            Some(V3Identifier::SourceIp(range)) => cidr(range),
+            Some(V3Identifier::DirectRemoteIp(range)) => cidr(range),
+            Some(V3Identifier::RemoteIp(range)) => cidr(range),
+            Some(V3Identifier::Header(matcher)) => HeaderMatcher::try_from(matcher)
+                .map(Principal::Header)
+                .map_err(|err| err.to_string()),
+            Some(V3Identifier::UrlPath(matcher)) => PathMatcher::try_from(matcher)
+                .map(Principal::UrlPath)
+                .map_err(|err| err.to_string()),
+            Some(V3Identifier::Metadata(matcher)) => MetadataMatcher::try_from(matcher)
+                .map(Principal::Metadata)
+                .map_err(|err| err.to_string()),
+            Some(_) => Err("TODO: filter_state principals aren't supported".to_owned()),
+            None => Err("principal is missing its identifier".to_owned()),
+        }
+    }
+}
+
+/// Policy applies to requests matching any of its permissions and any of its
+/// principals.
+#[derive(Debug, Clone, PartialEq)]
+struct Policy {
+    permissions: Vec<Permission>,
+    principals: Vec<Principal>,
+}
+
+impl Policy {
+    fn matches(&self, req: &Request) -> bool {
+        self.permissions
+            .iter()
+            .any(|permission| permission.matches(req))
+            && self
+                .principals
+                .iter()
+                .any(|principal| principal.matches(req))
+    }
+}
+
+impl TryFrom<V3Policy> for Policy {
+    type Error = String;
+
+    fn try_from(value: V3Policy) -> Result<Self, Self::Error> {
+        if value.condition.is_some() || value.checked_condition.is_some() {
+            return Err("TODO: policy conditions aren't supported".to_owned());
+        }
+        Ok(Policy {
+            permissions: value
+                .permissions
+                .into_iter()
+                .map(Permission::try_from)
+                .collect::<Result<_, _>>()?,
+            principals: value
+                .principals
+                .into_iter()
+                .map(Principal::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    /// Allow allows only the requests matching a policy.
+    Allow,
+    /// Deny denies the requests matching a policy.
+    Deny,
+    /// Log allows every request, marking the ones matching a policy for logging.
+    Log,
+}
+
+/// Engine is a set of RBAC policies, like Envoy's `config.rbac.v3.RBAC`.
+#[derive(Debug, Clone, PartialEq)]
+struct Engine {
+    action: Action,
+    /// policies are kept in name order, which is the order Envoy checks them in
+    /// (so which one is reported when several match is the same).
+    policies: BTreeMap<String, Policy>,
+}
+
+/// Decision is the outcome of checking a request against an engine's policies.
+#[derive(Debug, Clone, PartialEq)]
+struct Decision {
+    allowed: bool,
+    /// policy is the name of the policy that matched, if any did.
+    policy: Option<String>,
+}
+
+impl Engine {
+    fn check(&self, req: &Request) -> Decision {
+        let policy = self
+            .policies
+            .iter()
+            .find(|(_, policy)| policy.matches(req))
+            .map(|(name, _)| name.clone());
+        let allowed = match self.action {
+            Action::Allow => policy.is_some(),
+            Action::Deny => policy.is_none(),
+            Action::Log => true,
+        };
+        Decision { allowed, policy }
+    }
+}
+
+impl TryFrom<V3Rbac> for Engine {
+    type Error = String;
+
+    fn try_from(value: V3Rbac) -> Result<Self, Self::Error> {
+        let action = match V3Action::from_i32(value.action) {
+            Some(V3Action::Allow) => Action::Allow,
+            Some(V3Action::Deny) => Action::Deny,
+            Some(V3Action::Log) => Action::Log,
+            None => return Err(format!("unknown action {}", value.action)),
+        };
+        let policies = value
+            .policies
+            .into_iter()
+            .map(|(name, policy)| {
+                let policy =
+                    Policy::try_from(policy).map_err(|err| format!("policy {}: {}", name, err))?;
+                Ok((name, policy))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Engine { action, policies })
+    }
+}
+
+/// Stats count the filter's decisions, like Envoy's `rbac.*` stats.
+#[derive(Debug, Default)]
+struct Stats {
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    shadow_allowed: AtomicU64,
+    shadow_denied: AtomicU64,
+}
+
+impl Stats {
+    fn record(allowed: &AtomicU64, denied: &AtomicU64, decision: &Decision) {
+        let counter = if decision.allowed { allowed } else { denied };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Config is an RBAC filter's config, or a route's override of it.
+#[derive(Debug)]
+struct Config {
+    /// rules are enforced: requests they don't allow are rejected.  There are no
+    /// rules if they aren't configured, which allows every request.
+    rules: Option<Engine>,
+    /// shadow_rules are only checked, with the outcome recorded in the request's
+    /// dynamic metadata and the stats.
+    shadow_rules: Option<Engine>,
+    stats: Stats,
+}
+
+impl TryFrom<V3RbacFilter> for Config {
+    type Error = Error;
+
+    fn try_from(value: V3RbacFilter) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+        let rules = value
+            .rules
+            .map(Engine::try_from)
+            .transpose()
+            .map_err(bad_config)?;
+        let shadow_rules = value
+            .shadow_rules
+            .map(Engine::try_from)
+            .transpose()
+            .map_err(|err| bad_config(format!("shadow_rules: {}", err)))?;
+        Ok(Config {
+            rules,
+            shadow_rules,
+            stats: Stats::default(),
+        })
+    }
+}
+
+/// record_decision adds an engine's decision to a request's dynamic metadata,
+/// under keys starting with `prefix` (`shadow_` or `enforced_`).
+fn record_decision(req: &mut Request, prefix: &str, decision: &Decision) {
+    let metadata = DynamicMetadata::get_or_insert(req.extensions_mut());
+    if let Some(policy) = &decision.policy {
+        metadata.set(
+            METADATA_NAMESPACE,
+            &format!("{}effective_policy_id", prefix),
+            policy.clone().into(),
+        );
+    }
+    let result = if decision.allowed {
+        "allowed"
+    } else {
+        "denied"
+    };
+    metadata.set(
+        METADATA_NAMESPACE,
+        &format!("{}engine_result", prefix),
+        result.into(),
+    );
+}
+
+/// Rbac is `envoy.filters.http.rbac`: it allows or denies requests based on
+/// policies about what they are for (their path, headers, etc.) and who they are
+/// from (e.g. their address).  Denied requests are rejected with a 403.
+#[derive(Debug)]
+struct Rbac {
+    config: Arc<Config>,
+}
+
+#[tonic::async_trait]
+impl HttpFilter for Rbac {
+    async fn decode_headers(&mut self, req: &mut Request, ctx: &FilterContext<'_>) -> FilterStatus {
+        let config = ctx
+            .per_filter_config::<Config>()
+            .unwrap_or_else(|| self.config.as_ref());
+        let stats = &config.stats;
+
+        if let Some(shadow_rules) = &config.shadow_rules {
+            let decision = shadow_rules.check(req);
+            Stats::record(&stats.shadow_allowed, &stats.shadow_denied, &decision);
+            record_decision(req, "shadow_", &decision);
+        }
+
+        let rules = match &config.rules {
+            Some(rules) => rules,
+            None => return FilterStatus::Continue,
+        };
+        let decision = rules.check(req);
+        Stats::record(&stats.allowed, &stats.denied, &decision);
+        record_decision(req, "enforced_", &decision);
+        if rules.action == Action::Log && decision.policy.is_some() {
+            DynamicMetadata::get_or_insert(req.extensions_mut()).set(
+                METADATA_NAMESPACE,
+                "access_log_hint",
+                true.into(),
+            );
+        }
+        if decision.allowed {
+            FilterStatus::Continue
+        } else {
+            FilterStatus::Respond(local_reply(403, None, "RBAC: access denied"))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FilterFactory(Arc<Config>);
+
+impl HttpFilterFactory for FilterFactory {
+    fn create(&self) -> Box<dyn HttpFilter> {
+        Box::new(Rbac {
+            config: self.0.clone(),
+        })
+    }
+}
+
+/// Factory creates RBAC filters, and their per-route configs.
+pub struct Factory;
+
+impl HttpFilterConfigFactory for Factory {
+    fn create_filter_factory(&self, config: &Any) -> Result<Arc<dyn HttpFilterFactory>, Error> {
+        let config = Config::try_from(decode::<V3RbacFilter>(config)?)?;
+        Ok(Arc::new(FilterFactory(Arc::new(config))))
+    }
+
+    fn create_per_filter_config(&self, config: &Any) -> Result<Arc<dyn PerFilterConfig>, Error> {
+        // a route without rbac isn't checked at all
+        let per_route = decode::<V3RbacPerRoute>(config)?;
+        let config = Config::try_from(per_route.rbac.unwrap_or_default())?;
+        Ok(Arc::new(config))
+    }
+}
+
+#[test]
+fn test_engine() {
+    use envoy_control_plane::envoy::config::rbac::v3::principal::Set as V3PrincipalSet;
+    use envoy_control_plane::envoy::config::route::v3::{
+        header_matcher::HeaderMatchSpecifier, HeaderMatcher as V3HeaderMatcher,
+    };
+    use envoy_control_plane::envoy::r#type::matcher::v3::{
+        path_matcher, string_matcher::MatchPattern, PathMatcher as V3PathMatcher,
+        StringMatcher as V3StringMatcher,
+    };
+
+    let admin_path = V3Permission {
+        rule: Some(V3Rule::UrlPath(V3PathMatcher {
+            rule: Some(path_matcher::Rule::Path(V3StringMatcher {
+                match_pattern: Some(MatchPattern::Prefix("/admin".to_owned())),
+                ..Default::default()
+            })),
+        })),
+    };
+    let internal = V3Principal {
+        identifier: Some(V3Identifier::RemoteIp(V3CidrRange {
+            address_prefix: "10.0.0.0".to_owned(),
+            prefix_len: Some(8),
+        })),
+    };
+    let get = V3Permission {
+        rule: Some(V3Rule::Header(V3HeaderMatcher {
+            name: ":method".to_owned(),
+            header_match_specifier: Some(HeaderMatchSpecifier::ExactMatch("GET".to_owned())),
+            ..Default::default()
+        })),
+    };
+    let any = V3Principal {
+        identifier: Some(V3Identifier::Any(true)),
+    };
+    let engine = |action: V3Action, policies: Vec<(&str, V3Policy)>| {
+        Engine::try_from(V3Rbac {
+            action: action as i32,
+            policies: policies
+                .into_iter()
+                .map(|(name, policy)| (name.to_owned(), policy))
+                .collect(),
+        })
+        .unwrap()
+    };
+    // deny admin requests that aren't from the internal network
+    let deny_external_admin = engine(
+        V3Action::Deny,
+        vec![(
+            "external-admin",
+            V3Policy {
+                permissions: vec![admin_path],
+                principals: vec![V3Principal {
+                    identifier: Some(V3Identifier::NotId(Box::new(internal))),
+                }],
+                ..Default::default()
+            },
+        )],
+    );
+    let allow_get = engine(
+        V3Action::Allow,
+        vec![(
+            "get",
+            V3Policy {
+                permissions: vec![get],
+                principals: vec![V3Principal {
+                    identifier: Some(V3Identifier::OrIds(V3PrincipalSet { ids: vec![any] })),
+                }],
+                ..Default::default()
+            },
+        )],
+    );
+
+    let req = |method: &str, path: &str, remote_addr: [u8; 4]| {
+        let mut req = axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(ConnectionInfo {
+            local_addr: ([127, 0, 0, 1], 10000).into(),
+            remote_addr: (remote_addr, 54321).into(),
+        });
+        req
+    };
+    let decision = |allowed: bool, policy: Option<&str>| Decision {
+        allowed,
+        policy: policy.map(str::to_owned),
+    };
+
+    let cases: Vec<(&Engine, Request, Decision)> = vec![
+        (
+            &deny_external_admin,
+            req("GET", "/admin/config", [192, 168, 1, 1]),
+            decision(false, Some("external-admin")),
+        ),
+        (
+            &deny_external_admin,
+            req("GET", "/admin/config", [10, 1, 2, 3]),
+            decision(true, None),
+        ),
+        (
+            &deny_external_admin,
+            req("GET", "/api", [192, 168, 1, 1]),
+            decision(true, None),
+        ),
+        (
+            &allow_get,
+            req("GET", "/api", [192, 168, 1, 1]),
+            decision(true, Some("get")),
+        ),
+        (
+            &allow_get,
+            req("POST", "/api", [192, 168, 1, 1]),
+            decision(false, None),
+        ),
+    ];
+
+    for (engine, req, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            engine.check(req),
+            "{} {}",
+            req.method(),
+            req.uri()
+        );
+    }
+}
+
+#[test]
+fn test_authenticated() {
+    let principal = V3Principal {
+        identifier: Some(V3Identifier::Authenticated(Default::default())),
+    };
+    let not = V3Principal {
+        identifier: Some(V3Identifier::NotId(Box::new(principal.clone()))),
+    };
+    for principal in [principal, not] {
+        assert!(
+            Principal::try_from(principal.clone()).is_err(),
+            "{:?}",
+            principal
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_rbac() {
+    use std::collections::HashMap;
+
+    use envoy_control_plane::envoy::config::route::v3::{
+        header_matcher::HeaderMatchSpecifier, HeaderMatcher as V3HeaderMatcher,
+    };
+    use envoy_control_plane::envoy::r#type::matcher::v3::{
+        path_matcher, string_matcher::MatchPattern, PathMatcher as V3PathMatcher,
+        StringMatcher as V3StringMatcher,
+    };
+    use envoy_control_plane::prost::Message;
+    use serde_json::Value;
+
+    use crate::testing::{test_clusters, test_http_conn_mgr, test_route};
+
+    let path_prefix = |prefix: &str| V3Permission {
+        rule: Some(V3Rule::UrlPath(V3PathMatcher {
+            rule: Some(path_matcher::Rule::Path(V3StringMatcher {
+                match_pattern: Some(MatchPattern::Prefix(prefix.to_owned())),
+                ..Default::default()
+            })),
+        })),
+    };
+    let get = V3Permission {
+        rule: Some(V3Rule::Header(V3HeaderMatcher {
+            name: ":method".to_owned(),
+            header_match_specifier: Some(HeaderMatchSpecifier::ExactMatch("GET".to_owned())),
+            ..Default::default()
+        })),
+    };
+    let internal = V3Principal {
+        identifier: Some(V3Identifier::RemoteIp(V3CidrRange {
+            address_prefix: "10.0.0.0".to_owned(),
+            prefix_len: Some(8),
+        })),
+    };
+    let any = V3Principal {
+        identifier: Some(V3Identifier::Any(true)),
+    };
+    let rbac =
+        |action: V3Action, name: &str, permission: V3Permission, principal: V3Principal| V3Rbac {
+            action: action as i32,
+            policies: HashMap::from([(
+                name.to_owned(),
+                V3Policy {
+                    permissions: vec![permission],
+                    principals: vec![principal],
+                    ..Default::default()
+                },
+            )]),
+        };
+
+    // admin requests from outside the internal network are denied, and GETs are
+    // shadow allowed
+    let config = Arc::new(
+        Config::try_from(V3RbacFilter {
+            rules: Some(rbac(
+                V3Action::Deny,
+                "external-admin",
+                path_prefix("/admin"),
+                V3Principal {
+                    identifier: Some(V3Identifier::NotId(Box::new(internal.clone()))),
+                },
+            )),
+            shadow_rules: Some(rbac(V3Action::Allow, "get", get, any.clone())),
+            ..Default::default()
+        })
+        .unwrap(),
+    );
+    // routes can turn the filter off, or replace its rules
+    let per_route = |name: &str, rbac: Option<V3Rbac>| {
+        let mut route = test_route(name, &format!("/{}", name), "upstream");
+        let per_route = V3RbacPerRoute {
+            rbac: rbac.map(|rules| V3RbacFilter {
+                rules: Some(rules),
+                ..Default::default()
+            }),
+        };
+        route.typed_per_filter_config = HashMap::from([(
+            "envoy.filters.http.rbac".to_owned(),
+            Any {
+                type_url: per_route.type_url(),
+                value: per_route.encode_to_vec(),
+            },
+        )]);
+        route
+    };
+    let http_conn_mgr = test_http_conn_mgr(
+        vec![
+            per_route("open", None),
+            per_route(
+                "internal",
+                Some(rbac(
+                    V3Action::Allow,
+                    "internal",
+                    path_prefix("/"),
+                    internal,
+                )),
+            ),
+            per_route(
+                "logged",
+                Some(rbac(V3Action::Log, "all", path_prefix("/"), any)),
+            ),
+            test_route("default", "/", "upstream"),
+        ],
+        test_clusters("upstream", ([127, 0, 0, 1], 1).into()),
+    );
+
+    let external = [192, 168, 1, 1];
+    let internal = [10, 1, 2, 3];
+    // (method, path, remote address, whether the request is allowed, and the
+    // dynamic metadata recorded)
+    let cases: Vec<(&str, &str, [u8; 4], bool, Vec<(&str, Value)>)> = vec![
+        (
+            "GET",
+            "/admin",
+            external,
+            false,
+            vec![
+                ("shadow_engine_result", "allowed".into()),
+                ("shadow_effective_policy_id", "get".into()),
+                ("enforced_engine_result", "denied".into()),
+                ("enforced_effective_policy_id", "external-admin".into()),
+            ],
+        ),
+        (
+            "POST",
+            "/admin",
+            internal,
+            true,
+            vec![
+                ("shadow_engine_result", "denied".into()),
+                ("enforced_engine_result", "allowed".into()),
+            ],
+        ),
+        (
+            "GET",
+            "/api",
+            external,
+            true,
+            vec![
+                ("shadow_engine_result", "allowed".into()),
+                ("shadow_effective_policy_id", "get".into()),
+                ("enforced_engine_result", "allowed".into()),
+            ],
+        ),
+        ("POST", "/open/admin", external, true, vec![]),
+        (
+            "GET",
+            "/internal",
+            external,
+            false,
+            vec![("enforced_engine_result", "denied".into())],
+        ),
+        (
+            "GET",
+            "/internal",
+            internal,
+            true,
+            vec![
+                ("enforced_engine_result", "allowed".into()),
+                ("enforced_effective_policy_id", "internal".into()),
+            ],
+        ),
+        (
+            "POST",
+            "/logged",
+            external,
+            true,
+            vec![
+                ("enforced_engine_result", "allowed".into()),
+                ("enforced_effective_policy_id", "all".into()),
+                ("access_log_hint", true.into()),
+            ],
+        ),
+    ];
+
+    for (method, path, remote_addr, allowed, metadata) in cases.into_iter() {
+        let mut req = axum::http::Request::builder()
+            .method(method)
+            .uri(path)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        req.extensions_mut().insert(ConnectionInfo {
+            local_addr: ([127, 0, 0, 1], 10000).into(),
+            remote_addr: (remote_addr, 54321).into(),
+        });
+        let routed = http_conn_mgr.get_cluster(&req);
+        let ctx = FilterContext {
+            routed: routed.as_ref(),
+            name: "envoy.filters.http.rbac",
+            clusters: http_conn_mgr.clusters(),
+        };
+        let mut filter = Rbac {
+            config: config.clone(),
+        };
+
+        match filter.decode_headers(&mut req, &ctx).await {
+            FilterStatus::Continue => assert!(allowed, "{} {}", method, path),
+            FilterStatus::Respond(resp) => {
+                assert!(!allowed, "{} {}", method, path);
+                assert_eq!(403, resp.status().as_u16(), "{} {}", method, path);
+            }
+        }
+        let expected = if metadata.is_empty() {
+            None
+        } else {
+            let mut expected = DynamicMetadata::default();
+            for (key, value) in metadata {
+                expected.set(METADATA_NAMESPACE, key, value);
+            }
+            Some(expected)
+        };
+        assert_eq!(
+            expected.as_ref(),
+            req.extensions().get::<DynamicMetadata>(),
+            "{} {}",
+            method,
+            path
+        );
+    }
+
+    // only the requests the filter's own rules were checked against are counted
+    let stats = &config.stats;
+    let counts = [
+        &stats.allowed,
+        &stats.denied,
+        &stats.shadow_allowed,
+        &stats.shadow_denied,
+    ]
+    .map(|counter| counter.load(Ordering::Relaxed));
+    assert_eq!([2, 1, 2, 1], counts);
+}
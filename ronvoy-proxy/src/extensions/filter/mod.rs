@@ -2,4 +2,5 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
-pub(crate) mod network;
+pub mod http;
+pub mod network;
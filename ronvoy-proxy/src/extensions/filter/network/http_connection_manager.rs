@@ -2,60 +2,416 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 
+use axum::http::header::{CONTENT_TYPE, HOST};
+use envoy_control_plane::envoy::config::route::v3::{
+    RouteConfiguration as V3RouteConfiguration, VirtualHost as V3VirtualHost,
+};
 use envoy_control_plane::envoy::extensions::filters::network::http_connection_manager::v3::{
-    http_connection_manager::RouteSpecifier, HttpConnectionManager as V3HttpConnectionManager,
+    http_connection_manager::RouteSpecifier, http_connection_manager::StripPortMode,
+    HttpConnectionManager as V3HttpConnectionManager,
 };
 
 use crate::cluster::{Cluster, Clusters};
-use crate::route::{Action, ClusterSpecifier, RouteAction};
+use crate::cors::{self, CorsPolicy};
+use crate::extensions::filter::http::{self as http_filter, FilterChain, FilterConfigs};
+use crate::headers::{self, HeaderPolicy};
+use crate::listener::ConnectionInfo;
+use crate::local_reply::{self, LocalReplyConfig};
+use crate::matcher;
+use crate::rate_limit::{self, RateLimit};
+use crate::retry::RetryPolicy;
+use crate::route::{self, Action, ClusterSpecifier, Route};
+use crate::route_table::RouteTable;
+use crate::scoped_routes::ScopedRoutes;
 use crate::Request;
 
 #[derive(thiserror::Error, Debug, Clone, PartialEq)]
 pub enum Error {
     #[error("virtual host's domain is invalid: {0}")]
-    BadDomainGlob(String),
-    #[error("TODO: only static route_config is supported for now")]
+    BadDomain(String),
+    #[error("domain {0} is used by more than one virtual host")]
+    DuplicateDomain(String),
+    #[error("virtual host's retry policy is invalid: {0}")]
+    BadRetryPolicy(matcher::Error),
+    #[error("virtual host's rate limits are invalid: {0}")]
+    BadRateLimit(rate_limit::Error),
+    #[error("virtual host's CORS policy is invalid: {0}")]
+    BadCors(cors::Error),
+    #[error("invalid route: {0}")]
+    BadRoute(route::Error),
+    #[error("invalid header mutations: {0}")]
+    BadHeaders(headers::Error),
+    #[error("invalid HTTP filter config: {0}")]
+    BadHttpFilter(http_filter::Error),
+    #[error("invalid local reply config: {0}")]
+    BadLocalReplyConfig(local_reply::Error),
+    #[error("only one of strip_matching_host_port and strip_any_host_port may be set")]
+    ConflictingStripPortModes,
+    #[error("TODO: only static route_config and scoped_routes are supported for now")]
     UnsupportedRouteConfig,
+    #[error("scoped routes are missing their scope_key_builder")]
+    MissingScopeKeyBuilder,
+    #[error("invalid scope key builder: {0}")]
+    BadScopeKeyBuilder(String),
+    #[error("scope {0} has an invalid key")]
+    BadScopeKey(String),
+    #[error("scope {0} has the same key as another scope")]
+    DuplicateScopeKey(String),
+    #[error("TODO: scope {0} must have an inline route_configuration until RDS is supported")]
+    UnsupportedScopedRouteConfig(String),
+    #[error("TODO: scoped_rds isn't supported until Ronvoy has an xDS client; use scoped_route_configurations_list")]
+    UnsupportedScopedRds,
+    #[error("scoped routes are missing their scoped route configurations")]
+    MissingScopedRouteConfigs,
 }
 
-#[derive(Debug, Default, Clone, PartialEq)]
+#[derive(Debug, Default, Clone)]
 pub struct VirtualHost {
-    name: String,
-    domains: Vec<glob::Pattern>,
-    routes: Vec<crate::route::Route>,
+    domains: Vec<String>,
+    routes: RouteTable,
+}
+
+/// VirtualHosts finds the virtual host for a request's host.  Like Envoy, an exact
+/// domain match wins over the longest matching suffix wildcard (`*.example.com`),
+/// which wins over the longest matching prefix wildcard (`example.*`), which wins
+/// over the default virtual host (`*`).
+#[derive(Debug, Default, Clone)]
+struct VirtualHosts {
+    hosts: Vec<VirtualHost>,
+    exact: HashMap<String, usize>,
+    // wildcards are grouped by the length of their fixed part, so that we can try
+    // the longest (most specific) ones first with a hash lookup per length.
+    suffixes: BTreeMap<usize, HashMap<String, usize>>,
+    prefixes: BTreeMap<usize, HashMap<String, usize>>,
+    default: Option<usize>,
+}
+
+impl VirtualHosts {
+    fn new(hosts: Vec<VirtualHost>) -> Result<Self, Error> {
+        let mut exact = HashMap::new();
+        let mut suffixes: BTreeMap<usize, HashMap<String, usize>> = BTreeMap::new();
+        let mut prefixes: BTreeMap<usize, HashMap<String, usize>> = BTreeMap::new();
+        let mut default = None;
+
+        for (i, host) in hosts.iter().enumerate() {
+            for domain in host.domains.iter() {
+                let domain = domain.to_ascii_lowercase();
+                // a wildcard may only be at the start or the end of a domain
+                let wildcards = domain.matches('*').count();
+                if domain.is_empty()
+                    || wildcards > 1
+                    || (wildcards == 1 && !domain.starts_with('*') && !domain.ends_with('*'))
+                {
+                    return Err(Error::BadDomain(domain));
+                }
+
+                let duplicate = if domain == "*" {
+                    default.replace(i).is_some()
+                } else if let Some(suffix) = domain.strip_prefix('*') {
+                    suffixes
+                        .entry(suffix.len())
+                        .or_default()
+                        .insert(suffix.to_owned(), i)
+                        .is_some()
+                } else if let Some(prefix) = domain.strip_suffix('*') {
+                    prefixes
+                        .entry(prefix.len())
+                        .or_default()
+                        .insert(prefix.to_owned(), i)
+                        .is_some()
+                } else {
+                    exact.insert(domain.clone(), i).is_some()
+                };
+                if duplicate {
+                    return Err(Error::DuplicateDomain(domain));
+                }
+            }
+        }
+
+        Ok(VirtualHosts {
+            hosts,
+            exact,
+            suffixes,
+            prefixes,
+            default,
+        })
+    }
+
+    /// find returns the virtual host for a (lowercase) host.
+    fn find(&self, host: &str) -> Option<&VirtualHost> {
+        if let Some(&i) = self.exact.get(host) {
+            return Some(&self.hosts[i]);
+        }
+        // a wildcard has to match at least one character, so only consider
+        // wildcards shorter than the host.
+        for (&len, suffixes) in self.suffixes.range(..host.len()).rev() {
+            if let Some(&i) = host.get(host.len() - len..).and_then(|s| suffixes.get(s)) {
+                return Some(&self.hosts[i]);
+            }
+        }
+        for (&len, prefixes) in self.prefixes.range(..host.len()).rev() {
+            if let Some(&i) = host.get(..len).and_then(|p| prefixes.get(p)) {
+                return Some(&self.hosts[i]);
+            }
+        }
+        self.default.map(|i| &self.hosts[i])
+    }
+}
+
+/// RouteConfig is a route configuration: the virtual hosts, and their routes,
+/// requests are matched against.
+#[derive(Debug, Default)]
+pub struct RouteConfig {
+    virtual_hosts: VirtualHosts,
+}
+
+impl RouteConfig {
+    /// find returns the first route matching a request whose (lowercase) host is `host`.
+    fn find(&self, host: &str, req: &Request) -> Option<&Arc<Route>> {
+        self.virtual_hosts.find(host)?.routes.find(req)
+    }
+}
+
+impl TryFrom<V3RouteConfiguration> for RouteConfig {
+    type Error = Error;
+
+    fn try_from(route_cfg: V3RouteConfiguration) -> Result<Self, Self::Error> {
+        let headers = Arc::new(
+            HeaderPolicy::new(
+                route_cfg.request_headers_to_add,
+                route_cfg.request_headers_to_remove,
+                route_cfg.response_headers_to_add,
+                route_cfg.response_headers_to_remove,
+            )
+            .map_err(Error::BadHeaders)?,
+        );
+        let virtual_hosts = route_cfg
+            .virtual_hosts
+            .into_iter()
+            .map(|v_host| VirtualHost::try_from((v_host, headers.clone())))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RouteConfig {
+            virtual_hosts: VirtualHosts::new(virtual_hosts)?,
+        })
+    }
+}
+
+/// Routes is where an HttpConnectionManager gets its route configuration from.
+#[derive(Debug, Clone)]
+enum Routes {
+    Static(Arc<RouteConfig>),
+    /// Scoped picks one of several route configurations by request headers.
+    Scoped(ScopedRoutes),
+}
+
+impl Default for Routes {
+    fn default() -> Self {
+        Routes::Static(Default::default())
+    }
+}
+
+/// StripPort says whether the port is removed from a request's host before
+/// looking up its virtual host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum StripPort {
+    Never,
+    /// Matching strips the port only if it is the port the listener is on.
+    Matching,
+    Any,
+}
+
+impl Default for StripPort {
+    fn default() -> Self {
+        StripPort::Never
+    }
+}
+
+/// split_port splits an authority into its host and port, if it has a port.
+fn split_port(authority: &str) -> Option<(&str, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    // an IPv6 address is only followed by a port if it's in brackets
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
+/// Routed is the result of routing a request: the route it matched and the
+/// upstream cluster it should be forwarded to.
+#[derive(Debug, Clone)]
+pub struct Routed {
+    pub cluster: Arc<Cluster>,
+    pub route: Arc<Route>,
+    /// weighted_cluster is the index of the cluster picked from the route's
+    /// weighted clusters, if it has them.
+    pub weighted_cluster: Option<usize>,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct HttpConnectionManager {
-    virtual_hosts: Vec<VirtualHost>,
+    routes: Routes,
+    strip_port: StripPort,
+    /// upgrade_configs are the upgrade types (like "websocket") that can be
+    /// proxied, and whether they are enabled for routes that don't say.
+    upgrade_configs: HashMap<String, bool>,
+    http_filters: FilterChain,
+    local_reply: LocalReplyConfig,
     clusters: Arc<Clusters>,
 }
 
+impl TryFrom<(V3VirtualHost, Arc<HeaderPolicy>)> for VirtualHost {
+    type Error = Error;
+
+    /// try_from builds a virtual host; `route_config_headers` are the header
+    /// mutations from the enclosing route configuration.
+    fn try_from(
+        (v_host, route_config_headers): (V3VirtualHost, Arc<HeaderPolicy>),
+    ) -> Result<Self, Self::Error> {
+        let retry_policy = v_host
+            .retry_policy
+            .map(RetryPolicy::try_from)
+            .transpose()
+            .map_err(Error::BadRetryPolicy)?;
+        let buffer_limit = v_host.per_request_buffer_limit_bytes;
+        let headers = Arc::new(
+            HeaderPolicy::new(
+                v_host.request_headers_to_add,
+                v_host.request_headers_to_remove,
+                v_host.response_headers_to_add,
+                v_host.response_headers_to_remove,
+            )
+            .map_err(Error::BadHeaders)?,
+        );
+        let filter_configs = FilterConfigs::try_from(v_host.typed_per_filter_config)
+            .map_err(Error::BadHttpFilter)?;
+        let rate_limits = v_host
+            .rate_limits
+            .into_iter()
+            .map(RateLimit::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::BadRateLimit)?;
+        let cors = v_host
+            .cors
+            .map(CorsPolicy::try_from)
+            .transpose()
+            .map_err(Error::BadCors)?;
+
+        let routes = v_host
+            .routes
+            .into_iter()
+            .map(Route::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(Error::BadRoute)?
+            .into_iter()
+            .map(|mut route| {
+                route.inherit(retry_policy.as_ref(), buffer_limit);
+                route.inherit_headers(&headers);
+                route.inherit_headers(&route_config_headers);
+                route.inherit_filter_configs(&filter_configs);
+                route.inherit_rate_limits(&rate_limits);
+                route.inherit_cors(cors.as_ref());
+                Arc::new(route)
+            })
+            .collect();
+
+        Ok(VirtualHost {
+            domains: v_host.domains,
+            routes: RouteTable::new(routes),
+        })
+    }
+}
+
 impl HttpConnectionManager {
-    pub fn get_cluster(&self, req: &Request) -> Option<Arc<Cluster>> {
-        // TODO: does Host header even work for H2?
-        if let Some(authority) = req.headers().get("Host") {
-            if let Ok(authority) = authority.to_str() {
-                for vh in self.virtual_hosts.iter() {
-                    if !vh.domains.iter().any(|domain| domain.matches(authority)) {
-                        // authority didn't match any of our virtual host domains; bail
-                        continue;
-                    }
-                    for route in vh.routes.iter() {
-                        if let Some(Action::Route(RouteAction {
-                            cluster: ClusterSpecifier::Name(cluster_name),
-                        })) = route.matches(req.uri())
-                        {
-                            let clusters = self.clusters.load();
-                            return clusters.get(cluster_name).cloned();
-                        }
-                    }
-                }
-            }
+    pub fn clusters(&self) -> &Clusters {
+        &self.clusters
+    }
+
+    pub fn http_filters(&self) -> &FilterChain {
+        &self.http_filters
+    }
+
+    pub fn local_reply(&self) -> &LocalReplyConfig {
+        &self.local_reply
+    }
+
+    /// allows_upgrade reports whether requests to upgrade to `upgrade_type` may be
+    /// proxied on `route`.  Routes can only enable or disable upgrade types that
+    /// are configured on the HttpConnectionManager.
+    pub fn allows_upgrade(&self, route: &Route, upgrade_type: &str) -> bool {
+        let Action::Route(action) = route.action();
+        match self.upgrade_configs.get(upgrade_type) {
+            Some(&enabled) => action
+                .upgrade_configs
+                .get(upgrade_type)
+                .copied()
+                .unwrap_or(enabled),
+            None => false,
+        }
+    }
+
+    /// route_headers returns the names of the request headers that routing looks
+    /// at, besides the method and URI: the Host header (when the URI has no
+    /// authority), the Content-Type of gRPC routes and any scope key headers.
+    pub fn route_headers(&self) -> Vec<&str> {
+        let mut names = vec![HOST.as_str(), CONTENT_TYPE.as_str()];
+        if let Routes::Scoped(scoped) = &self.routes {
+            names.extend(scoped.key_headers());
         }
-        None
+        names
+    }
+
+    /// host returns the (lowercase) host a request is for, which its virtual host
+    /// is chosen by.
+    fn host(&self, req: &Request) -> Option<String> {
+        // HTTP/2 requests carry the host in the :authority pseudo-header, which
+        // hyper exposes as the URI's authority (as it does for HTTP/1 requests
+        // in absolute form).
+        let authority = match req.uri().authority() {
+            Some(authority) => authority.as_str(),
+            None => req.headers().get(HOST)?.to_str().ok()?,
+        };
+        let authority = authority.to_ascii_lowercase();
+
+        let local_port = req
+            .extensions()
+            .get::<ConnectionInfo>()
+            .map(|conn| conn.local_addr.port());
+        let host = match (self.strip_port, split_port(&authority)) {
+            (StripPort::Any, Some((host, _))) => host,
+            (StripPort::Matching, Some((host, port))) if Some(port) == local_port => host,
+            _ => return Some(authority),
+        };
+        Some(host.to_owned())
+    }
+
+    /// get_cluster returns the upstream cluster a request should be forwarded to,
+    /// along with the route it matched.
+    pub fn get_cluster(&self, req: &Request) -> Option<Routed> {
+        let host = self.host(req)?;
+        let route = match &self.routes {
+            Routes::Static(route_config) => route_config.find(&host, req)?.clone(),
+            Routes::Scoped(scoped) => scoped
+                .route_config(req.headers())?
+                .find(&host, req)?
+                .clone(),
+        };
+        let Action::Route(action) = route.action();
+        let (cluster_name, weighted_cluster) = match &action.cluster {
+            ClusterSpecifier::Name(name) => (name, None),
+            ClusterSpecifier::Weighted(weighted) => {
+                let i = weighted.pick()?;
+                (&weighted.clusters[i].name, Some(i))
+            }
+        };
+        let clusters = self.clusters.load();
+        clusters.get(cluster_name).map(|cluster| Routed {
+            cluster: cluster.clone(),
+            route: route.clone(),
+            weighted_cluster,
+        })
     }
 }
 
@@ -65,41 +421,162 @@ impl TryFrom<(V3HttpConnectionManager, Arc<Clusters>)> for HttpConnectionManager
     fn try_from(
         (v3_conn_mgr, clusters): (V3HttpConnectionManager, Arc<Clusters>),
     ) -> Result<Self, Self::Error> {
-        if let Some(RouteSpecifier::RouteConfig(route_cfg)) = v3_conn_mgr.route_specifier {
-            let mut domain_err: Option<Error> = None;
-            let virtual_hosts = route_cfg
-                .virtual_hosts
-                .into_iter()
-                .map(|v_host| VirtualHost {
-                    name: v_host.name,
-                    domains: v_host
-                        .domains
-                        .into_iter()
-                        .filter_map(|domain| match glob::Pattern::new(&domain) {
-                            Ok(pattern) => Some(pattern),
-                            Err(_) => {
-                                domain_err = Some(Error::BadDomainGlob(domain));
-                                None
-                            }
-                        })
-                        .collect(),
-                    routes: v_host
-                        .routes
-                        .into_iter()
-                        .filter_map(|route| crate::route::Route::try_from(route).ok())
-                        .collect(),
-                })
-                .collect::<Vec<_>>();
-            // if we had a problem with the domain above, fail.
-            if let Some(err) = domain_err {
-                return Err(err);
+        let strip_port = match (
+            v3_conn_mgr.strip_matching_host_port,
+            v3_conn_mgr.strip_port_mode,
+        ) {
+            (true, Some(StripPortMode::StripAnyHostPort(true))) => {
+                return Err(Error::ConflictingStripPortModes)
             }
-            Ok(HttpConnectionManager {
-                virtual_hosts,
-                clusters,
+            (true, _) => StripPort::Matching,
+            (false, Some(StripPortMode::StripAnyHostPort(true))) => StripPort::Any,
+            (false, _) => StripPort::Never,
+        };
+
+        let upgrade_configs = v3_conn_mgr
+            .upgrade_configs
+            .into_iter()
+            .map(|config| {
+                let enabled = config.enabled.unwrap_or(true);
+                (config.upgrade_type.to_ascii_lowercase(), enabled)
             })
-        } else {
-            Err(Error::UnsupportedRouteConfig)
-        }
+            .collect();
+
+        let http_filters =
+            FilterChain::try_from(v3_conn_mgr.http_filters).map_err(Error::BadHttpFilter)?;
+
+        let local_reply = v3_conn_mgr
+            .local_reply_config
+            .map(LocalReplyConfig::try_from)
+            .transpose()
+            .map_err(Error::BadLocalReplyConfig)?
+            .unwrap_or_default();
+
+        let routes = match v3_conn_mgr.route_specifier {
+            Some(RouteSpecifier::RouteConfig(route_cfg)) => {
+                Routes::Static(Arc::new(RouteConfig::try_from(route_cfg)?))
+            }
+            Some(RouteSpecifier::ScopedRoutes(scoped_routes)) => {
+                Routes::Scoped(ScopedRoutes::try_from(scoped_routes)?)
+            }
+            _ => return Err(Error::UnsupportedRouteConfig),
+        };
+
+        Ok(HttpConnectionManager {
+            routes,
+            strip_port,
+            upgrade_configs,
+            http_filters,
+            local_reply,
+            clusters,
+        })
+    }
+}
+
+#[test]
+fn test_virtual_hosts() {
+    let vhost = |domains: &[&str]| VirtualHost {
+        domains: domains.iter().map(|d| d.to_string()).collect(),
+        routes: RouteTable::default(),
+    };
+    let virtual_hosts = VirtualHosts::new(vec![
+        vhost(&["*"]),
+        vhost(&["api.*"]),
+        vhost(&["api.example.*"]),
+        vhost(&["*.example.com"]),
+        vhost(&["*.api.example.com"]),
+        vhost(&["api.example.com", "Other.Example.com:8080"]),
+    ])
+    .unwrap();
+
+    // virtual hosts are told apart by their first domain
+    let cases: &[(&str, &str)] = &[
+        ("api.example.com", "api.example.com"),
+        ("other.example.com:8080", "api.example.com"),
+        ("www.example.com", "*.example.com"),
+        ("v1.api.example.com", "*.api.example.com"),
+        // suffix wildcards take precedence over prefix wildcards
+        ("api.example.com.au", "api.example.*"),
+        ("api.example.org", "api.*"),
+        ("api.other.example.com", "*.example.com"),
+        // wildcards must match at least one character
+        (".example.com", "*"),
+        ("example.com", "*"),
+        ("other.example.com", "*.example.com"),
+    ];
+
+    for (host, expected) in cases.iter() {
+        let actual = virtual_hosts.find(host).map(|vh| vh.domains[0].as_str());
+        assert_eq!(Some(*expected), actual, "{}", host);
+    }
+
+    let errors: &[(&[&str], Error)] = &[
+        (
+            &["a.com", "b.com", "a.com"],
+            Error::DuplicateDomain("a.com".to_owned()),
+        ),
+        (
+            &["*.a.com", "*.A.com"],
+            Error::DuplicateDomain("*.a.com".to_owned()),
+        ),
+        (&["a.*.com"], Error::BadDomain("a.*.com".to_owned())),
+        (&["*.a.*"], Error::BadDomain("*.a.*".to_owned())),
+    ];
+    for (domains, expected) in errors.iter() {
+        let actual = VirtualHosts::new(vec![vhost(domains)]).unwrap_err();
+        assert_eq!(*expected, actual);
+    }
+}
+
+#[test]
+fn test_bad_route() {
+    use envoy_control_plane::prost_wkt_types::Any;
+
+    use crate::testing::{test_clusters, test_route};
+
+    // a route that can't be built fails the whole config, rather than letting
+    // its requests fall through to a later route
+    let unknown_type_url = "type.googleapis.com/example.Unknown";
+    let mut route = test_route("unknown", "/", "upstream");
+    route.typed_per_filter_config.insert(
+        "example.unknown".to_owned(),
+        Any {
+            type_url: unknown_type_url.to_owned(),
+            value: vec![],
+        },
+    );
+    let v3_http_conn_mgr = V3HttpConnectionManager {
+        route_specifier: Some(RouteSpecifier::RouteConfig(V3RouteConfiguration {
+            virtual_hosts: vec![V3VirtualHost {
+                domains: vec!["*".to_owned()],
+                routes: vec![route, test_route("catch-all", "/", "upstream")],
+                ..Default::default()
+            }],
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    let expected = Error::BadRoute(route::Error::HttpFilter(
+        http_filter::Error::UnsupportedFilter(unknown_type_url.to_owned()),
+    ));
+    let clusters = test_clusters("upstream", ([127, 0, 0, 1], 1).into());
+    let actual = HttpConnectionManager::try_from((v3_http_conn_mgr, Arc::new(clusters)));
+    assert_eq!(Some(expected), actual.err());
+}
+
+#[test]
+fn test_split_port() {
+    let cases: &[(&str, Option<(&str, u16)>)] = &[
+        ("example.com", None),
+        ("example.com:8080", Some(("example.com", 8080))),
+        ("10.0.0.1:80", Some(("10.0.0.1", 80))),
+        ("[::1]:443", Some(("[::1]", 443))),
+        ("[::1]", None),
+        ("::1", None),
+        ("example.com:http", None),
+    ];
+
+    for (authority, expected) in cases.iter() {
+        assert_eq!(*expected, split_port(authority), "{}", authority);
     }
 }
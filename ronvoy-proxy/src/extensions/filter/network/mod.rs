@@ -2,4 +2,98 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
-pub(crate) mod http_connection_manager;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+
+use envoy_control_plane::prost::Message;
+use envoy_control_plane::prost_wkt_types::Any;
+use once_cell::sync::Lazy;
+
+use crate::cluster::Clusters;
+use crate::listener::ConnectionInfo;
+
+pub mod http_connection_manager;
+mod ratelimit;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("network filter {0} is missing its typed_config")]
+    MissingConfig(String),
+    #[error("unsupported network filter type {0}")]
+    UnsupportedFilter(String),
+    #[error("network filter {0} has an invalid config: {1}")]
+    BadConfig(String, String),
+}
+
+/// FilterStatus is what a network filter decides about a new connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterStatus {
+    /// Continue passes the connection on to the next filter.
+    Continue,
+    /// Close closes the connection without serving any requests on it.
+    Close,
+}
+
+/// NetworkFilter sees each new downstream connection to a listener, in the order
+/// the listener's filters are configured, before it is handed to the listener's
+/// HttpConnectionManager (which must be its last filter).
+// TODO: hyper owns the connection's reads and writes, so network filters can't
+// see (or change) the bytes flowing over it yet.
+#[tonic::async_trait]
+pub trait NetworkFilter: Debug + Send + Sync {
+    async fn on_new_connection(&self, conn: &ConnectionInfo) -> FilterStatus;
+}
+
+/// NetworkFilterConfigFactory creates network filters from their configs.  Filters
+/// are added to Ronvoy by registering their NetworkFilterConfigFactory under the
+/// type URL of their typed_config (see register).
+pub trait NetworkFilterConfigFactory: Send + Sync {
+    /// create_filter decodes a filter's typed_config from a listener's filter chain.
+    /// `clusters` are the clusters of the Ronvoy instance the listener belongs to.
+    fn create_filter(
+        &self,
+        config: &Any,
+        clusters: &Arc<Clusters>,
+    ) -> Result<Arc<dyn NetworkFilter>, Error>;
+}
+
+type Factories = HashMap<String, Arc<dyn NetworkFilterConfigFactory>>;
+
+/// FACTORIES are the network filters Ronvoy knows about, by the type URL of their
+/// configs.  It starts out with the filters built in to Ronvoy.
+static FACTORIES: Lazy<RwLock<Factories>> = Lazy::new(|| {
+    let mut factories: Factories = HashMap::new();
+    factories.insert(ratelimit::type_url(), Arc::new(ratelimit::Factory));
+    RwLock::new(factories)
+});
+
+/// register makes a network filter available to listeners: filters whose
+/// typed_config has the type URL `type_url` are created by `factory`.  It returns
+/// the factory previously registered for `type_url`, if any.  Filters must be
+/// registered before the listeners using them are built.
+pub fn register(
+    type_url: impl Into<String>,
+    factory: Arc<dyn NetworkFilterConfigFactory>,
+) -> Option<Arc<dyn NetworkFilterConfigFactory>> {
+    FACTORIES.write().unwrap().insert(type_url.into(), factory)
+}
+
+/// new_filter creates the network filter configured by `config`.
+pub(crate) fn new_filter(
+    config: &Any,
+    clusters: &Arc<Clusters>,
+) -> Result<Arc<dyn NetworkFilter>, Error> {
+    let factory = FACTORIES.read().unwrap().get(&config.type_url).cloned();
+    match factory {
+        Some(factory) => factory.create_filter(config, clusters),
+        None => Err(Error::UnsupportedFilter(config.type_url.clone())),
+    }
+}
+
+/// decode decodes a filter's typed_config as a T, for NetworkFilterConfigFactory
+/// implementations.
+pub fn decode<T: Message + Default>(config: &Any) -> Result<T, Error> {
+    T::decode(&*config.value)
+        .map_err(|err| Error::BadConfig(config.type_url.clone(), err.to_string()))
+}
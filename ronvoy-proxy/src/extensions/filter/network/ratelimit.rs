@@ -0,0 +1,172 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use envoy_control_plane::envoy::extensions::filters::network::ratelimit::v3::RateLimit as V3RateLimit;
+use envoy_control_plane::prost_wkt_types::{Any, MessageSerde};
+
+use super::{decode, Error, FilterStatus, NetworkFilter, NetworkFilterConfigFactory};
+use crate::cluster::Clusters;
+use crate::listener::ConnectionInfo;
+use crate::protobuf;
+use crate::rate_limit::{Descriptor, RateLimitService};
+
+// how long to wait for the rate limit service if the config doesn't say
+const DEFAULT_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// type_url is the type URL of the network rate limit filter's config.
+pub fn type_url() -> String {
+    V3RateLimit::default().type_url()
+}
+
+/// RateLimit is `envoy.filters.network.ratelimit`: it asks an external rate limit
+/// service whether the listener's (static) descriptors are over the limit for
+/// each new connection, and closes the connection if they are.
+#[derive(Debug)]
+struct RateLimit {
+    domain: String,
+    descriptors: Vec<Descriptor>,
+    /// failure_mode_deny closes connections if the rate limit service can't be
+    /// reached (or fails), rather than letting them through.
+    failure_mode_deny: bool,
+    service: RateLimitService,
+    clusters: Arc<Clusters>,
+}
+
+#[tonic::async_trait]
+impl NetworkFilter for RateLimit {
+    async fn on_new_connection(&self, _conn: &ConnectionInfo) -> FilterStatus {
+        let decision = self
+            .service
+            .should_rate_limit(&self.clusters, &self.domain, &self.descriptors)
+            .await;
+        match decision {
+            Ok(decision) if decision.over_limit => FilterStatus::Close,
+            Ok(_) => FilterStatus::Continue,
+            Err(_) if self.failure_mode_deny => FilterStatus::Close,
+            Err(_) => FilterStatus::Continue,
+        }
+    }
+}
+
+impl TryFrom<(V3RateLimit, Arc<Clusters>)> for RateLimit {
+    type Error = Error;
+
+    fn try_from((value, clusters): (V3RateLimit, Arc<Clusters>)) -> Result<Self, Self::Error> {
+        let bad_config = |msg: String| Error::BadConfig(type_url(), msg);
+
+        if value.domain.is_empty() {
+            return Err(bad_config("missing domain".to_owned()));
+        }
+        let descriptors: Vec<Descriptor> = value
+            .descriptors
+            .into_iter()
+            .map(|descriptor| {
+                descriptor
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect()
+            })
+            .collect();
+        if descriptors.is_empty() {
+            return Err(bad_config("missing descriptors".to_owned()));
+        }
+        let timeout = value
+            .timeout
+            .as_ref()
+            .map(protobuf::duration)
+            .unwrap_or(DEFAULT_TIMEOUT);
+        let service = value
+            .rate_limit_service
+            .ok_or_else(|| bad_config("missing rate_limit_service".to_owned()))
+            .and_then(|service| {
+                RateLimitService::try_from(service).map_err(|err| bad_config(err.to_string()))
+            })?
+            .with_timeout(timeout);
+
+        Ok(RateLimit {
+            domain: value.domain,
+            descriptors,
+            failure_mode_deny: value.failure_mode_deny,
+            service,
+            clusters,
+        })
+    }
+}
+
+/// Factory creates network rate limit filters.
+pub struct Factory;
+
+impl NetworkFilterConfigFactory for Factory {
+    fn create_filter(
+        &self,
+        config: &Any,
+        clusters: &Arc<Clusters>,
+    ) -> Result<Arc<dyn NetworkFilter>, Error> {
+        let config = decode::<V3RateLimit>(config)?;
+        Ok(Arc::new(RateLimit::try_from((config, clusters.clone()))?))
+    }
+}
+
+#[tokio::test]
+async fn test_on_new_connection() {
+    use envoy_control_plane::envoy::config::ratelimit::v3::RateLimitServiceConfig as V3RateLimitServiceConfig;
+    use envoy_control_plane::envoy::extensions::common::ratelimit::v3::{
+        rate_limit_descriptor::Entry as V3Entry, RateLimitDescriptor as V3RateLimitDescriptor,
+    };
+    use envoy_control_plane::prost_wkt_types::Duration as PbDuration;
+
+    use crate::testing::{test_clusters, test_grpc_service, TestRateLimitServer};
+
+    let rate_limiter = TestRateLimitServer::new();
+    let clusters = Arc::new(test_clusters("ratelimit", rate_limiter.addr));
+    let conn = ConnectionInfo {
+        local_addr: ([127, 0, 0, 1], 10000).into(),
+        remote_addr: ([127, 0, 0, 1], 50000).into(),
+    };
+
+    // (the value of the listener's `limit` descriptor entry, which the test rate
+    // limiter decides by, failure_mode_deny, and what becomes of connections)
+    let cases: &[(&str, bool, FilterStatus)] = &[
+        ("ok", false, FilterStatus::Continue),
+        ("over", false, FilterStatus::Close),
+        ("fail", false, FilterStatus::Continue),
+        ("fail", true, FilterStatus::Close),
+    ];
+
+    for (limit, failure_mode_deny, expected) in cases.iter() {
+        let config = V3RateLimit {
+            stat_prefix: "test".to_owned(),
+            domain: "test".to_owned(),
+            descriptors: vec![V3RateLimitDescriptor {
+                entries: vec![V3Entry {
+                    key: "limit".to_owned(),
+                    value: limit.to_string(),
+                }],
+                ..Default::default()
+            }],
+            timeout: Some(PbDuration {
+                seconds: 5,
+                nanos: 0,
+            }),
+            failure_mode_deny: *failure_mode_deny,
+            rate_limit_service: Some(V3RateLimitServiceConfig {
+                grpc_service: Some(test_grpc_service("ratelimit")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let filter = RateLimit::try_from((config, clusters.clone())).unwrap();
+        assert_eq!(
+            *expected,
+            filter.on_new_connection(&conn).await,
+            "{} {}",
+            limit,
+            failure_mode_deny
+        );
+    }
+}
@@ -2,4 +2,7 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
-pub(crate) mod filter;
+/// filter holds Ronvoy's HTTP and network filters.  Custom filters are added by
+/// registering a factory for the type URL of their config with
+/// `filter::http::register` or `filter::network::register`.
+pub mod filter;
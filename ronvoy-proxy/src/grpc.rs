@@ -0,0 +1,422 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::time::Duration;
+
+use axum::body::Bytes;
+use axum::http::header::{ACCEPT, CONTENT_TYPE, TE};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use envoy_control_plane::envoy::config::core::v3::{
+    grpc_service::TargetSpecifier as V3TargetSpecifier, GrpcService as V3GrpcService,
+};
+use envoy_control_plane::prost::Message;
+use hyper::body::HttpBody;
+use hyper::client::HttpConnector;
+use hyper::Body;
+
+use crate::cluster::Clusters;
+use crate::protobuf;
+
+/// TIMEOUT_HEADER is the deadline a gRPC client has set for a call.
+pub const TIMEOUT_HEADER: &str = "grpc-timeout";
+/// STATUS_HEADER carries the gRPC status code of a call.
+pub const STATUS_HEADER: &str = "grpc-status";
+/// MESSAGE_HEADER carries the (percent-encoded) error message of a failed call.
+pub const MESSAGE_HEADER: &str = "grpc-message";
+/// CONTENT_TYPE_GRPC is the Content-Type of gRPC requests and responses.
+pub const CONTENT_TYPE_GRPC: &str = "application/grpc";
+/// CONTENT_TYPE_GRPC_WEB and CONTENT_TYPE_GRPC_WEB_TEXT are the Content-Types of
+/// gRPC-Web requests and responses, whose bodies are binary or base64 encoded.
+pub const CONTENT_TYPE_GRPC_WEB: &str = "application/grpc-web";
+pub const CONTENT_TYPE_GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+
+// the length of the header before each message in a gRPC request or response body
+const FRAME_HEADER_LEN: usize = 5;
+
+// the longest value a grpc-timeout header can carry
+const MAX_TIMEOUT_VALUE: u64 = 99_999_999;
+
+/// is_grpc reports whether a request is a gRPC call, going by its Content-Type:
+/// `application/grpc`, optionally followed by `+proto` (or another codec) or
+/// parameters.  gRPC-Web requests don't count.
+pub fn is_grpc(headers: &HeaderMap) -> bool {
+    codec(headers, CONTENT_TYPE_GRPC).is_some()
+}
+
+/// codec returns the codec of a Content-Type that starts with `base`, like
+/// "+proto" (or "" for none), dropping any parameters.
+pub fn codec<'a>(headers: &'a HeaderMap, base: &str) -> Option<&'a str> {
+    let content_type = headers.get(CONTENT_TYPE)?.to_str().ok()?;
+    let rest = content_type.strip_prefix(base)?;
+    if !(rest.is_empty() || rest.starts_with('+') || rest.starts_with(';')) {
+        return None;
+    }
+    rest.split(';').next()
+}
+
+/// accepts_web_text reports whether a gRPC-Web client wants its response as
+/// grpc-web-text, whatever the format of its request.
+pub fn accepts_web_text(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .any(|v| v.contains(CONTENT_TYPE_GRPC_WEB_TEXT))
+}
+
+/// reply_content_type returns the Content-Type of a reply to a gRPC or gRPC-Web
+/// call (None if the request isn't one), which is what its trailers-only local
+/// replies are sent with.
+pub fn reply_content_type(headers: &HeaderMap) -> Option<&'static str> {
+    if is_grpc(headers) {
+        Some(CONTENT_TYPE_GRPC)
+    } else if codec(headers, CONTENT_TYPE_GRPC_WEB_TEXT).is_some() {
+        Some(CONTENT_TYPE_GRPC_WEB_TEXT)
+    } else if codec(headers, CONTENT_TYPE_GRPC_WEB).is_some() {
+        if accepts_web_text(headers) {
+            Some(CONTENT_TYPE_GRPC_WEB_TEXT)
+        } else {
+            Some(CONTENT_TYPE_GRPC_WEB)
+        }
+    } else {
+        None
+    }
+}
+
+/// parse_timeout parses a grpc-timeout header value: up to 8 digits followed by
+/// a unit, like "100m" for 100 milliseconds.
+pub fn parse_timeout(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (digits, unit) = value.split_at(value.len() - 1);
+    if !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let n: u64 = digits.parse().ok()?;
+    let timeout = match unit {
+        "H" => Duration::from_secs(n * 60 * 60),
+        "M" => Duration::from_secs(n * 60),
+        "S" => Duration::from_secs(n),
+        "m" => Duration::from_millis(n),
+        "u" => Duration::from_micros(n),
+        "n" => Duration::from_nanos(n),
+        _ => return None,
+    };
+    Some(timeout)
+}
+
+/// format_timeout renders a timeout as a grpc-timeout header value, using the
+/// finest unit (starting at milliseconds) that fits in the header's 8 digits.
+pub fn format_timeout(timeout: Duration) -> String {
+    let millis = timeout.as_millis();
+    if millis <= MAX_TIMEOUT_VALUE as u128 {
+        return format!("{}m", millis);
+    }
+    let secs = timeout.as_secs();
+    if secs <= MAX_TIMEOUT_VALUE {
+        format!("{}S", secs)
+    } else if secs / 60 <= MAX_TIMEOUT_VALUE {
+        format!("{}M", secs / 60)
+    } else {
+        format!("{}H", (secs / (60 * 60)).min(MAX_TIMEOUT_VALUE))
+    }
+}
+
+/// status_from_http maps an HTTP status to the gRPC status code a client should
+/// see instead, following the gRPC spec's HTTP to gRPC status mapping.
+pub fn status_from_http(status: StatusCode) -> u32 {
+    match status.as_u16() {
+        200 => 0,                    // OK
+        400 => 13,                   // INTERNAL
+        401 => 16,                   // UNAUTHENTICATED
+        403 => 7,                    // PERMISSION_DENIED
+        404 => 12,                   // UNIMPLEMENTED
+        429 | 502 | 503 | 504 => 14, // UNAVAILABLE
+        _ => 2,                      // UNKNOWN
+    }
+}
+
+/// encode_message percent-encodes a grpc-message value, as the gRPC spec requires
+/// for anything but printable ASCII (and '%' itself).
+pub fn encode_message(message: &str) -> String {
+    let mut result = String::with_capacity(message.len());
+    for b in message.bytes() {
+        if (b' '..=b'~').contains(&b) && b != b'%' {
+            result.push(b as char);
+        } else {
+            result.push_str(&format!("%{:02X}", b));
+        }
+    }
+    result
+}
+
+/// frame_message prefixes an encoded message with the gRPC length-prefixed
+/// message header: an (unset) compressed flag and the message's length.
+pub fn frame_message(message: &[u8]) -> Bytes {
+    let mut framed = Vec::with_capacity(FRAME_HEADER_LEN + message.len());
+    framed.push(0);
+    framed.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    framed.extend_from_slice(message);
+    Bytes::from(framed)
+}
+
+/// unframe_message returns the message of a unary call's body, which must be
+/// exactly one uncompressed length-prefixed message.
+pub fn unframe_message(body: &[u8]) -> Option<&[u8]> {
+    if body.len() < FRAME_HEADER_LEN || body[0] != 0 {
+        return None;
+    }
+    let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+    let message = &body[FRAME_HEADER_LEN..];
+    if message.len() == len {
+        Some(message)
+    } else {
+        None
+    }
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("gRPC service is missing its envoy_grpc target")]
+    MissingTarget,
+    #[error("TODO: google_grpc services aren't supported")]
+    UnsupportedGoogleGrpc,
+    #[error("unknown gRPC service cluster {0}")]
+    UnknownCluster(String),
+    #[error("no healthy upstream in gRPC service cluster {0}")]
+    NoHealthyUpstream(String),
+    #[error("gRPC call timed out")]
+    Timeout,
+    #[error("gRPC call failed: {0}")]
+    Transport(String),
+    #[error("gRPC call failed with status {0}: {1}")]
+    Status(u32, String),
+    #[error("invalid gRPC response: {0}")]
+    BadResponse(String),
+}
+
+/// Client makes unary gRPC calls to a service hosted by one of Ronvoy's clusters,
+/// like Envoy's envoy_grpc client.  Calls are made over HTTP/2 whatever protocol
+/// the cluster itself uses.
+#[derive(Debug, Clone)]
+pub struct Client {
+    cluster_name: String,
+    timeout: Option<Duration>,
+    client: hyper::Client<HttpConnector>,
+}
+
+impl Client {
+    pub fn new(cluster_name: String, timeout: Option<Duration>) -> Self {
+        Client {
+            cluster_name,
+            timeout,
+            client: hyper::Client::builder().http2_only(true).build_http(),
+        }
+    }
+
+    /// with_timeout overrides the gRPC service's timeout; a zero timeout means none.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = if timeout.is_zero() {
+            None
+        } else {
+            Some(timeout)
+        };
+        self
+    }
+
+    /// unary calls the method at `path` (`/<package>.<service>/<method>`) with
+    /// `message`, on a host of the service's cluster.
+    pub async fn unary<Req: Message, Resp: Message + Default>(
+        &self,
+        clusters: &Clusters,
+        path: &str,
+        message: &Req,
+    ) -> Result<Resp, Error> {
+        let call = self.call(clusters, path, message.encode_to_vec());
+        let body = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, call)
+                .await
+                .map_err(|_| Error::Timeout)??,
+            None => call.await?,
+        };
+        let message = unframe_message(&body)
+            .ok_or_else(|| Error::BadResponse("expected a single message".to_owned()))?;
+        Resp::decode(message).map_err(|err| Error::BadResponse(err.to_string()))
+    }
+
+    async fn call(
+        &self,
+        clusters: &Clusters,
+        path: &str,
+        message: Vec<u8>,
+    ) -> Result<Bytes, Error> {
+        let cluster = clusters
+            .load()
+            .get(&self.cluster_name)
+            .cloned()
+            .ok_or_else(|| Error::UnknownCluster(self.cluster_name.clone()))?;
+        let host = cluster
+            .choose_host()
+            .ok_or_else(|| Error::NoHealthyUpstream(self.cluster_name.clone()))?;
+
+        let mut req = hyper::Request::post(format!("http://{}{}", host.address, path))
+            .header(CONTENT_TYPE, CONTENT_TYPE_GRPC)
+            .header(TE, "trailers");
+        if let Some(timeout) = self.timeout {
+            req = req.header(TIMEOUT_HEADER, format_timeout(timeout));
+        }
+        let req = req
+            .body(Body::from(frame_message(&message)))
+            .map_err(|err| Error::Transport(err.to_string()))?;
+
+        let transport_error = |err: hyper::Error| Error::Transport(err.to_string());
+        let resp = self.client.request(req).await.map_err(transport_error)?;
+        if resp.status() != StatusCode::OK {
+            return Err(Error::Transport(format!("HTTP status {}", resp.status())));
+        }
+        let (parts, mut body) = resp.into_parts();
+        let mut data = Vec::new();
+        while let Some(chunk) = body.data().await {
+            data.extend_from_slice(&chunk.map_err(transport_error)?);
+        }
+        // the status is in the trailers, or in the headers of a trailers-only response
+        let trailers = body.trailers().await.map_err(transport_error)?;
+        let headers = trailers.as_ref().unwrap_or(&parts.headers);
+        let status = headers
+            .get(STATUS_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u32>().ok())
+            .ok_or_else(|| Error::BadResponse("missing grpc-status".to_owned()))?;
+        if status != 0 {
+            let message = headers
+                .get(MESSAGE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default();
+            return Err(Error::Status(status, message.to_owned()));
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+impl TryFrom<V3GrpcService> for Client {
+    type Error = Error;
+
+    fn try_from(value: V3GrpcService) -> Result<Self, Self::Error> {
+        let timeout = value.timeout.as_ref().and_then(protobuf::non_zero_duration);
+        match value.target_specifier {
+            Some(V3TargetSpecifier::EnvoyGrpc(envoy_grpc))
+                if !envoy_grpc.cluster_name.is_empty() =>
+            {
+                Ok(Client::new(envoy_grpc.cluster_name, timeout))
+            }
+            Some(V3TargetSpecifier::GoogleGrpc(_)) => Err(Error::UnsupportedGoogleGrpc),
+            _ => Err(Error::MissingTarget),
+        }
+    }
+}
+
+#[test]
+fn test_parse_timeout() {
+    let cases: &[(&str, Option<Duration>)] = &[
+        ("100m", Some(Duration::from_millis(100))),
+        ("1S", Some(Duration::from_secs(1))),
+        ("2M", Some(Duration::from_secs(120))),
+        ("1H", Some(Duration::from_secs(3600))),
+        ("250u", Some(Duration::from_micros(250))),
+        ("99999999n", Some(Duration::from_nanos(99_999_999))),
+        ("0m", Some(Duration::ZERO)),
+        ("100000000m", None),
+        ("m", None),
+        ("10", None),
+        ("10s", None),
+        ("-1S", None),
+        ("", None),
+    ];
+
+    for (value, expected) in cases.iter() {
+        let actual = parse_timeout(&HeaderValue::from_static(*value));
+        assert_eq!(*expected, actual, "{}", value);
+    }
+
+    for timeout in [Duration::from_millis(1500), Duration::from_secs(200_000)] {
+        let formatted = HeaderValue::from_str(&format_timeout(timeout)).unwrap();
+        assert_eq!(Some(timeout), parse_timeout(&formatted));
+    }
+}
+
+#[test]
+fn test_reply_content_type() {
+    let cases: &[(&[(&str, &str)], Option<&str>)] = &[
+        (&[], None),
+        (&[("content-type", "application/json")], None),
+        (&[("content-type", "application/grpcfoo")], None),
+        (
+            &[("content-type", "application/grpc")],
+            Some(CONTENT_TYPE_GRPC),
+        ),
+        (
+            &[("content-type", "application/grpc+proto")],
+            Some(CONTENT_TYPE_GRPC),
+        ),
+        (
+            &[("content-type", "application/grpc-web+proto")],
+            Some(CONTENT_TYPE_GRPC_WEB),
+        ),
+        (
+            &[("content-type", "application/grpc-web-text")],
+            Some(CONTENT_TYPE_GRPC_WEB_TEXT),
+        ),
+        (
+            &[
+                ("content-type", "application/grpc-web"),
+                ("accept", "application/grpc-web-text"),
+            ],
+            Some(CONTENT_TYPE_GRPC_WEB_TEXT),
+        ),
+        // only gRPC-Web clients get to choose
+        (
+            &[
+                ("content-type", "application/grpc"),
+                ("accept", "application/grpc-web-text"),
+            ],
+            Some(CONTENT_TYPE_GRPC),
+        ),
+    ];
+
+    for (headers, expected) in cases.iter() {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        assert_eq!(*expected, reply_content_type(&map), "{:?}", headers);
+    }
+}
+
+#[test]
+fn test_unframe_message() {
+    let cases: &[(&[u8], Option<&[u8]>)] = &[
+        (&[0, 0, 0, 0, 2, b'h', b'i'], Some(b"hi")),
+        (&[0, 0, 0, 0, 0], Some(b"")),
+        // truncated, in the header or the message
+        (&[], None),
+        (&[0, 0, 0], None),
+        (&[0, 0, 0, 0, 3, b'h', b'i'], None),
+        // more than one message
+        (&[0, 0, 0, 0, 1, b'h', b'i'], None),
+        (&[0, 0, 0, 0, 1, b'h', 0, 0, 0, 0, 1, b'i'], None),
+        // a length far beyond the body
+        (&[0, 0xff, 0xff, 0xff, 0xff, b'h', b'i'], None),
+        // compressed messages aren't supported
+        (&[1, 0, 0, 0, 2, b'h', b'i'], None),
+    ];
+
+    for (body, expected) in cases.iter() {
+        assert_eq!(*expected, unframe_message(body), "{:?}", body);
+        if let Some(message) = expected {
+            assert_eq!(*body, &frame_message(message)[..]);
+        }
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use axum::http::header::HeaderName;
+use axum::http::{HeaderMap, HeaderValue, StatusCode, Version};
+use envoy_control_plane::envoy::config::core::v3::HeaderValueOption as V3HeaderValueOption;
+
+use crate::cluster::Host;
+use crate::listener::ConnectionInfo;
+use crate::local_reply::ResponseFlag;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("invalid header name: {0}")]
+    BadHeaderName(String),
+    #[error("header {0} can't be modified")]
+    ProtectedHeader(String),
+    #[error("invalid header value format: {0}")]
+    BadFormat(String),
+}
+
+/// FormatContext is what formatters can refer to.  The response fields are
+/// only known when formatting local replies.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FormatContext<'a> {
+    pub connection: Option<&'a ConnectionInfo>,
+    pub upstream_host: Option<&'a Host>,
+    pub request_headers: Option<&'a HeaderMap>,
+    pub version: Option<Version>,
+    pub response_code: Option<StatusCode>,
+    pub response_flag: Option<ResponseFlag>,
+    pub local_reply_body: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    DownstreamRemoteAddress,
+    DownstreamRemoteAddressWithoutPort,
+    DownstreamLocalAddress,
+    DownstreamLocalAddressWithoutPort,
+    DownstreamLocalPort,
+    UpstreamRemoteAddress,
+    UpstreamMetadata(Vec<String>),
+    Protocol,
+    RequestHeader(String),
+    ResponseCode,
+    ResponseFlags,
+    LocalReplyBody,
+}
+
+/// Formatter renders strings containing Envoy's `%COMMAND%` substitutions, like
+/// `%DOWNSTREAM_REMOTE_ADDRESS%` or `%UPSTREAM_METADATA(["ns", "key"])%`, for
+/// header values and local reply bodies.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Formatter(Vec<Segment>);
+
+impl Formatter {
+    pub fn new(format: &str) -> Result<Self, Error> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut rest = format;
+        while let Some(start) = rest.find('%') {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start + 1..];
+            if let Some(after) = rest.strip_prefix('%') {
+                // "%%" is an escaped '%'
+                literal.push('%');
+                rest = after;
+                continue;
+            }
+            let end = rest
+                .find('%')
+                .ok_or_else(|| Error::BadFormat(format.to_owned()))?;
+            let command =
+                parse_command(&rest[..end]).ok_or_else(|| Error::BadFormat(format.to_owned()))?;
+            rest = &rest[end + 1..];
+
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(command);
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Formatter(segments))
+    }
+
+    pub fn format(&self, ctx: &FormatContext) -> String {
+        let mut result = String::new();
+        for segment in self.0.iter() {
+            let conn = ctx.connection;
+            match segment {
+                Segment::Literal(literal) => result.push_str(literal),
+                Segment::DownstreamRemoteAddress => {
+                    if let Some(conn) = conn {
+                        result.push_str(&conn.remote_addr.to_string());
+                    }
+                }
+                Segment::DownstreamRemoteAddressWithoutPort => {
+                    if let Some(conn) = conn {
+                        result.push_str(&conn.remote_addr.ip().to_string());
+                    }
+                }
+                Segment::DownstreamLocalAddress => {
+                    if let Some(conn) = conn {
+                        result.push_str(&conn.local_addr.to_string());
+                    }
+                }
+                Segment::DownstreamLocalAddressWithoutPort => {
+                    if let Some(conn) = conn {
+                        result.push_str(&conn.local_addr.ip().to_string());
+                    }
+                }
+                Segment::DownstreamLocalPort => {
+                    if let Some(conn) = conn {
+                        result.push_str(&conn.local_addr.port().to_string());
+                    }
+                }
+                Segment::UpstreamRemoteAddress => {
+                    if let Some(host) = ctx.upstream_host {
+                        result.push_str(&host.address.to_string());
+                    }
+                }
+                Segment::UpstreamMetadata(path) => {
+                    if let Some(value) = ctx.upstream_host.and_then(|host| host.metadata(path)) {
+                        match value {
+                            serde_json::Value::String(s) => result.push_str(s),
+                            serde_json::Value::Null => {}
+                            value => result.push_str(&value.to_string()),
+                        }
+                    }
+                }
+                Segment::Protocol => {
+                    let protocol = match ctx.version {
+                        Some(Version::HTTP_09) => "HTTP/0.9",
+                        Some(Version::HTTP_10) => "HTTP/1.0",
+                        Some(Version::HTTP_11) => "HTTP/1.1",
+                        Some(Version::HTTP_2) => "HTTP/2",
+                        Some(Version::HTTP_3) => "HTTP/3",
+                        _ => "",
+                    };
+                    result.push_str(protocol);
+                }
+                Segment::RequestHeader(name) => {
+                    if let Some(value) = ctx
+                        .request_headers
+                        .and_then(|headers| headers.get(name.as_str()))
+                        .and_then(|value| value.to_str().ok())
+                    {
+                        result.push_str(value);
+                    }
+                }
+                Segment::ResponseCode => {
+                    if let Some(code) = ctx.response_code {
+                        result.push_str(code.as_str());
+                    }
+                }
+                Segment::ResponseFlags => {
+                    // like Envoy, "-" stands for no flags
+                    result.push_str(ctx.response_flag.map(|flag| flag.as_str()).unwrap_or("-"));
+                }
+                Segment::LocalReplyBody => {
+                    if let Some(body) = ctx.local_reply_body {
+                        result.push_str(body);
+                    }
+                }
+            }
+        }
+        result
+    }
+
+    /// format_json renders the formatter as a JSON value.  Like Envoy, a format
+    /// that is nothing but `%RESPONSE_CODE%` renders as a number.
+    pub fn format_json(&self, ctx: &FormatContext) -> serde_json::Value {
+        if let ([Segment::ResponseCode], Some(code)) = (self.0.as_slice(), ctx.response_code) {
+            return serde_json::Value::from(code.as_u16());
+        }
+        serde_json::Value::String(self.format(ctx))
+    }
+}
+
+/// parse_command parses the text between a pair of '%'s into a Segment.
+fn parse_command(command: &str) -> Option<Segment> {
+    let (name, arg) = match command.split_once('(') {
+        Some((name, arg)) => (name, Some(arg.strip_suffix(')')?)),
+        None => (command, None),
+    };
+    let segment = match (name, arg) {
+        ("DOWNSTREAM_REMOTE_ADDRESS", None) => Segment::DownstreamRemoteAddress,
+        ("DOWNSTREAM_REMOTE_ADDRESS_WITHOUT_PORT", None) => {
+            Segment::DownstreamRemoteAddressWithoutPort
+        }
+        ("DOWNSTREAM_LOCAL_ADDRESS", None) => Segment::DownstreamLocalAddress,
+        ("DOWNSTREAM_LOCAL_ADDRESS_WITHOUT_PORT", None) => {
+            Segment::DownstreamLocalAddressWithoutPort
+        }
+        ("DOWNSTREAM_LOCAL_PORT", None) => Segment::DownstreamLocalPort,
+        ("UPSTREAM_REMOTE_ADDRESS", None) => Segment::UpstreamRemoteAddress,
+        ("UPSTREAM_METADATA", Some(arg)) => {
+            // the argument is a JSON array: the filter namespace followed by the key path
+            let path: Vec<String> = serde_json::from_str(arg).ok()?;
+            if path.len() < 2 {
+                return None;
+            }
+            Segment::UpstreamMetadata(path)
+        }
+        ("PROTOCOL", None) => Segment::Protocol,
+        ("REQ", Some(header)) if !header.is_empty() => {
+            Segment::RequestHeader(header.to_ascii_lowercase())
+        }
+        ("RESPONSE_CODE", None) => Segment::ResponseCode,
+        ("RESPONSE_FLAGS", None) => Segment::ResponseFlags,
+        ("LOCAL_REPLY_BODY", None) => Segment::LocalReplyBody,
+        _ => return None,
+    };
+    Some(segment)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct HeaderToAdd {
+    name: HeaderName,
+    value: Formatter,
+    append: bool,
+    keep_empty_value: bool,
+}
+
+/// HeaderMutations are the headers to add to and remove from a request or response
+/// at one level of the route configuration (route config, virtual host, route or
+/// weighted cluster).
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HeaderMutations {
+    to_add: Vec<HeaderToAdd>,
+    to_remove: Vec<HeaderName>,
+}
+
+impl HeaderMutations {
+    pub fn new(to_add: Vec<V3HeaderValueOption>, to_remove: Vec<String>) -> Result<Self, Error> {
+        let to_add = to_add
+            .into_iter()
+            .filter_map(|option| {
+                let header = option.header?;
+                Some((header, option.append, option.keep_empty_value))
+            })
+            .map(|(header, append, keep_empty_value)| {
+                Ok(HeaderToAdd {
+                    name: modifiable_header(&header.key)?,
+                    value: Formatter::new(&header.value)?,
+                    // Envoy appends unless told otherwise
+                    append: append.unwrap_or(true),
+                    keep_empty_value,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        let to_remove = to_remove
+            .iter()
+            .map(|name| modifiable_header(name))
+            .collect::<Result<_, _>>()?;
+        Ok(HeaderMutations { to_add, to_remove })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_add.is_empty() && self.to_remove.is_empty()
+    }
+
+    /// apply removes and then adds headers, evaluating header value formatters with `ctx`.
+    pub fn apply(&self, headers: &mut HeaderMap, ctx: &FormatContext) {
+        for name in self.to_remove.iter() {
+            headers.remove(name);
+        }
+        for header in self.to_add.iter() {
+            let value = header.value.format(ctx);
+            if value.is_empty() && !header.keep_empty_value {
+                continue;
+            }
+            let value = match HeaderValue::from_str(&value) {
+                Ok(value) => value,
+                Err(_) => continue,
+            };
+            if header.append {
+                headers.append(header.name.clone(), value);
+            } else {
+                headers.insert(header.name.clone(), value);
+            }
+        }
+    }
+}
+
+/// HeaderPolicy is the request and response header mutations configured at one
+/// level of the route configuration.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct HeaderPolicy {
+    pub request: HeaderMutations,
+    pub response: HeaderMutations,
+}
+
+impl HeaderPolicy {
+    pub fn new(
+        request_headers_to_add: Vec<V3HeaderValueOption>,
+        request_headers_to_remove: Vec<String>,
+        response_headers_to_add: Vec<V3HeaderValueOption>,
+        response_headers_to_remove: Vec<String>,
+    ) -> Result<Self, Error> {
+        Ok(HeaderPolicy {
+            request: HeaderMutations::new(request_headers_to_add, request_headers_to_remove)?,
+            response: HeaderMutations::new(response_headers_to_add, response_headers_to_remove)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.request.is_empty() && self.response.is_empty()
+    }
+}
+
+/// modifiable_header validates a header name used in a header mutation.  Like Envoy,
+/// we don't allow modifying pseudo-headers or the Host header this way.
+fn modifiable_header(name: &str) -> Result<HeaderName, Error> {
+    let name = name.to_ascii_lowercase();
+    if name.starts_with(':') || name == "host" {
+        return Err(Error::ProtectedHeader(name));
+    }
+    HeaderName::from_bytes(name.as_bytes()).map_err(|_| Error::BadHeaderName(name))
+}
+
+#[test]
+fn test_formatter() {
+    let conn = ConnectionInfo {
+        local_addr: "10.0.0.1:9000".parse().unwrap(),
+        remote_addr: "192.168.1.7:43210".parse().unwrap(),
+    };
+    let mut request_headers = HeaderMap::new();
+    request_headers.insert("x-tenant", HeaderValue::from_static("acme"));
+    let ctx = FormatContext {
+        connection: Some(&conn),
+        upstream_host: None,
+        request_headers: Some(&request_headers),
+        version: Some(Version::HTTP_11),
+        response_code: Some(StatusCode::SERVICE_UNAVAILABLE),
+        response_flag: Some(ResponseFlag::NoHealthyUpstream),
+        local_reply_body: Some("no healthy upstream"),
+    };
+
+    let cases: &[(&str, Option<&str>)] = &[
+        ("static", Some("static")),
+        ("%DOWNSTREAM_REMOTE_ADDRESS%", Some("192.168.1.7:43210")),
+        (
+            "ip=%DOWNSTREAM_REMOTE_ADDRESS_WITHOUT_PORT%;port=%DOWNSTREAM_LOCAL_PORT%",
+            Some("ip=192.168.1.7;port=9000"),
+        ),
+        ("100%% %PROTOCOL%", Some("100% HTTP/1.1")),
+        ("tenant-%REQ(X-Tenant)%", Some("tenant-acme")),
+        ("%UPSTREAM_METADATA([\"envoy.lb\", \"version\"])%", Some("")),
+        ("%UPSTREAM_METADATA([\"envoy.lb\"])%", None),
+        (
+            "%RESPONSE_CODE% %RESPONSE_FLAGS%: %LOCAL_REPLY_BODY%",
+            Some("503 UH: no healthy upstream"),
+        ),
+        ("%NOT_A_COMMAND%", None),
+        ("%DOWNSTREAM_REMOTE_ADDRESS", None),
+    ];
+
+    for (format, expected) in cases.iter() {
+        let actual = Formatter::new(format).ok().map(|f| f.format(&ctx));
+        assert_eq!(*expected, actual.as_deref(), "{}", format);
+    }
+}
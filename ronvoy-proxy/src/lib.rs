@@ -10,16 +10,42 @@ use envoy_control_plane::envoy::config::core::v3::Node;
 use ronvoy_core::net::TcpListenerCloner;
 
 mod address;
+mod body;
 pub mod build_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 mod cluster;
+mod compression;
 pub mod config;
-mod extensions;
+mod cors;
+pub mod extensions;
+mod grpc;
+mod headers;
+mod jwt;
 mod listener;
+mod local_reply;
+mod matcher;
+mod metadata;
+mod protobuf;
+mod rate_limit;
+mod retry;
 mod route;
+mod route_table;
+mod router;
+mod scoped_routes;
 #[cfg(test)]
 mod testing;
+mod upgrade;
+
+pub use crate::listener::ConnectionInfo;
+
+/// bench exposes internals to the benchmarks, which can only use the crate's
+/// public API.  It isn't part of that API.
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::route::Route;
+    pub use crate::route_table::RouteTable;
+}
 
 pub type Request = ronvoy_core::Request;
 pub type Response = ronvoy_core::Response;
@@ -4,26 +4,32 @@
 
 use std::convert::Infallible;
 use std::future::Future;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
 
 use anyhow::{anyhow, Error as AnyhowError};
+use axum::http::HeaderMap;
 use envoy_control_plane::envoy::config::listener::v3::filter::ConfigType as V3ConfigType;
 use envoy_control_plane::envoy::config::listener::v3::Listener as V3Listener;
 use envoy_control_plane::envoy::extensions::filters::network::http_connection_manager::v3::HttpConnectionManager as V3HttpConnectionManager;
 use hyper::server::conn::AddrStream;
 use hyper::service::Service;
-use ronvoy_core::response;
 
 use crate::cluster::Clusters;
 use crate::extensions::filter::network::http_connection_manager::HttpConnectionManager;
+use crate::extensions::filter::network::{self, FilterStatus, NetworkFilter};
+use crate::grpc;
+use crate::headers::FormatContext;
+use crate::upgrade;
 
 /// MakeHttpConnectionRouter is called when a new TCP connection is opened to us from a downstream client.
 #[derive(Clone, Debug)]
 pub struct MakeHttpConnectionRouter {
     pub listen_addr: SocketAddr,
+    /// network_filters see each new connection before its requests are served.
+    network_filters: Vec<Arc<dyn NetworkFilter>>,
     http_conn_mgr: Arc<HttpConnectionManager>,
 }
 
@@ -31,15 +37,16 @@ impl MakeHttpConnectionRouter {
     pub fn new(http_conn_mgr: HttpConnectionManager, addr: SocketAddr) -> Self {
         Self {
             listen_addr: addr,
+            network_filters: vec![],
             http_conn_mgr: Arc::new(http_conn_mgr),
         }
     }
 }
 
 impl<'t> Service<&'t AddrStream> for MakeHttpConnectionRouter {
-    type Error = Infallible;
+    type Error = AnyhowError;
     type Response = HttpConnectionRouter;
-    type Future = Pin<Box<dyn Future<Output = Result<HttpConnectionRouter, Infallible>> + Send>>;
+    type Future = Pin<Box<dyn Future<Output = Result<HttpConnectionRouter, AnyhowError>> + Send>>;
 
     fn poll_ready(&mut self, _ctx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
         // TODO: circuit breaker/rate limit here
@@ -49,8 +56,19 @@ impl<'t> Service<&'t AddrStream> for MakeHttpConnectionRouter {
     fn call(&mut self, target: &'t AddrStream) -> Self::Future {
         let remote_addr = target.remote_addr();
         let listen_addr = self.listen_addr;
+        let network_filters = self.network_filters.clone();
         let http_conn_mgr = self.http_conn_mgr.clone();
         Box::pin(async move {
+            let conn = ConnectionInfo {
+                local_addr: listen_addr,
+                remote_addr,
+            };
+            for filter in network_filters.iter() {
+                if filter.on_new_connection(&conn).await == FilterStatus::Close {
+                    // hyper drops connections it can't make a service for
+                    return Err(anyhow!("connection closed by {:?}", filter));
+                }
+            }
             Ok(HttpConnectionRouter {
                 listen_addr,
                 remote_addr,
@@ -60,12 +78,49 @@ impl<'t> Service<&'t AddrStream> for MakeHttpConnectionRouter {
     }
 }
 
+/// X_FORWARDED_PROTO is the header proxies in front of us use to tell us the
+/// scheme of the downstream's original request.
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// ConnectionInfo describes the downstream connection a request arrived on.  It is
+/// attached to every request's extensions by the HttpConnectionRouter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectionInfo {
+    pub local_addr: SocketAddr,
+    pub remote_addr: SocketAddr,
+}
+
+impl ConnectionInfo {
+    /// is_internal reports whether the downstream peer is on a loopback or private
+    /// (RFC 1918/RFC 4193) network, which is Envoy's default definition of an
+    /// internal, trusted request.
+    pub fn is_internal(&self) -> bool {
+        match self.remote_addr.ip() {
+            IpAddr::V4(ip) => ip.is_loopback() || ip.is_private(),
+            IpAddr::V6(ip) => ip.is_loopback() || (ip.segments()[0] & 0xfe00) == 0xfc00,
+        }
+    }
+
+    /// scheme is the scheme the downstream used for a request with `headers`, like
+    /// Envoy's `:scheme`.  Listeners don't terminate TLS, so it is "http", unless a
+    /// trusted (internal) proxy in front of us terminated TLS and says so with
+    /// x-forwarded-proto.
+    pub fn scheme(&self, headers: &HeaderMap) -> &'static str {
+        let forwarded_https = headers.get(X_FORWARDED_PROTO).map_or(false, |proto| {
+            proto.as_bytes().eq_ignore_ascii_case(b"https")
+        });
+        if forwarded_https && self.is_internal() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+}
+
 /// HttpConnectionRouter handles HTTP Requests that come in over a single connection
 #[derive(Clone, Debug)]
 pub struct HttpConnectionRouter {
-    #[allow(dead_code)]
     listen_addr: SocketAddr,
-    #[allow(dead_code)]
     remote_addr: SocketAddr,
     http_conn_mgr: Arc<HttpConnectionManager>,
 }
@@ -78,25 +133,44 @@ impl tower::Service<axum::http::Request<axum::body::Body>> for HttpConnectionRou
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, _ctx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        // TODO: circuit breaker/rate limit here
+        // TODO: circuit breaker here (requests are rate limited by HTTP filters)
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, req: axum::http::Request<axum::body::Body>) -> Self::Future {
-        let cluster = self.http_conn_mgr.get_cluster(&req);
+    fn call(&mut self, mut req: axum::http::Request<axum::body::Body>) -> Self::Future {
+        let conn = ConnectionInfo {
+            local_addr: self.listen_addr,
+            remote_addr: self.remote_addr,
+        };
+        req.extensions_mut().insert(conn);
+        let method = req.method().clone();
+        let upgrade_type = upgrade::upgrade_type(&method, req.headers_mut());
+        // gRPC-Web calls count too, before the gRPC-Web filter makes them gRPC ones
+        let grpc = grpc::reply_content_type(req.headers());
+        let version = req.version();
+        // local reply mappers and formats can refer to the request headers
+        let request_headers = if self.http_conn_mgr.local_reply().is_default() {
+            None
+        } else {
+            Some(req.headers().clone())
+        };
+        let http_conn_mgr = self.http_conn_mgr.clone();
         Box::pin(async move {
-            if let Some(cluster) = cluster {
-                // the routing layer found a cluster we should send the request to
-                let mut c = (&*cluster).clone();
-                c.call(req).await
-            } else {
-                Ok(response::json_error(
-                    404,
-                    "routing to upstream cluster failed",
-                ))
-            }
+            // the HTTP filters end with the router, which forwards the request upstream
+            let resp = http_conn_mgr
+                .http_filters()
+                .run(&http_conn_mgr, upgrade_type, req)
+                .await;
 
             // TODO: log line
+
+            let request = FormatContext {
+                connection: Some(&conn),
+                request_headers: request_headers.as_ref(),
+                version: Some(version),
+                ..Default::default()
+            };
+            Ok(http_conn_mgr.local_reply().render(resp, &request, grpc))
         })
     }
 }
@@ -105,18 +179,23 @@ impl TryFrom<(V3Listener, Arc<Clusters>)> for MakeHttpConnectionRouter {
     type Error = AnyhowError;
 
     fn try_from((listener, clusters): (V3Listener, Arc<Clusters>)) -> Result<Self, Self::Error> {
-        let filter_chain = &listener.filter_chains[0];
-        let filter = &filter_chain.filters[0];
-        if filter.name != "envoy.filters.network.http_connection_manager" {
-            return Err(anyhow!(
-                "expected 'envoy.filters.network.http_connection_manager' filter, not {}",
-                filter.name
-            ));
-        }
-
         use envoy_control_plane::prost::Message;
         use envoy_control_plane::prost_wkt_types::MessageSerde;
 
+        let filter_chain = &listener.filter_chains[0];
+        // the HttpConnectionManager is the last filter, which serves the connection
+        let (filter, network_filters) = filter_chain
+            .filters
+            .split_last()
+            .ok_or_else(|| anyhow!("expected a filter chain with at least one filter"))?;
+        let network_filters = network_filters
+            .iter()
+            .map(|filter| match filter.config_type.as_ref() {
+                Some(V3ConfigType::TypedConfig(config)) => network::new_filter(config, &clusters),
+                _ => Err(network::Error::MissingConfig(filter.name.clone())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
         let http_conn_mgr_type_url = V3HttpConnectionManager::default().type_url();
 
         let v3_http_conn_mgr = if let Some(V3ConfigType::TypedConfig(http_conn_mgr_any)) =
@@ -126,7 +205,7 @@ impl TryFrom<(V3Listener, Arc<Clusters>)> for MakeHttpConnectionRouter {
                 V3HttpConnectionManager::decode(&*http_conn_mgr_any.value)?
             } else {
                 return Err(anyhow!(
-                    "unsupported typed config: {}",
+                    "expected the last filter to be an HttpConnectionManager, not {}",
                     &http_conn_mgr_any.type_url
                 ));
             }
@@ -140,7 +219,9 @@ impl TryFrom<(V3Listener, Arc<Clusters>)> for MakeHttpConnectionRouter {
 
         if let Some(addr) = listener.address.clone() {
             let crate::address::Address::Socket(addr) = crate::address::Address::try_from(addr)?;
-            Ok(MakeHttpConnectionRouter::new(http_conn_mgr, addr))
+            let mut router = MakeHttpConnectionRouter::new(http_conn_mgr, addr);
+            router.network_filters = network_filters;
+            Ok(router)
         } else {
             Err(anyhow!("expected listener to specify address"))
         }
@@ -0,0 +1,635 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use axum::body::Body;
+use axum::http::header::{
+    ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, VARY,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use envoy_control_plane::envoy::config::accesslog::v3::{
+    access_log_filter::FilterSpecifier as V3FilterSpecifier, comparison_filter::Op as V3Op,
+    AccessLogFilter as V3AccessLogFilter,
+};
+use envoy_control_plane::envoy::config::core::v3::{
+    data_source::Specifier as V3DataSourceSpecifier,
+    substitution_format_string::Format as V3Format, DataSource as V3DataSource,
+    SubstitutionFormatString as V3SubstitutionFormatString,
+};
+use envoy_control_plane::envoy::extensions::filters::network::http_connection_manager::v3::{
+    LocalReplyConfig as V3LocalReplyConfig, ResponseMapper as V3ResponseMapper,
+};
+use ronvoy_core::response;
+
+use crate::grpc;
+use crate::headers::{self, FormatContext, Formatter, HeaderMutations};
+use crate::matcher::{self, HeaderMatcher};
+use crate::Response;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("response mapper is missing its filter")]
+    MissingFilter,
+    #[error("TODO: unsupported response mapper filter {0}")]
+    UnsupportedFilter(&'static str),
+    #[error("unknown response flag {0}")]
+    UnknownResponseFlag(String),
+    #[error("invalid status code {0}")]
+    BadStatusCode(u32),
+    #[error("TODO: only inline data sources are supported")]
+    UnsupportedDataSource,
+    #[error("invalid format: {0}")]
+    BadFormat(String),
+    #[error("invalid header matcher: {0}")]
+    BadHeaderMatcher(matcher::Error),
+    #[error("invalid header mutations: {0}")]
+    BadHeaders(headers::Error),
+}
+
+impl From<headers::Error> for Error {
+    fn from(err: headers::Error) -> Self {
+        Error::BadHeaders(err)
+    }
+}
+
+/// ResponseFlag is the reason Ronvoy replied to a request itself, named (in
+/// logs and in configuration) by Envoy's short response flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFlag {
+    NoRouteFound,
+    NoHealthyUpstream,
+    UpstreamConnectionFailure,
+    UpstreamRemoteReset,
+    UpstreamRequestTimeout,
+    DownstreamProtocolError,
+    RateLimited,
+    RateLimitServiceError,
+    UnauthorizedExternalService,
+    FaultInjected,
+}
+
+impl ResponseFlag {
+    const ALL: [ResponseFlag; 10] = [
+        ResponseFlag::NoRouteFound,
+        ResponseFlag::NoHealthyUpstream,
+        ResponseFlag::UpstreamConnectionFailure,
+        ResponseFlag::UpstreamRemoteReset,
+        ResponseFlag::UpstreamRequestTimeout,
+        ResponseFlag::DownstreamProtocolError,
+        ResponseFlag::RateLimited,
+        ResponseFlag::RateLimitServiceError,
+        ResponseFlag::UnauthorizedExternalService,
+        ResponseFlag::FaultInjected,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ResponseFlag::NoRouteFound => "NR",
+            ResponseFlag::NoHealthyUpstream => "UH",
+            ResponseFlag::UpstreamConnectionFailure => "UF",
+            ResponseFlag::UpstreamRemoteReset => "UR",
+            ResponseFlag::UpstreamRequestTimeout => "UT",
+            ResponseFlag::DownstreamProtocolError => "DPE",
+            ResponseFlag::RateLimited => "RL",
+            ResponseFlag::RateLimitServiceError => "RLSE",
+            ResponseFlag::UnauthorizedExternalService => "UAEX",
+            ResponseFlag::FaultInjected => "FI",
+        }
+    }
+
+    fn parse(flag: &str) -> Result<Self, Error> {
+        ResponseFlag::ALL
+            .iter()
+            .copied()
+            .find(|known| known.as_str() == flag)
+            .ok_or_else(|| Error::UnknownResponseFlag(flag.to_owned()))
+    }
+}
+
+/// LocalReply marks a response that Ronvoy generated itself rather than
+/// forwarding from an upstream, so that it can be adapted to what the downstream
+/// expects (for example, gRPC clients expect errors as a grpc-status).
+/// Responses that a filter relays from another service instead of the upstream
+/// (like an authorization service's denial) aren't local replies: they are sent
+/// as they are, and only carry the ResponseFlag saying why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalReply {
+    pub flag: Option<ResponseFlag>,
+    pub message: String,
+}
+
+/// local_reply returns a response with a JSON body describing the error, marked
+/// as a LocalReply.  It is rendered for the downstream by LocalReplyConfig.
+pub fn local_reply(status: u16, flag: Option<ResponseFlag>, message: &str) -> Response {
+    let mut resp = response::json_error(status, message);
+    resp.extensions_mut().insert(LocalReply {
+        flag,
+        message: message.to_owned(),
+    });
+    resp
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Comparison {
+    Eq,
+    Ge,
+    Le,
+}
+
+/// Filter decides which local replies a ResponseMapper applies to.  It is the
+/// subset of Envoy's access log filters that make sense for local replies.
+#[derive(Debug, Clone, PartialEq)]
+enum Filter {
+    StatusCode(Comparison, u16),
+    /// Header matches a request header.
+    Header(HeaderMatcher),
+    /// ResponseFlags matches replies with any of the flags, or with any flag at
+    /// all if the list is empty.
+    ResponseFlags(Vec<ResponseFlag>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Filter {
+    fn matches(
+        &self,
+        status: StatusCode,
+        request_headers: Option<&HeaderMap>,
+        flag: Option<ResponseFlag>,
+    ) -> bool {
+        match self {
+            Filter::StatusCode(comparison, value) => match comparison {
+                Comparison::Eq => status.as_u16() == *value,
+                Comparison::Ge => status.as_u16() >= *value,
+                Comparison::Le => status.as_u16() <= *value,
+            },
+            Filter::Header(matcher) => request_headers
+                .map(|headers| matcher.matches(headers))
+                .unwrap_or(false),
+            Filter::ResponseFlags(flags) => match flag {
+                Some(flag) => flags.is_empty() || flags.contains(&flag),
+                None => false,
+            },
+            Filter::And(filters) => filters
+                .iter()
+                .all(|filter| filter.matches(status, request_headers, flag)),
+            Filter::Or(filters) => filters
+                .iter()
+                .any(|filter| filter.matches(status, request_headers, flag)),
+        }
+    }
+}
+
+impl TryFrom<V3AccessLogFilter> for Filter {
+    type Error = Error;
+
+    fn try_from(value: V3AccessLogFilter) -> Result<Self, Self::Error> {
+        let filters = |filters: Vec<V3AccessLogFilter>| {
+            filters
+                .into_iter()
+                .map(Filter::try_from)
+                .collect::<Result<Vec<_>, _>>()
+        };
+        match value.filter_specifier.ok_or(Error::MissingFilter)? {
+            V3FilterSpecifier::StatusCodeFilter(filter) => {
+                let comparison = filter.comparison.unwrap_or_default();
+                let op = match V3Op::from_i32(comparison.op) {
+                    Some(V3Op::Eq) | None => Comparison::Eq,
+                    Some(V3Op::Ge) => Comparison::Ge,
+                    Some(V3Op::Le) => Comparison::Le,
+                };
+                // we don't support runtime overrides, so the runtime key is ignored
+                let value = comparison.value.unwrap_or_default().default_value;
+                let value = u16::try_from(value).map_err(|_| Error::BadStatusCode(value))?;
+                Ok(Filter::StatusCode(op, value))
+            }
+            V3FilterSpecifier::HeaderFilter(filter) => {
+                let matcher = HeaderMatcher::try_from(filter.header.unwrap_or_default())
+                    .map_err(Error::BadHeaderMatcher)?;
+                Ok(Filter::Header(matcher))
+            }
+            V3FilterSpecifier::ResponseFlagFilter(filter) => {
+                let flags = filter
+                    .flags
+                    .iter()
+                    .map(|flag| ResponseFlag::parse(flag))
+                    .collect::<Result<_, _>>()?;
+                Ok(Filter::ResponseFlags(flags))
+            }
+            V3FilterSpecifier::AndFilter(filter) => Ok(Filter::And(filters(filter.filters)?)),
+            V3FilterSpecifier::OrFilter(filter) => Ok(Filter::Or(filters(filter.filters)?)),
+            V3FilterSpecifier::DurationFilter(_) => Err(Error::UnsupportedFilter("duration")),
+            V3FilterSpecifier::NotHealthCheckFilter(_) => {
+                Err(Error::UnsupportedFilter("not_health_check"))
+            }
+            V3FilterSpecifier::TraceableFilter(_) => Err(Error::UnsupportedFilter("traceable")),
+            V3FilterSpecifier::RuntimeFilter(_) => Err(Error::UnsupportedFilter("runtime")),
+            V3FilterSpecifier::GrpcStatusFilter(_) => Err(Error::UnsupportedFilter("grpc_status")),
+            V3FilterSpecifier::ExtensionFilter(_) => Err(Error::UnsupportedFilter("extension")),
+            V3FilterSpecifier::MetadataFilter(_) => Err(Error::UnsupportedFilter("metadata")),
+        }
+    }
+}
+
+/// data_source returns the contents of an inline DataSource.
+fn data_source(value: V3DataSource) -> Result<String, Error> {
+    match value.specifier {
+        Some(V3DataSourceSpecifier::InlineString(s)) => Ok(s),
+        Some(V3DataSourceSpecifier::InlineBytes(bytes)) => {
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        _ => Err(Error::UnsupportedDataSource),
+    }
+}
+
+/// JsonFormat is a JSON body template, whose string values are formatters.
+#[derive(Debug, Clone, PartialEq)]
+enum JsonFormat {
+    Format(Formatter),
+    Object(Vec<(String, JsonFormat)>),
+    Value(serde_json::Value),
+}
+
+impl JsonFormat {
+    fn new(value: serde_json::Value) -> Result<Self, Error> {
+        match value {
+            serde_json::Value::String(format) => Ok(JsonFormat::Format(
+                Formatter::new(&format).map_err(|_| Error::BadFormat(format))?,
+            )),
+            serde_json::Value::Object(fields) => Ok(JsonFormat::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| Ok((key, JsonFormat::new(value)?)))
+                    .collect::<Result<_, Error>>()?,
+            )),
+            value => Ok(JsonFormat::Value(value)),
+        }
+    }
+
+    /// format renders the template, leaving out fields that format as empty
+    /// strings if `omit_empty_values` is set.
+    fn format(&self, ctx: &FormatContext, omit_empty_values: bool) -> serde_json::Value {
+        match self {
+            JsonFormat::Format(formatter) => formatter.format_json(ctx),
+            JsonFormat::Object(fields) => serde_json::Value::Object(
+                fields
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.format(ctx, omit_empty_values)))
+                    .filter(|(_, value)| !(omit_empty_values && value == ""))
+                    .collect(),
+            ),
+            JsonFormat::Value(value) => value.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BodyFormat {
+    Text(Formatter),
+    Json(JsonFormat),
+}
+
+/// SubstitutionFormat renders the body of a local reply, like Envoy's
+/// SubstitutionFormatString.
+#[derive(Debug, Clone, PartialEq)]
+struct SubstitutionFormat {
+    format: BodyFormat,
+    omit_empty_values: bool,
+    content_type: HeaderValue,
+}
+
+impl SubstitutionFormat {
+    fn format(&self, ctx: &FormatContext) -> String {
+        match &self.format {
+            BodyFormat::Text(formatter) => formatter.format(ctx),
+            BodyFormat::Json(json) => {
+                let mut body = json.format(ctx, self.omit_empty_values).to_string();
+                body.push('\n');
+                body
+            }
+        }
+    }
+}
+
+impl TryFrom<V3SubstitutionFormatString> for SubstitutionFormat {
+    type Error = Error;
+
+    fn try_from(value: V3SubstitutionFormatString) -> Result<Self, Self::Error> {
+        let text = |format: String| {
+            Formatter::new(&format)
+                .map(BodyFormat::Text)
+                .map_err(|_| Error::BadFormat(format))
+        };
+        let (format, default_content_type) = match value.format {
+            Some(V3Format::TextFormat(format)) => (text(format)?, "text/plain"),
+            Some(V3Format::TextFormatSource(source)) => (text(data_source(source)?)?, "text/plain"),
+            Some(V3Format::JsonFormat(json)) => {
+                let json =
+                    serde_json::to_value(&json).map_err(|err| Error::BadFormat(err.to_string()))?;
+                (BodyFormat::Json(JsonFormat::new(json)?), "application/json")
+            }
+            None => return Err(Error::BadFormat("missing format".to_owned())),
+        };
+        let content_type = if value.content_type.is_empty() {
+            HeaderValue::from_static(default_content_type)
+        } else {
+            HeaderValue::from_str(&value.content_type)
+                .map_err(|_| Error::BadFormat(value.content_type))?
+        };
+        Ok(SubstitutionFormat {
+            format,
+            omit_empty_values: value.omit_empty_values,
+            content_type,
+        })
+    }
+}
+
+/// ResponseMapper rewrites the local replies its filter matches.
+#[derive(Debug, Clone, PartialEq)]
+struct ResponseMapper {
+    filter: Filter,
+    status: Option<StatusCode>,
+    /// body replaces the reply's message (what `%LOCAL_REPLY_BODY%` refers to).
+    body: Option<String>,
+    body_format: Option<SubstitutionFormat>,
+    headers: HeaderMutations,
+}
+
+impl TryFrom<V3ResponseMapper> for ResponseMapper {
+    type Error = Error;
+
+    fn try_from(value: V3ResponseMapper) -> Result<Self, Self::Error> {
+        let filter = Filter::try_from(value.filter.ok_or(Error::MissingFilter)?)?;
+        let status = value
+            .status_code
+            .map(|code| {
+                u16::try_from(code)
+                    .ok()
+                    .and_then(|code| StatusCode::from_u16(code).ok())
+                    .ok_or(Error::BadStatusCode(code))
+            })
+            .transpose()?;
+        Ok(ResponseMapper {
+            filter,
+            status,
+            body: value.body.map(data_source).transpose()?,
+            body_format: value
+                .body_format_override
+                .map(SubstitutionFormat::try_from)
+                .transpose()?,
+            headers: HeaderMutations::new(value.headers_to_add, vec![])?,
+        })
+    }
+}
+
+/// LocalReplyConfig customizes the local replies an HttpConnectionManager sends:
+/// the first mapper matching a reply can change its status, message and headers,
+/// and the body is rendered with a text or JSON format.  Without a body format,
+/// replies keep Ronvoy's `{"error": ...}` JSON body.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LocalReplyConfig {
+    mappers: Vec<ResponseMapper>,
+    body_format: Option<SubstitutionFormat>,
+}
+
+impl TryFrom<V3LocalReplyConfig> for LocalReplyConfig {
+    type Error = Error;
+
+    fn try_from(value: V3LocalReplyConfig) -> Result<Self, Self::Error> {
+        Ok(LocalReplyConfig {
+            mappers: value
+                .mappers
+                .into_iter()
+                .map(ResponseMapper::try_from)
+                .collect::<Result<_, _>>()?,
+            body_format: value
+                .body_format
+                .map(SubstitutionFormat::try_from)
+                .transpose()?,
+        })
+    }
+}
+
+impl LocalReplyConfig {
+    /// is_default reports whether local replies are left as they are (for
+    /// non-gRPC requests, at least).
+    pub fn is_default(&self) -> bool {
+        self.mappers.is_empty() && self.body_format.is_none()
+    }
+
+    /// render adapts a local reply for the downstream, according to the config
+    /// and whether the request was a gRPC call.  `request` describes the request
+    /// the reply is for, and `grpc` is the Content-Type of replies to it if it
+    /// was a gRPC (or gRPC-Web) call (see grpc::reply_content_type).  Responses
+    /// from upstreams are returned unchanged.
+    pub fn render(
+        &self,
+        mut resp: Response,
+        request: &FormatContext,
+        grpc: Option<&'static str>,
+    ) -> Response {
+        let local_reply = match resp.extensions().get::<LocalReply>() {
+            Some(local_reply) => local_reply.clone(),
+            None => return resp,
+        };
+        if self.is_default() && grpc.is_none() {
+            return resp;
+        }
+
+        let mut status = resp.status();
+        let mut message = local_reply.message;
+        let mut body_format = self.body_format.as_ref();
+        let mapper = self.mappers.iter().find(|mapper| {
+            mapper
+                .filter
+                .matches(status, request.request_headers, local_reply.flag)
+        });
+        if let Some(mapper) = mapper {
+            status = mapper.status.unwrap_or(status);
+            if let Some(body) = &mapper.body {
+                message = body.clone();
+            }
+            if mapper.body_format.is_some() {
+                body_format = mapper.body_format.as_ref();
+            }
+        }
+
+        let ctx = FormatContext {
+            response_code: Some(status),
+            response_flag: local_reply.flag,
+            local_reply_body: Some(message.as_str()),
+            ..*request
+        };
+        if let Some(mapper) = mapper {
+            mapper.headers.apply(resp.headers_mut(), &ctx);
+        }
+        *resp.status_mut() = status;
+        resp.headers_mut().remove(CONTENT_LENGTH);
+        strip_encoding(resp.headers_mut());
+
+        if let Some(content_type) = grpc {
+            return to_grpc(resp, &message, content_type);
+        }
+        match body_format {
+            Some(body_format) => {
+                resp.headers_mut()
+                    .insert(CONTENT_TYPE, body_format.content_type.clone());
+                *resp.body_mut() = Body::from(body_format.format(&ctx));
+            }
+            None => *resp.body_mut() = response::json_error(status, &message).into_body(),
+        }
+        resp
+    }
+}
+
+/// strip_encoding removes what encode filters (like the compressor) said about
+/// a reply's body, as the body they saw is replaced.
+fn strip_encoding(headers: &mut HeaderMap) {
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(ETAG);
+    let vary: Vec<String> = headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case(ACCEPT_ENCODING.as_str()))
+        .map(str::to_owned)
+        .collect();
+    headers.remove(VARY);
+    if let Ok(vary) = HeaderValue::from_str(&vary.join(", ")) {
+        if !vary.is_empty() {
+            headers.insert(VARY, vary);
+        }
+    }
+}
+
+/// to_grpc converts a local reply into a gRPC "trailers-only" response: an
+/// HTTP 200 with no body, whose grpc-status and grpc-message describe the error.
+/// gRPC-Web clients read these the same way, given a gRPC-Web Content-Type.
+fn to_grpc(mut resp: Response, message: &str, content_type: &'static str) -> Response {
+    let status = grpc::status_from_http(resp.status());
+
+    *resp.status_mut() = StatusCode::OK;
+    *resp.body_mut() = Body::empty();
+    let headers = resp.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(grpc::STATUS_HEADER, HeaderValue::from(status));
+    if let Ok(message) = HeaderValue::from_str(&grpc::encode_message(message)) {
+        headers.insert(grpc::MESSAGE_HEADER, message);
+    }
+    resp
+}
+
+#[test]
+fn test_render() {
+    let config = LocalReplyConfig::default();
+    let request = FormatContext::default();
+
+    // gRPC and gRPC-Web calls get trailers-only replies, in their own Content-Type
+    let content_types = [
+        grpc::CONTENT_TYPE_GRPC,
+        grpc::CONTENT_TYPE_GRPC_WEB,
+        grpc::CONTENT_TYPE_GRPC_WEB_TEXT,
+    ];
+    for content_type in content_types {
+        let resp = config.render(
+            local_reply(
+                503,
+                Some(ResponseFlag::NoHealthyUpstream),
+                "no healthy upstream",
+            ),
+            &request,
+            Some(content_type),
+        );
+        assert_eq!(StatusCode::OK, resp.status(), "{}", content_type);
+        let headers = resp.headers();
+        assert_eq!(content_type, headers[CONTENT_TYPE]);
+        assert_eq!("14", headers[grpc::STATUS_HEADER], "{}", content_type);
+        assert_eq!(
+            "no healthy upstream",
+            headers[grpc::MESSAGE_HEADER],
+            "{}",
+            content_type
+        );
+    }
+
+    // upstream responses are left alone
+    let resp = config.render(
+        response::json_error(503, "upstream"),
+        &request,
+        Some(grpc::CONTENT_TYPE_GRPC),
+    );
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+    assert!(!resp.headers().contains_key(grpc::STATUS_HEADER));
+
+    let header =
+        |key: &str, value: &str| envoy_control_plane::envoy::config::core::v3::HeaderValueOption {
+            header: Some(envoy_control_plane::envoy::config::core::v3::HeaderValue {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+    let config = LocalReplyConfig {
+        mappers: vec![ResponseMapper {
+            filter: Filter::And(vec![
+                Filter::StatusCode(Comparison::Ge, 500),
+                Filter::ResponseFlags(vec![ResponseFlag::NoHealthyUpstream]),
+            ]),
+            status: Some(StatusCode::BAD_GATEWAY),
+            body: Some("try again later".to_owned()),
+            body_format: None,
+            headers: HeaderMutations::new(vec![header("x-reason", "%RESPONSE_FLAGS%")], vec![])
+                .unwrap(),
+        }],
+        body_format: Some(SubstitutionFormat {
+            format: BodyFormat::Text(
+                Formatter::new("%RESPONSE_CODE%: %LOCAL_REPLY_BODY%").unwrap(),
+            ),
+            omit_empty_values: false,
+            content_type: HeaderValue::from_static("text/plain"),
+        }),
+    };
+
+    let cases: &[(u16, ResponseFlag, StatusCode, Option<&str>)] = &[
+        (
+            503,
+            ResponseFlag::NoHealthyUpstream,
+            StatusCode::BAD_GATEWAY,
+            Some("UH"),
+        ),
+        (
+            504,
+            ResponseFlag::UpstreamRequestTimeout,
+            StatusCode::GATEWAY_TIMEOUT,
+            None,
+        ),
+    ];
+    for (status, flag, expected_status, expected_reason) in cases.iter() {
+        let resp = config.render(local_reply(*status, Some(*flag), "test"), &request, None);
+        assert_eq!(*expected_status, resp.status());
+        let reason = resp.headers().get("x-reason");
+        assert_eq!(
+            *expected_reason,
+            reason.map(|value| value.to_str().unwrap())
+        );
+        assert_eq!("text/plain", resp.headers()[CONTENT_TYPE]);
+    }
+
+    // what the compressor said about a reply's body doesn't apply to the body
+    // it is replaced with
+    for grpc in [None, Some(grpc::CONTENT_TYPE_GRPC)] {
+        let mut compressed = local_reply(503, Some(ResponseFlag::NoHealthyUpstream), "test");
+        let headers = compressed.headers_mut();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        headers.insert(ETAG, HeaderValue::from_static("W/\"1\""));
+        headers.insert(VARY, HeaderValue::from_static("Origin, Accept-Encoding"));
+        let resp = config.render(compressed, &request, grpc);
+        let headers = resp.headers();
+        assert!(!headers.contains_key(CONTENT_ENCODING), "{:?}", grpc);
+        assert!(!headers.contains_key(ETAG), "{:?}", grpc);
+        assert_eq!("Origin", headers[VARY], "{:?}", grpc);
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::borrow::Cow;
+
+use axum::http::header::HOST;
+use axum::http::HeaderMap;
+use envoy_control_plane::envoy::config::route::v3::{
+    header_matcher::HeaderMatchSpecifier as V3HeaderMatchSpecifier,
+    HeaderMatcher as V3HeaderMatcher,
+};
+use envoy_control_plane::envoy::r#type::matcher::v3::{
+    double_matcher::MatchPattern as V3DoubleMatchPattern,
+    metadata_matcher::path_segment::Segment as V3Segment, path_matcher::Rule as V3PathRule,
+    string_matcher::MatchPattern as V3MatchPattern,
+    value_matcher::MatchPattern as V3ValueMatchPattern, MetadataMatcher as V3MetadataMatcher,
+    PathMatcher as V3PathMatcher, RegexMatcher as V3RegexMatcher, StringMatcher as V3StringMatcher,
+    ValueMatcher as V3ValueMatcher,
+};
+
+use crate::metadata::DynamicMetadata;
+use crate::Request;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("invalid regex: {0}")]
+    BadRegex(String),
+    #[error("matcher is missing its pattern (possibly bad protobuf/serialization)")]
+    MissingPattern,
+    #[error("TODO: {0} matchers aren't supported")]
+    Unsupported(&'static str),
+}
+
+/// Regex is a compiled RE2-compatible regular expression from an Envoy `RegexMatcher`.
+#[derive(Debug, Clone)]
+pub struct Regex(regex::Regex);
+
+impl Regex {
+    /// is_full_match reports whether the regex matches all of `value`, which is
+    /// how Envoy applies `safe_regex` matchers.
+    pub fn is_full_match(&self, value: &str) -> bool {
+        self.0.is_match(value)
+    }
+}
+
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl TryFrom<V3RegexMatcher> for Regex {
+    type Error = Error;
+
+    fn try_from(value: V3RegexMatcher) -> Result<Self, Self::Error> {
+        // anchor the regex, as Envoy only considers full matches
+        let anchored = format!("^(?:{})$", value.regex);
+        regex::Regex::new(&anchored)
+            .map(Regex)
+            .map_err(|_| Error::BadRegex(value.regex))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum StringPattern {
+    Exact(String),
+    Prefix(String),
+    Suffix(String),
+    Contains(String),
+    Regex(Regex),
+}
+
+/// StringMatcher matches a string value, like Envoy's `type.matcher.v3.StringMatcher`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StringMatcher {
+    pattern: StringPattern,
+    ignore_case: bool,
+}
+
+impl StringMatcher {
+    pub fn matches(&self, value: &str) -> bool {
+        let value = if self.ignore_case {
+            Cow::Owned(value.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(value)
+        };
+        // patterns were lowercased at construction time if ignore_case is set
+        match &self.pattern {
+            StringPattern::Exact(exact) => value == exact.as_str(),
+            StringPattern::Prefix(prefix) => value.starts_with(prefix.as_str()),
+            StringPattern::Suffix(suffix) => value.ends_with(suffix.as_str()),
+            StringPattern::Contains(substr) => value.contains(substr.as_str()),
+            // ignore_case has no effect on regexes in Envoy
+            StringPattern::Regex(regex) => regex.is_full_match(&value),
+        }
+    }
+}
+
+impl TryFrom<V3StringMatcher> for StringMatcher {
+    type Error = Error;
+
+    fn try_from(value: V3StringMatcher) -> Result<Self, Self::Error> {
+        let ignore_case = value.ignore_case;
+        let normalize = |s: String| {
+            if ignore_case {
+                s.to_ascii_lowercase()
+            } else {
+                s
+            }
+        };
+        let pattern = match value.match_pattern {
+            Some(V3MatchPattern::Exact(exact)) => StringPattern::Exact(normalize(exact)),
+            Some(V3MatchPattern::Prefix(prefix)) => StringPattern::Prefix(normalize(prefix)),
+            Some(V3MatchPattern::Suffix(suffix)) => StringPattern::Suffix(normalize(suffix)),
+            Some(V3MatchPattern::Contains(substr)) => StringPattern::Contains(normalize(substr)),
+            Some(V3MatchPattern::SafeRegex(regex)) => StringPattern::Regex(Regex::try_from(regex)?),
+            None => return Err(Error::MissingPattern),
+        };
+        Ok(StringMatcher {
+            pattern,
+            ignore_case,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum HeaderPattern {
+    Present(bool),
+    Range { start: i64, end: i64 },
+    String(StringMatcher),
+}
+
+/// HeaderMatcher matches a single request or response header, like Envoy's
+/// `config.route.v3.HeaderMatcher`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HeaderMatcher {
+    pub name: String,
+    pattern: HeaderPattern,
+    invert: bool,
+}
+
+impl HeaderMatcher {
+    /// matches checks the matcher against a header map (e.g. response headers).
+    pub fn matches(&self, headers: &HeaderMap) -> bool {
+        self.matches_value(header_value(headers, &self.name).as_deref())
+    }
+
+    /// matches_request checks the matcher against a request's headers, including
+    /// the `:method`, `:path`, `:authority` and `:scheme` pseudo-headers.
+    pub fn matches_request(&self, req: &Request) -> bool {
+        let value = match self.name.as_str() {
+            ":method" => Some(Cow::Borrowed(req.method().as_str())),
+            ":path" => Some(Cow::Borrowed(
+                req.uri().path_and_query().map_or("/", |pq| pq.as_str()),
+            )),
+            ":authority" => req
+                .headers()
+                .get(HOST)
+                .and_then(|v| v.to_str().ok())
+                .or_else(|| req.uri().host())
+                .map(Cow::Borrowed),
+            ":scheme" => Some(Cow::Borrowed(req.uri().scheme_str().unwrap_or("http"))),
+            name => header_value(req.headers(), name),
+        };
+        self.matches_value(value.as_deref())
+    }
+
+    fn matches_value(&self, value: Option<&str>) -> bool {
+        let matched = match (&self.pattern, value) {
+            (HeaderPattern::Present(present), value) => *present == value.is_some(),
+            (_, None) => false,
+            (HeaderPattern::Range { start, end }, Some(value)) => value
+                .parse::<i64>()
+                .map(|n| *start <= n && n < *end)
+                .unwrap_or(false),
+            (HeaderPattern::String(matcher), Some(value)) => matcher.matches(value),
+        };
+        matched != self.invert
+    }
+}
+
+/// header_value returns the value of a header, joining multiple values with ','
+/// the way Envoy does for matching.
+pub fn header_value<'a>(headers: &'a HeaderMap, name: &str) -> Option<Cow<'a, str>> {
+    let mut values = headers.get_all(name).iter().filter_map(|v| v.to_str().ok());
+    let first = values.next()?;
+    match values.next() {
+        None => Some(Cow::Borrowed(first)),
+        Some(second) => {
+            let mut joined = format!("{},{}", first, second);
+            for value in values {
+                joined.push(',');
+                joined.push_str(value);
+            }
+            Some(Cow::Owned(joined))
+        }
+    }
+}
+
+impl TryFrom<V3HeaderMatcher> for HeaderMatcher {
+    type Error = Error;
+
+    fn try_from(value: V3HeaderMatcher) -> Result<Self, Self::Error> {
+        let string = |pattern: StringPattern| {
+            HeaderPattern::String(StringMatcher {
+                pattern,
+                ignore_case: false,
+            })
+        };
+        let pattern = match value.header_match_specifier {
+            Some(V3HeaderMatchSpecifier::ExactMatch(exact)) => string(StringPattern::Exact(exact)),
+            Some(V3HeaderMatchSpecifier::SafeRegexMatch(regex)) => {
+                string(StringPattern::Regex(Regex::try_from(regex)?))
+            }
+            Some(V3HeaderMatchSpecifier::RangeMatch(range)) => HeaderPattern::Range {
+                start: range.start,
+                end: range.end,
+            },
+            Some(V3HeaderMatchSpecifier::PresentMatch(present)) => HeaderPattern::Present(present),
+            Some(V3HeaderMatchSpecifier::PrefixMatch(prefix)) => {
+                string(StringPattern::Prefix(prefix))
+            }
+            Some(V3HeaderMatchSpecifier::SuffixMatch(suffix)) => {
+                string(StringPattern::Suffix(suffix))
+            }
+            Some(V3HeaderMatchSpecifier::ContainsMatch(substr)) => {
+                string(StringPattern::Contains(substr))
+            }
+            Some(V3HeaderMatchSpecifier::StringMatch(matcher)) => {
+                HeaderPattern::String(StringMatcher::try_from(matcher)?)
+            }
+            // no specifier means "match if present"
+            None => HeaderPattern::Present(true),
+        };
+        Ok(HeaderMatcher {
+            name: value.name.to_ascii_lowercase(),
+            pattern,
+            invert: value.invert_match,
+        })
+    }
+}
+
+/// PathMatcher matches a request's path, without its query string, like Envoy's
+/// `type.matcher.v3.PathMatcher`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathMatcher(StringMatcher);
+
+impl PathMatcher {
+    pub fn matches(&self, req: &Request) -> bool {
+        self.0.matches(req.uri().path())
+    }
+}
+
+impl TryFrom<V3PathMatcher> for PathMatcher {
+    type Error = Error;
+
+    fn try_from(value: V3PathMatcher) -> Result<Self, Self::Error> {
+        match value.rule {
+            Some(V3PathRule::Path(matcher)) => Ok(PathMatcher(StringMatcher::try_from(matcher)?)),
+            None => Err(Error::MissingPattern),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ValuePattern {
+    Present(bool),
+    Bool(bool),
+    DoubleExact(f64),
+    DoubleRange { start: f64, end: f64 },
+    String(StringMatcher),
+}
+
+/// MetadataMatcher matches a value in a request's dynamic metadata, like Envoy's
+/// `type.matcher.v3.MetadataMatcher`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataMatcher {
+    /// path is the filter namespace followed by the keys to descend through.
+    path: Vec<String>,
+    pattern: ValuePattern,
+}
+
+impl MetadataMatcher {
+    pub fn matches(&self, metadata: Option<&DynamicMetadata>) -> bool {
+        let value = metadata.and_then(|metadata| metadata.get(&self.path));
+        match (&self.pattern, value) {
+            (ValuePattern::Present(present), value) => *present == value.is_some(),
+            (ValuePattern::Bool(expected), Some(value)) => value.as_bool() == Some(*expected),
+            (ValuePattern::DoubleExact(expected), Some(value)) => value.as_f64() == Some(*expected),
+            (ValuePattern::DoubleRange { start, end }, Some(value)) => {
+                value.as_f64().map_or(false, |n| *start <= n && n < *end)
+            }
+            (ValuePattern::String(matcher), Some(value)) => {
+                value.as_str().map_or(false, |s| matcher.matches(s))
+            }
+            (_, None) => false,
+        }
+    }
+}
+
+impl TryFrom<V3ValueMatcher> for ValuePattern {
+    type Error = Error;
+
+    fn try_from(value: V3ValueMatcher) -> Result<Self, Self::Error> {
+        match value.match_pattern {
+            Some(V3ValueMatchPattern::PresentMatch(present)) => Ok(ValuePattern::Present(present)),
+            Some(V3ValueMatchPattern::BoolMatch(expected)) => Ok(ValuePattern::Bool(expected)),
+            Some(V3ValueMatchPattern::DoubleMatch(double)) => match double.match_pattern {
+                Some(V3DoubleMatchPattern::Exact(exact)) => Ok(ValuePattern::DoubleExact(exact)),
+                Some(V3DoubleMatchPattern::Range(range)) => Ok(ValuePattern::DoubleRange {
+                    start: range.start,
+                    end: range.end,
+                }),
+                None => Err(Error::MissingPattern),
+            },
+            Some(V3ValueMatchPattern::StringMatch(matcher)) => {
+                Ok(ValuePattern::String(StringMatcher::try_from(matcher)?))
+            }
+            Some(V3ValueMatchPattern::NullMatch(_)) => Err(Error::Unsupported("null value")),
+            Some(V3ValueMatchPattern::ListMatch(_)) => Err(Error::Unsupported("list value")),
+            None => Err(Error::MissingPattern),
+        }
+    }
+}
+
+impl TryFrom<V3MetadataMatcher> for MetadataMatcher {
+    type Error = Error;
+
+    fn try_from(value: V3MetadataMatcher) -> Result<Self, Self::Error> {
+        let mut path = vec![value.filter];
+        for segment in value.path {
+            match segment.segment {
+                Some(V3Segment::Key(key)) => path.push(key),
+                None => return Err(Error::MissingPattern),
+            }
+        }
+        let pattern = ValuePattern::try_from(value.value.ok_or(Error::MissingPattern)?)?;
+        Ok(MetadataMatcher { path, pattern })
+    }
+}
+
+#[test]
+fn test_header_matcher() {
+    let present = |name: &str, present: bool, invert: bool| HeaderMatcher {
+        name: name.to_owned(),
+        pattern: HeaderPattern::Present(present),
+        invert,
+    };
+    let string = |name: &str, pattern: StringPattern| HeaderMatcher {
+        name: name.to_owned(),
+        pattern: HeaderPattern::String(StringMatcher {
+            pattern,
+            ignore_case: false,
+        }),
+        invert: false,
+    };
+    let regex = |s: &str| {
+        Regex::try_from(V3RegexMatcher {
+            regex: s.to_owned(),
+            ..Default::default()
+        })
+        .unwrap()
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-present", "yes".parse().unwrap());
+    headers.append("x-multi", "a".parse().unwrap());
+    headers.append("x-multi", "b".parse().unwrap());
+    headers.insert("x-num", "42".parse().unwrap());
+
+    let cases: &[(HeaderMatcher, bool)] = &[
+        (present("x-present", true, false), true),
+        (present("x-absent", true, false), false),
+        (present("x-absent", false, false), true),
+        (present("x-present", true, true), false),
+        (
+            string("x-multi", StringPattern::Exact("a,b".to_owned())),
+            true,
+        ),
+        (
+            string("x-present", StringPattern::Prefix("y".to_owned())),
+            true,
+        ),
+        (
+            string("x-absent", StringPattern::Prefix("y".to_owned())),
+            false,
+        ),
+        (string("x-num", StringPattern::Regex(regex("[0-9]+"))), true),
+        (string("x-num", StringPattern::Regex(regex("[0-9]"))), false),
+        (
+            HeaderMatcher {
+                name: "x-num".to_owned(),
+                pattern: HeaderPattern::Range { start: 40, end: 42 },
+                invert: false,
+            },
+            false,
+        ),
+    ];
+
+    for (matcher, expected) in cases.iter() {
+        assert_eq!(*expected, matcher.matches(&headers), "{:?}", matcher);
+    }
+}
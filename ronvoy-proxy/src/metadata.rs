@@ -0,0 +1,45 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::collections::HashMap;
+
+use axum::http::Extensions;
+
+/// DynamicMetadata is what filters have learned about a request, like Envoy's
+/// dynamic metadata: values keyed by the filter's namespace (e.g.
+/// `envoy.filters.http.rbac`), which later filters can match on.  It is kept in
+/// the request's extensions.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DynamicMetadata(HashMap<String, serde_json::Map<String, serde_json::Value>>);
+
+impl DynamicMetadata {
+    /// get_or_insert returns the dynamic metadata in a request's extensions,
+    /// adding empty metadata if it doesn't have any yet.
+    pub fn get_or_insert(extensions: &mut Extensions) -> &mut DynamicMetadata {
+        if extensions.get::<DynamicMetadata>().is_none() {
+            extensions.insert(DynamicMetadata::default());
+        }
+        extensions.get_mut::<DynamicMetadata>().unwrap()
+    }
+
+    /// get looks up a value: `path` is the filter namespace followed by the keys
+    /// to descend through.
+    pub fn get(&self, path: &[String]) -> Option<&serde_json::Value> {
+        let (namespace, keys) = path.split_first()?;
+        let (first, rest) = keys.split_first()?;
+        let mut value = self.0.get(namespace)?.get(first)?;
+        for key in rest {
+            value = value.get(key.as_str())?;
+        }
+        Some(value)
+    }
+
+    /// set sets a key in a filter's namespace.
+    pub fn set(&mut self, namespace: &str, key: &str, value: serde_json::Value) {
+        self.0
+            .entry(namespace.to_owned())
+            .or_default()
+            .insert(key.to_owned(), value);
+    }
+}
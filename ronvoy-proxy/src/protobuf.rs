@@ -0,0 +1,99 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::time::Duration;
+
+use envoy_control_plane::envoy::config::core::v3::{
+    RuntimeFeatureFlag as V3RuntimeFeatureFlag,
+    RuntimeFractionalPercent as V3RuntimeFractionalPercent,
+};
+use envoy_control_plane::envoy::r#type::v3::{
+    fractional_percent::DenominatorType, FractionalPercent as V3FractionalPercent,
+};
+use envoy_control_plane::prost_wkt_types::Duration as PbDuration;
+use rand::Rng;
+
+/// duration converts a protobuf Duration to a std Duration, clamping negative values to zero.
+pub(crate) fn duration(d: &PbDuration) -> Duration {
+    if d.seconds < 0 || d.nanos < 0 {
+        return Duration::ZERO;
+    }
+    Duration::new(d.seconds as u64, d.nanos as u32)
+}
+
+/// non_zero_duration converts a protobuf Duration where Envoy treats zero as "disabled".
+pub(crate) fn non_zero_duration(d: &PbDuration) -> Option<Duration> {
+    let d = duration(d);
+    if d.is_zero() {
+        None
+    } else {
+        Some(d)
+    }
+}
+
+/// Fraction is a probability, like Envoy's FractionalPercent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fraction {
+    numerator: u32,
+    denominator: u32,
+}
+
+impl Fraction {
+    pub const ALWAYS: Fraction = Fraction {
+        numerator: 1,
+        denominator: 1,
+    };
+    pub const NEVER: Fraction = Fraction {
+        numerator: 0,
+        denominator: 100,
+    };
+
+    /// sample randomly returns true with the probability this fraction represents.
+    pub fn sample(&self) -> bool {
+        if self.numerator >= self.denominator {
+            return true;
+        }
+        rand::thread_rng().gen_range(0..self.denominator) < self.numerator
+    }
+
+    /// capped returns the fraction with its numerator lowered to `numerator` if
+    /// that is smaller, which is how requests can ask for less of a fault.
+    pub fn capped(self, numerator: u32) -> Fraction {
+        Fraction {
+            numerator: self.numerator.min(numerator),
+            ..self
+        }
+    }
+}
+
+impl From<&V3FractionalPercent> for Fraction {
+    fn from(value: &V3FractionalPercent) -> Self {
+        let denominator = match DenominatorType::from_i32(value.denominator) {
+            Some(DenominatorType::TenThousand) => 10_000,
+            Some(DenominatorType::Million) => 1_000_000,
+            Some(DenominatorType::Hundred) | None => 100,
+        };
+        Fraction {
+            numerator: value.numerator,
+            denominator,
+        }
+    }
+}
+
+/// runtime_fraction returns the default value of a RuntimeFractionalPercent.  We
+/// don't support runtime overrides, so the runtime key is ignored.
+pub(crate) fn runtime_fraction(value: &V3RuntimeFractionalPercent) -> Fraction {
+    value
+        .default_value
+        .as_ref()
+        .map(Fraction::from)
+        .unwrap_or(Fraction::ALWAYS)
+}
+
+/// runtime_feature_flag returns the default value of a RuntimeFeatureFlag, which
+/// is enabled if it doesn't have one.  Like runtime_fraction, the runtime key is
+/// ignored.
+pub(crate) fn runtime_feature_flag(value: &V3RuntimeFeatureFlag) -> bool {
+    value.default_value.unwrap_or(true)
+}
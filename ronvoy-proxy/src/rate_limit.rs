@@ -0,0 +1,421 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::time::Duration;
+
+use axum::http::header::{HeaderName, HeaderValue};
+use envoy_control_plane::envoy::config::core::v3::HeaderValue as V3HeaderValue;
+use envoy_control_plane::envoy::config::ratelimit::v3::RateLimitServiceConfig as V3RateLimitServiceConfig;
+use envoy_control_plane::envoy::config::route::v3::{
+    rate_limit::action::ActionSpecifier as V3ActionSpecifier, rate_limit::Action as V3Action,
+    RateLimit as V3RateLimit,
+};
+use envoy_control_plane::envoy::extensions::common::ratelimit::v3::{
+    rate_limit_descriptor::Entry as V3Entry, RateLimitDescriptor as V3RateLimitDescriptor,
+};
+use envoy_control_plane::envoy::service::ratelimit::v3::{
+    rate_limit_response::rate_limit::Unit as V3Unit, rate_limit_response::Code as V3Code,
+    RateLimitRequest as V3RateLimitRequest, RateLimitResponse as V3RateLimitResponse,
+};
+
+use crate::cluster::Clusters;
+use crate::grpc;
+use crate::listener::ConnectionInfo;
+use crate::matcher::{self, HeaderMatcher};
+use crate::protobuf;
+use crate::Request;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum Error {
+    #[error("rate limit action is missing its action_specifier")]
+    MissingAction,
+    #[error("TODO: unsupported rate limit action {0}")]
+    UnsupportedAction(&'static str),
+    #[error("rate limit action {0} is invalid")]
+    BadAction(&'static str),
+    #[error("invalid header matcher in rate limit action: {0}")]
+    BadHeaderMatcher(matcher::Error),
+}
+
+/// Descriptor describes a request to a rate limiter, as a list of key/value
+/// entries.  Rate limits are configured for (and looked up by) descriptors.
+pub type Descriptor = Vec<(String, String)>;
+
+/// Action produces one entry of a descriptor.
+#[derive(Debug, Clone, PartialEq)]
+enum Action {
+    /// DestinationCluster is the upstream cluster the request was routed to.
+    DestinationCluster,
+    /// RequestHeader is the value of a request header.  If the header is
+    /// missing, the entry is skipped when `skip_if_absent` is set; otherwise no
+    /// descriptor is produced.
+    RequestHeader {
+        name: String,
+        key: String,
+        skip_if_absent: bool,
+    },
+    /// RemoteAddress is the downstream peer's IP address.
+    RemoteAddress,
+    GenericKey {
+        key: String,
+        value: String,
+    },
+    /// HeaderValueMatch produces an entry if the request's headers match (or,
+    /// if `expect_match` is false, don't match) all of `headers`.
+    HeaderValueMatch {
+        value: String,
+        expect_match: bool,
+        headers: Vec<HeaderMatcher>,
+    },
+}
+
+impl Action {
+    /// entry returns the descriptor entry for a request, None if the entry is
+    /// skipped, or Err if the request gets no descriptor at all.
+    fn entry(&self, req: &Request, cluster: &str) -> Result<Option<(String, String)>, ()> {
+        let entry = |key: &str, value: String| Ok(Some((key.to_owned(), value)));
+        match self {
+            Action::DestinationCluster => entry("destination_cluster", cluster.to_owned()),
+            Action::RequestHeader {
+                name,
+                key,
+                skip_if_absent,
+            } => match matcher::header_value(req.headers(), name) {
+                Some(value) => entry(key, value.into_owned()),
+                None if *skip_if_absent => Ok(None),
+                None => Err(()),
+            },
+            Action::RemoteAddress => match req.extensions().get::<ConnectionInfo>() {
+                Some(conn) => entry("remote_address", conn.remote_addr.ip().to_string()),
+                None => Err(()),
+            },
+            Action::GenericKey { key, value } => entry(key, value.clone()),
+            Action::HeaderValueMatch {
+                value,
+                expect_match,
+                headers,
+            } => {
+                let matched = headers.iter().all(|header| header.matches(req.headers()));
+                if matched == *expect_match {
+                    entry("header_match", value.clone())
+                } else {
+                    Err(())
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<V3Action> for Action {
+    type Error = Error;
+
+    fn try_from(value: V3Action) -> Result<Self, Self::Error> {
+        let action = match value.action_specifier.ok_or(Error::MissingAction)? {
+            V3ActionSpecifier::DestinationCluster(_) => Action::DestinationCluster,
+            V3ActionSpecifier::RequestHeaders(headers) => {
+                if headers.header_name.is_empty() || headers.descriptor_key.is_empty() {
+                    return Err(Error::BadAction("request_headers"));
+                }
+                Action::RequestHeader {
+                    name: headers.header_name.to_ascii_lowercase(),
+                    key: headers.descriptor_key,
+                    skip_if_absent: headers.skip_if_absent,
+                }
+            }
+            V3ActionSpecifier::RemoteAddress(_) => Action::RemoteAddress,
+            V3ActionSpecifier::GenericKey(generic) => {
+                if generic.descriptor_value.is_empty() {
+                    return Err(Error::BadAction("generic_key"));
+                }
+                let key = if generic.descriptor_key.is_empty() {
+                    "generic_key".to_owned()
+                } else {
+                    generic.descriptor_key
+                };
+                Action::GenericKey {
+                    key,
+                    value: generic.descriptor_value,
+                }
+            }
+            V3ActionSpecifier::HeaderValueMatch(header_match) => {
+                if header_match.descriptor_value.is_empty() || header_match.headers.is_empty() {
+                    return Err(Error::BadAction("header_value_match"));
+                }
+                Action::HeaderValueMatch {
+                    value: header_match.descriptor_value,
+                    expect_match: header_match.expect_match.unwrap_or(true),
+                    headers: header_match
+                        .headers
+                        .into_iter()
+                        .map(HeaderMatcher::try_from)
+                        .collect::<Result<_, _>>()
+                        .map_err(Error::BadHeaderMatcher)?,
+                }
+            }
+            // the source cluster is the local service's cluster, which we don't know
+            V3ActionSpecifier::SourceCluster(_) => {
+                return Err(Error::UnsupportedAction("source_cluster"))
+            }
+            _ => return Err(Error::UnsupportedAction("metadata")),
+        };
+        Ok(action)
+    }
+}
+
+/// RateLimit is one of a route's (or virtual host's) rate_limits: how to build a
+/// descriptor for requests using the route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    /// stage is the rate limit filter stage this descriptor is for; filters only
+    /// use the descriptors of their own stage.
+    pub stage: u32,
+    actions: Vec<Action>,
+}
+
+impl RateLimit {
+    /// descriptor returns the descriptor for a request routed to `cluster`, or
+    /// None if any of the rate limit's actions doesn't apply to it.
+    pub fn descriptor(&self, req: &Request, cluster: &str) -> Option<Descriptor> {
+        let mut descriptor = vec![];
+        for action in self.actions.iter() {
+            if let Some(entry) = action.entry(req, cluster).ok()? {
+                descriptor.push(entry);
+            }
+        }
+        if descriptor.is_empty() {
+            None
+        } else {
+            Some(descriptor)
+        }
+    }
+}
+
+impl TryFrom<V3RateLimit> for RateLimit {
+    type Error = Error;
+
+    fn try_from(value: V3RateLimit) -> Result<Self, Self::Error> {
+        let actions = value
+            .actions
+            .into_iter()
+            .map(Action::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RateLimit {
+            stage: value.stage.unwrap_or_default(),
+            actions,
+        })
+    }
+}
+
+/// descriptors returns the descriptors of the rate limits for `stage` that apply
+/// to a request routed to `cluster`.
+pub fn descriptors(
+    rate_limits: &[RateLimit],
+    stage: u32,
+    req: &Request,
+    cluster: &str,
+) -> Vec<Descriptor> {
+    rate_limits
+        .iter()
+        .filter(|rate_limit| rate_limit.stage == stage)
+        .filter_map(|rate_limit| rate_limit.descriptor(req, cluster))
+        .collect()
+}
+
+/// SHOULD_RATE_LIMIT_PATH is the rate limit service method Ronvoy calls.
+const SHOULD_RATE_LIMIT_PATH: &str = "/envoy.service.ratelimit.v3.RateLimitService/ShouldRateLimit";
+
+/// Decision is a rate limit service's answer for a request (or connection).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Decision {
+    pub over_limit: bool,
+    /// request_headers_to_add are added to an allowed request before it is
+    /// forwarded, and response_headers_to_add to its response.
+    pub request_headers_to_add: Vec<(HeaderName, HeaderValue)>,
+    pub response_headers_to_add: Vec<(HeaderName, HeaderValue)>,
+    /// limits are the current limits of the descriptors, for those the service
+    /// reported one.
+    pub limits: Vec<Limit>,
+}
+
+/// Limit is the state of one descriptor's limit, as reported by the service.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limit {
+    pub name: String,
+    pub requests_per_unit: u32,
+    /// unit is how long the limit's window is.
+    pub unit: Duration,
+    pub remaining: u32,
+    /// reset is how long until the window ends.
+    pub reset: Duration,
+}
+
+/// RateLimitService is a client of an external rate limit service: it implements
+/// `envoy.service.ratelimit.v3.RateLimitService`, which decides whether
+/// descriptors are over their (globally shared) limits.
+#[derive(Debug, Clone)]
+pub struct RateLimitService {
+    client: grpc::Client,
+}
+
+impl RateLimitService {
+    /// with_timeout sets how long to wait for the service's decision.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        RateLimitService {
+            client: self.client.with_timeout(timeout),
+        }
+    }
+
+    /// should_rate_limit asks the service whether `descriptors` in `domain` are
+    /// over the limit.
+    pub async fn should_rate_limit(
+        &self,
+        clusters: &Clusters,
+        domain: &str,
+        descriptors: &[Descriptor],
+    ) -> Result<Decision, grpc::Error> {
+        let req = V3RateLimitRequest {
+            domain: domain.to_owned(),
+            descriptors: descriptors
+                .iter()
+                .map(|descriptor| V3RateLimitDescriptor {
+                    entries: descriptor
+                        .iter()
+                        .map(|(key, value)| V3Entry {
+                            key: key.clone(),
+                            value: value.clone(),
+                        })
+                        .collect(),
+                    ..Default::default()
+                })
+                .collect(),
+            hits_addend: 0,
+        };
+        let resp: V3RateLimitResponse = self
+            .client
+            .unary(clusters, SHOULD_RATE_LIMIT_PATH, &req)
+            .await?;
+        let over_limit = match V3Code::from_i32(resp.overall_code) {
+            Some(V3Code::Ok) => false,
+            Some(V3Code::OverLimit) => true,
+            _ => {
+                return Err(grpc::Error::BadResponse(format!(
+                    "unexpected overall_code {}",
+                    resp.overall_code
+                )))
+            }
+        };
+        let headers = |headers: Vec<V3HeaderValue>| {
+            headers
+                .into_iter()
+                .filter_map(|header| {
+                    let name = HeaderName::from_bytes(header.key.as_bytes()).ok()?;
+                    let value = HeaderValue::from_str(&header.value).ok()?;
+                    Some((name, value))
+                })
+                .collect()
+        };
+        let limits = resp
+            .statuses
+            .into_iter()
+            .filter_map(|status| {
+                let limit = status.current_limit?;
+                let unit = match V3Unit::from_i32(limit.unit)? {
+                    V3Unit::Second => 1,
+                    V3Unit::Minute => 60,
+                    V3Unit::Hour => 60 * 60,
+                    V3Unit::Day => 24 * 60 * 60,
+                    _ => return None,
+                };
+                Some(Limit {
+                    name: limit.name,
+                    requests_per_unit: limit.requests_per_unit,
+                    unit: Duration::from_secs(unit),
+                    remaining: status.limit_remaining,
+                    reset: status
+                        .duration_until_reset
+                        .as_ref()
+                        .map(protobuf::duration)
+                        .unwrap_or_default(),
+                })
+            })
+            .collect();
+        Ok(Decision {
+            over_limit,
+            request_headers_to_add: headers(resp.request_headers_to_add),
+            response_headers_to_add: headers(resp.response_headers_to_add),
+            limits,
+        })
+    }
+}
+
+impl TryFrom<V3RateLimitServiceConfig> for RateLimitService {
+    type Error = grpc::Error;
+
+    fn try_from(value: V3RateLimitServiceConfig) -> Result<Self, Self::Error> {
+        let client = grpc::Client::try_from(value.grpc_service.ok_or(grpc::Error::MissingTarget)?)?;
+        Ok(RateLimitService { client })
+    }
+}
+
+#[test]
+fn test_descriptor() {
+    let rate_limit = |actions: Vec<Action>| RateLimit { stage: 0, actions };
+    let header = |name: &str, skip_if_absent: bool| Action::RequestHeader {
+        name: name.to_owned(),
+        key: name.to_owned(),
+        skip_if_absent,
+    };
+    let generic = Action::GenericKey {
+        key: "generic_key".to_owned(),
+        value: "api".to_owned(),
+    };
+
+    let mut req = axum::http::Request::builder()
+        .uri("/")
+        .header("x-user", "alice")
+        .body(axum::body::Body::empty())
+        .unwrap();
+    req.extensions_mut().insert(ConnectionInfo {
+        local_addr: ([127, 0, 0, 1], 10000).into(),
+        remote_addr: ([10, 0, 0, 2], 54321).into(),
+    });
+
+    let pairs = |entries: &[(&str, &str)]| -> Descriptor {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    };
+    let cases: Vec<(RateLimit, Option<Descriptor>)> = vec![
+        (
+            rate_limit(vec![Action::DestinationCluster, header("x-user", false)]),
+            Some(pairs(&[
+                ("destination_cluster", "svc"),
+                ("x-user", "alice"),
+            ])),
+        ),
+        (
+            rate_limit(vec![Action::RemoteAddress, generic.clone()]),
+            Some(pairs(&[
+                ("remote_address", "10.0.0.2"),
+                ("generic_key", "api"),
+            ])),
+        ),
+        (
+            rate_limit(vec![generic.clone(), header("x-tenant", true)]),
+            Some(pairs(&[("generic_key", "api")])),
+        ),
+        (rate_limit(vec![generic, header("x-tenant", false)]), None),
+        (rate_limit(vec![header("x-tenant", true)]), None),
+    ];
+
+    for (rate_limit, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            rate_limit.descriptor(&req, "svc"),
+            "{:?}",
+            rate_limit
+        );
+    }
+}
@@ -0,0 +1,369 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::net::SocketAddr;
+use std::ops::BitOr;
+use std::time::Duration;
+
+use axum::http::{HeaderMap, StatusCode};
+use envoy_control_plane::envoy::config::route::v3::RetryPolicy as V3RetryPolicy;
+use rand::Rng;
+
+use crate::cluster::UpstreamFailure;
+use crate::matcher::{self, HeaderMatcher};
+use crate::protobuf;
+use crate::Response;
+
+/// RETRY_ON_HEADER lets trusted callers add retry conditions to a request.
+pub const RETRY_ON_HEADER: &str = "x-envoy-retry-on";
+/// MAX_RETRIES_HEADER lets trusted callers override the number of retries.
+pub const MAX_RETRIES_HEADER: &str = "x-envoy-max-retries";
+
+/// PREVIOUS_HOSTS_PREDICATE is the only retry host predicate we support.
+const PREVIOUS_HOSTS_PREDICATE: &str = "envoy.retry_host_predicates.previous_hosts";
+
+const DEFAULT_NUM_RETRIES: u32 = 1;
+const DEFAULT_BASE_INTERVAL: Duration = Duration::from_millis(25);
+
+/// RetryOn is the set of conditions under which an upstream request is retried,
+/// as specified in Envoy's `retry_on` policy field or the `x-envoy-retry-on` header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryOn(u32);
+
+impl RetryOn {
+    pub const FIVE_XX: RetryOn = RetryOn(1 << 0);
+    pub const GATEWAY_ERROR: RetryOn = RetryOn(1 << 1);
+    pub const RESET: RetryOn = RetryOn(1 << 2);
+    pub const CONNECT_FAILURE: RetryOn = RetryOn(1 << 3);
+    pub const RETRIABLE_4XX: RetryOn = RetryOn(1 << 4);
+    pub const RETRIABLE_STATUS_CODES: RetryOn = RetryOn(1 << 5);
+    pub const RETRIABLE_HEADERS: RetryOn = RetryOn(1 << 6);
+
+    /// parse converts a comma-separated list of conditions into a RetryOn.
+    /// Unknown conditions are ignored, like Envoy does for the request header.
+    pub fn parse(conditions: &str) -> RetryOn {
+        conditions
+            .split(',')
+            .map(|condition| match condition.trim() {
+                "5xx" => RetryOn::FIVE_XX,
+                "gateway-error" => RetryOn::GATEWAY_ERROR,
+                "reset" => RetryOn::RESET,
+                "connect-failure" => RetryOn::CONNECT_FAILURE,
+                "retriable-4xx" => RetryOn::RETRIABLE_4XX,
+                "retriable-status-codes" => RetryOn::RETRIABLE_STATUS_CODES,
+                "retriable-headers" => RetryOn::RETRIABLE_HEADERS,
+                _ => RetryOn::default(),
+            })
+            .fold(RetryOn::default(), RetryOn::bitor)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// intersects reports whether any of the conditions in `other` are set.
+    pub fn intersects(self, other: RetryOn) -> bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for RetryOn {
+    type Output = RetryOn;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        RetryOn(self.0 | rhs.0)
+    }
+}
+
+/// RetryPolicy describes how upstream requests made for a route are attempted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    pub retry_on: RetryOn,
+    pub num_retries: u32,
+    /// per_try_timeout bounds each individual attempt, as opposed to the route's timeout
+    /// which bounds the request as a whole.
+    pub per_try_timeout: Option<Duration>,
+    pub retriable_status_codes: Vec<u16>,
+    pub retriable_headers: Vec<HeaderMatcher>,
+    pub base_interval: Duration,
+    pub max_interval: Duration,
+    /// avoid_previous_hosts is set by the `previous_hosts` retry host predicate.
+    pub avoid_previous_hosts: bool,
+    pub host_selection_retry_max_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retry_on: RetryOn::default(),
+            num_retries: DEFAULT_NUM_RETRIES,
+            per_try_timeout: None,
+            retriable_status_codes: vec![],
+            retriable_headers: vec![],
+            base_interval: DEFAULT_BASE_INTERVAL,
+            max_interval: DEFAULT_BASE_INTERVAL * 10,
+            avoid_previous_hosts: false,
+            host_selection_retry_max_attempts: 1,
+        }
+    }
+}
+
+impl TryFrom<V3RetryPolicy> for RetryPolicy {
+    type Error = matcher::Error;
+
+    fn try_from(value: V3RetryPolicy) -> Result<Self, Self::Error> {
+        let defaults = RetryPolicy::default();
+
+        let (base_interval, max_interval) = match value.retry_back_off {
+            Some(back_off) => {
+                let base = back_off
+                    .base_interval
+                    .as_ref()
+                    .and_then(protobuf::non_zero_duration)
+                    .unwrap_or(defaults.base_interval);
+                let max = back_off
+                    .max_interval
+                    .as_ref()
+                    .and_then(protobuf::non_zero_duration)
+                    .unwrap_or(base * 10)
+                    .max(base);
+                (base, max)
+            }
+            None => (defaults.base_interval, defaults.max_interval),
+        };
+
+        let avoid_previous_hosts = value
+            .retry_host_predicate
+            .iter()
+            .any(|predicate| predicate.name == PREVIOUS_HOSTS_PREDICATE);
+
+        Ok(RetryPolicy {
+            retry_on: RetryOn::parse(&value.retry_on),
+            num_retries: value.num_retries.unwrap_or(defaults.num_retries),
+            per_try_timeout: value
+                .per_try_timeout
+                .as_ref()
+                .and_then(protobuf::non_zero_duration),
+            retriable_status_codes: value
+                .retriable_status_codes
+                .into_iter()
+                .filter_map(|code| u16::try_from(code).ok())
+                .collect(),
+            retriable_headers: value
+                .retriable_headers
+                .into_iter()
+                .map(HeaderMatcher::try_from)
+                .collect::<Result<_, _>>()?,
+            base_interval,
+            max_interval,
+            avoid_previous_hosts,
+            host_selection_retry_max_attempts: value
+                .host_selection_retry_max_attempts
+                .max(1)
+                .try_into()
+                .unwrap_or(u32::MAX),
+        })
+    }
+}
+
+/// Outcome is the result of a single upstream attempt.
+pub enum Outcome {
+    Response(Response),
+    /// Timeout means the attempt's per-try timeout elapsed.
+    Timeout,
+}
+
+/// RetryState tracks the retries made on behalf of a single downstream request.
+#[derive(Debug)]
+pub struct RetryState {
+    policy: RetryPolicy,
+    retries_remaining: u32,
+    retries_made: u32,
+    previous_hosts: Vec<SocketAddr>,
+}
+
+impl RetryState {
+    /// new creates the retry state for a request, taking into account the
+    /// `x-envoy-retry-on` and `x-envoy-max-retries` headers from trusted
+    /// callers.  Those headers are consumed so they aren't forwarded upstream.
+    pub fn new(policy: Option<&RetryPolicy>, headers: &mut HeaderMap, trusted: bool) -> Self {
+        let mut policy = policy.cloned().unwrap_or_default();
+        if trusted {
+            if let Some(retry_on) = headers.get(RETRY_ON_HEADER).and_then(|v| v.to_str().ok()) {
+                policy.retry_on = policy.retry_on | RetryOn::parse(retry_on);
+            }
+            if let Some(max_retries) = headers
+                .get(MAX_RETRIES_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+            {
+                policy.num_retries = max_retries;
+            }
+        }
+        headers.remove(RETRY_ON_HEADER);
+        headers.remove(MAX_RETRIES_HEADER);
+
+        let retries_remaining = if policy.retry_on.is_empty() {
+            0
+        } else {
+            policy.num_retries
+        };
+
+        RetryState {
+            policy,
+            retries_remaining,
+            retries_made: 0,
+            previous_hosts: vec![],
+        }
+    }
+
+    /// enabled reports whether this request could be retried at all, and so
+    /// whether its body needs to be buffered for replay.
+    pub fn enabled(&self) -> bool {
+        self.retries_remaining > 0
+    }
+
+    /// host_reselection_attempts is how many times to pick another host if the
+    /// retry host predicate rejects the one the load balancer chose.
+    pub fn host_reselection_attempts(&self) -> u32 {
+        if self.policy.avoid_previous_hosts {
+            self.policy.host_selection_retry_max_attempts
+        } else {
+            0
+        }
+    }
+
+    /// accept_host reports whether a host is acceptable to the retry host predicate.
+    pub fn accept_host(&self, host: &SocketAddr) -> bool {
+        !self.policy.avoid_previous_hosts || !self.previous_hosts.contains(host)
+    }
+
+    pub fn record_attempt(&mut self, host: SocketAddr) {
+        self.previous_hosts.push(host);
+    }
+
+    /// should_retry decides whether the outcome of an attempt warrants another one.
+    /// If it returns true a retry has been consumed, and the caller should wait for
+    /// `backoff()` before trying again.
+    pub fn should_retry(&mut self, outcome: &Outcome) -> bool {
+        if self.retries_remaining == 0 || !self.is_retriable(outcome) {
+            return false;
+        }
+        self.retries_remaining -= 1;
+        self.retries_made += 1;
+        true
+    }
+
+    fn is_retriable(&self, outcome: &Outcome) -> bool {
+        let retry_on = self.policy.retry_on;
+        let resp = match outcome {
+            Outcome::Timeout => {
+                return retry_on
+                    .intersects(RetryOn::FIVE_XX | RetryOn::GATEWAY_ERROR | RetryOn::RESET);
+            }
+            Outcome::Response(resp) => resp,
+        };
+
+        match resp.extensions().get::<UpstreamFailure>() {
+            Some(UpstreamFailure::ConnectFailure) => {
+                return retry_on.intersects(
+                    RetryOn::FIVE_XX
+                        | RetryOn::GATEWAY_ERROR
+                        | RetryOn::RESET
+                        | RetryOn::CONNECT_FAILURE,
+                );
+            }
+            Some(UpstreamFailure::Reset) => {
+                return retry_on
+                    .intersects(RetryOn::FIVE_XX | RetryOn::GATEWAY_ERROR | RetryOn::RESET);
+            }
+            None => {}
+        }
+
+        let status = resp.status();
+        (retry_on.intersects(RetryOn::FIVE_XX) && status.is_server_error())
+            || (retry_on.intersects(RetryOn::GATEWAY_ERROR)
+                && matches!(
+                    status,
+                    StatusCode::BAD_GATEWAY
+                        | StatusCode::SERVICE_UNAVAILABLE
+                        | StatusCode::GATEWAY_TIMEOUT
+                ))
+            || (retry_on.intersects(RetryOn::RETRIABLE_4XX) && status == StatusCode::CONFLICT)
+            || (retry_on.intersects(RetryOn::RETRIABLE_STATUS_CODES)
+                && self
+                    .policy
+                    .retriable_status_codes
+                    .contains(&status.as_u16()))
+            || (retry_on.intersects(RetryOn::RETRIABLE_HEADERS)
+                && self
+                    .policy
+                    .retriable_headers
+                    .iter()
+                    .any(|matcher| matcher.matches(resp.headers())))
+    }
+
+    /// backoff returns how long to wait before the next retry: a fully jittered
+    /// exponential backoff between `base_interval` and `max_interval`.
+    pub fn backoff(&self) -> Duration {
+        let exponent = self.retries_made.saturating_sub(1).min(16);
+        let ceiling = (self.policy.base_interval * (1 << exponent)).min(self.policy.max_interval);
+        let ceiling_ms = ceiling.as_millis() as u64;
+        if ceiling_ms == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(rand::thread_rng().gen_range(0..=ceiling_ms))
+    }
+}
+
+#[test]
+fn test_should_retry() {
+    use axum::http::HeaderValue;
+    use ronvoy_core::response;
+
+    let policy = RetryPolicy {
+        retry_on: RetryOn::parse("gateway-error,retriable-status-codes"),
+        num_retries: 2,
+        retriable_status_codes: vec![418],
+        ..Default::default()
+    };
+    let status = |code: u16| Outcome::Response(response::json_error(code, "test"));
+    let failure = |failure: UpstreamFailure| {
+        let mut resp = response::json_error(503, "test");
+        resp.extensions_mut().insert(failure);
+        Outcome::Response(resp)
+    };
+
+    let cases: &[(Outcome, bool)] = &[
+        (status(200), false),
+        (status(500), false),
+        (status(502), true),
+        (status(418), true),
+        (status(409), false),
+        (failure(UpstreamFailure::ConnectFailure), true),
+        (Outcome::Timeout, true),
+    ];
+
+    for (outcome, expected) in cases.iter() {
+        let mut state = RetryState::new(Some(&policy), &mut HeaderMap::new(), false);
+        assert_eq!(*expected, state.should_retry(outcome));
+    }
+
+    // retries are limited to num_retries
+    let mut state = RetryState::new(Some(&policy), &mut HeaderMap::new(), false);
+    assert!(state.should_retry(&status(503)));
+    assert!(state.should_retry(&status(503)));
+    assert!(!state.should_retry(&status(503)));
+
+    // trusted callers can enable retries with request headers
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_ON_HEADER, HeaderValue::from_static("5xx"));
+    headers.insert(MAX_RETRIES_HEADER, HeaderValue::from_static("3"));
+    let state = RetryState::new(None, &mut headers.clone(), false);
+    assert!(!state.enabled());
+    let mut state = RetryState::new(None, &mut headers, true);
+    assert!(state.enabled());
+    assert!(state.should_retry(&status(500)));
+    assert!(headers.is_empty());
+}
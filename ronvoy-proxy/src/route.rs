@@ -2,15 +2,46 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
-use axum::http::Uri;
+use axum::http::header::HOST;
+use axum::http::{HeaderValue, Method, StatusCode, Uri};
+use std::collections::HashMap;
 use std::error::Error as StdError;
 use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::Duration;
 
+use envoy_control_plane::envoy::config::core::v3::TypedExtensionConfig as V3TypedExtensionConfig;
 use envoy_control_plane::envoy::config::route::v3::{
     route::Action as V3Action, route_action::ClusterSpecifier as V3ClusterSpecifier,
-    route_match::PathSpecifier as V3PathSpecifier, Route as V3Route, RouteAction as V3RouteAction,
-    RouteMatch as V3RouteMatch,
+    route_action::HostRewriteSpecifier as V3HostRewriteSpecifier,
+    route_action::RequestMirrorPolicy as V3RequestMirrorPolicy,
+    route_match::PathSpecifier as V3PathSpecifier,
+    InternalRedirectPolicy as V3InternalRedirectPolicy, Route as V3Route,
+    RouteAction as V3RouteAction, RouteMatch as V3RouteMatch, WeightedCluster as V3WeightedCluster,
 };
+use envoy_control_plane::envoy::extensions::internal_redirect::{
+    allow_listed_routes::v3::AllowListedRoutesConfig as V3AllowListedRoutesConfig,
+    previous_routes::v3::PreviousRoutesConfig as V3PreviousRoutesConfig,
+    safe_cross_scheme::v3::SafeCrossSchemeConfig as V3SafeCrossSchemeConfig,
+};
+use envoy_control_plane::envoy::r#type::matcher::v3::RegexMatchAndSubstitute as V3RegexMatchAndSubstitute;
+use rand::Rng;
+
+use crate::cors::{self, CorsPolicy};
+use crate::extensions::filter::http::{self as http_filter, FilterConfigs, PerFilterConfig};
+use crate::grpc;
+use crate::headers::{self, HeaderPolicy};
+use crate::matcher;
+use crate::protobuf::{self, Fraction};
+use crate::rate_limit::{self, RateLimit};
+use crate::retry::RetryPolicy;
+use crate::Request;
+
+/// DEFAULT_TIMEOUT is Envoy's upstream timeout for routes that don't specify one.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// ORIGINAL_PATH_HEADER records the downstream path when a route rewrites it, like Envoy does.
+const ORIGINAL_PATH_HEADER: &str = "x-envoy-original-path";
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Error {
@@ -18,6 +49,15 @@ pub enum Error {
     MissingAction,
     UnsupportedMatchType,
     UnsupportedClusterSpecifier,
+    ConflictingPathRewrites,
+    BadRegex(String),
+    Matcher(matcher::Error),
+    Headers(headers::Error),
+    UnsupportedRedirectPredicate(String),
+    BadRedirectPredicate(String),
+    HttpFilter(http_filter::Error),
+    RateLimit(rate_limit::Error),
+    Cors(cors::Error),
 }
 
 impl Display for Error {
@@ -35,34 +75,488 @@ impl Display for Error {
             Error::UnsupportedClusterSpecifier => {
                 write!(f, "route: unsupported cluster specifier type (TODO)")
             }
+            Error::ConflictingPathRewrites => {
+                write!(
+                    f,
+                    "route: only one of prefix_rewrite or regex_rewrite may be set"
+                )
+            }
+            Error::BadRegex(regex) => write!(f, "route: invalid regex: {}", regex),
+            Error::Matcher(err) => write!(f, "route: {}", err),
+            Error::Headers(err) => write!(f, "route: {}", err),
+            Error::UnsupportedRedirectPredicate(type_url) => {
+                write!(
+                    f,
+                    "route: unsupported internal redirect predicate {}",
+                    type_url
+                )
+            }
+            Error::BadRedirectPredicate(name) => {
+                write!(f, "route: invalid internal redirect predicate {}", name)
+            }
+            Error::HttpFilter(err) => write!(f, "route: {}", err),
+            Error::RateLimit(err) => write!(f, "route: {}", err),
+            Error::Cors(err) => write!(f, "route: {}", err),
         }
     }
 }
 
 impl StdError for Error {}
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+impl From<matcher::Error> for Error {
+    fn from(err: matcher::Error) -> Self {
+        Error::Matcher(err)
+    }
+}
+
+impl From<headers::Error> for Error {
+    fn from(err: headers::Error) -> Self {
+        Error::Headers(err)
+    }
+}
+
+impl From<rate_limit::Error> for Error {
+    fn from(err: rate_limit::Error) -> Self {
+        Error::RateLimit(err)
+    }
+}
+
+impl From<cors::Error> for Error {
+    fn from(err: cors::Error) -> Self {
+        Error::Cors(err)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum ClusterSpecifier {
     Name(String),
     // Header(String),
-    // Weighted
+    Weighted(WeightedClusters),
+}
+
+/// WeightedCluster is one of the upstream clusters a route splits traffic between.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightedCluster {
+    pub name: String,
+    pub weight: u32,
+    pub headers: HeaderPolicy,
+}
+
+/// WeightedClusters splits a route's traffic between clusters in proportion to their weights.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WeightedClusters {
+    pub clusters: Vec<WeightedCluster>,
+}
+
+impl WeightedClusters {
+    /// pick randomly chooses a cluster according to the weights, returning its index.
+    pub fn pick(&self) -> Option<usize> {
+        let total: u64 = self.clusters.iter().map(|c| c.weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut n = rand::thread_rng().gen_range(0..total);
+        for (i, cluster) in self.clusters.iter().enumerate() {
+            if n < cluster.weight as u64 {
+                return Some(i);
+            }
+            n -= cluster.weight as u64;
+        }
+        None
+    }
+}
+
+impl TryFrom<V3WeightedCluster> for WeightedClusters {
+    type Error = Error;
+
+    fn try_from(value: V3WeightedCluster) -> Result<Self, Self::Error> {
+        let clusters = value
+            .clusters
+            .into_iter()
+            .map(|cluster| {
+                Ok(WeightedCluster {
+                    name: cluster.name,
+                    weight: cluster.weight.unwrap_or_default(),
+                    headers: HeaderPolicy::new(
+                        cluster.request_headers_to_add,
+                        cluster.request_headers_to_remove,
+                        cluster.response_headers_to_add,
+                        cluster.response_headers_to_remove,
+                    )?,
+                })
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(WeightedClusters { clusters })
+    }
+}
+
+/// RegexRewrite replaces every match of `pattern` in a string with `substitution`.
+#[derive(Debug, Clone)]
+pub struct RegexRewrite {
+    pattern: regex::Regex,
+    // substitution is in the `regex` crate's syntax (`${1}`), converted from Envoy's (`\1`)
+    substitution: String,
+}
+
+impl RegexRewrite {
+    fn rewrite(&self, input: &str) -> String {
+        self.pattern
+            .replace_all(input, self.substitution.as_str())
+            .into_owned()
+    }
+}
+
+impl PartialEq for RegexRewrite {
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern.as_str() == other.pattern.as_str() && self.substitution == other.substitution
+    }
+}
+
+impl TryFrom<V3RegexMatchAndSubstitute> for RegexRewrite {
+    type Error = Error;
+
+    fn try_from(value: V3RegexMatchAndSubstitute) -> Result<Self, Self::Error> {
+        let regex = value.pattern.unwrap_or_default().regex;
+        let pattern = regex::Regex::new(&regex).map_err(|_| Error::BadRegex(regex))?;
+        Ok(RegexRewrite {
+            pattern,
+            substitution: regex_substitution(&value.substitution),
+        })
+    }
+}
+
+/// regex_substitution converts an Envoy (RE2) substitution string, where capture
+/// groups are referenced as `\1`, to the syntax the `regex` crate expects.
+fn regex_substitution(envoy_substitution: &str) -> String {
+    let mut result = String::with_capacity(envoy_substitution.len());
+    let mut chars = envoy_substitution.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(d) if d.is_ascii_digit() => {
+                    result.push_str(&format!("${{{}}}", d));
+                    chars.next();
+                }
+                Some('\\') => {
+                    result.push('\\');
+                    chars.next();
+                }
+                _ => result.push('\\'),
+            },
+            // a literal '$' must be escaped for the regex crate
+            '$' => result.push_str("$$"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+/// HostRewrite describes how the Host header of a forwarded request is rewritten.
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostRewrite {
+    Literal(String),
+    /// Auto rewrites the Host to the hostname of the chosen upstream host,
+    /// which can only be done once the cluster has picked an endpoint.
+    Auto,
+    Header(String),
+    PathRegex(RegexRewrite),
+}
+
+/// RequestMirrorPolicy sends a copy of a fraction of a route's requests to a
+/// shadow cluster, without waiting for (or caring about) its responses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestMirrorPolicy {
+    pub cluster: String,
+    pub fraction: Fraction,
+}
+
+impl From<V3RequestMirrorPolicy> for RequestMirrorPolicy {
+    fn from(value: V3RequestMirrorPolicy) -> Self {
+        RequestMirrorPolicy {
+            cluster: value.cluster,
+            fraction: value
+                .runtime_fraction
+                .as_ref()
+                .map(protobuf::runtime_fraction)
+                .unwrap_or(Fraction::ALWAYS),
+        }
+    }
+}
+
+/// ConnectConfig is set on routes that terminate CONNECT requests themselves,
+/// tunneling the payload to the upstream over TCP, instead of forwarding them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectConfig {
+    /// allow_post also tunnels the bodies of POST requests as raw TCP.
+    pub allow_post: bool,
+}
+
+/// GrpcTimeout derives the timeout of gRPC requests from the deadline their
+/// client sets with the grpc-timeout header, instead of using the route timeout.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrpcTimeout {
+    /// max caps the timeout a client can ask for.  None means it isn't capped.
+    pub max: Option<Duration>,
+    /// offset is subtracted from the client's deadline, so that we time out (and
+    /// can tell the client why) before the client gives up on the call.
+    pub offset: Option<Duration>,
+}
+
+impl GrpcTimeout {
+    /// timeout returns the timeout for a request whose grpc-timeout header asks
+    /// for `requested`, where None (or zero) means the client set no deadline.
+    pub fn timeout(&self, requested: Option<Duration>) -> Option<Duration> {
+        let requested = requested
+            .filter(|requested| !requested.is_zero())
+            .map(|requested| match self.offset {
+                Some(offset) if offset < requested => requested - offset,
+                _ => requested,
+            });
+        match (requested, self.max) {
+            (Some(requested), Some(max)) => Some(requested.min(max)),
+            (requested, max) => requested.or(max),
+        }
+    }
+}
+
+/// RedirectPredicate is an extra condition a redirect must meet to be followed internally.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedirectPredicate {
+    /// PreviousRoutes rejects redirects to routes the request has already been through.
+    PreviousRoutes,
+    /// AllowListedRoutes only accepts redirects to the named routes.
+    AllowListedRoutes(Vec<String>),
+    /// SafeCrossScheme rejects redirects from plain HTTP to HTTPS.
+    SafeCrossScheme,
+}
+
+impl TryFrom<V3TypedExtensionConfig> for RedirectPredicate {
+    type Error = Error;
+
+    fn try_from(value: V3TypedExtensionConfig) -> Result<Self, Self::Error> {
+        use envoy_control_plane::prost::Message;
+        use envoy_control_plane::prost_wkt_types::MessageSerde;
+
+        let config = value
+            .typed_config
+            .ok_or_else(|| Error::BadRedirectPredicate(value.name.clone()))?;
+        if config.type_url == V3PreviousRoutesConfig::default().type_url() {
+            Ok(RedirectPredicate::PreviousRoutes)
+        } else if config.type_url == V3AllowListedRoutesConfig::default().type_url() {
+            let allow_listed = V3AllowListedRoutesConfig::decode(&*config.value)
+                .map_err(|_| Error::BadRedirectPredicate(value.name))?;
+            Ok(RedirectPredicate::AllowListedRoutes(
+                allow_listed.allowed_route_names,
+            ))
+        } else if config.type_url == V3SafeCrossSchemeConfig::default().type_url() {
+            Ok(RedirectPredicate::SafeCrossScheme)
+        } else {
+            Err(Error::UnsupportedRedirectPredicate(config.type_url))
+        }
+    }
+}
+
+/// InternalRedirectPolicy lets us follow an upstream's redirects ourselves,
+/// re-routing the request to the redirect's target, rather than passing them on
+/// to the downstream.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InternalRedirectPolicy {
+    /// max_internal_redirects bounds how many redirects a single downstream
+    /// request can follow.
+    pub max_internal_redirects: u32,
+    pub redirect_response_codes: Vec<StatusCode>,
+    pub allow_cross_scheme_redirect: bool,
+    pub predicates: Vec<RedirectPredicate>,
+}
+
+impl InternalRedirectPolicy {
+    /// accepts_target checks a redirect to `target` against the predicates.
+    /// `visited` are the names of the routes the request has been through.
+    pub fn accepts_target(
+        &self,
+        target: &Route,
+        visited: &[String],
+        downstream_https: bool,
+        target_https: bool,
+    ) -> bool {
+        self.predicates.iter().all(|predicate| match predicate {
+            RedirectPredicate::PreviousRoutes => !visited.contains(&target.name),
+            RedirectPredicate::AllowListedRoutes(names) => names.contains(&target.name),
+            RedirectPredicate::SafeCrossScheme => downstream_https || !target_https,
+        })
+    }
+}
+
+impl TryFrom<V3InternalRedirectPolicy> for InternalRedirectPolicy {
+    type Error = Error;
+
+    fn try_from(value: V3InternalRedirectPolicy) -> Result<Self, Self::Error> {
+        // like Envoy, only 302 is followed by default, and codes other than
+        // 301, 302, 303, 307 and 308 are ignored.
+        let mut redirect_response_codes: Vec<StatusCode> = value
+            .redirect_response_codes
+            .into_iter()
+            .filter(|code| matches!(code, 301 | 302 | 303 | 307 | 308))
+            .filter_map(|code| StatusCode::from_u16(code as u16).ok())
+            .collect();
+        if redirect_response_codes.is_empty() {
+            redirect_response_codes.push(StatusCode::FOUND);
+        }
+        Ok(InternalRedirectPolicy {
+            max_internal_redirects: value.max_internal_redirects.unwrap_or(1),
+            redirect_response_codes,
+            allow_cross_scheme_redirect: value.allow_cross_scheme_redirect,
+            predicates: value
+                .predicates
+                .into_iter()
+                .map(RedirectPredicate::try_from)
+                .collect::<Result<_, _>>()?,
+        })
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct RouteAction {
     pub cluster: ClusterSpecifier,
+    pub prefix_rewrite: Option<String>,
+    pub regex_rewrite: Option<RegexRewrite>,
+    pub host_rewrite: Option<HostRewrite>,
+    /// timeout bounds the time from forwarding the request until the upstream
+    /// response is complete.  None means the route has no timeout.
+    pub timeout: Option<Duration>,
+    /// idle_timeout bounds the time the upstream stream can go without activity.
+    pub idle_timeout: Option<Duration>,
+    /// grpc_timeout, if set, replaces `timeout` for gRPC requests.
+    pub grpc_timeout: Option<GrpcTimeout>,
+    pub retry_policy: Option<RetryPolicy>,
+    pub request_mirror_policies: Vec<RequestMirrorPolicy>,
+    /// upgrade_configs enables or disables, by (lowercase) upgrade type, upgrades
+    /// the HttpConnectionManager allows.
+    pub upgrade_configs: HashMap<String, bool>,
+    pub connect_config: Option<ConnectConfig>,
+    pub internal_redirect_policy: Option<InternalRedirectPolicy>,
+    /// rate_limits describe requests using the route to rate limit filters.
+    pub rate_limits: Vec<RateLimit>,
+    /// include_vh_rate_limits says whether the virtual host's rate limits also
+    /// apply when the route has rate limits of its own.
+    pub include_vh_rate_limits: bool,
+    /// cors is the CORS policy the CORS filter enforces for the route.
+    pub cors: Option<CorsPolicy>,
 }
 
 impl TryFrom<V3RouteAction> for RouteAction {
     type Error = Error;
 
     fn try_from(value: V3RouteAction) -> Result<Self, Self::Error> {
-        match value.cluster_specifier {
-            Some(V3ClusterSpecifier::Cluster(name)) => Ok(RouteAction {
-                cluster: ClusterSpecifier::Name(name),
-            }),
-            _ => Err(Error::UnsupportedClusterSpecifier),
+        let cluster = match value.cluster_specifier {
+            Some(V3ClusterSpecifier::Cluster(name)) => ClusterSpecifier::Name(name),
+            Some(V3ClusterSpecifier::WeightedClusters(weighted)) => {
+                ClusterSpecifier::Weighted(WeightedClusters::try_from(weighted)?)
+            }
+            _ => return Err(Error::UnsupportedClusterSpecifier),
+        };
+
+        let prefix_rewrite = if value.prefix_rewrite.is_empty() {
+            None
+        } else {
+            Some(value.prefix_rewrite)
+        };
+        let regex_rewrite = value
+            .regex_rewrite
+            .map(RegexRewrite::try_from)
+            .transpose()?;
+        if prefix_rewrite.is_some() && regex_rewrite.is_some() {
+            return Err(Error::ConflictingPathRewrites);
         }
+
+        let host_rewrite = match value.host_rewrite_specifier {
+            Some(V3HostRewriteSpecifier::HostRewriteLiteral(host)) if !host.is_empty() => {
+                Some(HostRewrite::Literal(host))
+            }
+            Some(V3HostRewriteSpecifier::AutoHostRewrite(true)) => Some(HostRewrite::Auto),
+            Some(V3HostRewriteSpecifier::HostRewriteHeader(header)) if !header.is_empty() => {
+                Some(HostRewrite::Header(header))
+            }
+            Some(V3HostRewriteSpecifier::HostRewritePathRegex(regex)) => {
+                Some(HostRewrite::PathRegex(RegexRewrite::try_from(regex)?))
+            }
+            _ => None,
+        };
+
+        // an explicit timeout of 0 disables it, but an unset one gets Envoy's default
+        let timeout = match value.timeout {
+            Some(timeout) => protobuf::non_zero_duration(&timeout),
+            None => Some(DEFAULT_TIMEOUT),
+        };
+
+        // the grpc_timeout_header_* fields of max_stream_duration supersede the
+        // deprecated max_grpc_timeout and grpc_timeout_offset.
+        let max_stream_duration = value.max_stream_duration.as_ref();
+        #[allow(deprecated)]
+        let grpc_timeout_max = max_stream_duration
+            .and_then(|d| d.grpc_timeout_header_max.as_ref())
+            .or_else(|| value.max_grpc_timeout.as_ref());
+        #[allow(deprecated)]
+        let grpc_timeout_offset = max_stream_duration
+            .and_then(|d| d.grpc_timeout_header_offset.as_ref())
+            .or_else(|| value.grpc_timeout_offset.as_ref());
+        let grpc_timeout = grpc_timeout_max.map(|max| GrpcTimeout {
+            // a max of 0 means the client's deadline isn't capped
+            max: protobuf::non_zero_duration(max),
+            offset: grpc_timeout_offset.and_then(protobuf::non_zero_duration),
+        });
+
+        let connect_config = value
+            .upgrade_configs
+            .iter()
+            .find(|config| {
+                config.upgrade_type.eq_ignore_ascii_case("connect")
+                    && config.enabled.unwrap_or(true)
+            })
+            .and_then(|config| config.connect_config.as_ref())
+            .map(|config| ConnectConfig {
+                allow_post: config.allow_post,
+            });
+
+        Ok(RouteAction {
+            cluster,
+            prefix_rewrite,
+            regex_rewrite,
+            host_rewrite,
+            timeout,
+            idle_timeout: value
+                .idle_timeout
+                .as_ref()
+                .and_then(protobuf::non_zero_duration),
+            grpc_timeout,
+            retry_policy: value.retry_policy.map(RetryPolicy::try_from).transpose()?,
+            request_mirror_policies: value
+                .request_mirror_policies
+                .into_iter()
+                .filter(|policy| !policy.cluster.is_empty())
+                .map(RequestMirrorPolicy::from)
+                .collect(),
+            upgrade_configs: value
+                .upgrade_configs
+                .into_iter()
+                .map(|config| {
+                    let enabled = config.enabled.unwrap_or(true);
+                    (config.upgrade_type.to_ascii_lowercase(), enabled)
+                })
+                .collect(),
+            connect_config,
+            internal_redirect_policy: value
+                .internal_redirect_policy
+                .map(InternalRedirectPolicy::try_from)
+                .transpose()?,
+            rate_limits: value
+                .rate_limits
+                .into_iter()
+                .map(RateLimit::try_from)
+                .collect::<Result<_, _>>()?,
+            include_vh_rate_limits: value.include_vh_rate_limits.unwrap_or(false),
+            cors: value.cors.map(CorsPolicy::try_from).transpose()?,
+        })
     }
 }
 
@@ -78,7 +572,22 @@ pub enum RouteMatch {
     Prefix(String),
     ExactPath(String),
     // SafeRegex
-    // ConnectMatcher
+    /// Connect matches CONNECT requests, which (in HTTP/1.1 at least) have no
+    /// path for the other matchers to match.
+    Connect,
+}
+
+impl RouteMatch {
+    pub fn matches(&self, req: &Request) -> bool {
+        // authority-form (CONNECT) URIs have an empty path, which only the
+        // CONNECT matcher accepts.
+        let path = req.uri().path();
+        match self {
+            RouteMatch::Prefix(prefix) => !path.is_empty() && path.starts_with(prefix.as_str()),
+            RouteMatch::ExactPath(exact) => !path.is_empty() && path == exact.as_str(),
+            RouteMatch::Connect => req.method() == Method::CONNECT,
+        }
+    }
 }
 
 impl TryFrom<V3RouteMatch> for RouteMatch {
@@ -88,6 +597,7 @@ impl TryFrom<V3RouteMatch> for RouteMatch {
         match value.path_specifier {
             Some(V3PathSpecifier::Prefix(prefix)) => Ok(RouteMatch::Prefix(prefix)),
             Some(V3PathSpecifier::Path(prefix)) => Ok(RouteMatch::ExactPath(prefix)),
+            Some(V3PathSpecifier::ConnectMatcher(_)) => Ok(RouteMatch::Connect),
             _ => Err(Error::UnsupportedMatchType),
         }
     }
@@ -97,25 +607,176 @@ impl TryFrom<V3RouteMatch> for RouteMatch {
 pub struct Route {
     pub name: String,
     matcher: RouteMatch,
+    /// grpc restricts the route to gRPC requests.
+    grpc: bool,
     action: Action,
+    /// per_request_buffer_limit_bytes bounds how much of a request body is
+    /// buffered so that it can be replayed for retries.
+    pub per_request_buffer_limit_bytes: Option<u32>,
+    /// header_policies are the header mutations for requests using this route, from
+    /// the route itself out to its route configuration.
+    header_policies: Vec<Arc<HeaderPolicy>>,
+    /// per_filter_configs override the config of HTTP filters for requests using
+    /// this route, including those inherited from its virtual host.
+    per_filter_configs: FilterConfigs,
 }
 
 impl Route {
-    pub fn matches(&self, uri: &Uri) -> Option<&Action> {
-        match &self.matcher {
-            RouteMatch::Prefix(prefix) => {
-                if uri.path().starts_with(prefix) {
-                    return Some(&self.action);
-                }
+    pub fn matches(&self, req: &Request) -> Option<&Action> {
+        if self.matcher.matches(req) && (!self.grpc || grpc::is_grpc(req.headers())) {
+            Some(&self.action)
+        } else {
+            None
+        }
+    }
+
+    pub fn matcher(&self) -> &RouteMatch {
+        &self.matcher
+    }
+
+    pub fn action(&self) -> &Action {
+        &self.action
+    }
+
+    /// inherit applies settings from the route's virtual host that the route
+    /// itself doesn't specify.
+    pub fn inherit(&mut self, retry_policy: Option<&RetryPolicy>, buffer_limit: Option<u32>) {
+        let Action::Route(action) = &mut self.action;
+        if action.retry_policy.is_none() {
+            action.retry_policy = retry_policy.cloned();
+        }
+        if self.per_request_buffer_limit_bytes.is_none() {
+            self.per_request_buffer_limit_bytes = buffer_limit;
+        }
+    }
+
+    /// inherit_rate_limits adds the rate limits of the route's virtual host.  Like
+    /// Envoy, they only apply to routes with rate limits of their own if the
+    /// route asks for them with include_vh_rate_limits.
+    pub fn inherit_rate_limits(&mut self, rate_limits: &[RateLimit]) {
+        let Action::Route(action) = &mut self.action;
+        if action.rate_limits.is_empty() || action.include_vh_rate_limits {
+            action.rate_limits.extend_from_slice(rate_limits);
+        }
+    }
+
+    /// inherit_cors sets the route's CORS policy to its virtual host's, unless the
+    /// route has one of its own.
+    pub fn inherit_cors(&mut self, cors: Option<&CorsPolicy>) {
+        let Action::Route(action) = &mut self.action;
+        if action.cors.is_none() {
+            action.cors = cors.cloned();
+        }
+    }
+
+    /// inherit_headers adds the header mutations of an enclosing level of the route
+    /// configuration (virtual host, then route configuration).  Like Envoy, mutations
+    /// from enclosing levels are applied after, and so take precedence over, the
+    /// route's own.
+    pub fn inherit_headers(&mut self, policy: &Arc<HeaderPolicy>) {
+        if !policy.is_empty() {
+            self.header_policies.push(policy.clone());
+        }
+    }
+
+    /// inherit_filter_configs adds the per-filter configs of the route's virtual host
+    /// for filters the route doesn't override itself.
+    pub fn inherit_filter_configs(&mut self, configs: &FilterConfigs) {
+        self.per_filter_configs.inherit(configs);
+    }
+
+    /// per_filter_config returns the config override for the HTTP filter named
+    /// `name`, if the route (or its virtual host) has one.
+    pub fn per_filter_config(&self, name: &str) -> Option<&dyn PerFilterConfig> {
+        self.per_filter_configs.get(name)
+    }
+
+    /// header_policies returns the header mutations that apply to a request using this
+    /// route, in the order they should be applied.  `weighted_cluster` is the index of
+    /// the weighted cluster the request is being sent to, if the route splits traffic.
+    pub fn header_policies(
+        &self,
+        weighted_cluster: Option<usize>,
+    ) -> impl Iterator<Item = &HeaderPolicy> {
+        let Action::Route(action) = &self.action;
+        let weighted = match (&action.cluster, weighted_cluster) {
+            (ClusterSpecifier::Weighted(weighted), Some(i)) => {
+                weighted.clusters.get(i).map(|cluster| &cluster.headers)
             }
-            RouteMatch::ExactPath(path) => {
-                if uri.path() == path {
-                    return Some(&self.action);
+            _ => None,
+        };
+        weighted
+            .into_iter()
+            .chain(self.header_policies.iter().map(|policy| policy.as_ref()))
+    }
+
+    /// rewrite_request applies the route's path and Host rewrites to a request that
+    /// matched this route.  Automatic host rewriting depends on the upstream host
+    /// chosen by the cluster, so it is handled by the router instead.
+    pub fn rewrite_request(&self, req: &mut Request) {
+        let Action::Route(action) = &self.action;
+
+        // the Host rewrite from path regex is based on the downstream (un-rewritten) path.
+        let host = match &action.host_rewrite {
+            Some(HostRewrite::Literal(host)) => Some(host.clone()),
+            Some(HostRewrite::Header(name)) => req
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_owned()),
+            Some(HostRewrite::PathRegex(regex)) => Some(regex.rewrite(req.uri().path())),
+            Some(HostRewrite::Auto) | None => None,
+        };
+        if let Some(host) = host {
+            if let Ok(host) = HeaderValue::from_str(&host) {
+                req.headers_mut().insert(HOST, host);
+            }
+        }
+
+        if let Some(path) = self.rewritten_path(req.uri().path()) {
+            let original = req
+                .uri()
+                .path_and_query()
+                .map(|pq| pq.as_str().to_owned())
+                .unwrap_or_default();
+            if let Some(uri) = with_path(req.uri(), &path) {
+                *req.uri_mut() = uri;
+                if let Ok(original) = HeaderValue::from_str(&original) {
+                    req.headers_mut().insert(ORIGINAL_PATH_HEADER, original);
                 }
             }
-        };
-        None
+        }
     }
+
+    /// rewritten_path returns the path a request should be forwarded upstream with,
+    /// or None if the route doesn't rewrite paths.
+    fn rewritten_path(&self, path: &str) -> Option<String> {
+        let Action::Route(action) = &self.action;
+        if let Some(prefix_rewrite) = &action.prefix_rewrite {
+            let rest = match &self.matcher {
+                RouteMatch::Prefix(prefix) => path.get(prefix.len()..).unwrap_or_default(),
+                RouteMatch::ExactPath(_) => "",
+                RouteMatch::Connect => return None,
+            };
+            Some(format!("{}{}", prefix_rewrite, rest))
+        } else {
+            action
+                .regex_rewrite
+                .as_ref()
+                .map(|regex_rewrite| regex_rewrite.rewrite(path))
+        }
+    }
+}
+
+/// with_path returns a copy of `uri` with its path replaced, preserving the query string.
+fn with_path(uri: &Uri, path: &str) -> Option<Uri> {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_owned(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    Uri::from_parts(parts).ok()
 }
 
 impl TryFrom<V3Route> for Route {
@@ -124,13 +785,28 @@ impl TryFrom<V3Route> for Route {
     fn try_from(route: V3Route) -> Result<Self, Self::Error> {
         if let Some(matcher) = route.r#match {
             if let Some(V3Action::Route(action)) = route.action {
+                let grpc = matcher.grpc.is_some();
                 let matcher = RouteMatch::try_from(matcher)?;
                 let action = RouteAction::try_from(action)?;
-                Ok(Route {
+                let headers = HeaderPolicy::new(
+                    route.request_headers_to_add,
+                    route.request_headers_to_remove,
+                    route.response_headers_to_add,
+                    route.response_headers_to_remove,
+                )?;
+                let per_filter_configs = FilterConfigs::try_from(route.typed_per_filter_config)
+                    .map_err(Error::HttpFilter)?;
+                let mut route = Route {
                     name: route.name,
                     matcher,
+                    grpc,
                     action: Action::Route(action),
-                })
+                    per_request_buffer_limit_bytes: route.per_request_buffer_limit_bytes,
+                    header_policies: vec![],
+                    per_filter_configs,
+                };
+                route.inherit_headers(&Arc::new(headers));
+                Ok(route)
             } else {
                 Err(Error::MissingAction)
             }
@@ -139,3 +815,210 @@ impl TryFrom<V3Route> for Route {
         }
     }
 }
+
+#[test]
+fn test_rewrite_request() {
+    let route = |matcher: RouteMatch, action: RouteAction| Route {
+        name: "test".to_owned(),
+        matcher,
+        grpc: false,
+        action: Action::Route(action),
+        per_request_buffer_limit_bytes: None,
+        header_policies: vec![],
+        per_filter_configs: Default::default(),
+    };
+    let action = RouteAction {
+        cluster: ClusterSpecifier::Name("svc".to_owned()),
+        prefix_rewrite: None,
+        regex_rewrite: None,
+        host_rewrite: None,
+        timeout: Some(DEFAULT_TIMEOUT),
+        idle_timeout: None,
+        grpc_timeout: None,
+        retry_policy: None,
+        request_mirror_policies: vec![],
+        upgrade_configs: Default::default(),
+        connect_config: None,
+        internal_redirect_policy: None,
+        rate_limits: vec![],
+        include_vh_rate_limits: false,
+        cors: None,
+    };
+    let regex_rewrite = |pattern: &str, substitution: &str| RegexRewrite {
+        pattern: regex::Regex::new(pattern).unwrap(),
+        substitution: regex_substitution(substitution),
+    };
+
+    let cases: &[(Route, &str, &str, Option<&str>)] = &[
+        (
+            route(RouteMatch::Prefix("/".to_owned()), action.clone()),
+            "/svc/foo/bar?a=b",
+            "/svc/foo/bar?a=b",
+            None,
+        ),
+        (
+            route(
+                RouteMatch::Prefix("/svc/foo/".to_owned()),
+                RouteAction {
+                    prefix_rewrite: Some("/".to_owned()),
+                    ..action.clone()
+                },
+            ),
+            "/svc/foo/bar?a=b",
+            "/bar?a=b",
+            None,
+        ),
+        (
+            route(
+                RouteMatch::ExactPath("/old".to_owned()),
+                RouteAction {
+                    prefix_rewrite: Some("/new".to_owned()),
+                    host_rewrite: Some(HostRewrite::Literal("backend.internal".to_owned())),
+                    ..action.clone()
+                },
+            ),
+            "/old",
+            "/new",
+            Some("backend.internal"),
+        ),
+        (
+            route(
+                RouteMatch::Prefix("/".to_owned()),
+                RouteAction {
+                    regex_rewrite: Some(regex_rewrite(
+                        "^/service/([^/]+)(/.*)$",
+                        "\\2/instance/\\1",
+                    )),
+                    host_rewrite: Some(HostRewrite::Header("x-backend".to_owned())),
+                    ..action.clone()
+                },
+            ),
+            "/service/foo/v1/api?x=1",
+            "/v1/api/instance/foo?x=1",
+            Some("foo.backend"),
+        ),
+    ];
+
+    for (route, path, expected_path, expected_host) in cases.iter() {
+        let mut req = axum::http::Request::builder()
+            .uri(*path)
+            .header(HOST, "downstream.example")
+            .header("x-backend", "foo.backend")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        route.rewrite_request(&mut req);
+        assert_eq!(*expected_path, req.uri().to_string());
+        let host = req.headers().get(HOST).unwrap().to_str().unwrap();
+        assert_eq!(expected_host.unwrap_or("downstream.example"), host);
+    }
+}
+
+#[test]
+fn test_grpc_timeout() {
+    let secs = |secs: u64| Some(Duration::from_secs(secs));
+    let grpc_timeout = |max, offset| GrpcTimeout { max, offset };
+
+    let cases: &[(GrpcTimeout, Option<Duration>, Option<Duration>)] = &[
+        (grpc_timeout(None, None), None, None),
+        (grpc_timeout(None, None), secs(5), secs(5)),
+        (grpc_timeout(secs(2), None), secs(5), secs(2)),
+        (grpc_timeout(secs(10), None), secs(5), secs(5)),
+        (grpc_timeout(secs(10), None), None, secs(10)),
+        (grpc_timeout(secs(10), None), Some(Duration::ZERO), secs(10)),
+        (grpc_timeout(None, secs(1)), secs(5), secs(4)),
+        (grpc_timeout(None, secs(5)), secs(5), secs(5)),
+        (grpc_timeout(secs(3), secs(1)), secs(5), secs(3)),
+    ];
+
+    for (grpc_timeout, requested, expected) in cases.iter() {
+        assert_eq!(
+            *expected,
+            grpc_timeout.timeout(*requested),
+            "{:?} {:?}",
+            grpc_timeout,
+            requested
+        );
+    }
+}
+
+#[test]
+fn test_internal_redirect_policy() {
+    let route = |name: &str| Route {
+        name: name.to_owned(),
+        matcher: RouteMatch::Prefix("/".to_owned()),
+        grpc: false,
+        action: Action::Route(
+            RouteAction::try_from(V3RouteAction {
+                cluster_specifier: Some(V3ClusterSpecifier::Cluster("svc".to_owned())),
+                ..Default::default()
+            })
+            .unwrap(),
+        ),
+        per_request_buffer_limit_bytes: None,
+        header_policies: vec![],
+        per_filter_configs: Default::default(),
+    };
+    let policy = |predicates: Vec<RedirectPredicate>| InternalRedirectPolicy {
+        max_internal_redirects: 1,
+        redirect_response_codes: vec![StatusCode::FOUND],
+        allow_cross_scheme_redirect: true,
+        predicates,
+    };
+    let visited = vec!["assets".to_owned()];
+
+    let cases: &[(InternalRedirectPolicy, &str, bool, bool, bool)] = &[
+        (policy(vec![]), "assets", false, true, true),
+        (
+            policy(vec![RedirectPredicate::PreviousRoutes]),
+            "assets",
+            false,
+            false,
+            false,
+        ),
+        (
+            policy(vec![RedirectPredicate::PreviousRoutes]),
+            "blobs",
+            false,
+            false,
+            true,
+        ),
+        (
+            policy(vec![RedirectPredicate::AllowListedRoutes(vec![
+                "blobs".to_owned()
+            ])]),
+            "blobs",
+            false,
+            false,
+            true,
+        ),
+        (
+            policy(vec![RedirectPredicate::AllowListedRoutes(vec![
+                "blobs".to_owned()
+            ])]),
+            "other",
+            false,
+            false,
+            false,
+        ),
+        (
+            policy(vec![RedirectPredicate::SafeCrossScheme]),
+            "blobs",
+            true,
+            false,
+            true,
+        ),
+        (
+            policy(vec![RedirectPredicate::SafeCrossScheme]),
+            "blobs",
+            false,
+            true,
+            false,
+        ),
+    ];
+
+    for (policy, target, downstream_https, target_https, expected) in cases.iter() {
+        let actual =
+            policy.accepts_target(&route(target), &visited, *downstream_https, *target_https);
+        assert_eq!(*expected, actual, "{:?} {}", policy.predicates, target);
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::Arc;
+
+use crate::route::{Route, RouteMatch};
+use crate::Request;
+
+/// RouteTable is a virtual host's routes, compiled for fast lookup.  Prefix and
+/// exact path matchers are indexed in a radix tree, so finding the routes that
+/// could match a path takes time proportional to the length of the path rather
+/// than to the number of routes.  Like Envoy, the first route (in configuration
+/// order) that matches a request wins.
+#[derive(Debug, Default, Clone)]
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+    root: Node,
+    // indexes of the routes that don't match on the path, which are always candidates
+    unindexed: Vec<usize>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Node {
+    // indexes of the routes whose prefix is the path leading to this node
+    prefixes: Vec<usize>,
+    // indexes of the routes whose exact path is the path leading to this node
+    paths: Vec<usize>,
+    // sorted by the first byte of their label, which is unique among siblings
+    children: Vec<Edge>,
+}
+
+#[derive(Debug, Clone)]
+struct Edge {
+    label: Vec<u8>,
+    node: Node,
+}
+
+impl Node {
+    /// insert returns the node for `key`, splitting edges and creating nodes as needed.
+    fn insert(&mut self, key: &[u8]) -> &mut Node {
+        if key.is_empty() {
+            return self;
+        }
+        match self.child(key[0]) {
+            Ok(i) => {
+                let edge = &mut self.children[i];
+                let common = edge
+                    .label
+                    .iter()
+                    .zip(key.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if common < edge.label.len() {
+                    // split the edge where the key diverges from (or ends within) it
+                    let suffix = edge.label.split_off(common);
+                    let node = std::mem::take(&mut edge.node);
+                    edge.node.children.push(Edge {
+                        label: suffix,
+                        node,
+                    });
+                }
+                edge.node.insert(&key[common..])
+            }
+            Err(i) => {
+                self.children.insert(
+                    i,
+                    Edge {
+                        label: key.to_vec(),
+                        node: Node::default(),
+                    },
+                );
+                &mut self.children[i].node
+            }
+        }
+    }
+
+    fn child(&self, first: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&first, |edge| edge.label[0])
+    }
+
+    /// candidates adds the indexes of the routes that match `path` to `out`.
+    fn candidates(&self, path: &[u8], out: &mut Vec<usize>) {
+        let mut node = self;
+        let mut rest = path;
+        loop {
+            // every prefix on the way down to the end of the path matches
+            out.extend_from_slice(&node.prefixes);
+            if rest.is_empty() {
+                out.extend_from_slice(&node.paths);
+                return;
+            }
+            let edge = match node.child(rest[0]) {
+                Ok(i) => &node.children[i],
+                Err(_) => return,
+            };
+            if !rest.starts_with(&edge.label) {
+                return;
+            }
+            rest = &rest[edge.label.len()..];
+            node = &edge.node;
+        }
+    }
+}
+
+impl RouteTable {
+    pub fn new(routes: Vec<Arc<Route>>) -> Self {
+        let mut root = Node::default();
+        let mut unindexed = vec![];
+        for (i, route) in routes.iter().enumerate() {
+            match route.matcher() {
+                RouteMatch::Prefix(prefix) => root.insert(prefix.as_bytes()).prefixes.push(i),
+                RouteMatch::ExactPath(path) => root.insert(path.as_bytes()).paths.push(i),
+                RouteMatch::Connect => unindexed.push(i),
+            }
+        }
+        RouteTable {
+            routes,
+            root,
+            unindexed,
+        }
+    }
+
+    /// find returns the first route that matches a request.
+    pub fn find(&self, req: &Request) -> Option<&Arc<Route>> {
+        let mut candidates = self.unindexed.clone();
+        self.root
+            .candidates(req.uri().path().as_bytes(), &mut candidates);
+        // the lowest index is the first match in configuration order.  Candidates
+        // are still checked against the route's own matcher, which is cheap and
+        // keeps the table honest as matchers grow conditions beyond the path.
+        candidates.sort_unstable();
+        candidates
+            .into_iter()
+            .map(|i| &self.routes[i])
+            .find(|route| route.matches(req).is_some())
+    }
+}
+
+#[test]
+fn test_route_table() {
+    use envoy_control_plane::envoy::config::route::v3::{
+        route::Action as V3Action, route_action::ClusterSpecifier as V3ClusterSpecifier,
+        route_match::PathSpecifier as V3PathSpecifier, Route as V3Route,
+        RouteAction as V3RouteAction, RouteMatch as V3RouteMatch,
+    };
+
+    let route = |name: &str, path_specifier: V3PathSpecifier| {
+        let route = V3Route {
+            name: name.to_owned(),
+            r#match: Some(V3RouteMatch {
+                path_specifier: Some(path_specifier),
+                ..Default::default()
+            }),
+            action: Some(V3Action::Route(V3RouteAction {
+                cluster_specifier: Some(V3ClusterSpecifier::Cluster("svc".to_owned())),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        Arc::new(Route::try_from(route).unwrap())
+    };
+    let prefix = |name: &str, prefix: &str| route(name, V3PathSpecifier::Prefix(prefix.to_owned()));
+    let path = |name: &str, path: &str| route(name, V3PathSpecifier::Path(path.to_owned()));
+    let request = |method: &str, uri: &str| {
+        axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    };
+
+    let routes = vec![
+        path("exact-api", "/api"),
+        prefix("api-v1", "/api/v1/"),
+        prefix("api-v1-users", "/api/v1/users"),
+        prefix("apple", "/apple"),
+        path("exact-api-v1-users", "/api/v1/users"),
+        prefix("api", "/api"),
+        prefix("empty", ""),
+        prefix("root", "/"),
+        route(
+            "connect",
+            V3PathSpecifier::ConnectMatcher(Default::default()),
+        ),
+    ];
+    let table = RouteTable::new(routes.clone());
+
+    let cases: &[(&str, &str, &str)] = &[
+        ("GET", "/api", "exact-api"),
+        ("GET", "/api?x=1", "exact-api"),
+        ("GET", "/api/v1/users", "api-v1"),
+        ("GET", "/api/v1", "api"),
+        ("GET", "/api/v2/users", "api"),
+        ("GET", "/apples", "apple"),
+        ("GET", "/ap", "empty"),
+        ("GET", "/", "empty"),
+        ("GET", "/other", "empty"),
+        ("CONNECT", "example.com:443", "connect"),
+    ];
+
+    for (method, uri, expected) in cases.iter() {
+        let req = request(method, uri);
+        let actual = table.find(&req).map(|route| route.name.as_str());
+        assert_eq!(Some(*expected), actual, "{} {}", method, uri);
+        // the table must agree with checking each route in order
+        let linear = routes.iter().find(|route| route.matches(&req).is_some());
+        assert_eq!(linear, table.find(&req), "{} {}", method, uri);
+    }
+
+    // without the catch-all routes, some requests don't match anything
+    let table = RouteTable::new(routes[..6].to_vec());
+    for uri in ["/", "/ap", "/other", "/apx", "example.com:443"] {
+        let method = if uri.starts_with('/') {
+            "GET"
+        } else {
+            "CONNECT"
+        };
+        assert_eq!(None, table.find(&request(method, uri)), "{}", uri);
+    }
+}
@@ -0,0 +1,1000 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::http::header::{CONTENT_LENGTH, HOST, LOCATION};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, Version};
+use hyper::upgrade::OnUpgrade;
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tower::Service;
+
+use crate::body::{self, Buffered};
+use crate::cluster::{self, Cluster, Clusters, Host, UpstreamFailure};
+use crate::extensions::filter::network::http_connection_manager::{HttpConnectionManager, Routed};
+use crate::grpc;
+use crate::headers::FormatContext;
+use crate::listener::ConnectionInfo;
+use crate::local_reply::{local_reply, LocalReply, ResponseFlag};
+use crate::retry::{Outcome, RetryState};
+use crate::route::{Action, HostRewrite, Route, RouteAction, DEFAULT_TIMEOUT};
+use crate::upgrade;
+use crate::{Request, Response};
+
+/// DEFAULT_PER_REQUEST_BUFFER_LIMIT is how much of a request body we buffer for
+/// retries if the route doesn't say, matching Envoy's default connection buffer limit.
+const DEFAULT_PER_REQUEST_BUFFER_LIMIT: usize = 1024 * 1024;
+
+/// UPSTREAM_RQ_TIMEOUT_HEADER lets trusted callers override the route timeout.
+const UPSTREAM_RQ_TIMEOUT_HEADER: &str = "x-envoy-upstream-rq-timeout-ms";
+/// UPSTREAM_RQ_PER_TRY_TIMEOUT_HEADER lets trusted callers override the per-try timeout.
+const UPSTREAM_RQ_PER_TRY_TIMEOUT_HEADER: &str = "x-envoy-upstream-rq-per-try-timeout-ms";
+/// EXPECTED_RQ_TIMEOUT_HEADER tells the upstream how long we will wait for it.
+const EXPECTED_RQ_TIMEOUT_HEADER: &str = "x-envoy-expected-rq-timeout-ms";
+/// ORIGINAL_URL_HEADER records the downstream URL of a request that followed an
+/// internal redirect, like Envoy does.
+const ORIGINAL_URL_HEADER: &str = "x-envoy-original-url";
+
+/// Timeouts are the effective timeouts for a single downstream request.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Timeouts {
+    global: Option<Duration>,
+    per_try: Option<Duration>,
+    idle: Option<Duration>,
+}
+
+impl Timeouts {
+    /// new computes the timeouts for a request from its route (or, for gRPC
+    /// requests on routes that allow it, the grpc-timeout header) and, if the
+    /// caller is trusted, the `x-envoy-upstream-rq-*` override headers.
+    /// Override headers are always consumed so they aren't forwarded upstream.
+    fn new(action: &RouteAction, headers: &mut HeaderMap, trusted: bool) -> Self {
+        let mut global = action.timeout;
+        if let Some(grpc_timeout) = &action.grpc_timeout {
+            if grpc::is_grpc(headers) {
+                let requested = headers
+                    .get(grpc::TIMEOUT_HEADER)
+                    .and_then(grpc::parse_timeout);
+                global = grpc_timeout.timeout(requested);
+            }
+        }
+        let mut per_try = action
+            .retry_policy
+            .as_ref()
+            .and_then(|policy| policy.per_try_timeout);
+
+        if trusted {
+            if let Some(timeout) = header_millis(headers, UPSTREAM_RQ_TIMEOUT_HEADER) {
+                global = timeout;
+            }
+            if let Some(timeout) = header_millis(headers, UPSTREAM_RQ_PER_TRY_TIMEOUT_HEADER) {
+                per_try = timeout;
+            }
+        }
+        headers.remove(UPSTREAM_RQ_TIMEOUT_HEADER);
+        headers.remove(UPSTREAM_RQ_PER_TRY_TIMEOUT_HEADER);
+
+        // a per-try timeout at least as long as the global timeout is meaningless
+        if let (Some(global), Some(try_timeout)) = (global, per_try) {
+            if try_timeout >= global {
+                per_try = None;
+            }
+        }
+
+        Timeouts {
+            global,
+            per_try,
+            idle: action.idle_timeout,
+        }
+    }
+}
+
+/// header_millis parses a timeout header in milliseconds.  The outer Option is
+/// None if the header is absent or invalid; the inner is None if the timeout is
+/// disabled (0).
+fn header_millis(headers: &HeaderMap, name: &str) -> Option<Option<Duration>> {
+    let millis: u64 = headers.get(name)?.to_str().ok()?.trim().parse().ok()?;
+    if millis == 0 {
+        Some(None)
+    } else {
+        Some(Some(Duration::from_millis(millis)))
+    }
+}
+
+/// earliest returns the sooner of two optional deadlines.
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// choose_host picks an upstream host for the next attempt, consulting the
+/// retry host predicate.
+fn choose_host(cluster: &Cluster, retry_state: &RetryState) -> Option<Arc<Host>> {
+    let mut host = cluster.choose_host()?;
+    for _ in 0..retry_state.host_reselection_attempts() {
+        if retry_state.accept_host(&host.address) {
+            break;
+        }
+        host = cluster.choose_host()?;
+    }
+    Some(host)
+}
+
+/// MAX_MIRRORS_IN_FLIGHT bounds how many mirrored requests can be outstanding at
+/// once, so a slow shadow cluster can't pile up tasks and connections.
+const MAX_MIRRORS_IN_FLIGHT: u64 = 1024;
+
+/// MIRRORS_IN_FLIGHT counts the mirrored requests that haven't finished yet.
+static MIRRORS_IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+
+/// InFlightMirror counts a mirrored request in MIRRORS_IN_FLIGHT until it is dropped.
+#[derive(Debug)]
+struct InFlightMirror;
+
+impl InFlightMirror {
+    /// start counts a new mirrored request, unless MAX_MIRRORS_IN_FLIGHT already are.
+    fn start() -> Option<InFlightMirror> {
+        let in_flight = MIRRORS_IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        if in_flight >= MAX_MIRRORS_IN_FLIGHT {
+            MIRRORS_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(InFlightMirror)
+    }
+}
+
+impl Drop for InFlightMirror {
+    fn drop(&mut self) {
+        MIRRORS_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// shadow_host appends "-shadow" to the host part of an authority, the way Envoy
+/// marks mirrored requests (e.g. "foo:8080" becomes "foo-shadow:8080").  IPv6
+/// literals can't be suffixed, so they are left alone.
+fn shadow_host(authority: &str) -> String {
+    if authority.starts_with('[') {
+        return authority.to_owned();
+    }
+    if let Some((host, port)) = authority.rsplit_once(':') {
+        if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) {
+            return format!("{}-shadow:{}", host, port);
+        }
+    }
+    format!("{}-shadow", authority)
+}
+
+/// mirror sends a copy of a request to a shadow cluster in the background, giving
+/// up on it at `deadline`.  The shadow response is discarded, and failures are
+/// ignored.
+fn mirror(
+    shadow: &Cluster,
+    in_flight: InFlightMirror,
+    parts: &Parts,
+    body: Body,
+    deadline: Instant,
+) {
+    let mut req = Request::new(body);
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+
+    let authority = parts
+        .headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| parts.uri.authority().map(|authority| authority.as_str()));
+    if let Some(authority) = authority {
+        if let Ok(host) = HeaderValue::from_str(&shadow_host(authority)) {
+            req.headers_mut().insert(HOST, host);
+        }
+    }
+
+    let mut shadow = shadow.clone();
+    tokio::spawn(async move {
+        let _in_flight = in_flight;
+        let _ = tokio::time::timeout_at(deadline, shadow.call(req)).await;
+    });
+}
+
+/// spawn_tunnel connects the downstream and upstream connections of an upgrade
+/// the upstream accepted (with `resp`), once hyper hands them over to us.
+fn spawn_tunnel(downstream: OnUpgrade, resp: &mut Response, idle_timeout: Option<Duration>) {
+    let upstream = hyper::upgrade::on(resp);
+    tokio::spawn(async move {
+        if let Ok((downstream, upstream)) = tokio::try_join!(downstream, upstream) {
+            let _ = upgrade::tunnel(downstream, upstream, idle_timeout).await;
+        }
+    });
+}
+
+/// terminate_connect handles a CONNECT request (or, if the route allows it, a
+/// POST) itself rather than forwarding it: it opens a TCP connection to an
+/// upstream host and tunnels the downstream's payload over it.
+async fn terminate_connect(
+    cluster: &Cluster,
+    req: Request,
+    downstream_upgrade: Option<OnUpgrade>,
+    timeouts: &Timeouts,
+) -> (Response, Option<Arc<Host>>) {
+    let host = match cluster.choose_host() {
+        Some(host) => host,
+        None => {
+            return (
+                local_reply(
+                    503,
+                    Some(ResponseFlag::NoHealthyUpstream),
+                    "no healthy upstream",
+                ),
+                None,
+            )
+        }
+    };
+    let deadline = timeouts.global.map(|timeout| Instant::now() + timeout);
+    let upstream = match body::within(None, deadline, TcpStream::connect(host.address)).await {
+        Some(Ok(upstream)) => upstream,
+        Some(Err(err)) => {
+            let resp = cluster::upstream_error(UpstreamFailure::ConnectFailure, &err);
+            return (resp, Some(host));
+        }
+        None => {
+            let resp = local_reply(
+                504,
+                Some(ResponseFlag::UpstreamRequestTimeout),
+                "upstream request timeout",
+            );
+            return (resp, Some(host));
+        }
+    };
+
+    let idle = timeouts.idle;
+    let resp = match downstream_upgrade {
+        // once we respond to the CONNECT, hyper hands us the downstream connection
+        Some(downstream) => {
+            tokio::spawn(async move {
+                if let Ok(downstream) = downstream.await {
+                    let _ = upgrade::tunnel(downstream, upstream, idle).await;
+                }
+            });
+            Response::new(Body::empty())
+        }
+        None => {
+            let resp_body = upgrade::tunnel_body(req.into_body(), upstream);
+            Response::new(body::with_timeouts(resp_body, idle, None))
+        }
+    };
+    (resp, Some(host))
+}
+
+/// Replayable is a downstream request with a fully buffered body, which can be
+/// sent more than once.
+#[derive(Debug, Clone)]
+struct Replayable {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    connection: Option<ConnectionInfo>,
+    body: Bytes,
+    trailers: Option<HeaderMap>,
+}
+
+impl Replayable {
+    fn request(&self) -> Request {
+        let mut req = Request::new(body::replay(self.body.clone(), self.trailers.clone()));
+        *req.method_mut() = self.method.clone();
+        *req.uri_mut() = self.uri.clone();
+        *req.version_mut() = self.version;
+        *req.headers_mut() = self.headers.clone();
+        if let Some(conn) = self.connection {
+            req.extensions_mut().insert(conn);
+        }
+        req
+    }
+
+    /// url returns the request's full URL, like "http://example.com/path?query".
+    fn url(&self) -> Option<String> {
+        let authority = match self.uri.authority() {
+            Some(authority) => authority.as_str(),
+            None => self.headers.get(HOST)?.to_str().ok()?,
+        };
+        let path_and_query = self
+            .uri
+            .path_and_query()
+            .map(|pq| pq.as_str())
+            .unwrap_or("/");
+        Some(format!(
+            "{}://{}{}",
+            self.scheme(),
+            authority,
+            path_and_query
+        ))
+    }
+
+    /// scheme is the scheme the downstream used.  It comes from the connection:
+    /// HTTP/1 requests don't carry one, and an HTTP/2 `:scheme` is only a claim.
+    fn scheme(&self) -> &'static str {
+        self.connection
+            .map_or("http", |conn| conn.scheme(&self.headers))
+    }
+
+    /// redirect turns the request into one for `location`, the absolute URL an
+    /// upstream redirected it to with `status`.
+    fn redirect(&mut self, status: StatusCode, location: &Uri) -> Option<()> {
+        let authority = HeaderValue::from_str(location.authority()?.as_str()).ok()?;
+        if !self.headers.contains_key(ORIGINAL_URL_HEADER) {
+            if let Some(url) = self.url().and_then(|url| HeaderValue::from_str(&url).ok()) {
+                self.headers.insert(ORIGINAL_URL_HEADER, url);
+            }
+        }
+        // requests that arrived with an absolute URI (like HTTP/2 ones) keep one
+        self.uri = if self.uri.authority().is_some() {
+            location.clone()
+        } else {
+            let path_and_query = location
+                .path_and_query()
+                .map(|pq| pq.as_str())
+                .unwrap_or("/");
+            path_and_query.parse().ok()?
+        };
+        self.headers.insert(HOST, authority);
+        // like a browser, a 303 is followed with a GET
+        if status == StatusCode::SEE_OTHER && self.method != Method::HEAD {
+            self.method = Method::GET;
+            self.body = Bytes::new();
+            self.trailers = None;
+            self.headers.remove(CONTENT_LENGTH);
+        }
+        Some(())
+    }
+}
+
+/// route is the router filter, which ends every HTTP filter chain: it forwards a
+/// request to the cluster it was routed to, or replies with a 404 if it wasn't
+/// routed anywhere (or a 403 if its route doesn't allow its `upgrade_type`).
+pub async fn route(
+    http_conn_mgr: &HttpConnectionManager,
+    routed: Option<Routed>,
+    upgrade_type: Option<String>,
+    req: Request,
+) -> Response {
+    let routed = match routed {
+        Some(routed) => routed,
+        None => {
+            return local_reply(
+                404,
+                Some(ResponseFlag::NoRouteFound),
+                "routing to upstream cluster failed",
+            )
+        }
+    };
+    match upgrade_type {
+        Some(upgrade_type) if !http_conn_mgr.allows_upgrade(&routed.route, &upgrade_type) => {
+            local_reply(403, None, "upgrade failed")
+        }
+        _ => forward_with_redirects(http_conn_mgr, routed, req).await,
+    }
+}
+
+/// forward_with_redirects forwards a routed request like forward, but when the
+/// upstream responds with a redirect the route's internal redirect policy allows,
+/// it follows the redirect itself: the request is routed again (which may pick a
+/// different route and cluster) and sent to the redirect's target, and only the
+/// final response is returned to the downstream.
+pub async fn forward_with_redirects(
+    http_conn_mgr: &HttpConnectionManager,
+    mut routed: Routed,
+    req: Request,
+) -> Response {
+    let clusters = http_conn_mgr.clusters();
+    let Action::Route(action) = routed.route.action();
+    // upgraded connections can't be replayed
+    let upgrade = req.method() == Method::CONNECT || req.extensions().get::<OnUpgrade>().is_some();
+    if action.internal_redirect_policy.is_none() || upgrade {
+        return forward(clusters, routed, req).await;
+    }
+
+    let limit = routed
+        .route
+        .per_request_buffer_limit_bytes
+        .map(|limit| limit as usize)
+        .unwrap_or(DEFAULT_PER_REQUEST_BUFFER_LIMIT);
+    let (parts, req_body) = req.into_parts();
+    let (body, trailers) = match body::buffer(req_body, limit).await {
+        Ok(Buffered::Complete(data, trailers)) => (data, trailers),
+        // requests whose bodies are too big to buffer can't be redirected
+        Ok(Buffered::Streaming(req_body)) => {
+            return forward(clusters, routed, Request::from_parts(parts, req_body)).await
+        }
+        Err(_) => {
+            return local_reply(
+                400,
+                Some(ResponseFlag::DownstreamProtocolError),
+                "error reading request body",
+            )
+        }
+    };
+    let mut replayable = Replayable {
+        method: parts.method,
+        uri: parts.uri,
+        version: parts.version,
+        headers: parts.headers,
+        connection: parts.extensions.get::<ConnectionInfo>().copied(),
+        body,
+        trailers,
+    };
+    let downstream_https = replayable.scheme() == "https";
+
+    let mut visited = vec![routed.route.name.clone()];
+    loop {
+        let resp = forward(clusters, routed.clone(), replayable.request()).await;
+
+        let Action::Route(action) = routed.route.action();
+        let policy = match &action.internal_redirect_policy {
+            Some(policy) => policy,
+            None => return resp,
+        };
+        let redirects = visited.len() as u32 - 1;
+        if !policy.redirect_response_codes.contains(&resp.status())
+            || redirects >= policy.max_internal_redirects
+            || resp.extensions().get::<LocalReply>().is_some()
+        {
+            return resp;
+        }
+        // only absolute URLs can be followed
+        let location = resp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| location.parse::<Uri>().ok());
+        let location = match location {
+            Some(location) if location.authority().is_some() => location,
+            _ => return resp,
+        };
+        let target_https = match location.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return resp,
+        };
+        if !policy.allow_cross_scheme_redirect && downstream_https != target_https {
+            return resp;
+        }
+
+        let mut redirected = replayable.clone();
+        if redirected.redirect(resp.status(), &location).is_none() {
+            return resp;
+        }
+        let next = match http_conn_mgr.get_cluster(&redirected.request()) {
+            Some(next) => next,
+            None => return resp,
+        };
+        if !policy.accepts_target(&next.route, &visited, downstream_https, target_https) {
+            return resp;
+        }
+
+        // the redirect response (and its body) is discarded
+        visited.push(next.route.name.clone());
+        routed = next;
+        replayable = redirected;
+    }
+}
+
+/// forward sends a routed request to an upstream host in its cluster, applying
+/// the route's request transformations on the way out, enforcing the route's
+/// timeouts and retrying failed attempts according to its retry policy.  A copy of
+/// the request may also be mirrored to shadow clusters from `clusters`.  The
+/// route's response header mutations are applied to whatever response we end up with.
+pub async fn forward(clusters: &Clusters, routed: Routed, req: Request) -> Response {
+    let Routed {
+        cluster,
+        route,
+        weighted_cluster,
+    } = routed;
+
+    let mutates_response = route
+        .header_policies(weighted_cluster)
+        .any(|policy| !policy.response.is_empty());
+    if !mutates_response {
+        return proxy(clusters, &cluster, &route, weighted_cluster, req)
+            .await
+            .0;
+    }
+
+    // response header formatters can refer to the downstream request
+    let conn = req.extensions().get::<ConnectionInfo>().copied();
+    let version = req.version();
+    let request_headers = req.headers().clone();
+
+    let (mut resp, host) = proxy(clusters, &cluster, &route, weighted_cluster, req).await;
+    let ctx = FormatContext {
+        connection: conn.as_ref(),
+        upstream_host: host.as_deref(),
+        request_headers: Some(&request_headers),
+        version: Some(version),
+        response_code: Some(resp.status()),
+        ..Default::default()
+    };
+    for policy in route.header_policies(weighted_cluster) {
+        policy.response.apply(resp.headers_mut(), &ctx);
+    }
+    resp
+}
+
+/// proxy does the work of forward, also returning the upstream host the
+/// response came from, if any.
+async fn proxy(
+    clusters: &Clusters,
+    cluster: &Cluster,
+    route: &Route,
+    weighted_cluster: Option<usize>,
+    mut req: Request,
+) -> (Response, Option<Arc<Host>>) {
+    route.rewrite_request(&mut req);
+
+    // the HttpConnectionManager has already checked that the upgrade is allowed
+    let method = req.method().clone();
+    let connect = method == Method::CONNECT;
+    let mut downstream_upgrade = match upgrade::upgrade_type(&method, req.headers_mut()) {
+        Some(_) => Some(hyper::upgrade::on(&mut req)),
+        None => None,
+    };
+
+    let Action::Route(action) = route.action();
+    let trusted = req
+        .extensions()
+        .get::<ConnectionInfo>()
+        .map(|conn| conn.is_internal())
+        .unwrap_or(false);
+    let timeouts = Timeouts::new(action, req.headers_mut(), trusted);
+    // on routes that derive timeouts from grpc-timeout, the upstream is told how
+    // long we will actually wait with an updated grpc-timeout.
+    let update_grpc_timeout = action.grpc_timeout.is_some() && grpc::is_grpc(req.headers());
+    let mut retry_state = RetryState::new(action.retry_policy.as_ref(), req.headers_mut(), trusted);
+
+    if let Some(connect_config) = action.connect_config {
+        if connect || (connect_config.allow_post && method == Method::POST) {
+            return terminate_connect(cluster, req, downstream_upgrade, &timeouts).await;
+        }
+    }
+
+    let mirrors: Vec<(Arc<Cluster>, InFlightMirror)> = {
+        let clusters = clusters.load();
+        action
+            .request_mirror_policies
+            .iter()
+            .filter(|policy| policy.fraction.sample())
+            .filter_map(|policy| clusters.get(&policy.cluster).cloned())
+            .map_while(|shadow| Some((shadow, InFlightMirror::start()?)))
+            .collect()
+    };
+
+    let limit = route
+        .per_request_buffer_limit_bytes
+        .map(|limit| limit as usize)
+        .unwrap_or(DEFAULT_PER_REQUEST_BUFFER_LIMIT);
+    let (parts, req_body) = req.into_parts();
+    // mirrors get copies of the body as it streams, so the request never waits on
+    // them; they are bounded by the route timeout like the request itself.
+    let (req_body, copies) = body::tee(req_body, mirrors.len(), limit);
+    let mirror_deadline = Instant::now() + timeouts.global.unwrap_or(DEFAULT_TIMEOUT);
+    for ((shadow, in_flight), copy) in mirrors.into_iter().zip(copies) {
+        mirror(&shadow, in_flight, &parts, copy, mirror_deadline);
+    }
+
+    // bodies are buffered so they can be replayed, but only if we might retry.
+    let mut req_body = if retry_state.enabled() {
+        match body::buffer(req_body, limit).await {
+            Ok(buffered) => buffered,
+            Err(_) => {
+                return (
+                    local_reply(
+                        400,
+                        Some(ResponseFlag::DownstreamProtocolError),
+                        "error reading request body",
+                    ),
+                    None,
+                )
+            }
+        }
+    } else {
+        Buffered::Streaming(req_body)
+    };
+
+    let deadline = timeouts.global.map(|timeout| Instant::now() + timeout);
+    loop {
+        let now = Instant::now();
+        let try_deadline = earliest(deadline, timeouts.per_try.map(|timeout| now + timeout));
+
+        let host = match choose_host(cluster, &retry_state) {
+            Some(host) => host,
+            None => {
+                return (
+                    local_reply(
+                        503,
+                        Some(ResponseFlag::NoHealthyUpstream),
+                        "no healthy upstream",
+                    ),
+                    None,
+                )
+            }
+        };
+        retry_state.record_attempt(host.address);
+
+        // a streaming body can only be sent once, so it can't be retried
+        let replayable = matches!(req_body, Buffered::Complete(..));
+        let attempt_body = match &mut req_body {
+            Buffered::Complete(data, trailers) => body::replay(data.clone(), trailers.clone()),
+            Buffered::Streaming(body) => std::mem::take(body),
+        };
+        let mut attempt = Request::new(attempt_body);
+        *attempt.method_mut() = parts.method.clone();
+        *attempt.uri_mut() = parts.uri.clone();
+        *attempt.version_mut() = parts.version;
+        *attempt.headers_mut() = parts.headers.clone();
+
+        if let Some(try_deadline) = try_deadline {
+            let expected_ms = try_deadline.saturating_duration_since(now).as_millis();
+            attempt.headers_mut().insert(
+                EXPECTED_RQ_TIMEOUT_HEADER,
+                HeaderValue::from(expected_ms as u64),
+            );
+            if update_grpc_timeout {
+                let expected = try_deadline.saturating_duration_since(now);
+                if let Ok(timeout) = HeaderValue::from_str(&grpc::format_timeout(expected)) {
+                    attempt.headers_mut().insert(grpc::TIMEOUT_HEADER, timeout);
+                }
+            }
+        }
+        if let Some(HostRewrite::Auto) = action.host_rewrite {
+            if let Ok(hostname) = HeaderValue::from_str(&host.hostname()) {
+                attempt.headers_mut().insert(HOST, hostname);
+            }
+        }
+        let ctx = FormatContext {
+            connection: parts.extensions.get::<ConnectionInfo>(),
+            upstream_host: Some(&host),
+            request_headers: Some(&parts.headers),
+            version: Some(parts.version),
+            ..Default::default()
+        };
+        for policy in route.header_policies(weighted_cluster) {
+            policy.request.apply(attempt.headers_mut(), &ctx);
+        }
+
+        let send = async {
+            if connect {
+                cluster.send_connect(&host, attempt).await
+            } else {
+                cluster.send(&host, attempt).await
+            }
+        };
+        let outcome = match body::within(timeouts.idle, try_deadline, send).await {
+            Some(resp) => Outcome::Response(resp),
+            None => Outcome::Timeout,
+        };
+
+        if replayable && retry_state.should_retry(&outcome) {
+            let backoff = retry_state.backoff();
+            // don't start a retry that the route timeout won't let finish
+            if deadline
+                .map(|deadline| Instant::now() + backoff < deadline)
+                .unwrap_or(true)
+            {
+                tokio::time::sleep(backoff).await;
+                continue;
+            }
+        }
+
+        let resp = match outcome {
+            Outcome::Response(mut resp)
+                if resp.status() == StatusCode::SWITCHING_PROTOCOLS
+                    || (connect && resp.status().is_success()) =>
+            {
+                // once upgraded, only the idle timeout applies to the connection
+                if let Some(downstream) = downstream_upgrade.take() {
+                    spawn_tunnel(downstream, &mut resp, timeouts.idle);
+                }
+                resp
+            }
+            Outcome::Response(resp) => {
+                // the route timeout (and idle timeout) continue to apply while the
+                // response body streams back to the downstream.
+                let (parts, resp_body) = resp.into_parts();
+                let resp_body = body::with_timeouts(resp_body, timeouts.idle, deadline);
+                Response::from_parts(parts, resp_body)
+            }
+            Outcome::Timeout => local_reply(
+                504,
+                Some(ResponseFlag::UpstreamRequestTimeout),
+                "upstream request timeout",
+            ),
+        };
+        return (resp, Some(host));
+    }
+}
+
+#[test]
+fn test_timeouts() {
+    use crate::retry::RetryPolicy;
+    use crate::route::{ClusterSpecifier, GrpcTimeout};
+
+    let action = RouteAction {
+        cluster: ClusterSpecifier::Name("svc".to_owned()),
+        prefix_rewrite: None,
+        regex_rewrite: None,
+        host_rewrite: None,
+        timeout: Some(DEFAULT_TIMEOUT),
+        idle_timeout: None,
+        grpc_timeout: None,
+        retry_policy: Some(RetryPolicy {
+            per_try_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        }),
+        request_mirror_policies: vec![],
+        upgrade_configs: Default::default(),
+        connect_config: None,
+        internal_redirect_policy: None,
+        rate_limits: vec![],
+        include_vh_rate_limits: false,
+        cors: None,
+    };
+
+    let cases: &[(&[(&str, &str)], bool, Timeouts)] = &[
+        (
+            &[],
+            false,
+            Timeouts {
+                global: Some(DEFAULT_TIMEOUT),
+                per_try: Some(Duration::from_secs(1)),
+                idle: None,
+            },
+        ),
+        (
+            &[(UPSTREAM_RQ_TIMEOUT_HEADER, "100")],
+            false,
+            Timeouts {
+                global: Some(DEFAULT_TIMEOUT),
+                per_try: Some(Duration::from_secs(1)),
+                idle: None,
+            },
+        ),
+        (
+            &[(UPSTREAM_RQ_TIMEOUT_HEADER, "100")],
+            true,
+            Timeouts {
+                global: Some(Duration::from_millis(100)),
+                per_try: None,
+                idle: None,
+            },
+        ),
+        (
+            &[
+                (UPSTREAM_RQ_TIMEOUT_HEADER, "0"),
+                (UPSTREAM_RQ_PER_TRY_TIMEOUT_HEADER, "250"),
+            ],
+            true,
+            Timeouts {
+                global: None,
+                per_try: Some(Duration::from_millis(250)),
+                idle: None,
+            },
+        ),
+        (
+            &[(UPSTREAM_RQ_TIMEOUT_HEADER, "soon")],
+            true,
+            Timeouts {
+                global: Some(DEFAULT_TIMEOUT),
+                per_try: Some(Duration::from_secs(1)),
+                idle: None,
+            },
+        ),
+    ];
+
+    for (headers, trusted, expected) in cases.iter() {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            header_map.insert(*name, HeaderValue::from_static(*value));
+        }
+        let actual = Timeouts::new(&action, &mut header_map, *trusted);
+        assert_eq!(*expected, actual);
+        assert!(!header_map.contains_key(UPSTREAM_RQ_TIMEOUT_HEADER));
+    }
+
+    // gRPC requests use their own deadline, on routes that allow it
+    let action = RouteAction {
+        grpc_timeout: Some(GrpcTimeout {
+            max: Some(Duration::from_secs(30)),
+            offset: None,
+        }),
+        ..action
+    };
+    let cases: &[(&str, Option<&str>, Option<Duration>)] = &[
+        (
+            "application/grpc",
+            Some("500m"),
+            Some(Duration::from_millis(500)),
+        ),
+        (
+            "application/grpc+proto",
+            Some("1H"),
+            Some(Duration::from_secs(30)),
+        ),
+        ("application/grpc", None, Some(Duration::from_secs(30))),
+        ("application/json", Some("500m"), Some(DEFAULT_TIMEOUT)),
+    ];
+    for (content_type, grpc_timeout, expected) in cases.iter() {
+        let mut header_map = HeaderMap::new();
+        header_map.insert("content-type", HeaderValue::from_static(*content_type));
+        if let Some(grpc_timeout) = grpc_timeout {
+            header_map.insert(
+                grpc::TIMEOUT_HEADER,
+                HeaderValue::from_static(*grpc_timeout),
+            );
+        }
+        let actual = Timeouts::new(&action, &mut header_map, false);
+        assert_eq!(
+            *expected, actual.global,
+            "{} {:?}",
+            content_type, grpc_timeout
+        );
+    }
+}
+
+#[test]
+fn test_shadow_host() {
+    let cases: &[(&str, &str)] = &[
+        ("foo", "foo-shadow"),
+        ("foo.example.com:8080", "foo.example.com-shadow:8080"),
+        ("10.0.0.1:80", "10.0.0.1-shadow:80"),
+        ("[::1]:80", "[::1]:80"),
+        ("[::1]", "[::1]"),
+    ];
+
+    for (authority, expected) in cases.iter() {
+        assert_eq!(*expected, shadow_host(authority));
+    }
+}
+
+#[tokio::test]
+async fn test_forward_with_redirects() {
+    use axum::routing::any;
+    use envoy_control_plane::envoy::config::route::v3::{
+        route::Action as V3Action, InternalRedirectPolicy as V3InternalRedirectPolicy,
+    };
+
+    use crate::testing::{test_clusters, test_http_conn_mgr, test_route, TestHttpServer};
+
+    // the upstream's redirects lead back to itself (only the Host header of the
+    // redirected request changes), and /echo describes the request it got.
+    let redirect = |status: StatusCode, location: &'static str| {
+        any(move || async move {
+            axum::http::Response::builder()
+                .status(status)
+                .header(LOCATION, location)
+                .body(Body::empty())
+                .unwrap()
+        })
+    };
+    let echo = |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| async move {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("-")
+                .to_owned()
+        };
+        format!(
+            "{} {}{} {} {}",
+            method,
+            header(HOST.as_str()),
+            uri.path(),
+            header(ORIGINAL_URL_HEADER),
+            String::from_utf8_lossy(&body)
+        )
+    };
+    let upstream = TestHttpServer::with_router(
+        axum::Router::new()
+            .route("/echo", any(echo))
+            .route(
+                "/found",
+                redirect(StatusCode::FOUND, "http://upstream.test/echo"),
+            )
+            .route(
+                "/see-other",
+                redirect(StatusCode::SEE_OTHER, "http://upstream.test/echo"),
+            )
+            .route(
+                "/loop",
+                redirect(StatusCode::FOUND, "http://upstream.test/loop"),
+            )
+            .route(
+                "/secure",
+                redirect(StatusCode::FOUND, "https://upstream.test/echo"),
+            ),
+    );
+
+    let mut route = test_route("redirects", "/", "upstream");
+    route.per_request_buffer_limit_bytes = Some(8);
+    if let Some(V3Action::Route(action)) = &mut route.action {
+        action.internal_redirect_policy = Some(V3InternalRedirectPolicy {
+            max_internal_redirects: Some(1),
+            redirect_response_codes: vec![302, 303],
+            ..Default::default()
+        });
+    }
+    let http_conn_mgr = test_http_conn_mgr(vec![route], test_clusters("upstream", upstream.addr));
+
+    // (path, request body, the downstream that says it used https, and the
+    // status and body of the response)
+    let cases: &[(&str, &str, Option<[u8; 4]>, (u16, &str))] = &[
+        (
+            "/found",
+            "hello",
+            None,
+            (
+                200,
+                "POST upstream.test/echo http://example.com/found hello",
+            ),
+        ),
+        // a 303 is followed with a GET, without the body
+        (
+            "/see-other",
+            "hello",
+            None,
+            (200, "GET upstream.test/echo http://example.com/see-other "),
+        ),
+        // bodies over the route's buffer limit can't be replayed
+        ("/found", "too big to buffer", None, (302, "")),
+        // only max_internal_redirects redirects are followed
+        ("/loop", "", None, (302, "")),
+        // https targets need an https downstream...
+        ("/secure", "", None, (302, "")),
+        (
+            "/secure",
+            "",
+            Some([127, 0, 0, 1]),
+            (200, "POST upstream.test/echo https://example.com/secure "),
+        ),
+        // ...which untrusted downstreams can't claim to be
+        ("/secure", "", Some([203, 0, 113, 1]), (302, "")),
+    ];
+
+    for (path, body, https_from, (status, expected)) in cases.iter() {
+        let mut req = axum::http::Request::builder()
+            .method(Method::POST)
+            .uri(*path)
+            .header(HOST, "example.com")
+            .body(Body::from(*body))
+            .unwrap();
+        let remote_addr = https_from.unwrap_or([203, 0, 113, 1]);
+        req.extensions_mut().insert(ConnectionInfo {
+            local_addr: ([127, 0, 0, 1], 10000).into(),
+            remote_addr: (remote_addr, 50000).into(),
+        });
+        if https_from.is_some() {
+            req.headers_mut()
+                .insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        }
+
+        let routed = http_conn_mgr.get_cluster(&req);
+        let resp = route(&http_conn_mgr, routed, None, req).await;
+        assert_eq!(*status, resp.status().as_u16(), "{} {:?}", path, https_from);
+        let resp_body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(
+            expected.as_bytes(),
+            &resp_body[..],
+            "{} {:?}",
+            path,
+            https_from
+        );
+    }
+}
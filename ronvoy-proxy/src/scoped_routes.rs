@@ -0,0 +1,374 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use axum::http::HeaderMap;
+use envoy_control_plane::envoy::config::route::v3::{
+    scoped_route_configuration::key::fragment::Type as V3KeyFragmentType,
+    ScopedRouteConfiguration as V3ScopedRouteConfiguration,
+};
+use envoy_control_plane::envoy::extensions::filters::network::http_connection_manager::v3::{
+    scoped_routes::scope_key_builder::fragment_builder::header_value_extractor::ExtractType as V3ExtractType,
+    scoped_routes::scope_key_builder::fragment_builder::HeaderValueExtractor as V3HeaderValueExtractor,
+    scoped_routes::scope_key_builder::fragment_builder::Type as V3FragmentBuilderType,
+    scoped_routes::ConfigSpecifier as V3ConfigSpecifier,
+    scoped_routes::ScopeKeyBuilder as V3ScopeKeyBuilder, ScopedRoutes as V3ScopedRoutes,
+};
+
+use crate::extensions::filter::network::http_connection_manager::{Error, RouteConfig};
+
+/// ScopeKey identifies a scope: one string per fragment of the key builder.
+type ScopeKey = Vec<String>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Extract {
+    /// Whole uses the entire header value as the fragment.
+    Whole,
+    /// Index uses the n-th element of the header value.
+    Index(usize),
+    /// Element uses the value of the `key<separator>value` element with the given key.
+    Element { separator: String, key: String },
+}
+
+/// HeaderValueExtractor builds one fragment of a scope key from a request header.
+#[derive(Debug, Clone, PartialEq)]
+struct HeaderValueExtractor {
+    name: String,
+    element_separator: String,
+    extract: Extract,
+}
+
+impl HeaderValueExtractor {
+    fn extract(&self, headers: &HeaderMap) -> Option<String> {
+        let value = headers.get(self.name.as_str())?.to_str().ok()?;
+        let mut elements = value.split(self.element_separator.as_str()).map(str::trim);
+        let fragment = match &self.extract {
+            Extract::Whole => value,
+            Extract::Index(i) => elements.nth(*i)?,
+            Extract::Element { separator, key } => elements
+                .filter_map(|element| element.split_once(separator.as_str()))
+                .find(|(k, _)| *k == key.as_str())
+                .map(|(_, v)| v)?,
+        };
+        Some(fragment.to_owned())
+    }
+}
+
+impl TryFrom<V3HeaderValueExtractor> for HeaderValueExtractor {
+    type Error = Error;
+
+    fn try_from(value: V3HeaderValueExtractor) -> Result<Self, Self::Error> {
+        if value.name.is_empty() {
+            return Err(Error::BadScopeKeyBuilder("missing header name".to_owned()));
+        }
+        let extract = match (value.element_separator.is_empty(), value.extract_type) {
+            // without a separator, the whole value is the only element
+            (true, Some(V3ExtractType::Index(0))) | (true, None) => Extract::Whole,
+            (true, _) => {
+                return Err(Error::BadScopeKeyBuilder(format!(
+                    "header {} needs an element_separator",
+                    value.name
+                )))
+            }
+            (false, Some(V3ExtractType::Index(i))) => Extract::Index(i as usize),
+            (false, Some(V3ExtractType::Element(element))) if !element.separator.is_empty() => {
+                Extract::Element {
+                    separator: element.separator,
+                    key: element.key,
+                }
+            }
+            (false, _) => {
+                return Err(Error::BadScopeKeyBuilder(format!(
+                    "header {} has an invalid extract_type",
+                    value.name
+                )))
+            }
+        };
+        Ok(HeaderValueExtractor {
+            name: value.name.to_ascii_lowercase(),
+            element_separator: value.element_separator,
+            extract,
+        })
+    }
+}
+
+/// ScopeKeyBuilder builds a request's scope key from fragments of its headers.
+#[derive(Debug, Clone, PartialEq)]
+struct ScopeKeyBuilder {
+    fragments: Vec<HeaderValueExtractor>,
+}
+
+impl ScopeKeyBuilder {
+    /// build returns the scope key for a request, or None if any fragment is missing.
+    fn build(&self, headers: &HeaderMap) -> Option<ScopeKey> {
+        self.fragments
+            .iter()
+            .map(|fragment| fragment.extract(headers))
+            .collect()
+    }
+}
+
+impl TryFrom<V3ScopeKeyBuilder> for ScopeKeyBuilder {
+    type Error = Error;
+
+    fn try_from(value: V3ScopeKeyBuilder) -> Result<Self, Self::Error> {
+        let fragments = value
+            .fragments
+            .into_iter()
+            .map(|fragment| match fragment.r#type {
+                Some(V3FragmentBuilderType::HeaderValueExtractor(extractor)) => {
+                    HeaderValueExtractor::try_from(extractor)
+                }
+                None => Err(Error::BadScopeKeyBuilder("missing fragment".to_owned())),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if fragments.is_empty() {
+            return Err(Error::BadScopeKeyBuilder("no fragments".to_owned()));
+        }
+        Ok(ScopeKeyBuilder { fragments })
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Scopes {
+    keys: HashMap<String, ScopeKey>,
+    route_configs: HashMap<ScopeKey, Arc<RouteConfig>>,
+}
+
+/// ScopedRoutes picks the route configuration for a request by its scope key,
+/// which is built from request headers, like Envoy's scoped RDS.  Scopes can be
+/// added, replaced and removed individually while requests are being routed.
+#[derive(Debug, Clone)]
+pub struct ScopedRoutes {
+    key_builder: ScopeKeyBuilder,
+    scopes: Arc<ArcSwap<Scopes>>,
+}
+
+impl ScopedRoutes {
+    /// route_config returns the route configuration of a request's scope.
+    pub fn route_config(&self, headers: &HeaderMap) -> Option<Arc<RouteConfig>> {
+        let key = self.key_builder.build(headers)?;
+        self.scopes.load().route_configs.get(&key).cloned()
+    }
+
+    /// key_headers returns the names of the headers scope keys are built from.
+    pub fn key_headers(&self) -> impl Iterator<Item = &str> {
+        self.key_builder
+            .fragments
+            .iter()
+            .map(|fragment| fragment.name.as_str())
+    }
+
+    /// update adds or replaces the `updated` scopes and removes the scopes named
+    /// in `removed`, as an SRDS (incremental) update would.  If any scope is
+    /// invalid, none of the changes are made.
+    // TODO: subscribe to SRDS updates once Ronvoy has an xDS client
+    pub fn update(
+        &self,
+        updated: Vec<V3ScopedRouteConfiguration>,
+        removed: &[String],
+    ) -> Result<(), Error> {
+        let mut scopes = Scopes::clone(&self.scopes.load());
+        for name in removed.iter() {
+            if let Some(key) = scopes.keys.remove(name) {
+                scopes.route_configs.remove(&key);
+            }
+        }
+        for config in updated {
+            let name = config.name;
+            let key = config
+                .key
+                .and_then(|key| {
+                    key.fragments
+                        .into_iter()
+                        .map(|fragment| fragment.r#type.map(|V3KeyFragmentType::StringKey(s)| s))
+                        .collect::<Option<ScopeKey>>()
+                })
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| Error::BadScopeKey(name.clone()))?;
+            let route_config = config
+                .route_configuration
+                .ok_or_else(|| Error::UnsupportedScopedRouteConfig(name.clone()))?;
+            let route_config = Arc::new(RouteConfig::try_from(route_config)?);
+
+            if let Some(old_key) = scopes.keys.remove(&name) {
+                scopes.route_configs.remove(&old_key);
+            }
+            if scopes.route_configs.contains_key(&key) {
+                return Err(Error::DuplicateScopeKey(name));
+            }
+            scopes.route_configs.insert(key.clone(), route_config);
+            scopes.keys.insert(name, key);
+        }
+        self.scopes.store(Arc::new(scopes));
+        Ok(())
+    }
+}
+
+impl TryFrom<V3ScopedRoutes> for ScopedRoutes {
+    type Error = Error;
+
+    fn try_from(value: V3ScopedRoutes) -> Result<Self, Self::Error> {
+        let key_builder = ScopeKeyBuilder::try_from(
+            value
+                .scope_key_builder
+                .ok_or(Error::MissingScopeKeyBuilder)?,
+        )?;
+        let scoped_routes = ScopedRoutes {
+            key_builder,
+            scopes: Default::default(),
+        };
+        match value.config_specifier {
+            Some(V3ConfigSpecifier::ScopedRouteConfigurationsList(list)) => {
+                scoped_routes.update(list.scoped_route_configurations, &[])?;
+            }
+            Some(V3ConfigSpecifier::ScopedRds(_)) => return Err(Error::UnsupportedScopedRds),
+            None => return Err(Error::MissingScopedRouteConfigs),
+        }
+        Ok(scoped_routes)
+    }
+}
+
+#[test]
+fn test_scope_key_builder() {
+    use axum::http::HeaderValue;
+
+    let extractor = |name: &str, element_separator: &str, extract: Extract| HeaderValueExtractor {
+        name: name.to_owned(),
+        element_separator: element_separator.to_owned(),
+        extract,
+    };
+    let key_builder = ScopeKeyBuilder {
+        fragments: vec![
+            extractor("x-tenant", "", Extract::Whole),
+            extractor("x-path", "/", Extract::Index(1)),
+            extractor(
+                "cookie",
+                ";",
+                Extract::Element {
+                    separator: "=".to_owned(),
+                    key: "region".to_owned(),
+                },
+            ),
+        ],
+    };
+
+    let cases: &[(&[(&str, &str)], Option<&[&str]>)] = &[
+        (
+            &[
+                ("x-tenant", "acme"),
+                ("x-path", "/v1/users"),
+                ("cookie", "session=abc; region=eu"),
+            ],
+            Some(&["acme", "v1", "eu"]),
+        ),
+        (&[("x-tenant", "acme"), ("x-path", "/v1/users")], None),
+        (
+            &[
+                ("x-tenant", "acme"),
+                ("x-path", "v1"),
+                ("cookie", "region=us"),
+            ],
+            None,
+        ),
+        (
+            &[
+                ("x-tenant", "acme"),
+                ("x-path", "/v1"),
+                ("cookie", "session=abc"),
+            ],
+            None,
+        ),
+    ];
+
+    for (headers, expected) in cases.iter() {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            header_map.insert(*name, HeaderValue::from_static(*value));
+        }
+        let expected: Option<ScopeKey> =
+            expected.map(|key| key.iter().map(|s| s.to_string()).collect());
+        assert_eq!(expected, key_builder.build(&header_map), "{:?}", headers);
+    }
+}
+
+#[test]
+fn test_update() {
+    use axum::http::HeaderValue;
+    use envoy_control_plane::envoy::config::route::v3::scoped_route_configuration::{
+        key::Fragment as V3KeyFragment, Key as V3Key,
+    };
+
+    let scoped_routes = ScopedRoutes {
+        key_builder: ScopeKeyBuilder {
+            fragments: vec![HeaderValueExtractor {
+                name: "x-tenant".to_owned(),
+                element_separator: "".to_owned(),
+                extract: Extract::Whole,
+            }],
+        },
+        scopes: Default::default(),
+    };
+    let scope = |name: &str, key: &str| V3ScopedRouteConfiguration {
+        name: name.to_owned(),
+        key: Some(V3Key {
+            fragments: vec![V3KeyFragment {
+                r#type: Some(V3KeyFragmentType::StringKey(key.to_owned())),
+            }],
+        }),
+        route_configuration: Some(Default::default()),
+        ..Default::default()
+    };
+
+    // each update is applied to the scopes left by the previous ones: (scopes
+    // added or replaced, as (name, key), scopes removed, the result, and the keys
+    // requests can be routed by afterwards)
+    let cases: &[(&[(&str, &str)], &[&str], Result<(), Error>, &[&str])] = &[
+        (
+            &[("a", "acme"), ("b", "bravo")],
+            &[],
+            Ok(()),
+            &["acme", "bravo"],
+        ),
+        // replacing a scope drops its old key
+        (&[("a", "alpha")], &[], Ok(()), &["alpha", "bravo"]),
+        (&[], &["b"], Ok(()), &["alpha"]),
+        // a failed update changes nothing, not even the scopes before the bad one
+        (
+            &[("c", "charlie"), ("d", "alpha")],
+            &["a"],
+            Err(Error::DuplicateScopeKey("d".to_owned())),
+            &["alpha"],
+        ),
+        // a key is free again once its scope moves off it
+        (
+            &[("a", "bravo"), ("c", "alpha")],
+            &[],
+            Ok(()),
+            &["alpha", "bravo"],
+        ),
+    ];
+
+    for (updated, removed, expected, keys) in cases.iter() {
+        let scopes = updated.iter().map(|(name, key)| scope(name, key)).collect();
+        let removed: Vec<String> = removed.iter().map(|name| name.to_string()).collect();
+        let result = scoped_routes.update(scopes, &removed);
+        assert_eq!(*expected, result, "{:?} {:?}", updated, removed);
+        for key in ["acme", "alpha", "bravo", "charlie"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-tenant", HeaderValue::from_static(key));
+            assert_eq!(
+                keys.contains(&key),
+                scoped_routes.route_config(&headers).is_some(),
+                "{:?} {:?}: {}",
+                updated,
+                removed,
+                key
+            );
+        }
+    }
+}
@@ -2,17 +2,53 @@
 // Use of this source code is governed by the Apache License,
 // Version 2.0, that can be found in the LICENSE file.
 
+use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 
+use arc_swap::ArcSwap;
 use axum::{routing::get, Router};
+use envoy_control_plane::envoy::config::core::v3::{
+    grpc_service::EnvoyGrpc as V3EnvoyGrpc, grpc_service::TargetSpecifier as V3TargetSpecifier,
+    GrpcService as V3GrpcService, HeaderValue, HeaderValueOption,
+};
+use envoy_control_plane::envoy::config::route::v3::{
+    route::Action as V3Action, route_action::ClusterSpecifier as V3ClusterSpecifier,
+    route_match::PathSpecifier as V3PathSpecifier, Route as V3Route, RouteAction as V3RouteAction,
+    RouteConfiguration as V3RouteConfiguration, RouteMatch as V3RouteMatch,
+    VirtualHost as V3VirtualHost,
+};
+use envoy_control_plane::envoy::extensions::filters::network::http_connection_manager::v3::{
+    http_connection_manager::RouteSpecifier, HttpConnectionManager as V3HttpConnectionManager,
+};
+use envoy_control_plane::envoy::r#type::v3::HttpStatus;
+use envoy_control_plane::envoy::service::auth::v3::{
+    authorization_server::{Authorization, AuthorizationServer},
+    check_response::HttpResponse,
+    CheckRequest, CheckResponse, DeniedHttpResponse, OkHttpResponse,
+};
 use envoy_control_plane::envoy::service::discovery::v3::{
     DeltaDiscoveryRequest, DeltaDiscoveryResponse, DiscoveryRequest, DiscoveryResponse,
 };
 use envoy_control_plane::envoy::service::listener::v3::listener_discovery_service_server::ListenerDiscoveryService;
+use envoy_control_plane::envoy::service::ratelimit::v3::{
+    rate_limit_response::rate_limit::Unit as RateLimitUnit,
+    rate_limit_response::{
+        Code as RateLimitCode, DescriptorStatus, RateLimit as RateLimitResponseLimit,
+    },
+    rate_limit_service_server::{RateLimitService, RateLimitServiceServer},
+    RateLimitRequest, RateLimitResponse,
+};
+use envoy_control_plane::google::rpc::Status as RpcStatus;
+use envoy_control_plane::prost_wkt_types::Duration as PbDuration;
 use futures::Stream;
 use tonic::{Request, Response, Status, Streaming};
 
+use crate::address;
+use crate::cluster::{Cluster, Clusters};
+use crate::extensions::filter::network::http_connection_manager::HttpConnectionManager;
+
 pub(crate) const TEST_HANDLER_RESPONSE: &str = "hi there";
 
 #[cfg(test)]
@@ -33,8 +69,11 @@ pub(crate) struct TestHttpServer {
 
 impl TestHttpServer {
     pub(crate) fn new() -> Self {
-        let app = Router::new().route("/", get(test_handler));
+        Self::with_router(Router::new().route("/", get(test_handler)))
+    }
 
+    /// with_router starts a TestHttpServer that serves `app`.
+    pub(crate) fn with_router(app: Router) -> Self {
         let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
 
         let any_addr = SocketAddr::from(([127, 0, 0, 1], 0));
@@ -85,3 +124,261 @@ impl ListenerDiscoveryService for StaticLDS {
         Err(Status::unimplemented("TODO 3".to_owned()))
     }
 }
+
+/// test_clusters returns Clusters with a single cluster, `name`, whose only host is `addr`.
+pub(crate) fn test_clusters(name: &str, addr: SocketAddr) -> Clusters {
+    use envoy_control_plane::envoy::config::cluster::v3::Cluster as V3Cluster;
+    use envoy_control_plane::envoy::config::endpoint::v3::{
+        lb_endpoint::HostIdentifier, ClusterLoadAssignment, Endpoint, LbEndpoint,
+        LocalityLbEndpoints,
+    };
+
+    let v3_cluster = V3Cluster {
+        name: name.to_owned(),
+        load_assignment: Some(ClusterLoadAssignment {
+            cluster_name: name.to_owned(),
+            endpoints: vec![LocalityLbEndpoints {
+                lb_endpoints: vec![LbEndpoint {
+                    host_identifier: Some(HostIdentifier::Endpoint(Endpoint {
+                        address: Some(address::to_v3(addr)),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let cluster = Cluster::try_from(v3_cluster).unwrap();
+    let clusters = HashMap::from([(name.to_owned(), Arc::new(cluster))]);
+    ArcSwap::from_pointee(clusters)
+}
+
+/// test_route returns a route, `name`, sending requests for paths starting with
+/// `prefix` to `cluster`.
+pub(crate) fn test_route(name: &str, prefix: &str, cluster: &str) -> V3Route {
+    V3Route {
+        name: name.to_owned(),
+        r#match: Some(V3RouteMatch {
+            path_specifier: Some(V3PathSpecifier::Prefix(prefix.to_owned())),
+            ..Default::default()
+        }),
+        action: Some(V3Action::Route(V3RouteAction {
+            cluster_specifier: Some(V3ClusterSpecifier::Cluster(cluster.to_owned())),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+/// test_http_conn_mgr returns an HttpConnectionManager with `routes` in a single
+/// virtual host for any domain.
+pub(crate) fn test_http_conn_mgr(
+    routes: Vec<V3Route>,
+    clusters: Clusters,
+) -> HttpConnectionManager {
+    let route_config = V3RouteConfiguration {
+        virtual_hosts: vec![V3VirtualHost {
+            name: "test".to_owned(),
+            domains: vec!["*".to_owned()],
+            routes,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let v3_http_conn_mgr = V3HttpConnectionManager {
+        route_specifier: Some(RouteSpecifier::RouteConfig(route_config)),
+        ..Default::default()
+    };
+    HttpConnectionManager::try_from((v3_http_conn_mgr, Arc::new(clusters))).unwrap()
+}
+
+/// test_grpc_service returns a gRPC service config for the service hosted by
+/// `cluster`.
+pub(crate) fn test_grpc_service(cluster: &str) -> V3GrpcService {
+    V3GrpcService {
+        target_specifier: Some(V3TargetSpecifier::EnvoyGrpc(V3EnvoyGrpc {
+            cluster_name: cluster.to_owned(),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+}
+
+/// TestAuthorizer is a stand-in external authorization service: requests with the
+/// header `Authorization: Bearer alice` are allowed, and forwarded as user alice
+/// (`x-user: alice`).  Other requests are denied with a 401.
+struct TestAuthorizer;
+
+#[tonic::async_trait]
+impl Authorization for TestAuthorizer {
+    async fn check(
+        &self,
+        request: Request<CheckRequest>,
+    ) -> Result<Response<CheckResponse>, Status> {
+        let headers = request
+            .into_inner()
+            .attributes
+            .and_then(|attributes| attributes.request)
+            .and_then(|request| request.http)
+            .map(|http| http.headers)
+            .unwrap_or_default();
+        let header = |key: &str, value: &str| HeaderValueOption {
+            header: Some(HeaderValue {
+                key: key.to_owned(),
+                value: value.to_owned(),
+            }),
+            ..Default::default()
+        };
+
+        let resp = if headers.get("authorization").map(String::as_str) == Some("Bearer alice") {
+            CheckResponse {
+                status: Some(RpcStatus::default()),
+                http_response: Some(HttpResponse::OkResponse(OkHttpResponse {
+                    headers: vec![header("x-user", "alice")],
+                    ..Default::default()
+                })),
+                ..Default::default()
+            }
+        } else {
+            CheckResponse {
+                status: Some(RpcStatus {
+                    code: tonic::Code::PermissionDenied as i32,
+                    ..Default::default()
+                }),
+                http_response: Some(HttpResponse::DeniedResponse(DeniedHttpResponse {
+                    status: Some(HttpStatus { code: 401 }),
+                    headers: vec![header("www-authenticate", "Bearer")],
+                    body: "denied".to_owned(),
+                })),
+                ..Default::default()
+            }
+        };
+        Ok(Response::new(resp))
+    }
+}
+
+/// TestAuthorizationServer serves TestAuthorizer over gRPC on `addr`, until it is dropped.
+pub(crate) struct TestAuthorizationServer {
+    pub(crate) addr: SocketAddr,
+    #[allow(dead_code)]
+    shutdown_tx: tokio::sync::oneshot::Sender<()>,
+}
+
+impl TestAuthorizationServer {
+    pub(crate) fn new() -> Self {
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let any_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let service = AuthorizationServer::new(TestAuthorizer);
+        let server = hyper::Server::bind(&any_addr)
+            .http2_only(true)
+            .serve(tower::make::Shared::new(service));
+
+        let addr = server.local_addr();
+
+        let server = server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+
+        tokio::spawn(async move {
+            server.await.unwrap();
+        });
+
+        Self { addr, shutdown_tx }
+    }
+}
+
+/// TestRateLimiter is a stand-in rate limit service, which decides by the value of
+/// the `limit` entries of the descriptors: "over" is over the limit, and "fail"
+/// fails the call.  Each descriptor's limit is 10 requests a minute, with 30
+/// seconds until the window resets.  Allowed requests are forwarded with
+/// `x-ratelimit-checked: true`.
+struct TestRateLimiter;
+
+#[tonic::async_trait]
+impl RateLimitService for TestRateLimiter {
+    async fn should_rate_limit(
+        &self,
+        request: Request<RateLimitRequest>,
+    ) -> Result<Response<RateLimitResponse>, Status> {
+        let mut overall_code = RateLimitCode::Ok;
+        let mut statuses = vec![];
+        for descriptor in request.into_inner().descriptors.iter() {
+            let value = descriptor
+                .entries
+                .iter()
+                .find(|entry| entry.key == "limit")
+                .map(|entry| entry.value.as_str());
+            let (code, remaining) = match value {
+                Some("fail") => return Err(Status::unavailable("rate limiter is down")),
+                Some("over") => (RateLimitCode::OverLimit, 0),
+                _ => (RateLimitCode::Ok, 9),
+            };
+            if code == RateLimitCode::OverLimit {
+                overall_code = code;
+            }
+            statuses.push(DescriptorStatus {
+                code: code as i32,
+                current_limit: Some(RateLimitResponseLimit {
+                    name: "per_minute".to_owned(),
+                    requests_per_unit: 10,
+                    unit: RateLimitUnit::Minute as i32,
+                }),
+                limit_remaining: remaining,
+                duration_until_reset: Some(PbDuration {
+                    seconds: 30,
+                    nanos: 0,
+                }),
+                ..Default::default()
+            });
+        }
+        let request_headers_to_add = if overall_code == RateLimitCode::Ok {
+            vec![HeaderValue {
+                key: "x-ratelimit-checked".to_owned(),
+                value: "true".to_owned(),
+            }]
+        } else {
+            vec![]
+        };
+        Ok(Response::new(RateLimitResponse {
+            overall_code: overall_code as i32,
+            statuses,
+            request_headers_to_add,
+            ..Default::default()
+        }))
+    }
+}
+
+/// TestRateLimitServer serves TestRateLimiter over gRPC on `addr`, until it is dropped.
+pub(crate) struct TestRateLimitServer {
+    pub(crate) addr: SocketAddr,
+    #[allow(dead_code)]
+    shutdown_tx: tokio::sync::oneshot::Sender<()>,
+}
+
+impl TestRateLimitServer {
+    pub(crate) fn new() -> Self {
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+
+        let any_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let service = RateLimitServiceServer::new(TestRateLimiter);
+        let server = hyper::Server::bind(&any_addr)
+            .http2_only(true)
+            .serve(tower::make::Shared::new(service));
+
+        let addr = server.local_addr();
+
+        let server = server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+
+        tokio::spawn(async move {
+            server.await.unwrap();
+        });
+
+        Self { addr, shutdown_tx }
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2022 The Ronvoy Authors. All rights reserved.
+// Use of this source code is governed by the Apache License,
+// Version 2.0, that can be found in the LICENSE file.
+
+use std::io;
+use std::time::Duration;
+
+use axum::body::{Body, Bytes};
+use axum::http::header::{CONNECTION, UPGRADE};
+use axum::http::{HeaderMap, Method};
+use hyper::body::HttpBody;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const TUNNEL_BUFFER_SIZE: usize = 16 * 1024;
+
+/// CONNECT_UPGRADE_TYPE is the upgrade type Envoy treats CONNECT requests as.
+pub const CONNECT_UPGRADE_TYPE: &str = "connect";
+
+/// upgrade_type returns the (lowercase) protocol a request asks to upgrade to,
+/// like "websocket", if it is an HTTP/1.1 upgrade request.  CONNECT requests are
+/// treated as upgrades to "connect".  Like Envoy, h2c upgrades aren't proxied:
+/// their headers are stripped and the request is forwarded as a regular
+/// HTTP/1.1 request.
+pub fn upgrade_type(method: &Method, headers: &mut HeaderMap) -> Option<String> {
+    if method == Method::CONNECT {
+        return Some(CONNECT_UPGRADE_TYPE.to_owned());
+    }
+    let connection_upgrade = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    if !connection_upgrade {
+        return None;
+    }
+    let upgrade_type = headers
+        .get(UPGRADE)?
+        .to_str()
+        .ok()?
+        .trim()
+        .to_ascii_lowercase();
+    if upgrade_type == "h2c" {
+        headers.remove(UPGRADE);
+        headers.remove("http2-settings");
+        headers.remove(CONNECTION);
+        return None;
+    }
+    Some(upgrade_type)
+}
+
+/// tunnel copies bytes in both directions between a downstream and an upstream
+/// connection until both sides have closed.  If `idle_timeout` is set, the
+/// tunnel is torn down when no bytes have flowed in either direction for that long.
+pub async fn tunnel<D, U>(
+    mut downstream: D,
+    mut upstream: U,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()>
+where
+    D: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut downstream_buf = vec![0u8; TUNNEL_BUFFER_SIZE];
+    let mut upstream_buf = vec![0u8; TUNNEL_BUFFER_SIZE];
+    let mut downstream_open = true;
+    let mut upstream_open = true;
+
+    while downstream_open || upstream_open {
+        // recreated on each iteration, so any activity resets it
+        let idle = async {
+            match idle_timeout {
+                Some(timeout) => tokio::time::sleep(timeout).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            n = downstream.read(&mut downstream_buf), if downstream_open => {
+                let n = n?;
+                if n == 0 {
+                    downstream_open = false;
+                    upstream.shutdown().await?;
+                } else {
+                    upstream.write_all(&downstream_buf[..n]).await?;
+                }
+            }
+            n = upstream.read(&mut upstream_buf), if upstream_open => {
+                let n = n?;
+                if n == 0 {
+                    upstream_open = false;
+                    downstream.shutdown().await?;
+                } else {
+                    downstream.write_all(&upstream_buf[..n]).await?;
+                }
+            }
+            _ = idle => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "tunnel idle timeout"));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// tunnel_body tunnels a request body to `upstream` as raw TCP, returning a body
+/// that streams back whatever the upstream sends.  It is how POST requests are
+/// tunneled on routes that allow it.
+pub fn tunnel_body(mut body: Body, upstream: TcpStream) -> Body {
+    let (mut reader, mut writer) = upstream.into_split();
+    tokio::spawn(async move {
+        while let Some(Ok(chunk)) = body.data().await {
+            if writer.write_all(&chunk).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let (mut sender, resp_body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; TUNNEL_BUFFER_SIZE];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(n) => {
+                    if sender
+                        .send_data(Bytes::copy_from_slice(&buf[..n]))
+                        .await
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    resp_body
+}
+
+#[test]
+fn test_upgrade_type() {
+    let cases: &[(&[(&str, &str)], Option<&str>)] = &[
+        (&[], None),
+        (&[("upgrade", "websocket")], None),
+        (
+            &[("connection", "Upgrade"), ("upgrade", "WebSocket")],
+            Some("websocket"),
+        ),
+        (
+            &[
+                ("connection", "keep-alive, upgrade"),
+                ("upgrade", "websocket"),
+            ],
+            Some("websocket"),
+        ),
+        (&[("connection", "keep-alive")], None),
+        (&[("connection", "upgrade"), ("upgrade", "h2c")], None),
+    ];
+
+    for (headers, expected) in cases.iter() {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers.iter() {
+            header_map.append(*name, value.parse().unwrap());
+        }
+        assert_eq!(
+            *expected,
+            upgrade_type(&Method::GET, &mut header_map).as_deref(),
+            "{:?}",
+            headers
+        );
+    }
+
+    let mut header_map = HeaderMap::new();
+    assert_eq!(
+        Some(CONNECT_UPGRADE_TYPE),
+        upgrade_type(&Method::CONNECT, &mut header_map).as_deref()
+    );
+}
+
+#[tokio::test]
+async fn test_tunnel_idle_timeout() {
+    let (downstream, mut client) = tokio::io::duplex(64);
+    let (upstream, mut server) = tokio::io::duplex(64);
+    let tunnel = tokio::spawn(tunnel(
+        downstream,
+        upstream,
+        Some(Duration::from_millis(50)),
+    ));
+
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"ping", &buf);
+
+    server.write_all(b"pong").await.unwrap();
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(b"pong", &buf);
+
+    // with no further traffic the tunnel gives up
+    let err = tunnel.await.unwrap().unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, err.kind());
+}